@@ -0,0 +1,473 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context as _, Error, Result, bail};
+use humantime::{format_rfc3339_millis, format_rfc3339_seconds};
+use hyper::{
+    Method, Response, StatusCode, Version,
+    body::{Body, Bytes, Frame, SizeHint},
+    header::HeaderValue,
+};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::proxy::ResponseBody;
+
+/// Format of the per-request access log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// No access log.
+    Off,
+    /// A human-readable line logged through `tracing` under the `access_log` target.
+    Text,
+    /// One JSON object per line, written directly to the access log output.
+    Json,
+    /// Apache's Common Log Format, written directly to the access log output.
+    Clf,
+    /// Apache's Combined Log Format (Common Log Format with the referrer and user agent), written
+    /// directly to the access log output.
+    Combined,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "clf" => Ok(Self::Clf),
+            "combined" => Ok(Self::Combined),
+            _ => bail!(
+                "Unknown access log format {:?}, expected off, text, json, clf or combined",
+                s
+            ),
+        }
+    }
+}
+
+/// When the access log file is rotated.
+#[derive(Clone, Copy, Debug)]
+pub struct Rotation {
+    /// Size in bytes the file may reach before it is rotated, if limited.
+    pub max_size: Option<u64>,
+    /// How long a file is written to before it is rotated, if limited.
+    pub max_age: Option<Duration>,
+    /// Number of rotated files kept, as `<file>.1` (the newest) to `<file>.<max_files>`.
+    pub max_files: usize,
+}
+
+/// An access log file, rotated by size or age.
+pub struct LogFile {
+    /// Path of the current file.
+    path: PathBuf,
+    /// The current file, opened for appending.
+    file: File,
+    /// Size of the current file in bytes.
+    size: u64,
+    /// When the current file was created.
+    created: SystemTime,
+    /// When the file is rotated.
+    rotation: Rotation,
+}
+
+impl LogFile {
+    /// Append to the file at `path`, creating it if needed
+    fn open(path: &Path, rotation: Rotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            size: metadata.len(),
+            // Files kept across restarts and reloads are rotated by their original age
+            created: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            file,
+            rotation,
+        })
+    }
+
+    /// Append a line, rotating the file first if it is due
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.is_due(line.len() as u64)
+            && let Err(e) = self.rotate()
+        {
+            // Keep logging to the current file rather than losing lines
+            warn!(
+                "Failed to rotate access log file {}: {:?}",
+                self.path.display(),
+                e
+            );
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Whether the file should be rotated before appending `len` more bytes
+    ///
+    /// Empty files are never rotated, so a single line larger than the limit still gets written.
+    fn is_due(&self, len: u64) -> bool {
+        self.size > 0
+            && (self
+                .rotation
+                .max_size
+                .is_some_and(|max_size| self.size + len > max_size)
+                || self
+                    .rotation
+                    .max_age
+                    .is_some_and(|max_age| self.created.elapsed().is_ok_and(|age| age >= max_age)))
+    }
+
+    /// Shift the rotated files along, dropping the oldest, and start a new current file
+    fn rotate(&mut self) -> io::Result<()> {
+        // Another writer, such as the output of the configuration before a reload, may have
+        // rotated the file already, in which case only the new file needs opening
+        if names(&self.path, &self.file) {
+            if self.rotation.max_files == 0 {
+                fs::remove_file(&self.path)?;
+            } else {
+                for index in (1..self.rotation.max_files).rev() {
+                    match fs::rename(self.rotated(index), self.rotated(index + 1)) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => {}
+                    }
+                }
+                fs::rename(&self.path, self.rotated(1))?;
+            }
+        }
+
+        *self = Self::open(&self.path, self.rotation)?;
+        Ok(())
+    }
+
+    /// Get the path of the `index`th newest rotated file
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+}
+
+/// Whether `path` still names `file`, rather than having been moved away
+fn names(path: &Path, file: &File) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        match (fs::metadata(path), file.metadata()) {
+            (Ok(current), Ok(open)) => current.dev() == open.dev() && current.ino() == open.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = file;
+        path.exists()
+    }
+}
+
+/// Where access log lines are written, except for the text format which goes through `tracing`.
+#[derive(Clone)]
+pub enum AccessLogOutput {
+    /// Standard output.
+    Stdout,
+    /// A file opened for appending, rotated by size or age.
+    File(Arc<Mutex<LogFile>>),
+}
+
+impl AccessLogOutput {
+    /// Append to the file at `path`, creating it if needed, or write to stdout when unset.
+    pub fn open(path: Option<&Path>, rotation: Rotation) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::Stdout);
+        };
+
+        let file = LogFile::open(path, rotation)
+            .with_context(|| format!("Failed to open access log file {}", path.display()))?;
+        Ok(Self::File(Arc::new(Mutex::new(file))))
+    }
+
+    /// Write a line in a single write, so lines from concurrent requests don't interleave.
+    fn write_line(&self, mut line: String) {
+        line.push('\n');
+        let result = match self {
+            Self::Stdout => std::io::stdout().lock().write_all(line.as_bytes()),
+            Self::File(file) => file
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .write_line(line.as_bytes()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to write access log: {:?}", e);
+        }
+    }
+
+    /// Flush the lines written so far to stdout or to disk, e.g. before shutting down.
+    pub fn flush(&self) {
+        let result = match self {
+            Self::Stdout => std::io::stdout().lock().flush(),
+            Self::File(file) => file
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .file
+                .sync_data(),
+        };
+        if let Err(e) = result {
+            warn!("Failed to flush access log: {:?}", e);
+        }
+    }
+}
+
+/// Time spent waiting for the backend, attached to responses as an extension.
+#[derive(Clone, Copy, Debug)]
+pub struct UpstreamLatency(pub Duration);
+
+/// ALPN protocol negotiated on a TLS client connection, attached to its requests as an extension.
+#[derive(Clone, Copy, Debug)]
+pub struct Alpn(pub &'static str);
+
+/// Request details recorded in the access log.
+pub struct AccessLogEntry {
+    /// When the request was received.
+    pub timestamp: SystemTime,
+    /// The client IP.
+    pub client_ip: IpAddr,
+    /// The request method.
+    pub method: Method,
+    /// The request path.
+    pub path: String,
+    /// The query string of the request, if any.
+    pub query: Option<String>,
+    /// The HTTP version of the request.
+    pub version: Version,
+    /// The ALPN protocol negotiated on the client connection, or `None` for plaintext ones.
+    pub alpn: Option<&'static str>,
+    /// The user name sent with HTTP Basic authentication, when the proxy checks it.
+    pub user: Option<String>,
+    /// The `Referer` header of the request.
+    pub referer: Option<HeaderValue>,
+    /// The `User-Agent` header of the request.
+    pub user_agent: Option<HeaderValue>,
+    /// The ID used to correlate the request across services.
+    pub request_id: String,
+}
+
+impl AccessLogEntry {
+    /// Write the entry once the response has been fully sent.
+    fn write(
+        &self,
+        format: AccessLogFormat,
+        output: &AccessLogOutput,
+        status: StatusCode,
+        upstream_latency: Option<Duration>,
+        bytes_sent: u64,
+    ) {
+        let upstream_latency_ms = upstream_latency.map(|latency| latency.as_secs_f64() * 1000.0);
+
+        match format {
+            AccessLogFormat::Off => {}
+            AccessLogFormat::Text => info!(
+                target: "access_log",
+                "{} \"{} {}\" {} {} upstream_ms={} alpn={} request_id={}",
+                self.client_ip,
+                self.method,
+                self.path,
+                status.as_u16(),
+                bytes_sent,
+                upstream_latency_ms.map_or("-".to_string(), |ms| format!("{:.3}", ms)),
+                self.alpn.unwrap_or("-"),
+                self.request_id
+            ),
+            AccessLogFormat::Json => {
+                let line = json!({
+                    "timestamp": format_rfc3339_millis(self.timestamp).to_string(),
+                    "client_ip": self.client_ip,
+                    "method": self.method.as_str(),
+                    "path": self.path,
+                    "status": status.as_u16(),
+                    "upstream_latency_ms": upstream_latency_ms,
+                    "bytes_sent": bytes_sent,
+                    "alpn": self.alpn,
+                    "request_id": self.request_id,
+                });
+                output.write_line(line.to_string());
+            }
+            AccessLogFormat::Clf | AccessLogFormat::Combined => {
+                let mut line = self.common_log_line(status, bytes_sent);
+                if format == AccessLogFormat::Combined {
+                    let _ = write!(
+                        line,
+                        " \"{}\" \"{}\"",
+                        escape_field(self.referer.as_ref().map(HeaderValue::as_bytes)),
+                        escape_field(self.user_agent.as_ref().map(HeaderValue::as_bytes))
+                    );
+                }
+                output.write_line(line);
+            }
+        }
+    }
+
+    /// Format the entry as `%h %l %u %t "%r" %>s %b` in Apache's Common Log Format, with `-` for
+    /// missing fields
+    fn common_log_line(&self, status: StatusCode, bytes_sent: u64) -> String {
+        let target = match self.query {
+            Some(ref query) => format!("{}?{}", self.path, query),
+            None => self.path.clone(),
+        };
+        let request_line = format!("{} {} {:?}", self.method, target, self.version);
+
+        format!(
+            "{} - {} [{}] \"{}\" {} {}",
+            self.client_ip,
+            escape_field(self.user.as_deref().map(str::as_bytes)),
+            common_log_time(self.timestamp),
+            escape_field(Some(request_line.as_bytes())),
+            status.as_u16(),
+            if bytes_sent == 0 {
+                "-".to_string()
+            } else {
+                bytes_sent.to_string()
+            }
+        )
+    }
+}
+
+/// Format a time as `10/Oct/2000:13:55:36 +0000`, the timestamp of the Common Log Format, in UTC
+fn common_log_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    // Formatted as `2000-10-10T13:55:36Z`
+    let rfc3339 = format_rfc3339_seconds(time).to_string();
+    let month = rfc3339[5..7].parse::<usize>().unwrap_or(1);
+    format!(
+        "{}/{}/{}:{} +0000",
+        &rfc3339[8..10],
+        MONTHS[month - 1],
+        &rfc3339[0..4],
+        &rfc3339[11..19]
+    )
+}
+
+/// Escape a log field the way Apache does, with `-` when it is missing or empty
+///
+/// Quotes and backslashes are escaped with a backslash and other unprintable bytes as `\xhh`, so
+/// a field can't break out of its quotes or inject a line.
+fn escape_field(field: Option<&[u8]>) -> String {
+    let Some(bytes) = field.filter(|bytes| !bytes.is_empty()) else {
+        return "-".to_string();
+    };
+
+    let mut escaped = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                escaped.push('\\');
+                escaped.push(byte as char);
+            }
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => {
+                let _ = write!(escaped, "\\x{:02x}", byte);
+            }
+        }
+    }
+    escaped
+}
+
+/// Attach access logging to a response, so the entry is written once its body has been sent
+/// (or the client went away).
+pub fn log_response(
+    response: Response<ResponseBody>,
+    format: AccessLogFormat,
+    output: &AccessLogOutput,
+    entry: AccessLogEntry,
+) -> Response<ResponseBody> {
+    if format == AccessLogFormat::Off {
+        return response;
+    }
+
+    let status = response.status();
+    let upstream_latency = response
+        .extensions()
+        .get::<UpstreamLatency>()
+        .map(|latency| latency.0);
+
+    response.map(|inner| {
+        ResponseBody::new(LoggedBody {
+            inner,
+            bytes_sent: 0,
+            format,
+            output: output.clone(),
+            status,
+            upstream_latency,
+            entry,
+        })
+    })
+}
+
+/// A response body that counts the bytes sent and writes the access log when dropped.
+struct LoggedBody {
+    /// The wrapped body.
+    inner: ResponseBody,
+    /// Data bytes passed on so far.
+    bytes_sent: u64,
+    /// The access log format.
+    format: AccessLogFormat,
+    /// Where the entry is written.
+    output: AccessLogOutput,
+    /// The response status.
+    status: StatusCode,
+    /// Time spent waiting for the backend, if it was contacted.
+    upstream_latency: Option<Duration>,
+    /// The request details.
+    entry: AccessLogEntry,
+}
+
+impl Body for LoggedBody {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            self.bytes_sent += frame.data_ref().map_or(0, |data| data.len() as u64);
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.entry.write(
+            self.format,
+            &self.output,
+            self.status,
+            self.upstream_latency,
+            self.bytes_sent,
+        );
+    }
+}
@@ -0,0 +1,720 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use arc_swap::ArcSwap;
+use aws_lc_rs::{
+    rand::SystemRandom,
+    signature::{
+        ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair,
+    },
+};
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use dashmap::DashMap;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    HeaderMap, Method, Request, StatusCode,
+    body::Bytes,
+    header::{CONTENT_TYPE, LOCATION},
+};
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use rustls::{ServerConfig, pki_types::PrivateKeyDer};
+use serde_json::{Value, json};
+use tokio::{
+    spawn,
+    time::{interval, sleep, timeout},
+};
+use tracing::{debug, error, info};
+use x509_parser::{extensions::GeneralName, pem::parse_x509_pem};
+
+use crate::{
+    backend::BackendHttpVersion,
+    config::Config,
+    dns::DnsResolver,
+    proxy::{Connector, https_connector},
+    tls::{load_tls_config, native_roots_client_config},
+};
+
+/// Path prefix under which HTTP-01 challenge tokens are requested.
+const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+/// How often to check whether the certificate needs renewing.
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// Maximum time to wait for the CA to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time between checks on a pending authorization or order.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Most checks on a pending authorization or order before giving up.
+const MAX_POLLS: usize = 60;
+
+/// Settings for obtaining the TLS certificate from an ACME certificate authority.
+pub struct AcmeSettings {
+    /// URL of the CA's ACME directory.
+    pub directory_url: String,
+    /// Contact email registered with the account, if any.
+    pub contact_email: Option<String>,
+    /// Domains the certificate is issued for.
+    pub domains: Vec<String>,
+    /// Path of the ACME account key, created if missing.
+    pub account_key_path: PathBuf,
+    /// How long before expiry the certificate is renewed.
+    pub renew_before: Duration,
+}
+
+/// Get the pending HTTP-01 challenges, mapping tokens to their key authorizations
+fn challenges() -> &'static DashMap<String, String> {
+    static CHALLENGES: OnceLock<DashMap<String, String>> = OnceLock::new();
+    CHALLENGES.get_or_init(DashMap::new)
+}
+
+/// Get the body to answer an HTTP-01 challenge request for `path` with, if one is pending
+pub fn challenge_response(path: &str) -> Option<String> {
+    let token = path.strip_prefix(CHALLENGE_PREFIX)?;
+    challenges().get(token).map(|entry| entry.value().clone())
+}
+
+/// Obtain a new certificate when ACME is enabled and the current one is missing, expiring or
+/// doesn't cover every configured domain
+///
+/// The certificate and its key are written to `CERT_PATH` and `KEY_PATH`. Returns whether a new
+/// certificate was obtained.
+pub async fn ensure_certificate(config: &Config) -> Result<bool> {
+    let Some(ref acme) = config.acme else {
+        return Ok(false);
+    };
+    let Some(reason) = renewal_reason(&config.cert_path, acme) else {
+        debug!("ACME certificate at {:?} is up to date", config.cert_path);
+        return Ok(false);
+    };
+
+    info!(
+        "Requesting certificate for {} from {} ({})",
+        acme.domains.join(", "),
+        acme.directory_url,
+        reason
+    );
+    let (cert_pem, key_pem) = obtain_certificate(acme).await?;
+    write_file(&config.key_path, key_pem.as_bytes(), true)?;
+    write_file(&config.cert_path, cert_pem.as_bytes(), false)?;
+    info!("✓ Certificate obtained and saved to {:?}", config.cert_path);
+
+    Ok(true)
+}
+
+/// Spawn a background task that renews the ACME certificate before it expires, and swaps it into
+/// `current`
+///
+/// Failed renewals are logged and retried at the next check. Does nothing when ACME is disabled.
+pub fn spawn_acme_renewal(configs: &'static ArcSwap<Config>, current: Arc<ArcSwap<ServerConfig>>) {
+    if configs.load().acme.is_none() {
+        return;
+    }
+
+    spawn(async move {
+        let mut ticker = interval(RENEWAL_CHECK_INTERVAL);
+        // The certificate was checked at startup
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+
+            let config = configs.load_full();
+            match ensure_certificate(&config).await {
+                Ok(true) => match load_tls_config(&config) {
+                    Ok(tls_config) => current.store(tls_config),
+                    Err(e) => error!("Failed to load renewed certificate: {:?}", e),
+                },
+                Ok(false) => {}
+                Err(e) => error!("Failed to renew ACME certificate: {:?}", e),
+            }
+        }
+    });
+}
+
+/// Explain why the certificate at `cert_path` needs to be replaced, or return `None` if it
+/// doesn't
+fn renewal_reason(cert_path: &Path, acme: &AcmeSettings) -> Option<String> {
+    let Ok(contents) = std::fs::read(cert_path) else {
+        return Some(format!("no certificate at {:?}", cert_path));
+    };
+    let cert = match parse_x509_pem(&contents) {
+        Ok((_, pem)) => pem,
+        Err(e) => return Some(format!("failed to parse {:?}: {}", cert_path, e)),
+    };
+    let cert = match cert.parse_x509() {
+        Ok(cert) => cert,
+        Err(e) => return Some(format!("failed to parse {:?}: {}", cert_path, e)),
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64);
+    let remaining = cert.validity().not_after.timestamp() - now;
+    if remaining < acme.renew_before.as_secs() as i64 {
+        return Some(format!(
+            "certificate expires in {} days",
+            remaining.max(0) / 86400
+        ));
+    }
+
+    let names = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|san| {
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) => Some(*name),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    acme.domains
+        .iter()
+        .find(|domain| !names.iter().any(|name| name.eq_ignore_ascii_case(domain)))
+        .map(|domain| format!("certificate doesn't cover {}", domain))
+}
+
+/// Go through the ACME flow to obtain a certificate, returning it and its private key as PEM
+async fn obtain_certificate(acme: &AcmeSettings) -> Result<(String, String)> {
+    let account_key = load_account_key(&acme.account_key_path)?;
+    let mut client = AcmeClient::new(&acme.directory_url, account_key).await?;
+    client.register(acme.contact_email.as_deref()).await?;
+
+    let identifiers = acme
+        .domains
+        .iter()
+        .map(|domain| json!({ "type": "dns", "value": domain }))
+        .collect::<Vec<_>>();
+    let new_order = client.directory.new_order.clone();
+    let response = client
+        .post(&new_order, Some(&json!({ "identifiers": identifiers })))
+        .await?;
+    let order = response.json()?;
+    let order_url = response
+        .location
+        .context("CA did not return the URL of the new order")?;
+
+    for authorization in order["authorizations"].as_array().into_iter().flatten() {
+        let url = authorization
+            .as_str()
+            .context("Order has an invalid authorization URL")?;
+        client.authorize(url).await?;
+    }
+
+    let finalize = order["finalize"]
+        .as_str()
+        .context("Order has no finalize URL")?;
+    let key_document =
+        EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &SystemRandom::new())
+            .map_err(|_| anyhow!("Failed to generate certificate key"))?;
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, key_document.as_ref())
+        .map_err(|e| anyhow!("Failed to load generated certificate key: {}", e))?;
+    let csr = certificate_request(&acme.domains, &key)?;
+    client
+        .post(
+            finalize,
+            Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr) })),
+        )
+        .await?;
+
+    let order = client.poll(&order_url).await?;
+    let certificate_url = order["certificate"]
+        .as_str()
+        .context("Valid order has no certificate URL")?;
+    let certificate = client.post(certificate_url, None).await?;
+    let cert_pem = String::from_utf8(certificate.body.to_vec())
+        .context("CA returned a certificate that is not PEM")?;
+
+    Ok((cert_pem, pem("PRIVATE KEY", key_document.as_ref())))
+}
+
+/// Load the ACME account key, generating and saving a new one if the file doesn't exist
+fn load_account_key(path: &Path) -> Result<EcdsaKeyPair> {
+    if !path.exists() {
+        info!("Creating ACME account key at {:?}", path);
+        let document =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .map_err(|_| anyhow!("Failed to generate ACME account key"))?;
+        write_file(path, pem("PRIVATE KEY", document.as_ref()).as_bytes(), true)?;
+    }
+
+    let contents = std::fs::read(path)
+        .with_context(|| format!("Failed to read ACME account key {:?}", path))?;
+    let Some(PrivateKeyDer::Pkcs8(key)) = rustls_pemfile::private_key(&mut contents.as_slice())
+        .with_context(|| format!("Failed to parse ACME account key {:?}", path))?
+    else {
+        bail!("ACME account key {:?} must be a PKCS#8 P-256 key", path);
+    };
+
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, key.secret_pkcs8_der())
+        .map_err(|e| anyhow!("Invalid ACME account key {:?}: {}", path, e))
+}
+
+/// URLs of the ACME resources the proxy uses, from the CA's directory.
+struct Directory {
+    /// URL to fetch a fresh anti-replay nonce from.
+    new_nonce: String,
+    /// URL to create or look up the account at.
+    new_account: String,
+    /// URL to create certificate orders at.
+    new_order: String,
+}
+
+/// A response from the CA to a signed request.
+struct AcmeResponse {
+    /// The `Location` header, naming the created resource.
+    location: Option<String>,
+    /// The response body.
+    body: Bytes,
+}
+
+impl AcmeResponse {
+    /// Parse the response body as JSON.
+    fn json(&self) -> Result<Value> {
+        serde_json::from_slice(&self.body).context("CA returned invalid JSON")
+    }
+}
+
+/// A client for the ACME protocol (RFC 8555), acting for one account.
+struct AcmeClient {
+    /// HTTPS client for requests to the CA.
+    http: Client<Connector, Full<Bytes>>,
+    /// URLs of the CA's resources.
+    directory: Directory,
+    /// The account key, which signs every request.
+    key: EcdsaKeyPair,
+    /// The account URL, once registered.
+    account_url: Option<String>,
+    /// Nonce returned by the previous request, to use for the next one.
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Create a client for the CA whose directory is at `directory_url`.
+    async fn new(directory_url: &str, key: EcdsaKeyPair) -> Result<Self> {
+        let http = Client::builder(TokioExecutor::new()).build(https_connector(
+            native_roots_client_config()?,
+            DnsResolver::default(),
+            None,
+            None,
+            None,
+            BackendHttpVersion::Auto,
+        ));
+        let mut client = Self {
+            http,
+            directory: Directory {
+                new_nonce: String::new(),
+                new_account: String::new(),
+                new_order: String::new(),
+            },
+            key,
+            account_url: None,
+            nonce: None,
+        };
+
+        let (status, _, body) = client.send(Method::GET, directory_url, None).await?;
+        if !status.is_success() {
+            bail!(
+                "Failed to fetch ACME directory {}: {}",
+                directory_url,
+                status
+            );
+        }
+        let directory: Value =
+            serde_json::from_slice(&body).context("ACME directory is invalid JSON")?;
+        let url = |name: &str| {
+            directory[name]
+                .as_str()
+                .map(str::to_string)
+                .with_context(|| format!("ACME directory has no {}", name))
+        };
+        client.directory = Directory {
+            new_nonce: url("newNonce")?,
+            new_account: url("newAccount")?,
+            new_order: url("newOrder")?,
+        };
+
+        Ok(client)
+    }
+
+    /// Create the account, or look it up if the key is already registered.
+    async fn register(&mut self, contact_email: Option<&str>) -> Result<()> {
+        let mut account = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = contact_email {
+            account["contact"] = json!([format!("mailto:{}", email)]);
+        }
+
+        let new_account = self.directory.new_account.clone();
+        let response = self.post(&new_account, Some(&account)).await?;
+        let account_url = response
+            .location
+            .context("CA did not return the account URL")?;
+        debug!("Using ACME account {}", account_url);
+        self.account_url = Some(account_url);
+        Ok(())
+    }
+
+    /// Complete the HTTP-01 challenge of the authorization at `url`, unless it is already valid.
+    async fn authorize(&mut self, url: &str) -> Result<()> {
+        let authorization = self.post(url, None).await?.json()?;
+        let domain = authorization["identifier"]["value"]
+            .as_str()
+            .unwrap_or("unknown domain")
+            .to_string();
+        if authorization["status"] == "valid" {
+            debug!("Authorization for {} is already valid", domain);
+            return Ok(());
+        }
+
+        let challenge = authorization["challenges"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|challenge| challenge["type"] == "http-01")
+            .with_context(|| format!("CA offered no HTTP-01 challenge for {}", domain))?;
+        let (Some(token), Some(challenge_url)) =
+            (challenge["token"].as_str(), challenge["url"].as_str())
+        else {
+            bail!("Invalid HTTP-01 challenge for {}", domain);
+        };
+
+        debug!("Answering HTTP-01 challenge for {}", domain);
+        challenges().insert(
+            token.to_string(),
+            format!("{}.{}", token, self.thumbprint()),
+        );
+        let result = async {
+            self.post(challenge_url, Some(&json!({}))).await?;
+            self.poll(url).await
+        }
+        .await;
+        challenges().remove(token);
+
+        result.with_context(|| format!("Failed to validate {}", domain))?;
+        info!("Validated control of {}", domain);
+        Ok(())
+    }
+
+    /// Wait for the authorization or order at `url` to become valid, returning it.
+    async fn poll(&mut self, url: &str) -> Result<Value> {
+        for _ in 0..MAX_POLLS {
+            let resource = self.post(url, None).await?.json()?;
+            match resource["status"].as_str() {
+                Some("valid") => return Ok(resource),
+                Some("pending" | "processing" | "ready") => sleep(POLL_INTERVAL).await,
+                Some("invalid") => bail!("CA marked it invalid: {}", problem_detail(&resource)),
+                status => bail!("Unexpected status {:?}", status),
+            }
+        }
+
+        bail!("Timed out waiting for the CA")
+    }
+
+    /// Send a signed request to `url`, as a POST-as-GET when there is no payload.
+    ///
+    /// A request rejected for a stale nonce is retried once with a fresh one.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<AcmeResponse> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match self.account_url {
+                Some(ref account_url) => protected["kid"] = json!(account_url),
+                None => protected["jwk"] = self.jwk(),
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let payload = payload.map_or(String::new(), |payload| {
+                URL_SAFE_NO_PAD.encode(payload.to_string())
+            });
+            let signature = self
+                .key
+                .sign(
+                    &SystemRandom::new(),
+                    format!("{}.{}", protected, payload).as_bytes(),
+                )
+                .map_err(|_| anyhow!("Failed to sign ACME request"))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+            });
+
+            let (status, headers, body) =
+                self.send(Method::POST, url, Some(body.to_string())).await?;
+            self.nonce = header_string(&headers, "replay-nonce");
+            if status.is_success() {
+                return Ok(AcmeResponse {
+                    location: header_string(&headers, LOCATION.as_str()),
+                    body,
+                });
+            }
+
+            let problem: Value = serde_json::from_slice(&body).unwrap_or_default();
+            if !retried && problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                retried = true;
+                continue;
+            }
+            bail!(
+                "ACME request to {} failed with {}: {}",
+                url,
+                status,
+                problem_detail(&json!({ "error": problem }))
+            );
+        }
+    }
+
+    /// Fetch a fresh nonce.
+    async fn new_nonce(&self) -> Result<String> {
+        let (_, headers, _) = self
+            .send(Method::HEAD, &self.directory.new_nonce, None)
+            .await?;
+        header_string(&headers, "replay-nonce").context("CA did not return a nonce")
+    }
+
+    /// Send a request to the CA, returning the status, headers and body of the response.
+    async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<String>,
+    ) -> Result<(StatusCode, HeaderMap, Bytes)> {
+        let request = Request::builder()
+            .method(method)
+            .uri(url)
+            .header(CONTENT_TYPE, "application/jose+json")
+            .body(Full::new(Bytes::from(body.unwrap_or_default())))
+            .with_context(|| format!("Invalid ACME URL {:?}", url))?;
+
+        let response = timeout(REQUEST_TIMEOUT, self.http.request(request))
+            .await
+            .with_context(|| format!("Timed out waiting for {}", url))?
+            .with_context(|| format!("Failed to send ACME request to {}", url))?;
+        let (parts, body) = response.into_parts();
+        let body = timeout(REQUEST_TIMEOUT, body.collect())
+            .await
+            .with_context(|| format!("Timed out reading response from {}", url))??
+            .to_bytes();
+
+        Ok((parts.status, parts.headers, body))
+    }
+
+    /// The account public key as a JWK, with its members in the order its thumbprint uses.
+    fn jwk(&self) -> Value {
+        let point = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        })
+    }
+
+    /// The JWK thumbprint of the account key (RFC 7638), used in key authorizations.
+    fn thumbprint(&self) -> String {
+        let jwk = self.jwk();
+        // Members must be in lexicographic order with no whitespace
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            jwk["x"].as_str().unwrap_or_default(),
+            jwk["y"].as_str().unwrap_or_default()
+        );
+        URL_SAFE_NO_PAD.encode(aws_lc_rs::digest::digest(
+            &aws_lc_rs::digest::SHA256,
+            canonical.as_bytes(),
+        ))
+    }
+}
+
+/// Get a header as a string, if present and valid.
+fn header_string(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Describe the error of a failed ACME resource, including those of its challenges.
+fn problem_detail(resource: &Value) -> String {
+    let problem = if resource["error"].is_object() {
+        &resource["error"]
+    } else {
+        resource["challenges"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|challenge| &challenge["error"])
+            .find(|error| error.is_object())
+            .unwrap_or(&Value::Null)
+    };
+
+    match (problem["type"].as_str(), problem["detail"].as_str()) {
+        (_, Some(detail)) => detail.to_string(),
+        (Some(problem_type), None) => problem_type.to_string(),
+        (None, None) => "no details given".to_string(),
+    }
+}
+
+/// DER tag of a SEQUENCE.
+const SEQUENCE: u8 = 0x30;
+/// DER tag of a SET.
+const SET: u8 = 0x31;
+/// DER tag of an OCTET STRING.
+const OCTET_STRING: u8 = 0x04;
+/// DER tag of a BIT STRING.
+const BIT_STRING: u8 = 0x03;
+/// DER tag of a UTF8String.
+const UTF8_STRING: u8 = 0x0c;
+/// DER tag of a dNSName in a GeneralName.
+const DNS_NAME: u8 = 0x82;
+/// DER tag of the attributes of a certification request.
+const ATTRIBUTES: u8 = 0xa0;
+/// DER encoding of the certification request version (`v1`).
+const VERSION: &[u8] = &[0x02, 0x01, 0x00];
+/// DER encoding of the `commonName` OID (2.5.4.3).
+const OID_COMMON_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+/// DER encoding of the `id-ecPublicKey` OID (1.2.840.10045.2.1).
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+/// DER encoding of the `prime256v1` OID (1.2.840.10045.3.1.7).
+const OID_P256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+/// DER encoding of the `extensionRequest` OID (1.2.840.113549.1.9.14).
+const OID_EXTENSION_REQUEST: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e,
+];
+/// DER encoding of the `subjectAltName` OID (2.5.29.17).
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x11];
+/// DER encoding of the `ecdsa-with-SHA256` OID (1.2.840.10045.4.3.2).
+const OID_ECDSA_SHA256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+
+/// Build a PKCS#10 certificate signing request for `domains`, signed with `key`.
+///
+/// The first domain is also used as the subject common name when it fits in one.
+fn certificate_request(domains: &[String], key: &EcdsaKeyPair) -> Result<Vec<u8>> {
+    let names = domains
+        .iter()
+        .flat_map(|domain| der(DNS_NAME, domain.as_bytes()))
+        .collect::<Vec<_>>();
+    let extension = der(
+        SEQUENCE,
+        &[
+            OID_SUBJECT_ALT_NAME,
+            &der(OCTET_STRING, &der(SEQUENCE, &names)),
+        ]
+        .concat(),
+    );
+    let attributes = der(
+        ATTRIBUTES,
+        &der(
+            SEQUENCE,
+            &[OID_EXTENSION_REQUEST, &der(SET, &der(SEQUENCE, &extension))].concat(),
+        ),
+    );
+
+    let subject = match domains.first() {
+        Some(domain) if domain.len() <= 64 => der(
+            SEQUENCE,
+            &der(
+                SET,
+                &der(
+                    SEQUENCE,
+                    &[OID_COMMON_NAME, &der(UTF8_STRING, domain.as_bytes())].concat(),
+                ),
+            ),
+        ),
+        _ => der(SEQUENCE, &[]),
+    };
+    let public_key = der(
+        SEQUENCE,
+        &[
+            der(SEQUENCE, &[OID_EC_PUBLIC_KEY, OID_P256].concat()),
+            bit_string(key.public_key().as_ref()),
+        ]
+        .concat(),
+    );
+
+    let info = der(
+        SEQUENCE,
+        &[VERSION, &subject, &public_key, &attributes].concat(),
+    );
+    let signature = key
+        .sign(&SystemRandom::new(), &info)
+        .map_err(|_| anyhow!("Failed to sign certificate request"))?;
+
+    Ok(der(
+        SEQUENCE,
+        &[
+            info,
+            der(SEQUENCE, OID_ECDSA_SHA256),
+            bit_string(signature.as_ref()),
+        ]
+        .concat(),
+    ))
+}
+
+/// Encode a DER value with the given tag and contents.
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&byte| byte == 0).count();
+        encoded.push(0x80 | (bytes.len() - skip) as u8);
+        encoded.extend_from_slice(&bytes[skip..]);
+    }
+    encoded.extend_from_slice(contents);
+    encoded
+}
+
+/// Encode bytes as a DER BIT STRING with no unused bits.
+fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    der(BIT_STRING, &[&[0], bytes].concat())
+}
+
+/// Encode DER data as PEM with the given label.
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+/// Replace a file atomically, readable only by its owner when `private` is set.
+fn write_file(path: &Path, contents: &[u8], private: bool) -> Result<()> {
+    let temp_path = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+
+    std::io::Write::write_all(
+        &mut options
+            .open(&temp_path)
+            .with_context(|| format!("Failed to create {:?}", temp_path))?,
+        contents,
+    )
+    .with_context(|| format!("Failed to write {:?}", temp_path))?;
+    std::fs::rename(&temp_path, path).with_context(|| format!("Failed to replace {:?}", path))?;
+    Ok(())
+}
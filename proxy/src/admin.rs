@@ -0,0 +1,193 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use http_body_util::Full;
+use hyper::{
+    HeaderMap, Method, Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header::{AUTHORIZATION, CONTENT_TYPE, HeaderValue, WWW_AUTHENTICATE},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use rustls::ServerConfig;
+use serde_json::{Value, json};
+use subtle::ConstantTimeEq;
+use tokio::{net::TcpListener, spawn};
+use tracing::{debug, error, info, warn};
+
+use crate::{config::Config, metrics::metrics, server::reload};
+
+/// Serve the admin API on its own listener in a background task.
+///
+/// Requests must carry the current `ADMIN_TOKEN` as a bearer token.
+pub async fn spawn_admin_server(
+    addr: SocketAddr,
+    configs: &'static ArcSwap<Config>,
+    tls_config: Option<Arc<ArcSwap<ServerConfig>>>,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("✓ Admin API available on http://{}/admin", addr);
+
+    spawn(async move {
+        loop {
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept admin connection: {:?}", e);
+                    continue;
+                }
+            };
+            debug!("Accepted admin connection from {}", remote_addr.ip());
+
+            let tls_config = tls_config.clone();
+            spawn(async move {
+                if let Err(e) = http1::Builder::new()
+                    .timer(TokioTimer::new())
+                    .serve_connection(
+                        TokioIo::new(stream),
+                        service_fn(|req| serve_admin(req, configs, tls_config.clone())),
+                    )
+                    .await
+                {
+                    warn!("Admin connection error from {}: {:?}", remote_addr, e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Respond to a request on the admin listener
+async fn serve_admin(
+    req: Request<Incoming>,
+    configs: &'static ArcSwap<Config>,
+    tls_config: Option<Arc<ArcSwap<ServerConfig>>>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let config = configs.load_full();
+    if !is_authorized(req.headers(), config.admin_token.as_deref()) {
+        let mut response = json_response(
+            StatusCode::UNAUTHORIZED,
+            json!({ "error": "invalid or missing bearer token" }),
+        );
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return Ok(response);
+    }
+
+    let segments = req
+        .uri()
+        .path()
+        .trim_matches('/')
+        .split('/')
+        .collect::<Vec<_>>();
+
+    let response = match (req.method(), segments.as_slice()) {
+        (&Method::GET, ["admin", "backends"]) => {
+            let backends = config
+                .all_backends()
+                .iter()
+                .enumerate()
+                .map(|(id, backend)| {
+                    json!({
+                        "id": id,
+                        "url": backend.url(),
+                        "weight": backend.weight(),
+                        "healthy": backend.is_healthy(),
+                        "draining": backend.is_draining(),
+                        "circuit_open": backend.circuit().is_open(),
+                        "in_flight": backend.in_flight(),
+                        "warmup": config
+                            .slow_start
+                            .map_or(1.0, |slow_start| backend.warmup(slow_start)),
+                    })
+                })
+                .collect::<Vec<_>>();
+            json_response(StatusCode::OK, json!({ "backends": backends }))
+        }
+        (&Method::POST, ["admin", "backends", id, action @ ("drain" | "undrain")]) => {
+            let backend = id
+                .parse::<usize>()
+                .ok()
+                .and_then(|id| config.all_backends().into_iter().nth(id));
+            match backend {
+                Some(backend) => {
+                    let draining = *action == "drain";
+                    if backend.set_draining(draining) {
+                        if draining {
+                            warn!("Backend {} drained through the admin API", backend.url());
+                        } else {
+                            info!("Backend {} undrained through the admin API", backend.url());
+                        }
+                    }
+                    json_response(
+                        StatusCode::OK,
+                        json!({ "url": backend.url(), "draining": draining }),
+                    )
+                }
+                None => json_response(
+                    StatusCode::NOT_FOUND,
+                    json!({ "error": format!("no backend with id {}", id) }),
+                ),
+            }
+        }
+        (&Method::POST, ["admin", "maintenance", state @ ("on" | "off")]) => {
+            let enabled = *state == "on";
+            config.maintenance.set_enabled(enabled);
+            json_response(StatusCode::OK, json!({ "maintenance": enabled }))
+        }
+        (&Method::POST, ["admin", "reload"]) => {
+            info!("Reloading configuration through the admin API");
+            match reload(configs, tls_config).await {
+                Ok(()) => {
+                    info!("Configuration reloaded");
+                    json_response(StatusCode::OK, json!({ "reloaded": true }))
+                }
+                Err(e) => {
+                    error!("Failed to reload configuration, keeping previous: {:?}", e);
+                    json_response(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        json!({ "reloaded": false, "error": format!("{:#}", e) }),
+                    )
+                }
+            }
+        }
+        (&Method::GET, ["admin", "stats"]) => {
+            let mut stats = metrics().stats();
+            stats["maintenance"] = config.maintenance.is_enabled().into();
+            json_response(StatusCode::OK, stats)
+        }
+        _ => json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+    };
+
+    Ok(response)
+}
+
+/// Whether the request carries the admin token as a bearer token, compared in constant time
+pub fn is_authorized(headers: &HeaderMap, token: Option<&str>) -> bool {
+    let (Some(token), Some(provided)) = (
+        token,
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, provided)| provided.trim()),
+    ) else {
+        return false;
+    };
+
+    token.as_bytes().ct_eq(provided.as_bytes()).into()
+}
+
+/// Build a JSON response
+fn json_response(status: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
@@ -0,0 +1,183 @@
+use std::future::ready;
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use futures_util::future::BoxFuture;
+use hyper::{
+    HeaderMap, Request, StatusCode,
+    header::{AUTHORIZATION, HeaderValue, WWW_AUTHENTICATE},
+};
+use subtle::ConstantTimeEq;
+use tracing::debug;
+
+use crate::{
+    filter::{FilterContext, FilterOutcome, RequestFilter},
+    proxy::{RequestBody, empty_response},
+};
+
+/// HTTP Basic authentication credentials accepted by the proxy.
+pub struct BasicAuth {
+    /// Accepted `user:pass` pairs.
+    credentials: Vec<String>,
+    /// Realm sent in the `WWW-Authenticate` challenge.
+    pub realm: String,
+    /// Whether to remove the `Authorization` header before forwarding.
+    pub strip: bool,
+}
+
+impl BasicAuth {
+    /// Create a gate accepting any of the given `user:pass` pairs.
+    pub fn new(credentials: Vec<String>, realm: String, strip: bool) -> Self {
+        Self {
+            credentials,
+            realm,
+            strip,
+        }
+    }
+
+    /// Whether the request headers carry valid credentials.
+    ///
+    /// Every configured pair is compared in constant time, so the result doesn't reveal how much
+    /// of a guess was correct.
+    pub fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(decoded) = decode_credentials(headers) else {
+            return false;
+        };
+
+        self.credentials.iter().fold(0u8, |matched, credentials| {
+            matched | credentials.as_bytes().ct_eq(&decoded).unwrap_u8()
+        }) == 1
+    }
+
+    /// The user name sent in the request headers, whether or not the credentials are valid.
+    pub fn user(&self, headers: &HeaderMap) -> Option<String> {
+        let decoded = decode_credentials(headers)?;
+        let user = decoded.split(|&byte| byte == b':').next()?;
+        Some(String::from_utf8_lossy(user).into_owned())
+    }
+
+    /// The value of the `WWW-Authenticate` challenge header.
+    pub fn challenge(&self) -> String {
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm)
+    }
+}
+
+impl RequestFilter for BasicAuth {
+    fn on_request<'a>(
+        &'a self,
+        context: &'a FilterContext,
+        req: &'a mut Request<RequestBody>,
+    ) -> BoxFuture<'a, FilterOutcome> {
+        if !self.is_authorized(req.headers()) {
+            debug!(
+                "Unauthorized {} {} from {}",
+                context.method, context.uri, context.client_ip
+            );
+            let mut response = empty_response(StatusCode::UNAUTHORIZED);
+            if let Ok(challenge) = HeaderValue::from_str(&self.challenge()) {
+                response.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+            }
+            return Box::pin(ready(FilterOutcome::Respond(response)));
+        }
+
+        if self.strip {
+            req.headers_mut().remove(AUTHORIZATION);
+        }
+        Box::pin(ready(FilterOutcome::Continue))
+    }
+}
+
+/// Decode the `user:pass` pair of a Basic `Authorization` header
+fn decode_credentials(headers: &HeaderMap) -> Option<Vec<u8>> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, encoded)| BASE64_STANDARD.decode(encoded.trim()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::proxy::full_body;
+
+    fn auth(strip: bool) -> BasicAuth {
+        BasicAuth::new(
+            vec!["admin:secret".to_string(), "viewer:pa:ss".to_string()],
+            "proxy".to_string(),
+            strip,
+        )
+    }
+
+    fn authorization(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn basic(credentials: &str) -> HeaderMap {
+        authorization(&format!("Basic {}", BASE64_STANDARD.encode(credentials)))
+    }
+
+    #[test]
+    fn accepts_any_configured_pair() {
+        let auth = auth(true);
+        assert!(auth.is_authorized(&basic("admin:secret")));
+        assert!(auth.is_authorized(&basic("viewer:pa:ss")));
+        // The scheme is case-insensitive
+        let encoded = BASE64_STANDARD.encode("admin:secret");
+        assert!(auth.is_authorized(&authorization(&format!("basic {}", encoded))));
+    }
+
+    #[test]
+    fn rejects_wrong_or_missing_credentials() {
+        let auth = auth(true);
+        assert!(!auth.is_authorized(&HeaderMap::new()));
+        assert!(!auth.is_authorized(&basic("admin:wrong")));
+        assert!(!auth.is_authorized(&basic("admin:secret2")));
+        assert!(!auth.is_authorized(&basic("admin")));
+        assert!(!auth.is_authorized(&authorization("Bearer YWRtaW46c2VjcmV0")));
+        assert!(!auth.is_authorized(&authorization("Basic not base64!")));
+    }
+
+    #[test]
+    fn reads_the_user_whether_or_not_the_password_matches() {
+        let auth = auth(true);
+        assert_eq!(auth.user(&basic("admin:wrong")).as_deref(), Some("admin"));
+        assert_eq!(auth.user(&HeaderMap::new()), None);
+    }
+
+    async fn run(auth: &BasicAuth, headers: HeaderMap) -> (FilterOutcome, Request<RequestBody>) {
+        let mut req = Request::new(full_body(Default::default()));
+        *req.headers_mut() = headers;
+        let context = FilterContext::new(IpAddr::V4(Ipv4Addr::LOCALHOST), &req);
+        let outcome = auth.on_request(&context, &mut req).await;
+        (outcome, req)
+    }
+
+    #[tokio::test]
+    async fn challenges_unauthorized_requests() {
+        let (outcome, _) = run(&auth(true), basic("admin:wrong")).await;
+        let FilterOutcome::Respond(response) = outcome else {
+            panic!("unauthorized request was let through");
+        };
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[WWW_AUTHENTICATE],
+            "Basic realm=\"proxy\", charset=\"UTF-8\""
+        );
+    }
+
+    #[tokio::test]
+    async fn strips_credentials_only_when_asked() {
+        let (outcome, req) = run(&auth(true), basic("admin:secret")).await;
+        assert!(matches!(outcome, FilterOutcome::Continue));
+        assert!(!req.headers().contains_key(AUTHORIZATION));
+
+        let (outcome, req) = run(&auth(false), basic("admin:secret")).await;
+        assert!(matches!(outcome, FilterOutcome::Continue));
+        assert!(req.headers().contains_key(AUTHORIZATION));
+    }
+}
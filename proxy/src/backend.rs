@@ -0,0 +1,1017 @@
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Error, Result, bail, ensure};
+use arc_swap::ArcSwap;
+use dashmap::{DashMap, mapref::entry::Entry};
+use hyper::{Response, Version};
+use rustls::pki_types::ServerName;
+use tokio::{
+    spawn,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::AbortHandle,
+    time::{interval, timeout},
+};
+use tracing::info;
+
+use crate::{
+    circuit_breaker::Circuit,
+    discovery::{SRV_PREFIX, SrvName},
+    proxy::{ResponseBody, close_pooled_connections, hold_until_body_end},
+};
+
+/// Share of its weight a backend gets right after coming back into rotation under slow start.
+const SLOW_START_MIN_FACTOR: f64 = 0.1;
+/// How often removed backends are checked for requests still in flight while they drain.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How requests are distributed across the backends of a set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LbStrategy {
+    /// Each backend in turn.
+    RoundRobin,
+    /// Each backend in turn, as many times in a row as its weight.
+    Weighted,
+    /// The backend with the fewest requests in flight, breaking ties randomly.
+    LeastConn,
+    /// The one with fewer requests in flight of two backends picked at random in proportion to
+    /// their weights (power of two choices).
+    PowerOfTwoChoices,
+}
+
+impl FromStr for LbStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "round_robin" => Ok(Self::RoundRobin),
+            "weighted" => Ok(Self::Weighted),
+            "least_conn" => Ok(Self::LeastConn),
+            "p2c" => Ok(Self::PowerOfTwoChoices),
+            _ => bail!(
+                "Unknown load balancing strategy {:?}, expected round_robin, weighted, least_conn \
+                 or p2c",
+                s
+            ),
+        }
+    }
+}
+
+/// HTTP version spoken to backends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BackendHttpVersion {
+    /// HTTP/1.0, with a new connection for every request.
+    Http10,
+    /// HTTP/1.1 only.
+    Http11,
+    /// HTTP/2 only, with prior knowledge for plaintext backends.
+    Http2,
+    /// HTTP/1.1, or HTTP/2 when an HTTPS backend offers it.
+    Auto,
+}
+
+impl BackendHttpVersion {
+    /// Get the version set on requests sent to backends
+    pub fn request_version(self) -> Version {
+        match self {
+            Self::Http10 => Version::HTTP_10,
+            Self::Http2 => Version::HTTP_2,
+            // The client negotiates HTTP/2 with the backend on its own
+            Self::Http11 | Self::Auto => Version::HTTP_11,
+        }
+    }
+
+    /// Whether backends can be asked to upgrade the connection, e.g. for WebSocket handshakes
+    pub fn supports_upgrades(self) -> bool {
+        matches!(self, Self::Http11 | Self::Auto)
+    }
+}
+
+impl FromStr for BackendHttpVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "http1.0" => Ok(Self::Http10),
+            "http1.1" => Ok(Self::Http11),
+            "http2" => Ok(Self::Http2),
+            "auto" => Ok(Self::Auto),
+            _ => bail!(
+                "Unknown backend HTTP version {:?}, expected http1.0, http1.1, http2 or auto",
+                s
+            ),
+        }
+    }
+}
+
+/// A single upstream the proxy can forward requests to.
+pub struct Backend {
+    /// The backend URL.
+    url: String,
+    /// Relative share of requests under the weighted strategy.
+    weight: usize,
+    /// Server name sent in the TLS handshake instead of the URL's host, if overridden.
+    sni: Option<ServerName<'static>>,
+    /// Maximum number of requests handled by the backend at once, if limited.
+    max_concurrent: Option<usize>,
+    /// Slots for requests handled by the backend, if limited.
+    concurrency: Option<Arc<Semaphore>>,
+    /// Number of requests currently served by the backend, until their response body ends or
+    /// their upgraded connection closes.
+    in_flight: AtomicUsize,
+    /// Whether the backend is currently in rotation.
+    healthy: AtomicBool,
+    /// Whether the backend was taken out of rotation through the admin API, or removed from the
+    /// configuration while requests were still in flight on it.
+    draining: AtomicBool,
+    /// Number of consecutive failed health probes.
+    failures: AtomicU32,
+    /// When the backend was last brought back into rotation by a health probe, if ever.
+    recovered_at: Mutex<Option<Instant>>,
+    /// When a request to the backend or a health probe last failed, if ever.
+    last_error: Mutex<Option<SystemTime>>,
+    /// Circuit breaker state for proxied requests.
+    circuit: Circuit,
+    /// The task probing the backend's health, if health checks are enabled.
+    health_check: Mutex<Option<AbortHandle>>,
+}
+
+impl Backend {
+    /// Create a new backend, initially considered healthy, from a URL optionally followed by
+    /// `*WEIGHT` and then options each introduced by `#`: `#sni=NAME` and `#max_concurrent=N`.
+    fn new(url: &str) -> Result<Self> {
+        let mut options = url.split('#');
+        let url = options.next().unwrap_or_default();
+        let mut sni = None;
+        let mut max_concurrent = None;
+        for option in options {
+            match option.split_once('=') {
+                Some(("sni", name)) => {
+                    ensure!(
+                        url.starts_with("https://"),
+                        "sni for backend {:?} requires an https URL",
+                        url
+                    );
+                    sni = match ServerName::try_from(name.to_string()) {
+                        Ok(sni @ ServerName::DnsName(_)) => Some(sni),
+                        _ => bail!(
+                            "Invalid sni {:?} for backend {:?}, expected a DNS name",
+                            name,
+                            url
+                        ),
+                    };
+                }
+                Some(("max_concurrent", limit)) => {
+                    let limit = limit.parse::<usize>().with_context(|| {
+                        format!("Invalid max_concurrent {:?} for backend {:?}", limit, url)
+                    })?;
+                    ensure!(
+                        limit > 0,
+                        "max_concurrent for backend {:?} must be at least 1",
+                        url
+                    );
+                    max_concurrent = Some(limit);
+                }
+                _ => bail!("Unknown option {:?} for backend {:?}", option, url),
+            }
+        }
+
+        let (url, weight) = match url.rsplit_once('*') {
+            Some((url, weight)) => {
+                let weight = weight.parse().with_context(|| {
+                    format!("Invalid weight {:?} for backend {:?}", weight, url)
+                })?;
+                ensure!(
+                    weight > 0,
+                    "Weight for backend {:?} must be at least 1",
+                    url
+                );
+                (url, weight)
+            }
+            None => (url, 1),
+        };
+
+        Ok(Self {
+            url: url.to_string(),
+            weight,
+            sni,
+            max_concurrent,
+            concurrency: max_concurrent.map(|limit| Arc::new(Semaphore::new(limit))),
+            in_flight: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            draining: AtomicBool::new(false),
+            failures: AtomicU32::new(0),
+            recovered_at: Mutex::new(None),
+            last_error: Mutex::new(None),
+            circuit: Circuit::new(),
+            health_check: Mutex::new(None),
+        })
+    }
+
+    /// Whether `other` was created from the same URL and options, so one can stand in for the
+    /// other while keeping its state.
+    fn is_same(&self, other: &Backend) -> bool {
+        self.url == other.url
+            && self.weight == other.weight
+            && self.sni == other.sni
+            && self.max_concurrent == other.max_concurrent
+    }
+
+    /// Record the task now probing the backend's health, stopping the one it replaces, so a
+    /// backend kept across reloads is only ever probed with the latest settings.
+    pub fn set_health_check(&self, task: AbortHandle) {
+        if let Some(previous) = self.health_check.lock().unwrap().replace(task) {
+            previous.abort();
+        }
+    }
+
+    /// Get the backend URL.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Get the server name sent in the TLS handshake instead of the URL's host, if overridden.
+    pub fn sni(&self) -> Option<&ServerName<'static>> {
+        self.sni.as_ref()
+    }
+
+    /// Whether the backend is currently in rotation.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Get the circuit breaker state for proxied requests.
+    pub fn circuit(&self) -> &Circuit {
+        &self.circuit
+    }
+
+    /// Get the relative share of requests under the weighted strategy.
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    /// Get the share of its weight the backend currently gets while ramping up over `slow_start`
+    /// after coming back into rotation, from a tenth up to `1.0` once fully warmed up.
+    pub fn warmup(&self, slow_start: Duration) -> f64 {
+        let Some(recovered_at) = *self.recovered_at.lock().unwrap() else {
+            return 1.0;
+        };
+        if slow_start.is_zero() {
+            return 1.0;
+        }
+        let progress = recovered_at.elapsed().as_secs_f64() / slow_start.as_secs_f64();
+        (SLOW_START_MIN_FACTOR + (1.0 - SLOW_START_MIN_FACTOR) * progress).min(1.0)
+    }
+
+    /// Get the number of requests currently served by the backend.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Whether the backend was taken out of rotation through the admin API, or removed from the
+    /// configuration.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Take the backend out of rotation, or put it back, letting in-flight requests finish.
+    /// Returns `true` if this changed the backend's state.
+    pub fn set_draining(&self, draining: bool) -> bool {
+        self.draining.swap(draining, Ordering::Relaxed) != draining
+    }
+
+    /// Whether the backend can be picked for a request.
+    pub fn is_available(&self) -> bool {
+        self.is_healthy() && !self.is_draining() && !self.circuit.is_rejecting()
+    }
+
+    /// Count a request as in flight until the returned guard is dropped.
+    pub fn start_request(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight {
+            backend: self.clone(),
+        }
+    }
+
+    /// Take a slot in the backend's concurrency limit, or `None` if it is full.
+    pub fn try_acquire(&self) -> Option<ConcurrencyPermit> {
+        match &self.concurrency {
+            Some(semaphore) => semaphore
+                .clone()
+                .try_acquire_owned()
+                .ok()
+                .map(|permit| ConcurrencyPermit(Some(permit))),
+            None => Some(ConcurrencyPermit(None)),
+        }
+    }
+
+    /// Take a slot in the backend's concurrency limit, waiting up to `wait` for one to free up.
+    /// Returns `None` if none did.
+    pub async fn acquire(&self, wait: Duration) -> Option<ConcurrencyPermit> {
+        match &self.concurrency {
+            Some(semaphore) => timeout(wait, semaphore.clone().acquire_owned())
+                .await
+                .ok()
+                .and_then(Result::ok)
+                .map(|permit| ConcurrencyPermit(Some(permit))),
+            None => Some(ConcurrencyPermit(None)),
+        }
+    }
+
+    /// Record a successful health probe. Returns `true` if the backend was brought back into
+    /// rotation.
+    pub fn record_success(&self) -> bool {
+        self.failures.store(0, Ordering::Relaxed);
+        let recovered = !self.healthy.swap(true, Ordering::Relaxed);
+        if recovered {
+            *self.recovered_at.lock().unwrap() = Some(Instant::now());
+        }
+        recovered
+    }
+
+    /// Record a failed health probe. Returns `true` if the backend was taken out of rotation
+    /// because it reached `threshold` consecutive failures.
+    pub fn record_failure(&self, threshold: u32) -> bool {
+        self.record_error();
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        failures >= threshold && self.healthy.swap(false, Ordering::Relaxed)
+    }
+
+    /// Record that a request to the backend failed, with a connection error, a timeout or a `5xx`
+    /// response.
+    pub fn record_error(&self) {
+        *self.last_error.lock().unwrap() = Some(SystemTime::now());
+    }
+
+    /// Get when a request to the backend or a health probe last failed, if ever.
+    pub fn last_error(&self) -> Option<SystemTime> {
+        *self.last_error.lock().unwrap()
+    }
+}
+
+/// A drain of the backends removed with one URL, waiting for their requests in flight to finish
+/// before closing the URL's pooled connections.
+struct Drain {
+    /// Told apart from a later drain of the same URL, which takes over from this one.
+    id: u64,
+    backends: Vec<Arc<Backend>>,
+}
+
+/// Drains in progress by backend URL.
+fn drains() -> &'static DashMap<String, Drain> {
+    static DRAINS: OnceLock<DashMap<String, Drain>> = OnceLock::new();
+    DRAINS.get_or_init(DashMap::new)
+}
+
+/// Check whether the drain `id` of `url` is still pending, neither cancelled nor taken over.
+fn is_pending(url: &str, id: u64) -> bool {
+    drains().get(url).is_some_and(|drain| drain.id == id)
+}
+
+/// Drain the `removed` backends whose URL is no longer among the `current` ones
+///
+/// They are taken out of rotation, so retries of requests that started before the removal don't
+/// pick them, and their pooled connections are closed once the requests still in flight on them
+/// have finished. A drain still pending for a URL that is among the `current` ones again is
+/// cancelled, leaving the pooled connections to the backend that took its place.
+pub fn drain_removed(removed: Vec<Arc<Backend>>, current: &[Arc<Backend>]) {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    for backend in current {
+        if drains().remove(&backend.url).is_some() {
+            info!(
+                "Cancelled draining backend {}, which was added back",
+                backend.url
+            );
+        }
+    }
+
+    // The same URL may have had a backend in several sets
+    let mut by_url = BTreeMap::<String, Vec<Arc<Backend>>>::new();
+    for backend in removed {
+        if current.iter().any(|kept| kept.url == backend.url) {
+            continue;
+        }
+        backend.set_draining(true);
+        by_url.entry(backend.url.clone()).or_default().push(backend);
+    }
+
+    for (url, mut backends) in by_url {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        // A drain already pending for the URL is taken over, still waiting for its backends
+        match drains().entry(url.clone()) {
+            Entry::Occupied(mut entry) => {
+                let previous = entry.get_mut();
+                previous.backends.append(&mut backends);
+                previous.id = id;
+                backends = previous.backends.clone();
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Drain {
+                    id,
+                    backends: backends.clone(),
+                });
+            }
+        }
+
+        let in_flight = backends
+            .iter()
+            .map(|backend| backend.in_flight())
+            .sum::<usize>();
+        info!(
+            "Draining removed backend {} with {} requests in flight",
+            url, in_flight
+        );
+        spawn(async move {
+            let started = Instant::now();
+            let mut ticker = interval(DRAIN_CHECK_INTERVAL);
+            while backends.iter().any(|backend| backend.in_flight() > 0) {
+                if !is_pending(&url, id) {
+                    return;
+                }
+                ticker.tick().await;
+            }
+            // Checked and removed at once, so the URL being added back can't slip in between
+            if drains()
+                .remove_if(&url, |_, drain| drain.id == id)
+                .is_none()
+            {
+                return;
+            }
+            close_pooled_connections(&url);
+            info!(
+                "Backend {} finished draining after {:?}",
+                url,
+                started.elapsed()
+            );
+        });
+    }
+}
+
+/// Randomly admit a request with probability `factor`
+fn admits(factor: f64) -> bool {
+    if factor >= 1.0 {
+        return true;
+    }
+    let mut random = [0; size_of::<u32>()];
+    getrandom::getrandom(&mut random).expect("the OS random number generator is available");
+    (u32::from_ne_bytes(random) as f64 / (u32::MAX as f64 + 1.0)) < factor
+}
+
+/// A request counted as in flight on a backend, for as long as the guard is held.
+pub struct InFlight {
+    /// The backend the request was sent to.
+    backend: Arc<Backend>,
+}
+
+impl InFlight {
+    /// Keep the request in flight until the response body has been sent or dropped
+    pub fn hold(self, response: Response<ResponseBody>) -> Response<ResponseBody> {
+        hold_until_body_end(self, response)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.backend.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What to do with a request whose backend is at its concurrency limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnBackendFull {
+    /// Wait a little for a slot on the same backend.
+    Wait,
+    /// Send the request to another available backend with a free slot.
+    Failover,
+}
+
+impl FromStr for OnBackendFull {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "wait" => Ok(Self::Wait),
+            "failover" => Ok(Self::Failover),
+            _ => bail!(
+                "Unknown backend full mode {:?}, expected wait or failover",
+                s
+            ),
+        }
+    }
+}
+
+/// A slot held by a request in its backend's concurrency limit.
+///
+/// The slot is released when the permit is dropped, which for a response is once its body has
+/// been sent or dropped. Backends without a limit hand out empty permits.
+pub struct ConcurrencyPermit(Option<OwnedSemaphorePermit>);
+
+impl ConcurrencyPermit {
+    /// Keep the slot until the response body has been sent or dropped
+    pub fn hold(self, response: Response<ResponseBody>) -> Response<ResponseBody> {
+        if self.0.is_none() {
+            return response;
+        }
+        hold_until_body_end(self, response)
+    }
+}
+
+/// The backends of a set at one point in time.
+struct Snapshot {
+    /// The backends, in configuration (or discovery) order.
+    backends: Vec<Arc<Backend>>,
+    /// Sum of the backend weights.
+    total_weight: usize,
+}
+
+impl Snapshot {
+    /// Create a snapshot of the given backends. `backends` must not be empty, except for an SRV
+    /// set that hasn't been resolved yet.
+    fn new(backends: Vec<Arc<Backend>>) -> Self {
+        Self {
+            total_weight: backends.iter().map(|backend| backend.weight).sum(),
+            backends,
+        }
+    }
+
+    /// Get the index of the backend owning `slot` out of the total weight.
+    fn weighted_index(&self, mut slot: usize) -> usize {
+        for (index, backend) in self.backends.iter().enumerate() {
+            if slot < backend.weight {
+                return index;
+            }
+            slot -= backend.weight;
+        }
+        unreachable!("slot is less than the total weight")
+    }
+
+    /// Get a random one of the available backends with the fewest requests in flight, if any
+    /// backend is available.
+    fn least_conn(&self) -> Option<&Arc<Backend>> {
+        let available = self
+            .backends
+            .iter()
+            .filter(|backend| backend.is_available());
+        let fewest = available
+            .clone()
+            .map(|backend| backend.in_flight.load(Ordering::Relaxed))
+            .min()?;
+        let candidates = available
+            .filter(|backend| backend.in_flight.load(Ordering::Relaxed) == fewest)
+            .collect::<Vec<_>>();
+
+        // Counts may have changed since the minimum was taken, leaving no candidates
+        if candidates.is_empty() {
+            return None;
+        }
+
+        Some(candidates[random_below(candidates.len())])
+    }
+
+    /// Pick two different available backends at random, in proportion to their weights, and get
+    /// the one with fewer requests in flight, if any backend is available.
+    ///
+    /// Only comparing two backends avoids sending every request to the same least loaded backend
+    /// when many arrive before the counts catch up.
+    fn power_of_two_choices(&self) -> Option<&Arc<Backend>> {
+        let available = self
+            .backends
+            .iter()
+            .filter(|backend| backend.is_available())
+            .collect::<Vec<_>>();
+        match available.as_slice() {
+            [] => None,
+            [backend] => Some(backend),
+            _ => {
+                let first = weighted_choice(&available, None);
+                let second = weighted_choice(&available, Some(first));
+                let (first, second) = (available[first], available[second]);
+                Some(if second.in_flight() < first.in_flight() {
+                    second
+                } else {
+                    first
+                })
+            }
+        }
+    }
+}
+
+/// Get the index of a random one of `candidates` other than `skip`, in proportion to their
+/// weights
+fn weighted_choice(candidates: &[&Arc<Backend>], skip: Option<usize>) -> usize {
+    let weight = |index| {
+        if Some(index) == skip {
+            0
+        } else {
+            candidates[index].weight
+        }
+    };
+    let total_weight = (0..candidates.len()).map(weight).sum();
+    let mut slot = random_below(total_weight);
+    for index in 0..candidates.len() {
+        if slot < weight(index) {
+            return index;
+        }
+        slot -= weight(index);
+    }
+    unreachable!("slot is less than the total weight")
+}
+
+/// Get a random number below `bound`, which must not be zero
+fn random_below(bound: usize) -> usize {
+    let mut random = [0; size_of::<usize>()];
+    getrandom::getrandom(&mut random).expect("the OS random number generator is available");
+    usize::from_ne_bytes(random) % bound
+}
+
+/// The changes made by replacing the backends of a set.
+pub struct Replaced {
+    /// Backends that were not in the set before.
+    pub added: Vec<Arc<Backend>>,
+    /// Backends that are no longer in the set.
+    pub removed: Vec<Arc<Backend>>,
+}
+
+/// A set of backends that requests are distributed across.
+///
+/// The set is either fixed by the configuration, or discovered from DNS SRV records and replaced
+/// as a whole whenever it is re-resolved.
+pub struct Backends {
+    /// The current backends.
+    snapshot: ArcSwap<Snapshot>,
+    /// The SRV name the backends are discovered from, if any.
+    srv: Option<SrvName>,
+    /// Position of the next request in the rotation, modulo the number of backends (or the total
+    /// weight under the weighted strategy).
+    cursor: AtomicUsize,
+}
+
+impl Backends {
+    /// Create a new backend set from URLs optionally followed by `*WEIGHT` and `#` options, or
+    /// from a single `srv+dns:` name. A named set is empty until it is resolved with
+    /// [`resolve_service_names`](crate::discovery::resolve_service_names).
+    pub fn new(urls: Vec<String>) -> Result<Self> {
+        ensure!(!urls.is_empty(), "At least one backend is required");
+
+        let (urls, srv) = match urls.as_slice() {
+            [url] if url.starts_with(SRV_PREFIX) => (Vec::new(), Some(url.parse::<SrvName>()?)),
+            _ => {
+                ensure!(
+                    urls.iter().all(|url| !url.starts_with(SRV_PREFIX)),
+                    "{} backends cannot be mixed with other backends",
+                    SRV_PREFIX
+                );
+                (urls, None)
+            }
+        };
+
+        let backends = urls
+            .iter()
+            .map(|url| Backend::new(url).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            snapshot: ArcSwap::from_pointee(Snapshot::new(backends)),
+            srv,
+            cursor: AtomicUsize::new(0),
+        })
+    }
+
+    /// Get the SRV name the backends are discovered from, if any.
+    pub fn srv(&self) -> Option<&SrvName> {
+        self.srv.as_ref()
+    }
+
+    /// Replace the backends with a newly discovered set, returning the backends that were added
+    /// and those that were removed.
+    ///
+    /// Backends that are still present keep their health, circuit and in-flight state. `urls`
+    /// must not be empty.
+    pub fn replace(&self, urls: Vec<String>) -> Result<Replaced> {
+        let current = self.snapshot.load();
+        let mut added = Vec::new();
+        let backends = urls
+            .iter()
+            .map(|url| {
+                let backend = Backend::new(url)?;
+                let existing = current
+                    .backends
+                    .iter()
+                    .find(|existing| existing.is_same(&backend));
+                Ok(match existing {
+                    Some(existing) => existing.clone(),
+                    None => {
+                        let backend = Arc::new(backend);
+                        added.push(backend.clone());
+                        backend
+                    }
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let removed = current
+            .backends
+            .iter()
+            .filter(|old| !backends.iter().any(|kept| Arc::ptr_eq(kept, old)))
+            .cloned()
+            .collect();
+        self.snapshot.store(Arc::new(Snapshot::new(backends)));
+        Ok(Replaced { added, removed })
+    }
+
+    /// Pick a healthy, non-draining backend whose circuit is not open, using the given strategy.
+    ///
+    /// If every backend is unhealthy, draining or has an open circuit, rotation continues over all
+    /// of them rather than failing outright, since a probe may be wrong about a backend that is
+    /// still serving traffic. Open circuits are rejected by the caller.
+    ///
+    /// Under the weighted strategy, backends that recently came back into rotation are ramped up
+    /// over `slow_start`, if given, passing the rest of their share on to the next backend.
+    pub fn next(&self, strategy: LbStrategy, slow_start: Option<Duration>) -> Arc<Backend> {
+        let snapshot = self.snapshot.load();
+        let len = snapshot.backends.len();
+        let cursor = self.cursor.fetch_add(1, Ordering::Relaxed);
+        let start = match strategy {
+            LbStrategy::RoundRobin => cursor % len,
+            LbStrategy::Weighted => {
+                let index = snapshot.weighted_index(cursor % snapshot.total_weight);
+                match slow_start {
+                    Some(slow_start) if !admits(snapshot.backends[index].warmup(slow_start)) => {
+                        (index + 1) % len
+                    }
+                    _ => index,
+                }
+            }
+            LbStrategy::LeastConn => {
+                if let Some(backend) = snapshot.least_conn() {
+                    return backend.clone();
+                }
+                cursor % len
+            }
+            LbStrategy::PowerOfTwoChoices => {
+                if let Some(backend) = snapshot.power_of_two_choices() {
+                    return backend.clone();
+                }
+                cursor % len
+            }
+        };
+
+        (0..len)
+            .map(|offset| &snapshot.backends[(start + offset) % len])
+            .find(|backend| backend.is_available())
+            .unwrap_or(&snapshot.backends[start])
+            .clone()
+    }
+
+    /// Pick an available backend that `usable` accepts and that has a free concurrency slot, taking
+    /// the slot. `usable` is only asked about backends whose slot was taken. Returns `None` if no
+    /// such backend is left.
+    pub fn failover(
+        &self,
+        usable: impl Fn(&Backend) -> bool,
+    ) -> Option<(Arc<Backend>, ConcurrencyPermit)> {
+        let snapshot = self.snapshot.load();
+        let len = snapshot.backends.len();
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed) % len;
+        (0..len)
+            .map(|offset| &snapshot.backends[(start + offset) % len])
+            .filter(|backend| backend.is_available())
+            .find_map(|backend| {
+                let permit = backend.try_acquire()?;
+                usable(backend).then(|| (backend.clone(), permit))
+            })
+    }
+
+    /// Swap each backend for one of the `previous` backends created from the same URL and
+    /// options, if any, taking it out of `previous` so that it is only reused once.
+    ///
+    /// Reused backends keep their health, circuit, in-flight and slow start state, which a
+    /// reloaded configuration would otherwise lose.
+    pub fn reuse(&self, previous: &mut Vec<Arc<Backend>>) {
+        let current = self.snapshot.load();
+        let backends = current
+            .backends
+            .iter()
+            .map(|backend| {
+                match previous
+                    .iter()
+                    .position(|existing| existing.is_same(backend))
+                {
+                    Some(index) => previous.swap_remove(index),
+                    None => backend.clone(),
+                }
+            })
+            .collect();
+        self.snapshot.store(Arc::new(Snapshot::new(backends)));
+    }
+
+    /// Get all current backends.
+    pub fn all(&self) -> Vec<Arc<Backend>> {
+        self.snapshot.load().backends.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(urls: &[&str]) -> Snapshot {
+        Snapshot::new(
+            urls.iter()
+                .map(|url| Arc::new(Backend::new(url).unwrap()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn power_of_two_choices_prefers_less_loaded_backend() {
+        let snapshot = snapshot(&["http://127.0.0.1:8001", "http://127.0.0.1:8002"]);
+        let (busy, idle) = (&snapshot.backends[0], &snapshot.backends[1]);
+        let _requests = [busy.start_request(), busy.start_request()];
+
+        for _ in 0..100 {
+            let picked = snapshot.power_of_two_choices().unwrap();
+            assert_eq!(picked.url(), idle.url());
+        }
+    }
+
+    #[test]
+    fn power_of_two_choices_skips_unavailable_backends() {
+        let snapshot = snapshot(&[
+            "http://127.0.0.1:8001",
+            "http://127.0.0.1:8002",
+            "http://127.0.0.1:8003",
+        ]);
+        let (draining, busy, quiet) = (
+            &snapshot.backends[0],
+            &snapshot.backends[1],
+            &snapshot.backends[2],
+        );
+        draining.set_draining(true);
+        let _requests = [
+            busy.start_request(),
+            busy.start_request(),
+            quiet.start_request(),
+        ];
+
+        // The idle backend is never picked, and of the other two the less loaded one always wins
+        for _ in 0..100 {
+            let picked = snapshot.power_of_two_choices().unwrap();
+            assert_eq!(picked.url(), quiet.url());
+        }
+
+        // With a single backend left there is nothing to compare, however loaded it is
+        quiet.set_draining(true);
+        for _ in 0..10 {
+            assert_eq!(snapshot.power_of_two_choices().unwrap().url(), busy.url());
+        }
+
+        busy.set_draining(true);
+        assert!(snapshot.power_of_two_choices().is_none());
+    }
+
+    #[test]
+    fn power_of_two_choices_spreads_evenly_across_equally_loaded_backends() {
+        const PICKS: usize = 8000;
+
+        let urls = [
+            "http://127.0.0.1:8001",
+            "http://127.0.0.1:8002",
+            "http://127.0.0.1:8003",
+            "http://127.0.0.1:8004",
+        ];
+        let backends = Backends::new(urls.map(String::from).to_vec()).unwrap();
+        let all = backends.all();
+        let _requests = all
+            .iter()
+            .map(|backend| backend.start_request())
+            .collect::<Vec<_>>();
+
+        let mut picks = vec![0_usize; all.len()];
+        for _ in 0..PICKS {
+            let picked = backends.next(LbStrategy::PowerOfTwoChoices, None);
+            let index = all
+                .iter()
+                .position(|backend| Arc::ptr_eq(backend, &picked))
+                .unwrap();
+            picks[index] += 1;
+        }
+
+        // Each backend expects 2000 picks, with a standard deviation of about 40
+        let expected = PICKS / all.len();
+        for (url, count) in urls.iter().zip(&picks) {
+            assert!(
+                count.abs_diff(expected) < expected / 10,
+                "{} picked {} times: {:?}",
+                url,
+                count,
+                picks
+            );
+        }
+    }
+
+    #[test]
+    fn power_of_two_choices_falls_back_to_a_single_backend() {
+        let backends = Backends::new(vec!["http://127.0.0.1:8001".to_string()]).unwrap();
+        let only = backends.all().remove(0);
+        let _requests = [only.start_request(), only.start_request()];
+
+        for _ in 0..10 {
+            let picked = backends.next(LbStrategy::PowerOfTwoChoices, None);
+            assert!(Arc::ptr_eq(&picked, &only));
+        }
+
+        // Still picked when unavailable, as there is nothing else to send the request to
+        only.set_draining(true);
+        let picked = backends.next(LbStrategy::PowerOfTwoChoices, None);
+        assert!(Arc::ptr_eq(&picked, &only));
+    }
+
+    #[test]
+    fn reuses_unchanged_backends_with_their_state() {
+        let previous = Backends::new(vec![
+            "http://127.0.0.1:8001".to_string(),
+            "http://127.0.0.1:8002*2".to_string(),
+        ])
+        .unwrap();
+        let (down, reweighted) = (previous.all()[0].clone(), previous.all()[1].clone());
+        down.record_failure(1);
+        let _request = down.start_request();
+
+        let reloaded = Backends::new(vec![
+            "http://127.0.0.1:8001".to_string(),
+            "http://127.0.0.1:8002*3".to_string(),
+            "http://127.0.0.1:8003".to_string(),
+        ])
+        .unwrap();
+        let mut unused = previous.all();
+        reloaded.reuse(&mut unused);
+
+        let all = reloaded.all();
+        assert!(Arc::ptr_eq(&all[0], &down));
+        assert!(!all[0].is_healthy());
+        assert_eq!(all[0].in_flight(), 1);
+        // Changed and added backends start afresh
+        assert!(!Arc::ptr_eq(&all[1], &reweighted));
+        assert!(all[1].is_healthy() && all[2].is_healthy());
+        assert_eq!(unused.len(), 1);
+        assert!(Arc::ptr_eq(&unused[0], &reweighted));
+    }
+
+    #[tokio::test]
+    async fn cancels_draining_a_url_added_back() {
+        let url = "http://127.0.0.1:8101";
+        let removed = Arc::new(Backend::new(url).unwrap());
+        let request = removed.start_request();
+
+        drain_removed(vec![removed.clone()], &[]);
+        assert!(removed.is_draining());
+        assert!(drains().contains_key(url));
+
+        let added_back = Arc::new(Backend::new(url).unwrap());
+        drain_removed(Vec::new(), &[added_back]);
+        assert!(!drains().contains_key(url));
+        drop(request);
+    }
+
+    #[tokio::test]
+    async fn takes_over_a_pending_drain_of_the_same_url() {
+        let url = "http://127.0.0.1:8102";
+        let first = Arc::new(Backend::new(url).unwrap());
+        let second = Arc::new(Backend::new(url).unwrap());
+        let first_request = first.start_request();
+
+        drain_removed(vec![first.clone()], &[]);
+        drain_removed(vec![second.clone()], &[]);
+        let backends = drains().get(url).unwrap().backends.clone();
+        assert_eq!(backends.len(), 2);
+        assert!(Arc::ptr_eq(&backends[0], &first) && Arc::ptr_eq(&backends[1], &second));
+
+        // The drain waits for the requests of the first backend too
+        tokio::time::sleep(DRAIN_CHECK_INTERVAL * 2).await;
+        assert!(drains().contains_key(url));
+        drop(first_request);
+        tokio::time::sleep(DRAIN_CHECK_INTERVAL * 2).await;
+        assert!(!drains().contains_key(url));
+    }
+
+    #[test]
+    fn rejects_an_empty_set() {
+        assert!(Backends::new(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn leaves_a_named_set_to_be_resolved() {
+        let backends = Backends::new(vec!["srv+dns:_http._tcp.service.local".to_string()]).unwrap();
+        assert!(backends.srv().is_some());
+        assert!(backends.all().is_empty());
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    HeaderMap, Method, Request, Response, StatusCode,
+    body::Bytes,
+    header::{
+        AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, COOKIE, HOST, SET_COOKIE, UPGRADE, VARY,
+    },
+};
+use lru::LruCache;
+use tracing::debug;
+
+use crate::proxy::{ResponseBody, buffer_response_body};
+
+/// A cached response.
+struct Entry {
+    /// Response status.
+    status: StatusCode,
+    /// Response headers.
+    headers: HeaderMap,
+    /// Buffered response body.
+    body: Bytes,
+    /// When the entry stops being served.
+    expires_at: Instant,
+}
+
+/// An in-memory LRU cache of backend responses to GET requests.
+pub struct Cache {
+    /// Cached entries keyed by method, scheme, host, path and query.
+    entries: Mutex<LruCache<String, Entry>>,
+    /// TTL for responses that don't specify `max-age`.
+    default_ttl: Duration,
+    /// Largest response body that will be cached, in bytes.
+    max_entry_size: usize,
+}
+
+impl Cache {
+    /// Create a cache holding at most `capacity` responses.
+    pub fn new(capacity: NonZeroUsize, default_ttl: Duration, max_entry_size: usize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            default_ttl,
+            max_entry_size,
+        }
+    }
+
+    /// Get the cache key for a request, or `None` if its response must not be cached.
+    ///
+    /// Only GET requests without credentials or cookies are cached, since their responses may be
+    /// specific to one client, and clients can opt out with `Cache-Control: no-store`. Upgrade
+    /// requests are never cached.
+    ///
+    /// The key names the host the request was for, from the URI's authority or else the `Host`
+    /// header, so different virtual hosts never share entries, and the origin form of a URI sent
+    /// over HTTP/1.1 gets the same key as the absolute form sent over HTTP/2. The scheme is taken
+    /// from the URI, or else from whether the request came in over TLS.
+    pub fn key<B>(req: &Request<B>, is_tls: bool) -> Option<String> {
+        if req.method() != Method::GET
+            || req.headers().contains_key(AUTHORIZATION)
+            || req.headers().contains_key(COOKIE)
+            || req.headers().contains_key(UPGRADE)
+        {
+            return None;
+        }
+
+        let directives = CacheControl::parse(req.headers());
+        if directives.no_store {
+            return None;
+        }
+
+        let uri = req.uri();
+        let scheme = match uri.scheme_str() {
+            Some(scheme) => scheme.to_ascii_lowercase(),
+            None if is_tls => "https".to_string(),
+            None => "http".to_string(),
+        };
+        let host = match uri.authority() {
+            Some(authority) => authority.as_str(),
+            None => req
+                .headers()
+                .get(HOST)
+                .and_then(|host| host.to_str().ok())
+                .unwrap_or_default(),
+        };
+        let path_and_query = uri.path_and_query().map_or("/", |pq| pq.as_str());
+        Some(format!(
+            "{} {}://{}{}",
+            req.method(),
+            scheme,
+            host.to_ascii_lowercase(),
+            path_and_query
+        ))
+    }
+
+    /// Get a fresh cached response, evicting it if it has expired.
+    pub fn get(&self, key: &str) -> Option<Response<ResponseBody>> {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.get(key)?.expires_at <= Instant::now() {
+            entries.pop(key);
+            return None;
+        }
+        let entry = entries.peek(key)?;
+
+        let mut response = Response::new(
+            Full::new(entry.body.clone())
+                .map_err(|never| match never {})
+                .boxed_unsync(),
+        );
+        *response.status_mut() = entry.status;
+        *response.headers_mut() = entry.headers.clone();
+        Some(response)
+    }
+
+    /// Store a backend response if it is cacheable, returning the response to send to the
+    /// client.
+    ///
+    /// Only `200 OK` responses with a known `Content-Length` below the size threshold are
+    /// buffered, so large or streamed bodies pass through untouched.
+    pub async fn store(
+        &self,
+        key: String,
+        response: Response<ResponseBody>,
+    ) -> Response<ResponseBody> {
+        let Some(ttl) = self.ttl(&response) else {
+            return response;
+        };
+
+        let (parts, body) = response.into_parts();
+        let purpose = format!("for {}", key);
+        let body = match buffer_response_body(body, self.max_entry_size, &purpose).await {
+            Ok(collected) => collected.to_bytes(),
+            Err(response) => return response,
+        };
+
+        debug!("Caching {} for {:?}", key, ttl);
+        self.entries.lock().unwrap().put(
+            key,
+            Entry {
+                status: parts.status,
+                headers: parts.headers.clone(),
+                body: body.clone(),
+                expires_at: Instant::now() + ttl,
+            },
+        );
+
+        Response::from_parts(
+            parts,
+            Full::new(body)
+                .map_err(|never| match never {})
+                .boxed_unsync(),
+        )
+    }
+
+    /// Get how long a response may be cached for, or `None` if it is not cacheable.
+    ///
+    /// Responses with `Vary` are not cached, since the key doesn't include the request headers
+    /// they vary on.
+    fn ttl(&self, response: &Response<ResponseBody>) -> Option<Duration> {
+        if response.status() != StatusCode::OK
+            || response.headers().contains_key(SET_COOKIE)
+            || response.headers().contains_key(VARY)
+        {
+            return None;
+        }
+
+        let content_length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok())?;
+        if content_length > self.max_entry_size {
+            return None;
+        }
+
+        let directives = CacheControl::parse(response.headers());
+        if directives.no_store || directives.no_cache || directives.private {
+            return None;
+        }
+
+        let ttl = directives.max_age.unwrap_or(self.default_ttl);
+        (!ttl.is_zero()).then_some(ttl)
+    }
+}
+
+/// The `Cache-Control` directives relevant to the cache.
+#[derive(Default)]
+struct CacheControl {
+    /// `no-store` was given.
+    no_store: bool,
+    /// `no-cache` was given.
+    no_cache: bool,
+    /// `private` was given.
+    private: bool,
+    /// Freshness lifetime from `s-maxage`, or `max-age` if absent.
+    max_age: Option<Duration>,
+}
+
+impl CacheControl {
+    /// Parse all `Cache-Control` headers in a header map, ignoring unknown directives.
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut directives = Self::default();
+        let mut shared_max_age = None;
+
+        for directive in headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+        {
+            let (name, value) = match directive.trim().split_once('=') {
+                Some((name, value)) => (name, Some(value.trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let seconds = value
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs);
+
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "max-age" => directives.max_age = seconds,
+                "s-maxage" => shared_max_age = seconds,
+                _ => {}
+            }
+        }
+
+        directives.max_age = shared_max_age.or(directives.max_age);
+        directives
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::get("/page?q=1").header(HOST, "example.com");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn response(headers: &[(&str, &str)]) -> Response<ResponseBody> {
+        let mut builder = Response::builder().header(CONTENT_LENGTH, "2");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder
+            .body(
+                Full::new(Bytes::from_static(b"ok"))
+                    .map_err(|never| match never {})
+                    .boxed_unsync(),
+            )
+            .unwrap()
+    }
+
+    fn cache() -> Cache {
+        Cache::new(NonZeroUsize::new(8).unwrap(), Duration::from_secs(60), 1024)
+    }
+
+    #[test]
+    fn keys_anonymous_get_requests() {
+        assert_eq!(
+            Cache::key(&request(&[]), false).as_deref(),
+            Some("GET http://example.com/page?q=1")
+        );
+        assert_eq!(
+            Cache::key(&request(&[]), true).as_deref(),
+            Some("GET https://example.com/page?q=1")
+        );
+    }
+
+    #[test]
+    fn keys_hosts_separately() {
+        let first = Request::get("/").header(HOST, "a.example.com").body(());
+        let second = Request::get("/").header(HOST, "b.example.com").body(());
+        assert_ne!(
+            Cache::key(&first.unwrap(), false),
+            Cache::key(&second.unwrap(), false)
+        );
+    }
+
+    #[test]
+    fn keys_origin_and_absolute_forms_alike() {
+        let origin = Request::get("/page?q=1")
+            .header(HOST, "Example.COM:8443")
+            .body(())
+            .unwrap();
+        let absolute = Request::get("HTTPS://example.com:8443/page?q=1")
+            .body(())
+            .unwrap();
+        assert_eq!(Cache::key(&origin, true), Cache::key(&absolute, true));
+        assert!(Cache::key(&origin, true).is_some());
+    }
+
+    #[tokio::test]
+    async fn serves_entries_only_to_the_host_they_were_stored_for() {
+        let cache = cache();
+        let first = Request::get("/").header(HOST, "a.example.com").body(());
+        let second = Request::get("/").header(HOST, "b.example.com").body(());
+        let first = Cache::key(&first.unwrap(), false).unwrap();
+        let second = Cache::key(&second.unwrap(), false).unwrap();
+
+        cache.store(first.clone(), response(&[])).await;
+        assert!(cache.get(&first).is_some());
+        assert!(cache.get(&second).is_none());
+    }
+
+    #[test]
+    fn skips_requests_with_credentials_or_cookies() {
+        assert_eq!(
+            Cache::key(&request(&[("authorization", "Bearer x")]), false),
+            None
+        );
+        assert_eq!(
+            Cache::key(&request(&[("cookie", "session=abc")]), false),
+            None
+        );
+        assert_eq!(
+            Cache::key(&request(&[("cache-control", "no-store")]), false),
+            None
+        );
+    }
+
+    #[test]
+    fn skips_responses_with_vary() {
+        let cache = cache();
+        assert_eq!(cache.ttl(&response(&[])), Some(Duration::from_secs(60)));
+        assert_eq!(cache.ttl(&response(&[("vary", "Accept-Encoding")])), None);
+        assert_eq!(cache.ttl(&response(&[("set-cookie", "a=b")])), None);
+        assert_eq!(cache.ttl(&response(&[("cache-control", "private")])), None);
+    }
+}
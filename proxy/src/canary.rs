@@ -0,0 +1,85 @@
+use std::{net::IpAddr, str::FromStr};
+
+use anyhow::{Error, Result, bail};
+use hyper::{
+    HeaderMap,
+    header::{HeaderName, HeaderValue},
+};
+
+use crate::{backend::Backends, sticky::fnv1a};
+
+/// What requests are bucketed by when picking a share of them for the canary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CanaryBucket {
+    /// The client IP, so each client consistently sees either the canary or the stable backends.
+    ClientIp,
+    /// The request ID, spreading each client's requests over both.
+    RequestId,
+}
+
+impl FromStr for CanaryBucket {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "client_ip" => Ok(Self::ClientIp),
+            "request_id" => Ok(Self::RequestId),
+            _ => bail!(
+                "Unknown canary bucket {:?}, expected client_ip or request_id",
+                s
+            ),
+        }
+    }
+}
+
+/// Routing of a share of requests to canary backends instead of the stable ones.
+pub struct Canary {
+    /// The canary backends.
+    backends: Backends,
+    /// Share of requests sent to the canary, between 0 and 100.
+    percent: f64,
+    /// What requests are bucketed by.
+    bucket: CanaryBucket,
+    /// Header that sends a request to the canary when it has the given value, if any.
+    header: Option<(HeaderName, HeaderValue)>,
+}
+
+impl Canary {
+    /// Send `percent` percent of requests, bucketed by `bucket`, and those carrying `header` to
+    /// `backends`.
+    pub fn new(
+        backends: Backends,
+        percent: f64,
+        bucket: CanaryBucket,
+        header: Option<(HeaderName, HeaderValue)>,
+    ) -> Self {
+        Self {
+            backends,
+            percent,
+            bucket,
+            header,
+        }
+    }
+
+    /// Get the canary backends.
+    pub fn backends(&self) -> &Backends {
+        &self.backends
+    }
+
+    /// Whether a request goes to the canary, because it carries the canary header or falls in
+    /// the canary's share of buckets
+    pub fn selects(&self, headers: &HeaderMap, client_ip: IpAddr, request_id: &str) -> bool {
+        if let Some((ref name, ref value)) = self.header
+            && headers.get_all(name).iter().any(|sent| sent == value)
+        {
+            return true;
+        }
+
+        let hash = match self.bucket {
+            CanaryBucket::ClientIp => fnv1a(client_ip.to_string().as_bytes()),
+            CanaryBucket::RequestId => fnv1a(request_id.as_bytes()),
+        };
+        // Buckets of a hundredth of a percent
+        ((hash % 10_000) as f64) < self.percent * 100.0
+    }
+}
@@ -0,0 +1,405 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use crate::backend::Backend;
+
+/// Number of slices the rolling window is divided into.
+const WINDOW_BUCKETS: u32 = 10;
+
+/// Request outcomes counted during one slice of the rolling window.
+struct Bucket {
+    /// When the slice started.
+    started: Instant,
+    /// Outcomes within the slice.
+    outcomes: Outcomes,
+}
+
+/// Counts of request outcomes.
+#[derive(Default)]
+struct Outcomes {
+    /// Requests that succeeded.
+    successes: u32,
+    /// Requests that failed.
+    failures: u32,
+    /// Requests whose response latency was measured.
+    timed: u32,
+    /// Sum of the measured response latencies.
+    latency: Duration,
+}
+
+impl Outcomes {
+    /// Get the average measured response latency, if any was measured.
+    fn average_latency(&self) -> Option<Duration> {
+        (self.timed > 0).then(|| self.latency / self.timed)
+    }
+}
+
+/// The position of a circuit in the closed → open → half-open cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// Requests flow normally while outcomes are counted.
+    Closed,
+    /// Requests are rejected until the cooldown ends.
+    Open {
+        /// When the cooldown ends.
+        until: Instant,
+    },
+    /// A single trial request is let through to decide whether to close the circuit again.
+    HalfOpen {
+        /// When another trial request may be sent if this one hasn't reported back.
+        retry_at: Instant,
+    },
+}
+
+/// Mutable circuit state, guarded by the circuit's lock.
+struct Inner {
+    /// The current state.
+    state: State,
+    /// Outcome counts for the rolling window, oldest first.
+    buckets: VecDeque<Bucket>,
+}
+
+/// Circuit breaker state for a single backend.
+pub struct Circuit {
+    /// The circuit state.
+    inner: Mutex<Inner>,
+}
+
+impl Circuit {
+    /// Create a closed circuit.
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                buckets: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Whether the circuit is open and still cooling down.
+    pub fn is_open(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        matches!(inner.state, State::Open { until } if until > Instant::now())
+    }
+
+    /// Whether requests are currently turned away, because the circuit is open and still cooling
+    /// down or half-open with its trial request in flight.
+    pub fn is_rejecting(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        match inner.state {
+            State::Closed => false,
+            State::Open { until } => until > now,
+            State::HalfOpen { retry_at } => retry_at > now,
+        }
+    }
+
+    /// Name the state of the circuit: `closed`, `open` while cooling down, or `half_open` once a
+    /// trial request is due or in flight.
+    pub fn state_label(&self) -> &'static str {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::Closed => "closed",
+            State::Open { until } if until > Instant::now() => "open",
+            State::Open { .. } | State::HalfOpen { .. } => "half_open",
+        }
+    }
+}
+
+/// Settings shared by the circuit breakers of every backend.
+pub struct CircuitBreaker {
+    /// Fraction of failed requests within the window that opens the circuit, if enabled.
+    failure_ratio: Option<f64>,
+    /// Average response latency within the window that opens the circuit, if enabled.
+    latency_threshold: Option<Duration>,
+    /// Fewest requests within the window before the failure ratio or average latency is
+    /// considered.
+    min_requests: u32,
+    /// Length of the rolling window outcomes are counted over.
+    window: Duration,
+    /// How long an open circuit rejects requests before letting a trial request through.
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    /// Create circuit breaker settings.
+    pub fn new(
+        failure_ratio: Option<f64>,
+        latency_threshold: Option<Duration>,
+        min_requests: u32,
+        window: Duration,
+        cooldown: Duration,
+    ) -> Self {
+        Self {
+            failure_ratio,
+            latency_threshold,
+            min_requests: min_requests.max(1),
+            window,
+            cooldown,
+        }
+    }
+
+    /// Whether a request may be sent to `backend`.
+    ///
+    /// Once the cooldown of an open circuit has passed, the circuit becomes half-open and a single
+    /// trial request is allowed through. If that request never reports back (e.g. because the
+    /// client went away), another one is allowed after a further cooldown.
+    pub fn allow(&self, backend: &Backend) -> bool {
+        let now = Instant::now();
+        let mut inner = backend.circuit().inner.lock().unwrap();
+
+        match inner.state {
+            State::Closed => true,
+            State::Open { until } if now < until => false,
+            State::HalfOpen { retry_at } if now < retry_at => false,
+            state => {
+                if let State::Open { .. } = state {
+                    info!(
+                        "Circuit for backend {} half-open, sending a trial request",
+                        backend.url()
+                    );
+                }
+                inner.state = State::HalfOpen {
+                    retry_at: now + self.cooldown,
+                };
+                true
+            }
+        }
+    }
+
+    /// Record the outcome of a request sent to `backend`, along with how long its response took
+    /// if one was received, opening or closing its circuit as needed.
+    ///
+    /// A trial request only closes the circuit if it succeeded without exceeding the latency
+    /// threshold.
+    pub fn record(&self, backend: &Backend, success: bool, latency: Option<Duration>) {
+        let now = Instant::now();
+        let mut inner = backend.circuit().inner.lock().unwrap();
+        let slow = latency
+            .zip(self.latency_threshold)
+            .is_some_and(|(latency, threshold)| latency > threshold);
+
+        match inner.state {
+            State::HalfOpen { .. } if success && !slow => {
+                info!("Circuit for backend {} closed", backend.url());
+                inner.state = State::Closed;
+                inner.buckets.clear();
+            }
+            State::HalfOpen { .. } => {
+                warn!(
+                    "Trial request to backend {} {}, circuit open for {:?}",
+                    backend.url(),
+                    if success { "was too slow" } else { "failed" },
+                    self.cooldown
+                );
+                inner.state = State::Open {
+                    until: now + self.cooldown,
+                };
+            }
+            // Late outcomes of requests sent before the circuit opened don't matter
+            State::Open { .. } => {}
+            State::Closed => {
+                let outcomes = self.count(&mut inner.buckets, now, success, latency);
+                let total = outcomes.successes + outcomes.failures;
+                if total < self.min_requests {
+                    return;
+                }
+
+                if self.failure_ratio.is_some_and(|failure_ratio| {
+                    f64::from(outcomes.failures) / f64::from(total) >= failure_ratio
+                }) {
+                    warn!(
+                        "Circuit for backend {} open for {:?} after {} of {} requests failed",
+                        backend.url(),
+                        self.cooldown,
+                        outcomes.failures,
+                        total
+                    );
+                } else if let (Some(threshold), Some(average)) =
+                    (self.latency_threshold, outcomes.average_latency())
+                    && outcomes.timed >= self.min_requests
+                    && average > threshold
+                {
+                    warn!(
+                        "Circuit for backend {} open for {:?} after an average latency of {:?} \
+                         over {} responses",
+                        backend.url(),
+                        self.cooldown,
+                        average,
+                        outcomes.timed
+                    );
+                } else {
+                    return;
+                }
+                inner.state = State::Open {
+                    until: now + self.cooldown,
+                };
+                inner.buckets.clear();
+            }
+        }
+    }
+
+    /// Add an outcome to the rolling window, returning the outcomes within it.
+    fn count(
+        &self,
+        buckets: &mut VecDeque<Bucket>,
+        now: Instant,
+        success: bool,
+        latency: Option<Duration>,
+    ) -> Outcomes {
+        while buckets
+            .front()
+            .is_some_and(|bucket| now.duration_since(bucket.started) >= self.window)
+        {
+            buckets.pop_front();
+        }
+
+        let bucket_width = self.window / WINDOW_BUCKETS;
+        if buckets
+            .back()
+            .is_none_or(|bucket| now.duration_since(bucket.started) >= bucket_width)
+        {
+            buckets.push_back(Bucket {
+                started: now,
+                outcomes: Outcomes::default(),
+            });
+        }
+
+        let bucket = &mut buckets
+            .back_mut()
+            .expect("window has a current bucket")
+            .outcomes;
+        if success {
+            bucket.successes += 1;
+        } else {
+            bucket.failures += 1;
+        }
+        if let Some(latency) = latency {
+            bucket.timed += 1;
+            bucket.latency += latency;
+        }
+
+        buckets
+            .iter()
+            .fold(Outcomes::default(), |mut total, bucket| {
+                total.successes += bucket.outcomes.successes;
+                total.failures += bucket.outcomes.failures;
+                total.timed += bucket.outcomes.timed;
+                total.latency += bucket.outcomes.latency;
+                total
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread::sleep};
+
+    use super::*;
+    use crate::backend::Backends;
+
+    const COOLDOWN: Duration = Duration::from_millis(50);
+
+    fn breaker(failure_ratio: Option<f64>, latency_threshold: Option<Duration>) -> CircuitBreaker {
+        CircuitBreaker::new(
+            failure_ratio,
+            latency_threshold,
+            4,
+            Duration::from_secs(60),
+            COOLDOWN,
+        )
+    }
+
+    fn backend() -> Arc<Backend> {
+        Backends::new(vec!["http://127.0.0.1:8001".to_string()])
+            .unwrap()
+            .all()
+            .remove(0)
+    }
+
+    #[test]
+    fn opens_once_enough_requests_failed() {
+        let (breaker, backend) = (breaker(Some(0.5), None), backend());
+
+        // Too few requests to judge the backend by yet
+        for _ in 0..3 {
+            breaker.record(&backend, false, None);
+        }
+        assert!(breaker.allow(&backend));
+        assert_eq!(backend.circuit().state_label(), "closed");
+
+        breaker.record(&backend, false, None);
+        assert!(!breaker.allow(&backend));
+        assert!(backend.circuit().is_open());
+        assert_eq!(backend.circuit().state_label(), "open");
+    }
+
+    #[test]
+    fn stays_closed_below_the_failure_ratio() {
+        let (breaker, backend) = (breaker(Some(0.5), None), backend());
+        for success in [true, true, false, true, false] {
+            breaker.record(&backend, success, None);
+        }
+        assert!(breaker.allow(&backend));
+    }
+
+    #[test]
+    fn opens_on_slow_responses() {
+        let breaker = breaker(None, Some(Duration::from_millis(100)));
+        let backend = backend();
+        for _ in 0..4 {
+            breaker.record(&backend, true, Some(Duration::from_millis(150)));
+        }
+        assert!(!breaker.allow(&backend));
+    }
+
+    #[test]
+    fn lets_one_trial_request_through_after_the_cooldown() {
+        let (breaker, backend) = (breaker(Some(0.5), None), backend());
+        for _ in 0..4 {
+            breaker.record(&backend, false, None);
+        }
+
+        sleep(COOLDOWN);
+        assert_eq!(backend.circuit().state_label(), "half_open");
+        assert!(breaker.allow(&backend));
+        assert!(!breaker.allow(&backend));
+        assert!(backend.circuit().is_rejecting());
+
+        breaker.record(&backend, true, Some(Duration::from_millis(1)));
+        assert_eq!(backend.circuit().state_label(), "closed");
+        assert!(breaker.allow(&backend));
+    }
+
+    #[test]
+    fn opens_again_when_the_trial_request_fails() {
+        let (breaker, backend) = (breaker(Some(0.5), None), backend());
+        for _ in 0..4 {
+            breaker.record(&backend, false, None);
+        }
+
+        sleep(COOLDOWN);
+        assert!(breaker.allow(&backend));
+        breaker.record(&backend, false, None);
+        assert!(backend.circuit().is_open());
+        assert!(!breaker.allow(&backend));
+    }
+
+    #[test]
+    fn forgets_outcomes_that_left_the_window() {
+        let breaker = CircuitBreaker::new(Some(0.5), None, 4, Duration::from_millis(50), COOLDOWN);
+        let backend = backend();
+        for _ in 0..3 {
+            breaker.record(&backend, false, None);
+        }
+
+        sleep(Duration::from_millis(60));
+        breaker.record(&backend, false, None);
+        assert!(breaker.allow(&backend));
+    }
+}
@@ -0,0 +1,151 @@
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder};
+use futures_util::TryStreamExt;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::{
+    HeaderMap, Response, StatusCode,
+    body::{Body, Frame},
+    header::{
+        ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE,
+        HeaderValue, VARY,
+    },
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::proxy::ResponseBody;
+
+/// A content encoding the proxy can compress responses with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// Brotli (`br`).
+    Brotli,
+    /// Gzip (`gzip`).
+    Gzip,
+}
+
+impl Encoding {
+    /// The token used for this encoding in `Accept-Encoding` and `Content-Encoding`.
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Pick the preferred encoding supported by the client, based on its `Accept-Encoding`
+    /// headers. Brotli wins ties since it compresses better.
+    pub fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let mut best: Option<(Self, f32)> = None;
+
+        for entry in headers
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+        {
+            let mut params = entry.split(';').map(str::trim);
+            let token = params.next().unwrap_or_default();
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let encoding = match token.to_ascii_lowercase().as_str() {
+                "br" => Self::Brotli,
+                "gzip" | "*" => Self::Gzip,
+                _ => continue,
+            };
+
+            let preferred = best.is_none_or(|(current, best_quality)| {
+                quality > best_quality
+                    || (quality == best_quality
+                        && encoding == Self::Brotli
+                        && current == Self::Gzip)
+            });
+            if quality > 0.0 && preferred {
+                best = Some((encoding, quality));
+            }
+        }
+
+        best.map(|(encoding, _)| encoding)
+    }
+}
+
+/// Whether a content type is worth compressing.
+fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    // gRPC has its own compression, and its trailers would be lost
+    if mime.starts_with("application/grpc") {
+        return false;
+    }
+
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+        )
+}
+
+/// Compress a response with the given encoding if it is eligible.
+///
+/// Responses that are already encoded, have no body or only part of it, are not a compressible
+/// content type or are known to be smaller than `min_size` bytes are returned unchanged.
+pub fn compress(
+    response: Response<ResponseBody>,
+    encoding: Encoding,
+    min_size: u64,
+) -> Response<ResponseBody> {
+    let headers = response.headers();
+    let eligible = !matches!(
+        response.status(),
+        StatusCode::NO_CONTENT | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED
+    ) && !headers.contains_key(CONTENT_ENCODING)
+        && headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(is_compressible)
+        && response
+            .body()
+            .size_hint()
+            .exact()
+            .is_none_or(|size| size >= min_size);
+    if !eligible {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.remove(ACCEPT_RANGES);
+    parts.headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+    let reader = StreamReader::new(body.into_data_stream());
+    let body = match encoding {
+        Encoding::Brotli => {
+            StreamBody::new(ReaderStream::new(BrotliEncoder::new(reader)).map_ok(Frame::data))
+                .boxed_unsync()
+        }
+        Encoding::Gzip => {
+            StreamBody::new(ReaderStream::new(GzipEncoder::new(reader)).map_ok(Frame::data))
+                .boxed_unsync()
+        }
+    };
+
+    Response::from_parts(parts, body)
+}
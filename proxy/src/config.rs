@@ -1,51 +1,1407 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    collections::HashMap,
+    iter,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail, ensure};
+use hyper::{
+    Method, StatusCode,
+    header::{AUTHORIZATION, COOKIE, HeaderName, PROXY_AUTHORIZATION, SET_COOKIE},
+};
+use toml::{Table, Value};
+
+use crate::{
+    access_log::{AccessLogFormat, AccessLogOutput, Rotation},
+    acme::AcmeSettings,
+    auth::BasicAuth,
+    backend::{Backend, BackendHttpVersion, Backends, LbStrategy, OnBackendFull},
+    cache::Cache,
+    canary::{Canary, CanaryBucket},
+    circuit_breaker::CircuitBreaker,
+    connection_limit::{AcceptRate, ConnectionLimit, OverloadMode},
+    cors::Cors,
+    dns::DnsResolver,
+    error_page::{ErrorPage, ErrorPages},
+    filter::{DEFAULT_FILTERS, FilterChain, RequestFilter},
+    headers::{HeaderAllowlist, HeaderRules, parse_header},
+    ip_filter::{IpFilter, parse_ranges},
+    listener::{BindAddr, SocketOptions},
+    maintenance::Maintenance,
+    mirror::Mirror,
+    outbound_proxy::{OutboundProxy, ProxySettings},
+    path_filter::PathFilter,
+    proxy::RETRYABLE_METHODS,
+    rate_limit::RateLimiter,
+    request_buffer::RequestBuffer,
+    response_buffer::ResponseBuffer,
+    rewrite::BodyRewriter,
+    routes::Routes,
+    static_files::StaticFiles,
+    status::StatusEndpoint,
+    status_map::StatusMap,
+    sticky::StickySessions,
+    tls::{BackendTls, TlsVersion},
+};
 
 /// Configuration for the application.
 pub struct Config {
     /// The address to bind the server to.
-    pub bind_addr: SocketAddr,
-    /// The backend URL to connect to.
-    pub backend_url: String,
+    pub bind_addr: BindAddr,
+    /// Permissions applied to the Unix socket file, if binding to one.
+    pub unix_socket_mode: Option<u32>,
+    /// TCP options applied to accepted client connections.
+    pub socket_options: SocketOptions,
+    /// The backends to proxy requests to when no route matches.
+    pub backends: Backends,
+    /// Backends for requests that match no route when routes are configured, which are otherwise
+    /// answered with `404`.
+    pub default_backend: Option<Backends>,
+    /// Canary backends taking a share of the requests that match no route, if enabled.
+    pub canary: Option<Canary>,
+    /// Path-based routes to other backends.
+    pub routes: Routes,
+    /// Interval between re-resolving backends discovered through DNS SRV records; zero disables
+    /// re-resolving.
+    pub discovery_interval: Duration,
+    /// How requests are distributed across the backends of a set.
+    pub lb_strategy: LbStrategy,
+    /// How long backends that come back into rotation are ramped up for under the weighted
+    /// strategy, if enabled.
+    pub slow_start: Option<Duration>,
+    /// What to do with a request whose backend is at its `max_concurrent` limit.
+    pub on_backend_full: OnBackendFull,
+    /// Maximum time a request waits for a slot on a full backend with `ON_BACKEND_FULL=wait`.
+    pub backend_full_wait: Duration,
+    /// Cookie-based session affinity, if enabled.
+    pub sticky_sessions: Option<StickySessions>,
+    /// Path requested on each backend by active health checks.
+    pub health_check_path: String,
+    /// Interval between health checks; zero disables them.
+    pub health_check_interval: Duration,
+    /// Consecutive failed health checks before a backend is taken out of rotation.
+    pub health_check_threshold: u32,
+    /// Whether to check that backends are reachable on startup.
+    pub verify_backend_on_start: bool,
+    /// Whether to exit on startup when no backend is reachable.
+    pub require_backend_on_start: bool,
+    /// Path the proxy answers liveness probes on, if enabled.
+    pub liveness_path: Option<String>,
+    /// Path the proxy answers readiness probes on, if enabled.
+    pub readiness_path: Option<String>,
+    /// Maximum time to wait for a backend to respond to a proxied request.
+    pub upstream_timeout: Duration,
+    /// Maximum time from receiving a request's headers to having its response, including reading
+    /// its body, if limited.
+    pub request_timeout: Option<Duration>,
+    /// Longest upstream timeout clients may request with `X-Proxy-Timeout-Ms`, if the header is
+    /// allowed.
+    pub timeout_header_max: Option<Duration>,
+    /// Maximum time to wait for a connection to a backend to be established, if limited.
+    pub connect_timeout: Option<Duration>,
+    /// Forward proxy that backend connections are tunneled through, if any.
+    pub outbound_proxy: Option<OutboundProxy>,
+    /// Maximum time a request body sent with `Expect: 100-continue` is held back waiting for the
+    /// backend's `100 Continue`, if held back at all.
+    pub expect_continue_timeout: Option<Duration>,
+    /// Resolver for backend host names.
+    pub dns_resolver: DnsResolver,
+    /// HTTP version spoken to backends.
+    pub backend_http_version: BackendHttpVersion,
+    /// TLS settings for connections to HTTPS backends.
+    pub backend_tls: BackendTls,
+    /// Maximum number of idle connections kept open to each backend.
+    pub pool_max_idle_per_host: usize,
+    /// How long an idle backend connection is kept open.
+    pub pool_idle_timeout: Duration,
+    /// Maximum number of retries for idempotent requests after an upstream failure.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each further attempt.
+    pub retry_backoff: Duration,
+    /// Backend response statuses that are retried like connection errors.
+    pub retry_on_status: Vec<StatusCode>,
+    /// Request methods that are retried, all of them safe.
+    pub retry_methods: Vec<Method>,
+    /// Per-backend circuit breaker settings, if enabled.
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Maximum request body size in bytes; zero means unlimited.
+    pub max_body_size: usize,
+    /// Buffering of small request bodies so they can be retried, if enabled.
+    pub request_buffer: Option<RequestBuffer>,
+    /// Request methods that are proxied, or `None` to allow any method.
+    pub allowed_methods: Option<Vec<Method>>,
+    /// Whether to collapse repeated slashes and resolve dot segments in request paths.
+    pub normalize_path: bool,
+    /// Allow and block lists of request paths.
+    pub path_filter: PathFilter,
+    /// Cache for GET responses, if enabled.
+    pub cache: Option<Cache>,
+    /// Buffering of small backend responses, if enabled.
+    pub response_buffer: Option<ResponseBuffer>,
+    /// Mirroring of requests to a second backend, if enabled.
+    pub mirror: Option<Mirror>,
+    /// Find-and-replace rules for HTML response bodies, if any.
+    pub body_rewriter: Option<BodyRewriter>,
+    /// Rewriting of backend response statuses, if any.
+    pub status_map: Option<StatusMap>,
+    /// Whether to compress responses for clients that support it.
+    pub compression: bool,
+    /// Smallest response body in bytes that will be compressed.
+    pub compression_min_size: u64,
+    /// Whether connections start with a PROXY protocol header carrying the real client address.
+    pub accept_proxy_protocol: bool,
+    /// Client IP allow and deny lists.
+    pub ip_filter: IpFilter,
+    /// Per-client-IP rate limiter, if enabled.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// HTTP Basic authentication gate, if enabled.
+    pub basic_auth: Option<Arc<BasicAuth>>,
+    /// Filters requests go through around proxying, including rate limiting, authentication and
+    /// CORS, in the order set by `FILTERS`.
+    pub filters: FilterChain,
+    /// Request headers forwarded to backends, if limited to an allow-list.
+    pub forward_headers_allowlist: Option<HeaderAllowlist>,
+    /// Header rules applied to requests before they are forwarded.
+    pub request_headers: HeaderRules,
+    /// Header rules applied to responses from the backend.
+    pub response_headers: HeaderRules,
+    /// Whether to keep forwarding headers sent by the client instead of stripping them.
+    pub trust_forwarded_headers: bool,
+    /// Whether to forward the TLS version and cipher suite negotiated with the client.
+    pub forward_tls_info: bool,
+    /// Number of trusted proxies in front whose `X-Forwarded-For` entries are skipped to find the
+    /// client IP, if any.
+    pub trusted_proxy_count: usize,
+    /// Whether to forward the client's `Host` instead of the backend's authority.
+    pub preserve_host: bool,
+    /// Whether to report the upstream response time in a `Server-Timing` header.
+    pub emit_server_timing: bool,
+    /// Response header naming the backend that served a request, if enabled.
+    pub backend_header: Option<HeaderName>,
+    /// Bodies for error responses generated by the proxy.
+    pub error_pages: ErrorPages,
+    /// Duration after which a request is logged as slow, if enabled.
+    pub slow_request_threshold: Option<Duration>,
+    /// Maintenance mode settings.
+    pub maintenance: Maintenance,
+    /// Assets served under `/__proxy_static/` instead of being proxied, if enabled.
+    pub static_files: Option<StaticFiles>,
+    /// Header carrying the ID used to correlate a request across services.
+    pub request_id_header: HeaderName,
+    /// Format of the per-request access log.
+    pub access_log_format: AccessLogFormat,
+    /// Where access log lines are written.
+    pub access_log_output: AccessLogOutput,
+    /// Headers whose values are redacted when logging request and response headers, if enabled.
+    pub log_headers: Option<Vec<HeaderName>>,
+    /// Limit on the number of connections served concurrently.
+    pub connection_limit: ConnectionLimit,
+    /// Limit on the rate new connections are accepted at, if enabled.
+    pub accept_rate: Option<AcceptRate>,
+    /// Maximum bytes per second read from each client connection, or `0` for unlimited.
+    pub max_upload_bps: u64,
+    /// Maximum bytes per second written to each client connection, or `0` for unlimited.
+    pub max_download_bps: u64,
+    /// Maximum time to wait for in-flight connections to finish on shutdown.
+    pub shutdown_timeout: Duration,
+    /// Address to serve Prometheus metrics on, if enabled.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Address to serve the admin API on, if enabled.
+    pub admin_addr: Option<SocketAddr>,
+    /// Bearer token required by the admin API.
+    pub admin_token: Option<String>,
+    /// JSON endpoint reporting the state of every backend, if enabled.
+    pub status_endpoint: Option<StatusEndpoint>,
     /// Path to the TLS certificate file.
     pub cert_path: PathBuf,
     /// Path to the TLS key file.
     pub key_path: PathBuf,
+    /// Inline PEM certificate chain and private key, loaded instead of the files when set.
+    pub inline_pem: Option<(String, String)>,
     /// Whether to use TLS.
     pub use_tls: bool,
+    /// Address to serve redirects from plaintext HTTP to HTTPS on, if enabled.
+    pub redirect_http_addr: Option<SocketAddr>,
+    /// Settings for obtaining the certificate with ACME, if enabled.
+    pub acme: Option<AcmeSettings>,
+    /// Whether to offer HTTP/2 to TLS clients.
+    pub enable_h2: bool,
+    /// Whether to accept HTTP/2 with prior knowledge on plaintext connections.
+    pub enable_h2c: bool,
+    /// Whether to serve HTTP/3 over QUIC on the UDP port matching `BIND_ADDR`.
+    pub enable_http3: bool,
+    /// Maximum size in bytes of a request's headers.
+    pub max_header_size: usize,
+    /// Whether HTTP/1.1 connections are kept open between requests.
+    pub h1_keep_alive: bool,
+    /// Maximum time to wait for the headers of an HTTP/1.1 request, if limited.
+    pub h1_header_read_timeout: Option<Duration>,
+    /// Maximum time a client connection may go without requests before it is closed, if limited.
+    pub connection_idle_timeout: Option<Duration>,
+    /// Interval between HTTP/2 keep-alive pings, if enabled.
+    pub h2_keep_alive_interval: Option<Duration>,
+    /// Maximum time to wait for an HTTP/2 keep-alive ping to be acknowledged.
+    pub h2_keep_alive_timeout: Duration,
+    /// Maximum number of concurrent streams per HTTP/2 connection.
+    pub h2_max_concurrent_streams: u32,
+    /// Bytes a client may send on each HTTP/2 stream before the proxy reads them.
+    pub h2_initial_window_size: u32,
+    /// Bytes a client may send on an HTTP/2 connection, across all its streams, before the proxy
+    /// reads them.
+    pub h2_initial_connection_window_size: u32,
+    /// Interval between checks for changed TLS certificate or key files; zero disables reloading.
+    pub tls_reload_interval: Duration,
+    /// Lowest TLS protocol version accepted from clients.
+    pub tls_min_version: TlsVersion,
+    /// Names of the TLS cipher suites to allow; empty allows the rustls defaults.
+    pub tls_cipher_suites: Vec<String>,
+    /// Path to the CA bundle used to verify client certificates, if enabled.
+    pub client_ca_path: Option<PathBuf>,
+    /// Whether clients must present a valid certificate to complete the handshake.
+    pub require_client_cert: bool,
 }
 
 impl Config {
+    /// Iterate over every backend set, including those of routes, the default backend and the
+    /// canary.
+    pub fn backend_sets(&self) -> impl Iterator<Item = &Backends> {
+        iter::once(&self.backends)
+            .chain(self.routes.backend_sets())
+            .chain(&self.default_backend)
+            .chain(self.canary.as_ref().map(Canary::backends))
+    }
+
+    /// Get every current backend, including those of routes, the default backend and the canary.
+    pub fn all_backends(&self) -> Vec<Arc<Backend>> {
+        self.backend_sets().flat_map(Backends::all).collect()
+    }
+
+    /// Reuse the backends of `previous` that are still configured, so that a reload keeps their
+    /// state. Only backends whose URL or options changed, or that were added, start afresh.
+    pub fn reuse_backends(&self, previous: &Config) {
+        let mut backends = previous.all_backends();
+        for set in self.backend_sets() {
+            set.reuse(&mut backends);
+        }
+    }
+
+    /// Load configuration from the TOML file named by `CONFIG_PATH`, or from environment
+    /// variables alone when it is unset.
+    pub fn from_config_path() -> Result<Self> {
+        match std::env::var("CONFIG_PATH") {
+            Ok(path) => Self::from_file(Path::new(&path)),
+            Err(_) => Self::from_env(),
+        }
+    }
+
+    /// Get the names of settings that differ in `new` but only take effect after a restart.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let changes = [
+            ("BIND_ADDR", self.bind_addr != new.bind_addr),
+            (
+                "UNIX_SOCKET_MODE",
+                self.unix_socket_mode != new.unix_socket_mode,
+            ),
+            (
+                "TCP_NODELAY/TCP_KEEPALIVE_SECS",
+                self.socket_options != new.socket_options,
+            ),
+            ("USE_TLS", self.use_tls != new.use_tls),
+            ("ENABLE_HTTP3", self.enable_http3 != new.enable_http3),
+            (
+                "TLS_RELOAD_INTERVAL_SECS",
+                self.tls_reload_interval != new.tls_reload_interval,
+            ),
+            (
+                "REDIRECT_HTTP_ADDR",
+                self.redirect_http_addr != new.redirect_http_addr,
+            ),
+            (
+                "CONNECT_TIMEOUT_SECS",
+                self.connect_timeout != new.connect_timeout,
+            ),
+            (
+                "HTTP_PROXY/HTTPS_PROXY/NO_PROXY",
+                self.outbound_proxy != new.outbound_proxy,
+            ),
+            (
+                "DNS_CACHE_TTL_SECS/HOSTS_OVERRIDE",
+                self.dns_resolver != new.dns_resolver,
+            ),
+            (
+                "BACKEND_CA_PATH/INSECURE_SKIP_BACKEND_VERIFY/BACKEND_CLIENT_*_PATH",
+                self.backend_tls != new.backend_tls,
+            ),
+            (
+                "POOL_MAX_IDLE_PER_HOST/POOL_IDLE_TIMEOUT_SECS",
+                self.pool_max_idle_per_host != new.pool_max_idle_per_host
+                    || self.pool_idle_timeout != new.pool_idle_timeout,
+            ),
+            ("METRICS_ADDR", self.metrics_addr != new.metrics_addr),
+            ("ADMIN_ADDR", self.admin_addr != new.admin_addr),
+            (
+                "MAX_CONNECTIONS/ON_OVERLOAD/OVERLOAD_QUEUE_DEPTH/OVERLOAD_QUEUE_WAIT_MS",
+                self.connection_limit != new.connection_limit,
+            ),
+            (
+                "MAX_ACCEPT_RATE/MAX_ACCEPT_BURST",
+                self.accept_rate != new.accept_rate,
+            ),
+        ];
+
+        changes
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
     /// Load configuration from environment variables.
     pub fn from_env() -> Result<Self> {
-        let bind_addr = std::env::var("BIND_ADDR")
-            .unwrap_or_else(|_| "127.0.0.1:8000".to_string())
-            .parse()
-            .context("Failed to parse BIND_ADDR")?;
+        Self::load(&Source::default())
+    }
+
+    /// Load configuration from a TOML file, with environment variables taking precedence.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+
+        let file = contents
+            .parse::<Table>()
+            .with_context(|| format!("Failed to parse config file {:?}", path))?;
+
+        Self::load(&Source { file })
+    }
+
+    /// Build the configuration from the given source, applying defaults for unset values.
+    fn load(source: &Source) -> Result<Self> {
+        let bind_addr = source.get_or(
+            "BIND_ADDR",
+            BindAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 8000))),
+        )?;
+
+        let unix_socket_mode = source
+            .get::<String>("UNIX_SOCKET_MODE")?
+            .map(|mode| u32::from_str_radix(&mode, 8))
+            .transpose()
+            .context("Failed to parse UNIX_SOCKET_MODE as an octal mode")?;
+
+        let tcp_keepalive_secs = source.get_or("TCP_KEEPALIVE_SECS", 0)?;
+        let socket_options = SocketOptions {
+            nodelay: source.get_or("TCP_NODELAY", false)?,
+            keepalive: (tcp_keepalive_secs > 0).then(|| Duration::from_secs(tcp_keepalive_secs)),
+        };
+
+        // `BACKEND_URL` is still honoured for single-backend setups.
+        let backend_urls = match source.get_list("BACKEND_URLS")? {
+            Some(urls) => urls,
+            None => vec![source.get_or("BACKEND_URL", "https://vps.kodub.com".to_string())?],
+        };
+        ensure!(!backend_urls.is_empty(), "BACKEND_URLS must not be empty");
+
+        let canary_percent: f64 = source.get_or("CANARY_PERCENT", 0.0)?;
+        ensure!(
+            (0.0..=100.0).contains(&canary_percent),
+            "CANARY_PERCENT must be between 0 and 100"
+        );
+        let canary_bucket = source.get_or("CANARY_BUCKET", CanaryBucket::ClientIp)?;
+        let canary_header = source
+            .get::<String>("CANARY_HEADER")?
+            .map(|header| parse_header(&header))
+            .transpose()?;
+        let canary = match source.get_list("CANARY_BACKEND_URLS")? {
+            Some(urls) => {
+                ensure!(!urls.is_empty(), "CANARY_BACKEND_URLS must not be empty");
+                Some(Canary::new(
+                    Backends::new(urls)?,
+                    canary_percent,
+                    canary_bucket,
+                    canary_header,
+                ))
+            }
+            None => None,
+        };
+
+        let routes = Routes::new(source.get_list("ROUTES")?.unwrap_or_default());
+        let default_backend = source
+            .get_list("DEFAULT_BACKEND")?
+            .map(|urls| {
+                ensure!(!urls.is_empty(), "DEFAULT_BACKEND must not be empty");
+                Backends::new(urls)
+            })
+            .transpose()?;
+
+        let discovery_interval = Duration::from_secs(source.get_or("DISCOVERY_INTERVAL_SECS", 30)?);
+
+        let lb_strategy = source.get_or("LB_STRATEGY", LbStrategy::RoundRobin)?;
+
+        let slow_start_secs = source.get_or("SLOW_START_SECS", 0)?;
+        let slow_start = (slow_start_secs > 0).then(|| Duration::from_secs(slow_start_secs));
+
+        let on_backend_full = source.get_or("ON_BACKEND_FULL", OnBackendFull::Wait)?;
+        let backend_full_wait = Duration::from_millis(source.get_or("BACKEND_FULL_WAIT_MS", 100)?);
+
+        let sticky_sessions = if source.get_or("STICKY_SESSIONS", false)? {
+            let cookie_name = source.get_or("STICKY_COOKIE_NAME", "proxy_affinity".to_string())?;
+            ensure!(
+                !cookie_name.is_empty()
+                    && cookie_name
+                        .bytes()
+                        .all(|byte| byte.is_ascii_alphanumeric()
+                            || b"!#$%&'*+-.^_`|~".contains(&byte)),
+                "STICKY_COOKIE_NAME {:?} is not a valid cookie name",
+                cookie_name
+            );
+            Some(StickySessions::new(
+                cookie_name,
+                Duration::from_secs(source.get_or("STICKY_COOKIE_TTL_SECS", 3600)?),
+            ))
+        } else {
+            None
+        };
+
+        let health_check_path = source.get_or("HEALTH_CHECK_PATH", "/health".to_string())?;
+
+        let health_check_interval =
+            Duration::from_secs(source.get_or("HEALTH_CHECK_INTERVAL_SECS", 10)?);
+
+        let health_check_threshold = source.get_or("HEALTH_CHECK_FAILURE_THRESHOLD", 3)?;
+        ensure!(
+            health_check_threshold > 0,
+            "HEALTH_CHECK_FAILURE_THRESHOLD must be at least 1"
+        );
+
+        let require_backend_on_start = source.get_or("REQUIRE_BACKEND_ON_START", false)?;
+        let verify_backend_on_start =
+            require_backend_on_start || source.get_or("VERIFY_BACKEND_ON_START", false)?;
+
+        let liveness_path = probe_path(source.get_or("LIVENESS_PATH", "/healthz".to_string())?)?;
+        let readiness_path = probe_path(source.get_or("READINESS_PATH", "/readyz".to_string())?)?;
+
+        let upstream_timeout = Duration::from_secs(source.get_or("UPSTREAM_TIMEOUT_SECS", 30)?);
+        ensure!(
+            !upstream_timeout.is_zero(),
+            "UPSTREAM_TIMEOUT_SECS must be at least 1"
+        );
+
+        let request_timeout_secs = source.get_or("REQUEST_TIMEOUT_SECS", 0)?;
+        let request_timeout =
+            (request_timeout_secs > 0).then(|| Duration::from_secs(request_timeout_secs));
+
+        let timeout_header_max = source
+            .get_or("ALLOW_TIMEOUT_HEADER", false)?
+            .then(|| source.get_or("TIMEOUT_HEADER_MAX_MS", 300_000))
+            .transpose()?
+            .map(Duration::from_millis);
+        ensure!(
+            timeout_header_max.is_none_or(|max| !max.is_zero()),
+            "TIMEOUT_HEADER_MAX_MS must be at least 1"
+        );
+
+        let connect_timeout_secs = source.get_or("CONNECT_TIMEOUT_SECS", 10)?;
+        let connect_timeout =
+            (connect_timeout_secs > 0).then(|| Duration::from_secs(connect_timeout_secs));
+
+        let outbound_proxy = OutboundProxy::new(ProxySettings {
+            http: source.get("HTTP_PROXY")?,
+            https: source.get("HTTPS_PROXY")?,
+            no_proxy: source.get("NO_PROXY")?,
+        })?;
+
+        let expect_continue_timeout_ms = source.get_or("EXPECT_CONTINUE_TIMEOUT_MS", 1000)?;
+        let expect_continue_timeout = (expect_continue_timeout_ms > 0)
+            .then(|| Duration::from_millis(expect_continue_timeout_ms));
+
+        let dns_cache_ttl_secs = source.get_or("DNS_CACHE_TTL_SECS", 0)?;
+        let dns_resolver = DnsResolver::new(
+            &source.get_list("HOSTS_OVERRIDE")?.unwrap_or_default(),
+            (dns_cache_ttl_secs > 0).then(|| Duration::from_secs(dns_cache_ttl_secs)),
+        )?;
+
+        let backend_http_version =
+            source.get_or("BACKEND_HTTP_VERSION", BackendHttpVersion::Auto)?;
+
+        let pool_max_idle_per_host = source.get_or("POOL_MAX_IDLE_PER_HOST", 32)?;
+        ensure!(
+            pool_max_idle_per_host > 0,
+            "POOL_MAX_IDLE_PER_HOST must be at least 1"
+        );
+        let pool_idle_timeout = Duration::from_secs(source.get_or("POOL_IDLE_TIMEOUT_SECS", 90)?);
+        ensure!(
+            !pool_idle_timeout.is_zero(),
+            "POOL_IDLE_TIMEOUT_SECS must be at least 1"
+        );
+
+        let backend_client_cert = match (
+            source.get::<PathBuf>("BACKEND_CLIENT_CERT_PATH")?,
+            source.get::<PathBuf>("BACKEND_CLIENT_KEY_PATH")?,
+        ) {
+            (Some(cert_path), Some(key_path)) => Some((cert_path, key_path)),
+            (None, None) => None,
+            _ => bail!("BACKEND_CLIENT_CERT_PATH and BACKEND_CLIENT_KEY_PATH must be set together"),
+        };
+        let backend_tls = BackendTls::load(
+            source.get("BACKEND_CA_PATH")?,
+            source.get_or("INSECURE_SKIP_BACKEND_VERIFY", false)?,
+            backend_client_cert,
+        )?;
+
+        let max_retries = source.get_or("MAX_RETRIES", 0)?;
+        let retry_backoff = Duration::from_millis(source.get_or("RETRY_BACKOFF_MS", 100)?);
+        let retry_on_status = source
+            .get_list("RETRY_ON_STATUS")?
+            .unwrap_or_else(|| vec![StatusCode::BAD_GATEWAY]);
+        // Requests with other methods may have side effects, so sending them twice isn't safe
+        let retry_methods = source
+            .get_list::<String>("RETRY_METHODS")?
+            .map(|methods| {
+                methods
+                    .iter()
+                    .map(|method| {
+                        let method = Method::from_bytes(method.to_uppercase().as_bytes())
+                            .with_context(|| {
+                                format!("Invalid method {:?} in RETRY_METHODS", method)
+                            })?;
+                        ensure!(
+                            RETRYABLE_METHODS.contains(&method),
+                            "{} in RETRY_METHODS cannot be retried, only GET, HEAD and OPTIONS can",
+                            method
+                        );
+                        Ok(method)
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?
+            .unwrap_or_else(|| RETRYABLE_METHODS.to_vec());
+
+        let circuit_breaker_ratio: f64 = source.get_or("CIRCUIT_BREAKER_FAILURE_RATIO", 0.0)?;
+        ensure!(
+            (0.0..=1.0).contains(&circuit_breaker_ratio),
+            "CIRCUIT_BREAKER_FAILURE_RATIO must be between 0 and 1"
+        );
+        let circuit_breaker_latency =
+            Duration::from_millis(source.get_or("CIRCUIT_BREAKER_LATENCY_MS", 0)?);
+        let circuit_breaker_min_requests = source.get_or("CIRCUIT_BREAKER_MIN_REQUESTS", 20)?;
+        let circuit_breaker_window =
+            Duration::from_secs(source.get_or("CIRCUIT_BREAKER_WINDOW_SECS", 30)?);
+        ensure!(
+            !circuit_breaker_window.is_zero(),
+            "CIRCUIT_BREAKER_WINDOW_SECS must be at least 1"
+        );
+        let circuit_breaker_cooldown =
+            Duration::from_secs(source.get_or("CIRCUIT_BREAKER_COOLDOWN_SECS", 30)?);
+        let circuit_breaker_ratio = (circuit_breaker_ratio > 0.0).then_some(circuit_breaker_ratio);
+        let circuit_breaker_latency =
+            (!circuit_breaker_latency.is_zero()).then_some(circuit_breaker_latency);
+        let circuit_breaker =
+            (circuit_breaker_ratio.is_some() || circuit_breaker_latency.is_some()).then(|| {
+                CircuitBreaker::new(
+                    circuit_breaker_ratio,
+                    circuit_breaker_latency,
+                    circuit_breaker_min_requests,
+                    circuit_breaker_window,
+                    circuit_breaker_cooldown,
+                )
+            });
+
+        let max_body_size = source.get_or("MAX_BODY_SIZE", 0)?;
+
+        let allowed_methods = source
+            .get_list::<String>("ALLOWED_METHODS")?
+            .map(|methods| {
+                methods
+                    .iter()
+                    .map(|method| {
+                        Method::from_bytes(method.to_uppercase().as_bytes()).with_context(|| {
+                            format!("Invalid method {:?} in ALLOWED_METHODS", method)
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+
+        let normalize_path = source.get_or("NORMALIZE_PATH", false)?;
+
+        let path_filter = PathFilter::new(
+            &source.get_list("ALLOW_PATHS")?.unwrap_or_default(),
+            &source.get_list("BLOCK_PATHS")?.unwrap_or_default(),
+        )?;
+
+        let cache_size = source.get_or("CACHE_SIZE", 0)?;
+        let cache_ttl = Duration::from_secs(source.get_or("CACHE_TTL_SECS", 60)?);
+        let cache_max_entry_size = source.get_or("CACHE_MAX_ENTRY_SIZE", 1024 * 1024)?;
+        let cache = NonZeroUsize::new(cache_size)
+            .map(|capacity| Cache::new(capacity, cache_ttl, cache_max_entry_size));
+
+        let request_buffer = source
+            .get_or("BUFFER_REQUEST_BODY", false)?
+            .then(|| source.get_or("BUFFER_REQUEST_BODY_MAX_SIZE", 1024 * 1024))
+            .transpose()?
+            .map(RequestBuffer::new);
+
+        let response_buffer = source
+            .get_or("RESPONSE_BUFFER", false)?
+            .then(|| source.get_or("RESPONSE_BUFFER_MAX_SIZE", 64 * 1024))
+            .transpose()?
+            .map(ResponseBuffer::new);
+
+        let mirror_percent: f64 = source.get_or("MIRROR_PERCENT", 100.0)?;
+        ensure!(
+            (0.0..=100.0).contains(&mirror_percent),
+            "MIRROR_PERCENT must be between 0 and 100"
+        );
+        let mirror_max_body_size = source.get_or("MIRROR_MAX_BODY_SIZE", 1024 * 1024)?;
+        let mirror = source
+            .get::<String>("MIRROR_BACKEND")?
+            .map(|url| Backends::new(vec![url]))
+            .transpose()?
+            .map(|backends| Mirror::new(backends, mirror_percent, mirror_max_body_size));
+
+        let body_rewrite_rules: Vec<String> =
+            source.get_list("BODY_REWRITE_RULES")?.unwrap_or_default();
+        let body_rewriter = if body_rewrite_rules.is_empty() {
+            None
+        } else {
+            Some(BodyRewriter::new(
+                &body_rewrite_rules,
+                source.get_or("BODY_REWRITE_MAX_SIZE", 1024 * 1024)?,
+            )?)
+        };
+
+        let status_mappings: Vec<String> = source.get_list("STATUS_MAP")?.unwrap_or_default();
+        let status_map = if status_mappings.is_empty() {
+            None
+        } else {
+            Some(StatusMap::new(&status_mappings)?)
+        };
+
+        let compression = source.get_or("COMPRESSION", false)?;
+        let compression_min_size = source.get_or("COMPRESSION_MIN_SIZE", 1024)?;
+
+        let accept_proxy_protocol = source.get_or("ACCEPT_PROXY_PROTOCOL", false)?;
+
+        let ip_filter = IpFilter::new(
+            &source.get_list("ALLOW_IPS")?.unwrap_or_default(),
+            &source.get_list("DENY_IPS")?.unwrap_or_default(),
+        )?;
+
+        let rate_limit_rps: f64 = source.get_or("RATE_LIMIT_RPS", 0.0)?;
+        ensure!(rate_limit_rps >= 0.0, "RATE_LIMIT_RPS must not be negative");
+        let rate_limit_burst = source.get_or("RATE_LIMIT_BURST", rate_limit_rps.ceil() as u32)?;
+        let rate_limiter = (rate_limit_rps > 0.0)
+            .then(|| Arc::new(RateLimiter::new(rate_limit_rps, rate_limit_burst)));
+
+        let mut credentials: Vec<String> = source
+            .get_list("BASIC_AUTH_CREDENTIALS")?
+            .unwrap_or_default();
+        if let Some(user) = source.get::<String>("BASIC_AUTH_USER")? {
+            let pass = source
+                .get::<String>("BASIC_AUTH_PASS")?
+                .context("BASIC_AUTH_PASS must be set when BASIC_AUTH_USER is")?;
+            credentials.push(format!("{}:{}", user, pass));
+        }
+        ensure!(
+            credentials.iter().all(|pair| pair.contains(':')),
+            "BASIC_AUTH_CREDENTIALS entries must be in the form user:pass"
+        );
+        let basic_auth = if credentials.is_empty() {
+            None
+        } else {
+            Some(Arc::new(BasicAuth::new(
+                credentials,
+                source.get_or("BASIC_AUTH_REALM", "proxy".to_string())?,
+                source.get_or("BASIC_AUTH_STRIP", true)?,
+            )))
+        };
+
+        let cors_allowed_origins: Vec<String> =
+            source.get_list("CORS_ALLOWED_ORIGINS")?.unwrap_or_default();
+        let cors = if cors_allowed_origins.is_empty() {
+            None
+        } else {
+            let default_methods = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"]
+                .map(str::to_string)
+                .to_vec();
+            let allow_credentials = source.get_or("CORS_ALLOW_CREDENTIALS", false)?;
+            ensure!(
+                !allow_credentials || !cors_allowed_origins.iter().any(|origin| origin == "*"),
+                "CORS_ALLOWED_ORIGINS must list origins explicitly when CORS_ALLOW_CREDENTIALS is enabled"
+            );
+            Some(Cors::new(
+                cors_allowed_origins,
+                source
+                    .get_list("CORS_ALLOWED_METHODS")?
+                    .unwrap_or(default_methods),
+                source.get_list("CORS_ALLOWED_HEADERS")?.unwrap_or_default(),
+                allow_credentials,
+                source.get_or("CORS_MAX_AGE_SECS", 600)?,
+            ))
+        };
+
+        let filters = FilterChain::new(
+            &source
+                .get_list("FILTERS")?
+                .unwrap_or_else(|| DEFAULT_FILTERS.map(str::to_string).to_vec()),
+            vec![
+                (
+                    "cors",
+                    cors.map(|cors| Arc::new(cors) as Arc<dyn RequestFilter>),
+                ),
+                (
+                    "rate_limit",
+                    rate_limiter
+                        .clone()
+                        .map(|limiter| limiter as Arc<dyn RequestFilter>),
+                ),
+                (
+                    "basic_auth",
+                    basic_auth
+                        .clone()
+                        .map(|basic_auth| basic_auth as Arc<dyn RequestFilter>),
+                ),
+            ],
+        )?;
+
+        let request_headers_remove = source
+            .get_list("REQUEST_HEADERS_REMOVE")?
+            .unwrap_or_default();
+        let forward_headers_allowlist = source
+            .get_list::<String>("FORWARD_HEADERS_ALLOWLIST")?
+            .map(|allowed| HeaderAllowlist::new(&allowed))
+            .transpose()
+            .context("Failed to parse FORWARD_HEADERS_ALLOWLIST")?;
+        ensure!(
+            forward_headers_allowlist.is_none() || request_headers_remove.is_empty(),
+            "FORWARD_HEADERS_ALLOWLIST and REQUEST_HEADERS_REMOVE cannot both be set"
+        );
+        let request_headers = HeaderRules::new(
+            &source.get_list("REQUEST_HEADERS_ADD")?.unwrap_or_default(),
+            &source.get_list("REQUEST_HEADERS_SET")?.unwrap_or_default(),
+            &request_headers_remove,
+        )
+        .context("Failed to parse request header rules")?;
+        // `RESPONSE_STRIP_HEADERS` is a dedicated list for hiding headers leaked by backends, such
+        // as `Server` or `X-Powered-By`, merged into the general removals
+        let mut response_headers_remove: Vec<String> = source
+            .get_list("RESPONSE_HEADERS_REMOVE")?
+            .unwrap_or_default();
+        response_headers_remove.extend(
+            source
+                .get_list::<String>("RESPONSE_STRIP_HEADERS")?
+                .unwrap_or_default(),
+        );
+        let response_headers = HeaderRules::new(
+            &source.get_list("RESPONSE_HEADERS_ADD")?.unwrap_or_default(),
+            &source.get_list("RESPONSE_HEADERS_SET")?.unwrap_or_default(),
+            &response_headers_remove,
+        )
+        .context("Failed to parse response header rules")?;
+
+        let trust_forwarded_headers = source.get_or("TRUST_FORWARDED_HEADERS", false)?;
+        let trusted_proxy_count = source.get_or("TRUSTED_PROXY_COUNT", 0)?;
+        ensure!(
+            trusted_proxy_count == 0 || trust_forwarded_headers,
+            "TRUSTED_PROXY_COUNT requires TRUST_FORWARDED_HEADERS"
+        );
+
+        let forward_tls_info = source.get_or("FORWARD_TLS_INFO", false)?;
+
+        let preserve_host = source.get_or("PRESERVE_HOST", false)?;
+
+        let emit_server_timing = source.get_or("EMIT_SERVER_TIMING", false)?;
+
+        let backend_header = source
+            .get_or("EMIT_BACKEND_HEADER", false)?
+            .then(|| {
+                source.get_or(
+                    "BACKEND_HEADER_NAME",
+                    HeaderName::from_static("x-proxy-backend"),
+                )
+            })
+            .transpose()?;
+
+        let mut error_pages = HashMap::new();
+        for key in ["4XX", "5XX"]
+            .map(str::to_string)
+            .into_iter()
+            .chain((400..600).map(|code: u16| code.to_string()))
+        {
+            let Some(path) = source.get::<PathBuf>(&format!("ERROR_PAGE_{}", key))? else {
+                continue;
+            };
+            error_pages.insert(key, ErrorPage::load(&path)?);
+        }
 
-        let backend_url =
-            std::env::var("BACKEND_URL").unwrap_or_else(|_| "https://vps.kodub.com".to_string());
+        let maintenance = Maintenance::new(
+            source.get_or("MAINTENANCE_MODE", false)?,
+            Duration::from_secs(source.get_or("MAINTENANCE_RETRY_AFTER_SECS", 300)?),
+            source
+                .get::<PathBuf>("MAINTENANCE_PAGE")?
+                .map(|path| ErrorPage::load(&path))
+                .transpose()?,
+            parse_ranges(
+                &source
+                    .get_list("MAINTENANCE_ALLOWED_IPS")?
+                    .unwrap_or_default(),
+            )
+            .context("Failed to parse MAINTENANCE_ALLOWED_IPS")?,
+        );
 
-        let cert_path = std::env::var("CERT_PATH")
-            .unwrap_or_else(|_| "cert.pem".to_string())
-            .into();
+        let static_files = source
+            .get::<PathBuf>("STATIC_DIR")?
+            .map(|path| StaticFiles::new(&path))
+            .transpose()?;
 
-        let key_path = std::env::var("KEY_PATH")
-            .unwrap_or_else(|_| "key.pem".to_string())
-            .into();
+        let slow_request_ms = source.get_or("SLOW_REQUEST_MS", 0)?;
+        let slow_request_threshold =
+            (slow_request_ms > 0).then(|| Duration::from_millis(slow_request_ms));
 
-        let use_tls = std::env::var("USE_TLS")
-            .unwrap_or_else(|_| "true".to_string())
-            .parse()
-            .unwrap_or(true);
+        let request_id_header =
+            source.get_or("REQUEST_ID_HEADER", HeaderName::from_static("x-request-id"))?;
+
+        let access_log_format = source.get_or("ACCESS_LOG_FORMAT", AccessLogFormat::Off)?;
+        let access_log_max_size = source.get_or("ACCESS_LOG_MAX_SIZE", 0)?;
+        let access_log_max_age_secs = source.get_or("ACCESS_LOG_MAX_AGE_SECS", 0)?;
+        let access_log_output = AccessLogOutput::open(
+            source.get::<PathBuf>("ACCESS_LOG_FILE")?.as_deref(),
+            Rotation {
+                max_size: (access_log_max_size > 0).then_some(access_log_max_size),
+                max_age: (access_log_max_age_secs > 0)
+                    .then(|| Duration::from_secs(access_log_max_age_secs)),
+                max_files: source.get_or("ACCESS_LOG_MAX_FILES", 5)?,
+            },
+        )?;
+
+        let log_headers = if source.get_or("LOG_HEADERS", false)? {
+            Some(source.get_list("LOG_HEADERS_REDACT")?.unwrap_or_else(|| {
+                [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE].to_vec()
+            }))
+        } else {
+            None
+        };
+
+        let connection_limit = ConnectionLimit::new(
+            source.get_or("MAX_CONNECTIONS", 0)?,
+            source.get_or("ON_OVERLOAD", OverloadMode::Queue)?,
+            source.get_or("OVERLOAD_QUEUE_DEPTH", 100)?,
+            Duration::from_millis(source.get_or("OVERLOAD_QUEUE_WAIT_MS", 1000)?),
+        );
+
+        let max_accept_rate: f64 = source.get_or("MAX_ACCEPT_RATE", 0.0)?;
+        ensure!(
+            max_accept_rate >= 0.0,
+            "MAX_ACCEPT_RATE must not be negative"
+        );
+        let max_accept_burst = source.get_or("MAX_ACCEPT_BURST", max_accept_rate.ceil() as u32)?;
+        let accept_rate =
+            (max_accept_rate > 0.0).then(|| AcceptRate::new(max_accept_rate, max_accept_burst));
+
+        let max_upload_bps = source.get_or("MAX_UPLOAD_BPS", 0)?;
+        let max_download_bps = source.get_or("MAX_DOWNLOAD_BPS", 0)?;
+
+        let shutdown_timeout = Duration::from_secs(source.get_or("SHUTDOWN_TIMEOUT_SECS", 30)?);
+
+        let metrics_addr = source.get("METRICS_ADDR")?;
+
+        let admin_addr = source.get("ADMIN_ADDR")?;
+        let admin_token = source
+            .get::<String>("ADMIN_TOKEN")?
+            .filter(|token| !token.is_empty());
+        ensure!(
+            admin_addr.is_none() || admin_token.is_some(),
+            "ADMIN_ADDR requires ADMIN_TOKEN to be set"
+        );
+
+        let status_endpoint = match probe_path(source.get_or("STATUS_PATH", String::new())?)? {
+            Some(path) => {
+                let allowed =
+                    parse_ranges(&source.get_list("STATUS_ALLOWED_IPS")?.unwrap_or_default())
+                        .context("Failed to parse STATUS_ALLOWED_IPS")?;
+                ensure!(
+                    admin_token.is_some() || !allowed.is_empty(),
+                    "STATUS_PATH requires ADMIN_TOKEN or STATUS_ALLOWED_IPS to be set"
+                );
+                Some(StatusEndpoint::new(path, allowed))
+            }
+            None => None,
+        };
+
+        let cert_path = source.get::<PathBuf>("CERT_PATH")?;
+        let key_path = source.get::<PathBuf>("KEY_PATH")?;
+        let inline_pem = match (
+            source.get::<String>("CERT_PEM")?,
+            source.get::<String>("KEY_PEM")?,
+        ) {
+            (Some(cert_pem), Some(key_pem)) => {
+                ensure!(
+                    cert_path.is_none() && key_path.is_none(),
+                    "CERT_PEM and KEY_PEM can't be combined with CERT_PATH or KEY_PATH"
+                );
+                Some((cert_pem, key_pem))
+            }
+            (None, None) => None,
+            _ => bail!("CERT_PEM and KEY_PEM must be set together"),
+        };
+        let cert_path = cert_path.unwrap_or_else(|| PathBuf::from("cert.pem"));
+        let key_path = key_path.unwrap_or_else(|| PathBuf::from("key.pem"));
+
+        let use_tls = source.get_or("USE_TLS", true)?;
+
+        let redirect_http_addr = source.get("REDIRECT_HTTP_ADDR")?;
+        ensure!(
+            redirect_http_addr.is_none() || use_tls,
+            "REDIRECT_HTTP_ADDR requires USE_TLS to be enabled"
+        );
+
+        let acme_domains: Vec<String> = source.get_list("ACME_DOMAINS")?.unwrap_or_default();
+        let acme = if acme_domains.is_empty() {
+            None
+        } else {
+            ensure!(use_tls, "ACME_DOMAINS requires USE_TLS to be enabled");
+            ensure!(
+                inline_pem.is_none(),
+                "ACME_DOMAINS can't be combined with CERT_PEM and KEY_PEM, as the certificate is \
+                 saved to CERT_PATH and KEY_PATH"
+            );
+            ensure!(
+                redirect_http_addr.is_some(),
+                "ACME_DOMAINS requires REDIRECT_HTTP_ADDR, which answers the HTTP-01 challenges"
+            );
+            if let Some(domain) = acme_domains.iter().find(|domain| domain.starts_with("*.")) {
+                bail!(
+                    "Wildcard domain {:?} can't be validated with HTTP-01 challenges",
+                    domain
+                );
+            }
+
+            Some(AcmeSettings {
+                directory_url: source.get_or(
+                    "ACME_DIRECTORY_URL",
+                    "https://acme-v02.api.letsencrypt.org/directory".to_string(),
+                )?,
+                contact_email: source.get("ACME_EMAIL")?,
+                domains: acme_domains,
+                account_key_path: source
+                    .get_or("ACME_ACCOUNT_KEY_PATH", PathBuf::from("acme-account.pem"))?,
+                renew_before: Duration::from_secs(
+                    source.get_or::<u64>("ACME_RENEW_BEFORE_DAYS", 30)? * 24 * 60 * 60,
+                ),
+            })
+        };
+
+        let enable_h2 = source.get_or("ENABLE_H2", true)?;
+        let enable_h2c = source.get_or("ENABLE_H2C", false)?;
+        ensure!(enable_h2 || !enable_h2c, "ENABLE_H2C requires ENABLE_H2");
+
+        let enable_http3 = source.get_or("ENABLE_HTTP3", false)?;
+        ensure!(
+            !enable_http3 || use_tls,
+            "ENABLE_HTTP3 requires USE_TLS to be enabled"
+        );
+        ensure!(
+            !enable_http3 || matches!(bind_addr, BindAddr::Tcp(_)),
+            "ENABLE_HTTP3 requires BIND_ADDR to be a TCP address"
+        );
+
+        let max_header_size = source.get_or("MAX_HEADER_SIZE", 64 * 1024)?;
+        // hyper can't parse HTTP/1.1 requests with a smaller buffer
+        ensure!(
+            (8192..=u32::MAX as usize).contains(&max_header_size),
+            "MAX_HEADER_SIZE must be between 8192 and {}",
+            u32::MAX
+        );
+
+        let h1_keep_alive = source.get_or("H1_KEEP_ALIVE", true)?;
+
+        let h1_header_read_timeout_secs = source.get_or("H1_HEADER_READ_TIMEOUT_SECS", 30)?;
+        let h1_header_read_timeout = (h1_header_read_timeout_secs > 0)
+            .then(|| Duration::from_secs(h1_header_read_timeout_secs));
+
+        let connection_idle_timeout_secs = source.get_or("CONNECTION_IDLE_TIMEOUT_SECS", 0)?;
+        let connection_idle_timeout = (connection_idle_timeout_secs > 0)
+            .then(|| Duration::from_secs(connection_idle_timeout_secs));
+
+        let h2_keep_alive_interval_secs = source.get_or("H2_KEEP_ALIVE_INTERVAL_SECS", 20)?;
+        let h2_keep_alive_interval = (h2_keep_alive_interval_secs > 0)
+            .then(|| Duration::from_secs(h2_keep_alive_interval_secs));
+
+        let h2_keep_alive_timeout_secs = source.get_or("H2_KEEP_ALIVE_TIMEOUT_SECS", 10)?;
+        ensure!(
+            h2_keep_alive_timeout_secs > 0,
+            "H2_KEEP_ALIVE_TIMEOUT_SECS must be at least 1"
+        );
+        let h2_keep_alive_timeout = Duration::from_secs(h2_keep_alive_timeout_secs);
+
+        let h2_max_concurrent_streams = source.get_or("H2_MAX_CONCURRENT_STREAMS", 200)?;
+        ensure!(
+            (1..=i32::MAX as u32).contains(&h2_max_concurrent_streams),
+            "H2_MAX_CONCURRENT_STREAMS must be between 1 and {}",
+            i32::MAX
+        );
+
+        let h2_initial_window_size = source.get_or("H2_INITIAL_WINDOW_SIZE", 1024 * 1024)?;
+        ensure!(
+            (1..=i32::MAX as u32).contains(&h2_initial_window_size),
+            "H2_INITIAL_WINDOW_SIZE must be between 1 and {}",
+            i32::MAX
+        );
+
+        let h2_initial_connection_window_size =
+            source.get_or("H2_INITIAL_CONNECTION_WINDOW_SIZE", 1024 * 1024)?;
+        ensure!(
+            (65535..=i32::MAX as u32).contains(&h2_initial_connection_window_size),
+            "H2_INITIAL_CONNECTION_WINDOW_SIZE must be between 65535 and {}",
+            i32::MAX
+        );
+
+        let allow_unix_tls = source.get_or("ALLOW_UNIX_TLS", false)?;
+        #[cfg(unix)]
+        ensure!(
+            !(use_tls && matches!(bind_addr, BindAddr::Unix(_))) || allow_unix_tls,
+            "TLS is not supported on Unix sockets by default; set USE_TLS=false or ALLOW_UNIX_TLS=true"
+        );
+
+        let tls_reload_interval =
+            Duration::from_secs(source.get_or("TLS_RELOAD_INTERVAL_SECS", 30)?);
+
+        let tls_min_version = source.get_or("TLS_MIN_VERSION", TlsVersion::Tls12)?;
+
+        let tls_cipher_suites = source.get_list("TLS_CIPHER_SUITES")?.unwrap_or_default();
+
+        let client_ca_path: Option<PathBuf> = source.get("CLIENT_CA_PATH")?;
+        let require_client_cert = source.get_or("REQUIRE_CLIENT_CERT", false)?;
+        ensure!(
+            !require_client_cert || client_ca_path.is_some(),
+            "REQUIRE_CLIENT_CERT requires CLIENT_CA_PATH to be set"
+        );
 
         Ok(Config {
             bind_addr,
-            backend_url,
+            unix_socket_mode,
+            socket_options,
+            backends: Backends::new(backend_urls)?,
+            default_backend,
+            canary,
+            routes,
+            discovery_interval,
+            lb_strategy,
+            slow_start,
+            on_backend_full,
+            backend_full_wait,
+            sticky_sessions,
+            health_check_path,
+            health_check_interval,
+            health_check_threshold,
+            verify_backend_on_start,
+            require_backend_on_start,
+            liveness_path,
+            readiness_path,
+            upstream_timeout,
+            request_timeout,
+            timeout_header_max,
+            connect_timeout,
+            outbound_proxy,
+            expect_continue_timeout,
+            dns_resolver,
+            backend_http_version,
+            backend_tls,
+            pool_max_idle_per_host,
+            pool_idle_timeout,
+            max_retries,
+            retry_backoff,
+            retry_on_status,
+            retry_methods,
+            circuit_breaker,
+            max_body_size,
+            request_buffer,
+            allowed_methods,
+            normalize_path,
+            path_filter,
+            cache,
+            response_buffer,
+            mirror,
+            body_rewriter,
+            status_map,
+            compression,
+            compression_min_size,
+            accept_proxy_protocol,
+            ip_filter,
+            rate_limiter,
+            basic_auth,
+            filters,
+            forward_headers_allowlist,
+            request_headers,
+            response_headers,
+            trust_forwarded_headers,
+            forward_tls_info,
+            trusted_proxy_count,
+            preserve_host,
+            emit_server_timing,
+            backend_header,
+            error_pages: ErrorPages::new(error_pages),
+            maintenance,
+            static_files,
+            slow_request_threshold,
+            request_id_header,
+            access_log_format,
+            access_log_output,
+            log_headers,
+            connection_limit,
+            accept_rate,
+            max_upload_bps,
+            max_download_bps,
+            shutdown_timeout,
+            metrics_addr,
+            admin_addr,
+            admin_token,
+            status_endpoint,
             cert_path,
             key_path,
+            inline_pem,
             use_tls,
+            redirect_http_addr,
+            acme,
+            enable_h2,
+            enable_h2c,
+            enable_http3,
+            max_header_size,
+            h1_keep_alive,
+            h1_header_read_timeout,
+            connection_idle_timeout,
+            h2_keep_alive_interval,
+            h2_keep_alive_timeout,
+            h2_max_concurrent_streams,
+            h2_initial_window_size,
+            h2_initial_connection_window_size,
+            tls_reload_interval,
+            tls_min_version,
+            tls_cipher_suites,
+            client_ca_path,
+            require_client_cert,
         })
     }
 }
+
+/// Validate the path of one of the proxy's own probe endpoints, where an empty path disables it.
+fn probe_path(path: String) -> Result<Option<String>> {
+    if path.is_empty() {
+        return Ok(None);
+    }
+
+    ensure!(
+        path.starts_with('/'),
+        "Probe path {:?} must start with '/'",
+        path
+    );
+    Ok(Some(path))
+}
+
+/// A source of configuration values, layering environment variables over an optional TOML
+/// document.
+///
+/// Each setting is identified by its environment variable name; the matching file key is the
+/// same name in lowercase (e.g. `BIND_ADDR` and `bind_addr`).
+#[derive(Default)]
+struct Source {
+    /// Top-level table of the config file, empty when no file was given.
+    file: Table,
+}
+
+impl Source {
+    /// Look up a setting, returning `None` when neither the environment nor the file sets it.
+    fn get<T>(&self, var: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Into<anyhow::Error>,
+    {
+        if let Ok(raw) = std::env::var(var) {
+            return parse(&raw)
+                .map(Some)
+                .with_context(|| format!("Failed to parse {}", var));
+        }
+
+        let key = var.to_lowercase();
+        let Some(value) = self.file.get(&key) else {
+            return Ok(None);
+        };
+
+        parse(&value_to_string(&key, value)?)
+            .map(Some)
+            .with_context(|| format!("Failed to parse config key `{}`", key))
+    }
+
+    /// Look up a comma-separated list setting, skipping empty entries.
+    fn get_list<T>(&self, var: &str) -> Result<Option<Vec<T>>>
+    where
+        T: FromStr,
+        T::Err: Into<anyhow::Error>,
+    {
+        let Some(raw) = self.get::<String>(var)? else {
+            return Ok(None);
+        };
+
+        raw.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                parse(item).with_context(|| format!("Failed to parse {:?} in {}", item, var))
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    /// Look up a setting, falling back to `default` when it is unset.
+    fn get_or<T>(&self, var: &str, default: T) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: Into<anyhow::Error>,
+    {
+        Ok(self.get(var)?.unwrap_or(default))
+    }
+}
+
+/// Parse a raw setting value.
+fn parse<T>(raw: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    raw.parse().map_err(Into::into)
+}
+
+/// Convert a TOML value into the string form accepted by the matching environment variable.
+///
+/// Arrays are joined with commas so that list settings can be written natively in the file.
+fn value_to_string(key: &str, value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                Value::Array(_) | Value::Table(_) => {
+                    bail!("Config key `{}` must be a flat list of values", key)
+                }
+                item => value_to_string(key, item),
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(|items| items.join(",")),
+        Value::Datetime(_) | Value::Table(_) => {
+            bail!("Config key `{}` has an unsupported value type", key)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(toml: &str) -> Source {
+        Source {
+            file: toml.parse().unwrap(),
+        }
+    }
+
+    fn load(toml: &str) -> Result<Config> {
+        Config::load(&source(&format!("use_tls = false\n{}", toml)))
+    }
+
+    #[test]
+    fn reads_file_values_of_any_scalar_type() {
+        let source = source(
+            "bind_addr = \"0.0.0.0:8080\"\nmax_retries = 3\nmirror_percent = 12.5\n\
+             enable_h2 = false",
+        );
+        assert_eq!(
+            source.get::<SocketAddr>("BIND_ADDR").unwrap(),
+            Some(SocketAddr::from(([0, 0, 0, 0], 8080)))
+        );
+        assert_eq!(source.get::<u32>("MAX_RETRIES").unwrap(), Some(3));
+        assert_eq!(source.get::<f64>("MIRROR_PERCENT").unwrap(), Some(12.5));
+        assert_eq!(source.get::<bool>("ENABLE_H2").unwrap(), Some(false));
+        assert_eq!(source.get::<u32>("MAX_BODY_SIZE").unwrap(), None);
+        assert_eq!(source.get_or("MAX_BODY_SIZE", 7).unwrap(), 7);
+    }
+
+    #[test]
+    fn reads_lists_from_arrays_or_comma_separated_strings() {
+        let source = source("retry_on_status = [502, 503]\nallowed_methods = \" GET, ,POST ,\"");
+        assert_eq!(
+            source.get_list::<u16>("RETRY_ON_STATUS").unwrap(),
+            Some(vec![502, 503])
+        );
+        assert_eq!(
+            source.get_list::<String>("ALLOWED_METHODS").unwrap(),
+            Some(vec!["GET".to_string(), "POST".to_string()])
+        );
+    }
+
+    #[test]
+    fn rejects_unparsable_or_unsupported_values() {
+        let source = source(
+            "max_retries = \"many\"\nbackend_urls = [[\"http://a\"]]\nstatic_dir = { path = \"x\" }\n\
+             retry_on_status = \"502,abc\"",
+        );
+        let error = source.get::<u32>("MAX_RETRIES").unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("max_retries"),
+            "{:#}",
+            error
+        );
+        assert!(source.get_list::<String>("BACKEND_URLS").is_err());
+        assert!(source.get::<String>("STATIC_DIR").is_err());
+        assert!(source.get_list::<u16>("RETRY_ON_STATUS").is_err());
+    }
+
+    #[test]
+    fn applies_defaults_for_unset_settings() {
+        let config = load("").unwrap();
+        assert_eq!(
+            config.bind_addr,
+            BindAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 8000)))
+        );
+        assert_eq!(config.lb_strategy, LbStrategy::RoundRobin);
+        assert_eq!(config.max_retries, 0);
+        assert_eq!(config.retry_methods, RETRYABLE_METHODS);
+        assert_eq!(config.request_timeout, None);
+        assert!(config.circuit_breaker.is_none());
+    }
+
+    #[test]
+    fn loads_settings_from_the_file() {
+        let config = load(
+            "backend_urls = [\"http://127.0.0.1:8001\", \"http://127.0.0.1:8002\"]\n\
+             lb_strategy = \"least_conn\"\nmax_retries = 2\nretry_methods = \"get\"\n\
+             request_timeout_secs = 5",
+        )
+        .unwrap();
+        assert_eq!(config.backends.all().len(), 2);
+        assert_eq!(config.lb_strategy, LbStrategy::LeastConn);
+        assert_eq!(config.max_retries, 2);
+        assert_eq!(config.retry_methods, [Method::GET]);
+        assert_eq!(config.request_timeout, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        for toml in [
+            "backend_urls = \"\"",
+            "backend_urls = \"srv+dns:_http._tcp.a.local,http://127.0.0.1:8001\"",
+            "canary_percent = 150",
+            "circuit_breaker_failure_ratio = 2",
+            "retry_methods = \"POST\"",
+            "lb_strategy = \"fastest\"",
+            "basic_auth_user = \"admin\"",
+            "filters = \"cors,cors\"",
+        ] {
+            assert!(load(toml).is_err(), "{}", toml);
+        }
+    }
+}
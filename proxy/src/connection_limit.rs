@@ -0,0 +1,356 @@
+use std::{
+    io,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Error, Result, bail};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    listener::{Listener, Stream},
+    metrics::metrics,
+};
+
+/// What to do with new connections once the connection limit is reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverloadMode {
+    /// Stop accepting until a connection closes, leaving new clients in the listen backlog.
+    Queue,
+    /// Accept and immediately close new connections.
+    Reject,
+    /// Accept new connections and hold them in a bounded queue until a slot frees up, answering
+    /// with `503` when the queue is full or the wait runs out.
+    Wait,
+}
+
+impl FromStr for OverloadMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "queue" => Ok(Self::Queue),
+            "reject" => Ok(Self::Reject),
+            "wait" => Ok(Self::Wait),
+            _ => bail!(
+                "Unknown overload mode {:?}, expected queue, reject or wait",
+                s
+            ),
+        }
+    }
+}
+
+/// A slot held by a connection for as long as it is being served.
+///
+/// The slot is released when the permit is dropped, including when the task serving the
+/// connection panics.
+pub struct ConnectionPermit {
+    /// The semaphore permit backing the slot.
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ConnectionPermit {
+    /// Wrap a semaphore permit, counting it as in flight.
+    fn new(permit: OwnedSemaphorePermit) -> Self {
+        metrics().in_flight_connections.inc();
+        Self { _permit: permit }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        metrics().in_flight_connections.dec();
+    }
+}
+
+/// Whether an accepted connection may be served.
+pub enum Admission {
+    /// A slot was free when the connection was accepted.
+    Admitted(ConnectionPermit),
+    /// The connection waits in the queue for a slot to free up.
+    Queued(QueuedConnection),
+    /// The queue was full, so the connection is turned away.
+    Rejected,
+}
+
+impl Admission {
+    /// Wait for the connection's slot, returning `None` if it must be turned away.
+    pub async fn wait(self) -> Option<ConnectionPermit> {
+        match self {
+            Self::Admitted(permit) => Some(permit),
+            Self::Queued(queued) => queued.wait().await,
+            Self::Rejected => None,
+        }
+    }
+}
+
+/// A connection holding a place in the queue for a slot under the connection limit.
+pub struct QueuedConnection {
+    /// Semaphore of the connection limit to acquire a slot from.
+    semaphore: Arc<Semaphore>,
+    /// Maximum time to wait for a slot.
+    max_wait: Duration,
+    /// The place in the queue, released once the wait is over.
+    _place: OwnedSemaphorePermit,
+}
+
+impl QueuedConnection {
+    /// Take a place in the queue, counting it in the queue depth.
+    fn new(semaphore: Arc<Semaphore>, max_wait: Duration, place: OwnedSemaphorePermit) -> Self {
+        metrics().connection_queue_depth.inc();
+        Self {
+            semaphore,
+            max_wait,
+            _place: place,
+        }
+    }
+
+    /// Wait up to the maximum wait for a slot.
+    async fn wait(self) -> Option<ConnectionPermit> {
+        let started = Instant::now();
+        let permit = timeout(self.max_wait, self.semaphore.clone().acquire_owned()).await;
+        metrics()
+            .connection_queue_wait
+            .observe(started.elapsed().as_secs_f64());
+
+        match permit {
+            Ok(permit) => Some(ConnectionPermit::new(
+                permit.expect("connection semaphore is never closed"),
+            )),
+            Err(_) => {
+                metrics().rejected_connections.inc();
+                None
+            }
+        }
+    }
+}
+
+impl Drop for QueuedConnection {
+    fn drop(&mut self) {
+        metrics().connection_queue_depth.dec();
+    }
+}
+
+/// A limit on the number of connections served concurrently.
+pub struct ConnectionLimit {
+    /// Maximum number of concurrent connections; zero means unlimited.
+    max: usize,
+    /// Semaphore with one permit per allowed connection.
+    semaphore: Arc<Semaphore>,
+    /// What to do with new connections at the limit.
+    mode: OverloadMode,
+    /// Maximum number of connections waiting for a slot in wait mode.
+    queue_depth: usize,
+    /// Semaphore with one permit per place in the queue.
+    queue: Arc<Semaphore>,
+    /// Maximum time a connection waits for a slot in wait mode.
+    max_wait: Duration,
+}
+
+impl ConnectionLimit {
+    /// Create a limit of `max` concurrent connections, where zero means unlimited.
+    ///
+    /// In wait mode, up to `queue_depth` connections over the limit wait up to `max_wait` for a
+    /// slot.
+    pub fn new(max: usize, mode: OverloadMode, queue_depth: usize, max_wait: Duration) -> Self {
+        let permits = if max == 0 {
+            Semaphore::MAX_PERMITS
+        } else {
+            max
+        };
+
+        Self {
+            max,
+            semaphore: Arc::new(Semaphore::new(permits)),
+            mode,
+            queue_depth,
+            queue: Arc::new(Semaphore::new(queue_depth)),
+            max_wait,
+        }
+    }
+
+    /// Accept the next connection that fits within the limit.
+    ///
+    /// In queue mode this waits for a free slot before accepting. In reject mode connections
+    /// accepted while at the limit are closed straight away and accepting continues. In wait mode
+    /// connections accepted while at the limit are queued, or rejected if the queue is full.
+    pub async fn accept(&self, listener: &Listener) -> io::Result<(Stream, IpAddr, Admission)> {
+        loop {
+            if self.mode == OverloadMode::Queue {
+                let permit = self
+                    .semaphore
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("connection semaphore is never closed");
+                let (stream, client_ip) = listener.accept().await?;
+                let admission = Admission::Admitted(ConnectionPermit::new(permit));
+                return Ok((stream, client_ip, admission));
+            }
+
+            let (stream, client_ip) = listener.accept().await?;
+            if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+                let admission = Admission::Admitted(ConnectionPermit::new(permit));
+                return Ok((stream, client_ip, admission));
+            }
+
+            if self.mode == OverloadMode::Reject {
+                debug!(
+                    "Connection limit reached, closing connection from {}",
+                    client_ip
+                );
+                metrics().rejected_connections.inc();
+                continue;
+            }
+
+            return Ok((stream, client_ip, self.enqueue(client_ip)));
+        }
+    }
+
+    /// Admit a connection that was accepted without going through [`accept`](Self::accept), like
+    /// a QUIC connection, returning `None` if it must be refused.
+    ///
+    /// Such connections can't be left in the listen backlog, so in queue mode they are refused at
+    /// the limit as in reject mode. In wait mode they are queued like TCP connections.
+    pub fn admit(&self, client_ip: IpAddr) -> Option<Admission> {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return Some(Admission::Admitted(ConnectionPermit::new(permit)));
+        }
+
+        if self.mode != OverloadMode::Wait {
+            debug!(
+                "Connection limit reached, refusing connection from {}",
+                client_ip
+            );
+            metrics().rejected_connections.inc();
+            return None;
+        }
+        Some(self.enqueue(client_ip))
+    }
+
+    /// Queue a connection accepted at the limit, or reject it if the queue is full.
+    fn enqueue(&self, client_ip: IpAddr) -> Admission {
+        match self.queue.clone().try_acquire_owned() {
+            Ok(place) => {
+                debug!(
+                    "Connection limit reached, queueing connection from {}",
+                    client_ip
+                );
+                Admission::Queued(QueuedConnection::new(
+                    self.semaphore.clone(),
+                    self.max_wait,
+                    place,
+                ))
+            }
+            Err(_) => {
+                debug!(
+                    "Connection queue full, turning away connection from {}",
+                    client_ip
+                );
+                metrics().rejected_connections.inc();
+                Admission::Rejected
+            }
+        }
+    }
+}
+
+impl PartialEq for ConnectionLimit {
+    fn eq(&self, other: &Self) -> bool {
+        self.max == other.max
+            && self.mode == other.mode
+            && self.queue_depth == other.queue_depth
+            && self.max_wait == other.max_wait
+    }
+}
+
+/// How often a warning is logged while the accept rate limit is closing connections.
+const ACCEPT_RATE_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Token bucket state of the accept rate limit.
+struct AcceptBucket {
+    /// Tokens currently available.
+    tokens: f64,
+    /// When `tokens` was last brought up to date.
+    updated: Instant,
+    /// Connections closed since the last warning.
+    rejected: u64,
+    /// When the last warning was logged, if ever.
+    warned: Option<Instant>,
+}
+
+/// A token-bucket limit on the rate new connections are accepted at, across all clients.
+pub struct AcceptRate {
+    /// Tokens added per second.
+    rate: f64,
+    /// Maximum number of tokens the bucket can hold.
+    burst: f64,
+    /// The bucket.
+    bucket: Mutex<AcceptBucket>,
+}
+
+impl AcceptRate {
+    /// Create a limit of `rate` connections per second with bursts of up to `burst`.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self {
+            rate,
+            burst,
+            bucket: Mutex::new(AcceptBucket {
+                tokens: burst,
+                updated: Instant::now(),
+                rejected: 0,
+                warned: None,
+            }),
+        }
+    }
+
+    /// Take a token for a newly accepted connection, returning `false` if it must be closed
+    pub fn admit(&self) -> bool {
+        let now = Instant::now();
+        let mut bucket = self.bucket.lock().unwrap();
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Count a connection closed by the limit, warning once the limit starts closing connections
+    /// and then at most once per interval with the number closed since the last warning
+    pub fn record_rejected(&self) {
+        let now = Instant::now();
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rejected += 1;
+        if bucket
+            .warned
+            .is_some_and(|warned| now.duration_since(warned) < ACCEPT_RATE_WARN_INTERVAL)
+        {
+            return;
+        }
+
+        warn!(
+            "Accept rate limit of {} connections per second reached, {} connections closed since \
+             the last warning",
+            self.rate, bucket.rejected
+        );
+        bucket.rejected = 0;
+        bucket.warned = Some(now);
+    }
+}
+
+impl PartialEq for AcceptRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.rate == other.rate && self.burst == other.burst
+    }
+}
@@ -0,0 +1,276 @@
+use std::future::ready;
+
+use futures_util::future::BoxFuture;
+use hyper::{
+    HeaderMap, Method, Request, Response, StatusCode,
+    header::{
+        ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE,
+        ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, HeaderValue, ORIGIN, VARY,
+    },
+};
+use tracing::debug;
+
+use crate::{
+    filter::{FilterContext, FilterOutcome, RequestFilter},
+    proxy::{RequestBody, ResponseBody, empty_response},
+};
+
+/// Cross-origin resource sharing policy applied to every response.
+pub struct Cors {
+    /// Allowed origins, matched exactly; `*` allows any origin.
+    allowed_origins: Vec<String>,
+    /// Value of `Access-Control-Allow-Methods` sent on preflight responses.
+    allowed_methods: String,
+    /// Value of `Access-Control-Allow-Headers` sent on preflight responses; `None` echoes the
+    /// requested headers.
+    allowed_headers: Option<String>,
+    /// Whether to send `Access-Control-Allow-Credentials: true`.
+    allow_credentials: bool,
+    /// How long browsers may cache preflight responses, in seconds.
+    max_age: u64,
+}
+
+impl Cors {
+    /// Create a policy for the given origins, methods and headers.
+    pub fn new(
+        allowed_origins: Vec<String>,
+        allowed_methods: Vec<String>,
+        allowed_headers: Vec<String>,
+        allow_credentials: bool,
+        max_age: u64,
+    ) -> Self {
+        Self {
+            allowed_origins,
+            allowed_methods: allowed_methods.join(", "),
+            allowed_headers: (!allowed_headers.is_empty()).then(|| allowed_headers.join(", ")),
+            allow_credentials,
+            max_age,
+        }
+    }
+
+    /// Whether a request is a CORS preflight, which the proxy answers itself.
+    pub fn is_preflight<B>(req: &Request<B>) -> bool {
+        req.method() == Method::OPTIONS
+            && req.headers().contains_key(ORIGIN)
+            && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    /// Get the `Access-Control-Allow-Origin` value for a request origin, or `None` if the origin
+    /// is not allowed.
+    ///
+    /// The origin is echoed back when it matches exactly, and wildcard matches get `*`, which is
+    /// never combined with credentials since the configuration rejects that.
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        let origin_str = origin.to_str().ok()?;
+        if self
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed == origin_str)
+        {
+            return Some(origin.clone());
+        }
+
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*")
+            .then(|| HeaderValue::from_static("*"))
+    }
+
+    /// Build the response to a preflight request.
+    ///
+    /// Preflights from origins that are not allowed get a `403` without any CORS headers.
+    pub fn preflight(&self, headers: &HeaderMap) -> Response<ResponseBody> {
+        let Some(allow_origin) = headers.get(ORIGIN).and_then(|o| self.allow_origin(o)) else {
+            return empty_response(StatusCode::FORBIDDEN);
+        };
+
+        let mut response = empty_response(StatusCode::NO_CONTENT);
+        let response_headers = response.headers_mut();
+        self.insert_origin_headers(response_headers, allow_origin);
+
+        if let Ok(methods) = HeaderValue::from_str(&self.allowed_methods) {
+            response_headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        let allow_headers = match &self.allowed_headers {
+            Some(allowed) => HeaderValue::from_str(allowed).ok(),
+            None => headers.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned(),
+        };
+        if let Some(allow_headers) = allow_headers {
+            response_headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+        if self.max_age > 0 {
+            response_headers.insert(ACCESS_CONTROL_MAX_AGE, self.max_age.into());
+        }
+
+        response
+    }
+
+    /// Add CORS headers to a proxied response if the request origin is allowed.
+    pub fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        if let Some(allow_origin) = origin.and_then(|origin| self.allow_origin(origin)) {
+            self.insert_origin_headers(headers, allow_origin);
+        }
+    }
+
+    /// Insert the headers shared by preflight and regular responses.
+    fn insert_origin_headers(&self, headers: &mut HeaderMap, allow_origin: HeaderValue) {
+        if allow_origin != "*" {
+            headers.append(VARY, HeaderValue::from_static("origin"));
+        }
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if self.allow_credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+}
+
+impl RequestFilter for Cors {
+    fn on_request<'a>(
+        &'a self,
+        context: &'a FilterContext,
+        req: &'a mut Request<RequestBody>,
+    ) -> BoxFuture<'a, FilterOutcome> {
+        let outcome = if Self::is_preflight(req) {
+            debug!(
+                "CORS preflight for {} from {}",
+                req.uri(),
+                context.client_ip
+            );
+            FilterOutcome::Respond(self.preflight(req.headers()))
+        } else {
+            FilterOutcome::Continue
+        };
+        Box::pin(ready(outcome))
+    }
+
+    fn on_response<'a>(
+        &'a self,
+        context: &'a FilterContext,
+        response: &'a mut Response<ResponseBody>,
+    ) -> BoxFuture<'a, ()> {
+        self.apply(context.headers.get(ORIGIN), response.headers_mut());
+        Box::pin(ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderName;
+
+    use super::*;
+
+    fn cors(origins: &[&str], allowed_headers: &[&str], allow_credentials: bool) -> Cors {
+        Cors::new(
+            origins.iter().map(|origin| origin.to_string()).collect(),
+            vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers
+                .iter()
+                .map(|header| header.to_string())
+                .collect(),
+            allow_credentials,
+            600,
+        )
+    }
+
+    fn headers(pairs: &[(HeaderName, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    fn preflight_request() -> Request<()> {
+        let mut req = Request::new(());
+        *req.method_mut() = Method::OPTIONS;
+        *req.headers_mut() = headers(&[
+            (ORIGIN, "https://app.example.com"),
+            (ACCESS_CONTROL_REQUEST_METHOD, "POST"),
+            (ACCESS_CONTROL_REQUEST_HEADERS, "x-token"),
+        ]);
+        req
+    }
+
+    #[test]
+    fn recognises_preflights() {
+        let mut req = preflight_request();
+        assert!(Cors::is_preflight(&req));
+
+        req.headers_mut().remove(ACCESS_CONTROL_REQUEST_METHOD);
+        assert!(!Cors::is_preflight(&req));
+
+        let mut req = preflight_request();
+        *req.method_mut() = Method::GET;
+        assert!(!Cors::is_preflight(&req));
+    }
+
+    #[test]
+    fn answers_preflights_from_allowed_origins() {
+        let cors = cors(&["https://app.example.com"], &[], true);
+        let response = cors.preflight(preflight_request().headers());
+        let headers = response.headers();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, POST");
+        // Requested headers are echoed when none are configured
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], "x-token");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(headers[VARY], "origin");
+    }
+
+    #[test]
+    fn lists_configured_headers_on_preflights() {
+        let cors = cors(&["*"], &["content-type", "x-api-key"], false);
+        let response = cors.preflight(preflight_request().headers());
+        let headers = response.headers();
+
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type, x-api-key"
+        );
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+        assert!(!headers.contains_key(VARY));
+    }
+
+    #[test]
+    fn forbids_preflights_from_other_origins() {
+        let cors = cors(&["https://other.example.com"], &[], false);
+        let response = cors.preflight(preflight_request().headers());
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[test]
+    fn adds_headers_to_responses_for_allowed_origins_only() {
+        let cors = cors(&["https://app.example.com"], &[], false);
+
+        let mut response = HeaderMap::new();
+        cors.apply(
+            Some(&HeaderValue::from_static("https://app.example.com")),
+            &mut response,
+        );
+        assert_eq!(
+            response[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(response[VARY], "origin");
+
+        let mut response = HeaderMap::new();
+        cors.apply(
+            Some(&HeaderValue::from_static("https://evil.example.com")),
+            &mut response,
+        );
+        cors.apply(None, &mut response);
+        assert!(response.is_empty());
+    }
+}
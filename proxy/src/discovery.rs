@@ -0,0 +1,342 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Error, Result, bail, ensure};
+use tokio::{
+    net::UdpSocket,
+    spawn,
+    time::{interval, timeout},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    backend::{Replaced, drain_removed},
+    config::Config,
+    health::spawn_health_check,
+};
+
+/// Prefix marking a backend URL as a DNS SRV name to resolve.
+pub const SRV_PREFIX: &str = "srv+dns:";
+/// How long to wait for the nameserver to answer.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// DNS record type of SRV records.
+const TYPE_SRV: u16 = 33;
+/// DNS class of Internet records.
+const CLASS_IN: u16 = 1;
+/// Most compression pointers followed while reading a name, to stop loops in malformed messages.
+const MAX_POINTERS: usize = 16;
+
+/// A DNS SRV name whose records make up a set of backends, e.g. `_http._tcp.service.local`.
+#[derive(Clone, Debug)]
+pub struct SrvName {
+    /// The name to query, without a trailing dot.
+    name: String,
+    /// The URL scheme of the backends, taken from the service label.
+    scheme: &'static str,
+}
+
+impl SrvName {
+    /// Resolve the name into backend URLs, each followed by its SRV weight.
+    ///
+    /// Only the records with the lowest priority are used, since the others are fallbacks.
+    pub async fn resolve(&self) -> Result<Vec<String>> {
+        let nameserver = nameserver()?;
+        let socket = UdpSocket::bind(match nameserver {
+            IpAddr::V4(_) => "0.0.0.0:0",
+            IpAddr::V6(_) => "[::]:0",
+        })
+        .await?;
+        socket.connect(SocketAddr::new(nameserver, 53)).await?;
+
+        let mut id = [0; 2];
+        getrandom::getrandom(&mut id).expect("the OS random number generator is available");
+        let id = u16::from_be_bytes(id);
+        socket.send(&query(id, &self.name)?).await?;
+
+        let mut buf = [0; 4096];
+        let answer = async {
+            loop {
+                let n = socket.recv(&mut buf).await?;
+                // Ignore stray answers to earlier queries
+                if n >= 2 && u16::from_be_bytes([buf[0], buf[1]]) == id {
+                    return Ok::<_, Error>(n);
+                }
+            }
+        };
+        let n = timeout(QUERY_TIMEOUT, answer)
+            .await
+            .map_err(Error::from)
+            .and_then(|result| result)
+            .with_context(|| format!("No answer from nameserver {} for {}", nameserver, self))?;
+        let response = &buf[..n];
+
+        let mut records = parse_response(response)
+            .with_context(|| format!("Invalid DNS response for {}", self))?;
+        let Some(priority) = records.iter().map(|record| record.priority).min() else {
+            return Ok(Vec::new());
+        };
+        records.retain(|record| record.priority == priority);
+
+        Ok(records
+            .into_iter()
+            .map(|record| {
+                format!(
+                    "{}://{}:{}*{}",
+                    self.scheme,
+                    record.target,
+                    record.port,
+                    record.weight.max(1)
+                )
+            })
+            .collect())
+    }
+}
+
+impl FromStr for SrvName {
+    type Err = Error;
+
+    /// Parse a name in the form `srv+dns:_SERVICE._PROTO.DOMAIN`.
+    fn from_str(s: &str) -> Result<Self> {
+        let name = s
+            .strip_prefix(SRV_PREFIX)
+            .with_context(|| format!("SRV name {:?} must start with {:?}", s, SRV_PREFIX))?
+            .trim_end_matches('.');
+        ensure!(
+            !name.is_empty() && name.split('.').all(|label| (1..=63).contains(&label.len())),
+            "Invalid SRV name {:?}",
+            name
+        );
+
+        let scheme = match name.split('.').next() {
+            Some("_https") => "https",
+            _ => "http",
+        };
+        Ok(Self {
+            name: name.to_string(),
+            scheme,
+        })
+    }
+}
+
+impl Display for SrvName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", SRV_PREFIX, self.name)
+    }
+}
+
+/// An SRV record from a DNS response.
+struct SrvRecord {
+    /// Records with lower priorities are preferred.
+    priority: u16,
+    /// Relative share of requests among records with the same priority.
+    weight: u16,
+    /// Port the service listens on.
+    port: u16,
+    /// Host name of the server, without a trailing dot.
+    target: String,
+}
+
+/// Get the first nameserver listed in `/etc/resolv.conf`.
+fn nameserver() -> Result<IpAddr> {
+    let resolv_conf =
+        std::fs::read_to_string("/etc/resolv.conf").context("Failed to read /etc/resolv.conf")?;
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|address| address.trim().parse().ok())
+        .context("No nameserver configured in /etc/resolv.conf")
+}
+
+/// Build a recursive query for the SRV records of `name`.
+fn query(id: u16, name: &str) -> Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(18 + name.len());
+    message.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, with one question and no other records
+    message.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        ensure!(
+            (1..=63).contains(&label.len()),
+            "Invalid DNS label {:?}",
+            label
+        );
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+/// Parse the SRV records out of a DNS response.
+fn parse_response(message: &[u8]) -> Result<Vec<SrvRecord>> {
+    ensure!(message.len() >= 12, "Response is truncated");
+    let flags = u16::from_be_bytes([message[2], message[3]]);
+    ensure!(flags & 0x8000 != 0, "Message is not a response");
+    ensure!(
+        flags & 0x0200 == 0,
+        "Response was truncated, too many records"
+    );
+    match flags & 0x000f {
+        0 => {}
+        3 => bail!("Name does not exist"),
+        code => bail!("Nameserver returned error code {}", code),
+    }
+
+    let questions = u16::from_be_bytes([message[4], message[5]]);
+    let answers = u16::from_be_bytes([message[6], message[7]]);
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(message, offset)?.1 + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        let (_, end) = read_name(message, offset)?;
+        let header = message
+            .get(end..end + 10)
+            .context("Answer record is truncated")?;
+        let record_type = u16::from_be_bytes([header[0], header[1]]);
+        let data_len = usize::from(u16::from_be_bytes([header[8], header[9]]));
+        let data_start = end + 10;
+        let data = message
+            .get(data_start..data_start + data_len)
+            .context("Answer record is truncated")?;
+        offset = data_start + data_len;
+
+        // Answers can also include the CNAME records the name was resolved through
+        if record_type != TYPE_SRV {
+            continue;
+        }
+        ensure!(data.len() >= 7, "SRV record is truncated");
+        let (target, _) = read_name(message, data_start + 6)?;
+        // A target of "." means the service is deliberately not available
+        if target.is_empty() {
+            continue;
+        }
+
+        records.push(SrvRecord {
+            priority: u16::from_be_bytes([data[0], data[1]]),
+            weight: u16::from_be_bytes([data[2], data[3]]),
+            port: u16::from_be_bytes([data[4], data[5]]),
+            target,
+        });
+    }
+
+    Ok(records)
+}
+
+/// Read a possibly compressed name starting at `offset`, returning it along with the offset just
+/// after it.
+fn read_name(message: &[u8], mut offset: usize) -> Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let len = *message.get(offset).context("Name is truncated")?;
+        match len {
+            0 => break,
+            len if len & 0xc0 == 0xc0 => {
+                let low = *message.get(offset + 1).context("Name is truncated")?;
+                end.get_or_insert(offset + 2);
+                pointers += 1;
+                ensure!(pointers <= MAX_POINTERS, "Name has too many pointers");
+                offset = usize::from(u16::from_be_bytes([len & 0x3f, low]));
+            }
+            len if len & 0xc0 == 0 => {
+                let label = message
+                    .get(offset + 1..offset + 1 + usize::from(len))
+                    .context("Name is truncated")?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + usize::from(len);
+            }
+            _ => bail!("Unsupported label type in name"),
+        }
+    }
+
+    Ok((labels.join("."), end.unwrap_or(offset + 1)))
+}
+
+/// Resolve the SRV-discovered backend sets of a newly loaded `config` for the first time.
+///
+/// Fails if a name doesn't resolve or has no backends, so the configuration isn't used.
+pub async fn resolve_service_names(config: &Config) -> Result<()> {
+    for backends in config.backend_sets() {
+        let Some(srv) = backends.srv() else {
+            continue;
+        };
+        let urls = srv
+            .resolve()
+            .await
+            .with_context(|| format!("Failed to resolve {}", srv))?;
+        ensure!(!urls.is_empty(), "No backends found for {}", srv);
+        backends.replace(urls)?;
+    }
+    Ok(())
+}
+
+/// Spawn a background task per SRV-discovered backend set that periodically re-resolves it.
+///
+/// Resolution failures and empty answers keep the last known set in place. Backends that appear
+/// are health checked like the others. The tasks stop once `config` has been replaced by a reload
+/// and is no longer in use. Does nothing when the discovery interval is zero.
+pub fn spawn_service_discovery(config: &Arc<Config>) {
+    if config.discovery_interval.is_zero() {
+        return;
+    }
+
+    for (index, backends) in config.backend_sets().enumerate() {
+        let Some(srv) = backends.srv().cloned() else {
+            continue;
+        };
+        let interval_duration = config.discovery_interval;
+        let config = Arc::downgrade(config);
+
+        spawn(async move {
+            let mut ticker = interval(interval_duration);
+            // The set was resolved before the configuration was used
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+
+                let resolved = srv.resolve().await;
+
+                let Some(config) = config.upgrade() else {
+                    debug!("Stopping service discovery for {} after reload", srv);
+                    return;
+                };
+                let backends = config
+                    .backend_sets()
+                    .nth(index)
+                    .expect("backend sets don't change within a configuration");
+
+                match resolved {
+                    Ok(urls) if !urls.is_empty() => match backends.replace(urls) {
+                        Ok(Replaced { added, removed }) => {
+                            for backend in &added {
+                                info!("Discovered backend {} for {}", backend.url(), srv);
+                                spawn_health_check(&config, backend);
+                            }
+                            drain_removed(removed, &config.all_backends());
+                        }
+                        Err(e) => warn!("Invalid backend discovered for {}: {:?}", srv, e),
+                    },
+                    Ok(_) => warn!("No backends found for {}, keeping the last known set", srv),
+                    Err(e) => {
+                        warn!(
+                            "Failed to resolve {}, keeping the last known set: {:?}",
+                            srv, e
+                        )
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+    vec,
+};
+
+use anyhow::{Context as _, Result};
+use dashmap::DashMap;
+use futures_util::future::ready;
+use hyper_util::client::legacy::connect::dns::{GaiResolver, Name};
+use tower_service::Service;
+use tracing::debug;
+
+/// Addresses a host name resolved to, until they expire.
+struct CachedAddrs {
+    /// The resolved addresses.
+    addrs: Vec<SocketAddr>,
+    /// When the addresses must be resolved again.
+    expires: Instant,
+}
+
+/// Resolver for backend host names, with static overrides and an optional cache in front of the
+/// system resolver.
+#[derive(Clone)]
+pub struct DnsResolver {
+    /// The system resolver.
+    system: GaiResolver,
+    /// Addresses used for host names instead of looking them up.
+    overrides: Arc<HashMap<String, IpAddr>>,
+    /// How long resolved addresses are reused, if cached.
+    ttl: Option<Duration>,
+    /// Addresses resolved so far, by host name.
+    cache: Arc<DashMap<String, CachedAddrs>>,
+}
+
+impl DnsResolver {
+    /// Create a resolver from `HOST=IP` overrides, caching other lookups for `ttl` if given.
+    pub fn new(overrides: &[String], ttl: Option<Duration>) -> Result<Self> {
+        let overrides = overrides
+            .iter()
+            .map(|entry| {
+                let (host, ip) = entry
+                    .split_once('=')
+                    .with_context(|| format!("Host override {:?} must be HOST=IP", entry))?;
+                let ip = ip
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid IP address for host override {:?}", entry))?;
+                Ok((host.trim().to_ascii_lowercase(), ip))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            overrides: Arc::new(overrides),
+            ttl,
+            ..Self::default()
+        })
+    }
+}
+
+impl Default for DnsResolver {
+    /// The system resolver, without overrides or caching.
+    fn default() -> Self {
+        Self {
+            system: GaiResolver::new(),
+            overrides: Arc::default(),
+            ttl: None,
+            cache: Arc::default(),
+        }
+    }
+}
+
+impl PartialEq for DnsResolver {
+    fn eq(&self, other: &Self) -> bool {
+        self.overrides == other.overrides && self.ttl == other.ttl
+    }
+}
+
+impl Service<Name> for DnsResolver {
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+    type Response = vec::IntoIter<SocketAddr>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.system.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let host = name.as_str().to_ascii_lowercase();
+        // The connector fills in the port
+        if let Some(&ip) = self.overrides.get(&host) {
+            return Box::pin(ready(Ok(vec![SocketAddr::new(ip, 0)].into_iter())));
+        }
+
+        if let Some(cached) = self.cache.get(&host)
+            && cached.expires > Instant::now()
+        {
+            return Box::pin(ready(Ok(cached.addrs.clone().into_iter())));
+        }
+
+        let lookup = self.system.call(name);
+        let ttl = self.ttl;
+        let cache = self.cache.clone();
+        Box::pin(async move {
+            let addrs = lookup.await?.collect::<Vec<_>>();
+            if let Some(ttl) = ttl {
+                debug!("Resolved {} to {:?}, caching for {:?}", host, addrs, ttl);
+                cache.insert(
+                    host,
+                    CachedAddrs {
+                        addrs: addrs.clone(),
+                        expires: Instant::now() + ttl,
+                    },
+                );
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
@@ -0,0 +1,182 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{Context, Result};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    Response, StatusCode,
+    body::Bytes,
+    header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderValue},
+};
+
+use crate::proxy::ResponseBody;
+
+/// Placeholder replaced with the request ID when an error page is rendered.
+const REQUEST_ID_PLACEHOLDER: &str = "{{request_id}}";
+
+/// Marks a response generated by the proxy itself rather than received from a backend.
+#[derive(Clone, Copy, Debug)]
+pub struct Generated;
+
+/// The markup language of an error page, used to pick its content type and escape placeholders.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// An HTML document.
+    Html,
+    /// A JSON document.
+    Json,
+    /// Plain text.
+    Text,
+}
+
+impl Format {
+    /// Guess the format of an error page file from its extension.
+    fn from_path(path: &Path) -> Self {
+        match path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            Some("html" | "htm") => Self::Html,
+            Some("json") => Self::Json,
+            _ => Self::Text,
+        }
+    }
+
+    /// The `Content-Type` to serve pages in this format with.
+    fn content_type(self) -> HeaderValue {
+        HeaderValue::from_static(match self {
+            Format::Html => "text/html; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Text => "text/plain; charset=utf-8",
+        })
+    }
+
+    /// Escape a value so it can be substituted into a page of this format.
+    fn escape(self, value: &str) -> String {
+        match self {
+            Format::Html => value
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+                .replace('\'', "&#39;"),
+            Format::Json => {
+                let quoted = serde_json::to_string(value).expect("strings serialize to JSON");
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            Format::Text => value.to_string(),
+        }
+    }
+}
+
+/// A custom error page template.
+pub struct ErrorPage {
+    /// The page contents, possibly containing placeholders.
+    template: String,
+    /// The page format.
+    format: Format,
+}
+
+impl ErrorPage {
+    /// Load an error page from a file, guessing its format from the extension.
+    pub fn load(path: &Path) -> Result<Self> {
+        let template = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read error page {:?}", path))?;
+
+        Ok(Self {
+            template,
+            format: Format::from_path(path),
+        })
+    }
+
+    /// Replace the body of a response with this page, keeping its status and headers.
+    pub fn render(
+        &self,
+        response: Response<ResponseBody>,
+        request_id: &str,
+    ) -> Response<ResponseBody> {
+        let request_id = self.format.escape(request_id);
+        with_body(
+            response,
+            self.template.replace(REQUEST_ID_PLACEHOLDER, &request_id),
+            self.format.content_type(),
+        )
+    }
+}
+
+/// Bodies for the error responses the proxy generates itself.
+pub struct ErrorPages {
+    /// Custom pages keyed by status code (e.g. `502`) or status class (`4XX`, `5XX`).
+    pages: HashMap<String, ErrorPage>,
+}
+
+impl ErrorPages {
+    /// Create the error pages from custom pages keyed by status code or class.
+    pub fn new(pages: HashMap<String, ErrorPage>) -> Self {
+        Self { pages }
+    }
+
+    /// Give an error response generated by the proxy a body, keeping its status and headers.
+    ///
+    /// A custom page for the exact status takes precedence over one for its class, and the
+    /// built-in page is used when neither is configured. Responses from backends and successful
+    /// responses are returned unchanged.
+    pub fn render(
+        &self,
+        response: Response<ResponseBody>,
+        request_id: &str,
+    ) -> Response<ResponseBody> {
+        let status = response.status();
+        if !(status.is_client_error() || status.is_server_error())
+            || response.extensions().get::<Generated>().is_none()
+        {
+            return response;
+        }
+
+        let class = format!("{}XX", status.as_u16() / 100);
+        match self
+            .pages
+            .get(status.as_str())
+            .or_else(|| self.pages.get(&class))
+        {
+            Some(page) => page.render(response, request_id),
+            None => with_body(
+                response,
+                default_page(status, request_id),
+                Format::Html.content_type(),
+            ),
+        }
+    }
+}
+
+/// Replace the body of a response, keeping its status and headers.
+fn with_body(
+    response: Response<ResponseBody>,
+    body: String,
+    content_type: HeaderValue,
+) -> Response<ResponseBody> {
+    let (mut parts, _) = response.into_parts();
+    parts.headers.insert(CONTENT_TYPE, content_type);
+    parts.headers.insert(CONTENT_LENGTH, body.len().into());
+    Response::from_parts(
+        parts,
+        Full::new(Bytes::from(body))
+            .map_err(|never| match never {})
+            .boxed_unsync(),
+    )
+}
+
+/// Render the built-in error page.
+fn default_page(status: StatusCode, request_id: &str) -> String {
+    let title = format!(
+        "{} {}",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    );
+    let request_id = Format::Html.escape(request_id);
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{title}</title></head>\n<body>\n<h1>{title}</h1>\n<p>Request ID: {request_id}</p>\n</body>\n</html>\n"
+    )
+}
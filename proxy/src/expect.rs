@@ -0,0 +1,140 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use http_body_util::BodyExt;
+use hyper::{
+    HeaderMap, Request, StatusCode,
+    body::{Body, Bytes, Frame, SizeHint},
+    header::EXPECT,
+};
+use tokio::{sync::watch, time::timeout};
+
+use crate::proxy::{BoxError, RequestBody};
+
+/// What the backend has answered to a request expecting `100 Continue`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Answer {
+    /// Nothing yet.
+    Pending,
+    /// `100 Continue`, or no answer in time, so the body is sent.
+    Continue,
+    /// A final response, so the body is never sent.
+    Rejected,
+}
+
+/// Error ending a request body the backend answered before asking for it.
+#[derive(Debug)]
+struct BodyNotSent;
+
+impl fmt::Display for BodyNotSent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("backend responded before the request body was sent")
+    }
+}
+
+impl std::error::Error for BodyNotSent {}
+
+/// Handle on a request whose body is held back until the backend sends `100 Continue`.
+pub struct ExpectContinue {
+    /// Publishes the backend's answer to the held back body.
+    answer: watch::Sender<Answer>,
+}
+
+impl ExpectContinue {
+    /// Hold back the body of a request sending `Expect: 100-continue` until the backend answers
+    /// with `100 Continue`, or for at most `wait` if it doesn't
+    ///
+    /// The client's own `100 Continue` is sent by the server once the body is first read, so it
+    /// is only relayed after the backend's. Requests that don't expect `100 Continue` or have no
+    /// body are returned untouched.
+    pub fn hold(req: Request<RequestBody>, wait: Duration) -> (Request<RequestBody>, Option<Self>) {
+        if !expects_continue(req.headers()) || req.body().is_end_stream() {
+            return (req, None);
+        }
+
+        let (answer, mut receiver) = watch::channel(Answer::Pending);
+        let gate = Box::pin(async move {
+            match timeout(wait, receiver.wait_for(|answer| *answer != Answer::Pending)).await {
+                Ok(Ok(answer)) => *answer,
+                // Send the body anyway, as clients do when no `100 Continue` arrives in time
+                Ok(Err(_)) | Err(_) => Answer::Continue,
+            }
+        });
+        let mut req = req.map(|inner| {
+            HeldBody {
+                gate: Some(gate),
+                inner,
+            }
+            .boxed_unsync()
+        });
+
+        let informational = answer.clone();
+        // Only called for HTTP/1 backends, HTTP/2 ones get the body once `wait` has passed
+        hyper::ext::on_informational(&mut req, move |response| {
+            if response.status() == StatusCode::CONTINUE {
+                informational.send_replace(Answer::Continue);
+            }
+        });
+
+        (req, Some(Self { answer }))
+    }
+
+    /// Record that the backend sent its final response, so a body still held back is never sent
+    pub fn responded(&self) {
+        self.answer.send_if_modified(|answer| {
+            let pending = *answer == Answer::Pending;
+            if pending {
+                *answer = Answer::Rejected;
+            }
+            pending
+        });
+    }
+}
+
+/// Whether request headers ask for `100 Continue` before the body is sent
+fn expects_continue(headers: &HeaderMap) -> bool {
+    headers
+        .get(EXPECT)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
+
+/// Request body that isn't read until the backend has asked for it.
+struct HeldBody {
+    /// Resolves to the backend's answer, until it has been awaited.
+    gate: Option<Pin<Box<dyn Future<Output = Answer> + Send>>>,
+    /// The client's body.
+    inner: RequestBody,
+}
+
+impl Body for HeldBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        if let Some(gate) = self.gate.as_mut() {
+            if ready!(gate.as_mut().poll(cx)) == Answer::Rejected {
+                // Erroring rather than ending the body makes the client drop the backend
+                // connection, which is still expecting the body
+                return Poll::Ready(Some(Err(BodyNotSent.into())));
+            }
+            self.gate = None;
+        }
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.gate.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
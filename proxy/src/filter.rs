@@ -0,0 +1,155 @@
+use std::{net::IpAddr, sync::Arc};
+
+use anyhow::{Result, bail, ensure};
+use futures_util::future::BoxFuture;
+use hyper::{HeaderMap, Method, Request, Response, Uri};
+
+use crate::proxy::{RequestBody, ResponseBody};
+
+/// Names of the built-in filters, in their default order.
+///
+/// CORS comes first since preflights never carry credentials, so they must be answered before
+/// authentication.
+pub const DEFAULT_FILTERS: [&str; 3] = ["cors", "rate_limit", "basic_auth"];
+
+/// What a filter decided about a request.
+pub enum FilterOutcome {
+    /// Pass the request on to the next filter, and then to the backend.
+    Continue,
+    /// Answer the request with this response instead of passing it on.
+    Respond(Response<ResponseBody>),
+}
+
+/// Details of the request a filter runs for, as received from the client.
+pub struct FilterContext {
+    /// The client IP.
+    pub client_ip: IpAddr,
+    /// The request method.
+    pub method: Method,
+    /// The request URI.
+    pub uri: Uri,
+    /// The request headers, before any filter changed them.
+    pub headers: HeaderMap,
+}
+
+impl FilterContext {
+    /// Capture the details of a request from `client_ip`.
+    pub fn new<B>(client_ip: IpAddr, req: &Request<B>) -> Self {
+        Self {
+            client_ip,
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            headers: req.headers().clone(),
+        }
+    }
+}
+
+/// Logic run around proxying a request, e.g. to authenticate it or add headers to its response.
+///
+/// Filters are registered by name when the configuration is loaded, and run in the order
+/// given by `FILTERS`.
+pub trait RequestFilter: Send + Sync {
+    /// Inspect or change a request before it is proxied, or answer it straight away
+    fn on_request<'a>(
+        &'a self,
+        context: &'a FilterContext,
+        req: &'a mut Request<RequestBody>,
+    ) -> BoxFuture<'a, FilterOutcome>;
+
+    /// Inspect or change the response sent for a request
+    fn on_response<'a>(
+        &'a self,
+        context: &'a FilterContext,
+        response: &'a mut Response<ResponseBody>,
+    ) -> BoxFuture<'a, ()> {
+        let _ = (context, response);
+        Box::pin(async {})
+    }
+}
+
+/// The filters requests go through, in order.
+pub struct FilterChain {
+    /// The enabled filters, in the order their request hooks run.
+    filters: Vec<Arc<dyn RequestFilter>>,
+}
+
+impl FilterChain {
+    /// Chain the enabled ones of the `available` filters in the given `order` of names.
+    ///
+    /// Every name must be known and listed once, and every enabled filter must be listed, so a
+    /// typo can't silently turn off authentication. Disabled filters may be listed or not.
+    pub fn new(
+        order: &[String],
+        available: Vec<(&'static str, Option<Arc<dyn RequestFilter>>)>,
+    ) -> Result<Self> {
+        let mut filters = Vec::new();
+        for (index, name) in order.iter().enumerate() {
+            ensure!(
+                !order[..index].contains(name),
+                "Filter {:?} is listed twice in FILTERS",
+                name
+            );
+            match available.iter().find(|(known, _)| known == name) {
+                Some((_, Some(filter))) => filters.push(filter.clone()),
+                Some((_, None)) => {}
+                None => bail!(
+                    "Unknown filter {:?} in FILTERS, expected one of {}",
+                    name,
+                    available
+                        .iter()
+                        .map(|(known, _)| *known)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            }
+        }
+
+        if let Some((name, _)) = available
+            .iter()
+            .find(|(name, filter)| filter.is_some() && !order.iter().any(|listed| listed == name))
+        {
+            bail!("Filter {:?} is enabled but missing from FILTERS", name);
+        }
+
+        Ok(Self { filters })
+    }
+
+    /// Whether no filter is enabled.
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Run the request hooks in order until one answers the request
+    ///
+    /// Returns how many filters saw the request, including the one that answered it, along with
+    /// its response if one did.
+    pub async fn on_request(
+        &self,
+        context: &FilterContext,
+        req: &mut Request<RequestBody>,
+    ) -> (usize, Option<Response<ResponseBody>>) {
+        for (index, filter) in self.filters.iter().enumerate() {
+            if let FilterOutcome::Respond(response) = filter.on_request(context, req).await {
+                return (index + 1, Some(response));
+            }
+        }
+        (self.filters.len(), None)
+    }
+
+    /// Run the response hooks of the first `ran` filters in reverse order
+    pub async fn on_response(
+        &self,
+        context: &FilterContext,
+        ran: usize,
+        response: &mut Response<ResponseBody>,
+    ) {
+        for filter in self.filters[..ran].iter().rev() {
+            filter.on_response(context, response).await;
+        }
+    }
+
+    /// Get the number of enabled filters.
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+}
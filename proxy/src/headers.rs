@@ -0,0 +1,237 @@
+use std::fmt;
+
+use anyhow::{Context, Result, ensure};
+use hyper::{
+    HeaderMap,
+    header::{
+        CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HOST, HeaderName, HeaderValue,
+        UPGRADE,
+    },
+};
+
+use crate::proxy::is_hop_by_hop;
+
+/// Rules for adding, overriding and removing headers on a request or response.
+///
+/// Header names are matched case-insensitively.
+pub struct HeaderRules {
+    /// Headers appended alongside any existing values.
+    add: Vec<(HeaderName, HeaderValue)>,
+    /// Headers replacing any existing values.
+    set: Vec<(HeaderName, HeaderValue)>,
+    /// Headers removed entirely.
+    remove: Vec<HeaderName>,
+}
+
+impl HeaderRules {
+    /// Create rules from `Name: value` entries to add and set, and names to remove.
+    ///
+    /// Hop-by-hop headers only apply to a single connection, so they can be removed but not added.
+    pub fn new(add: &[String], set: &[String], remove: &[String]) -> Result<Self> {
+        Ok(Self {
+            add: parse_headers(add)?,
+            set: parse_headers(set)?,
+            remove: remove
+                .iter()
+                .map(|name| parse_name(name))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Get the `Host` header these rules set, if any.
+    pub fn host(&self) -> Option<&HeaderValue> {
+        self.set
+            .iter()
+            .rev()
+            .find(|(name, _)| name == HOST)
+            .map(|(_, value)| value)
+    }
+
+    /// Apply the rules to a header map: removals first, then overrides, then additions.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.insert(name, value.clone());
+        }
+        for (name, value) in &self.add {
+            headers.append(name, value.clone());
+        }
+    }
+}
+
+/// Headers needed to interpret a request, which are forwarded even when not allow-listed.
+const REQUIRED_HEADERS: [HeaderName; 6] = [
+    HOST,
+    CONTENT_LENGTH,
+    CONTENT_TYPE,
+    CONTENT_ENCODING,
+    CONNECTION,
+    UPGRADE,
+];
+
+/// An allow-list of request headers forwarded to backends, dropping all others.
+///
+/// Header names are matched case-insensitively.
+pub struct HeaderAllowlist {
+    /// Headers forwarded in addition to the required ones.
+    allowed: Vec<HeaderName>,
+}
+
+impl HeaderAllowlist {
+    /// Create an allow-list from header names.
+    pub fn new(allowed: &[String]) -> Result<Self> {
+        Ok(Self {
+            allowed: allowed
+                .iter()
+                .map(|name| parse_name(name))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Remove every header that is neither allowed, required, nor named `keep`.
+    pub fn apply(&self, headers: &mut HeaderMap, keep: &HeaderName) {
+        let dropped = headers
+            .keys()
+            .filter(|name| {
+                *name != keep && !REQUIRED_HEADERS.contains(name) && !self.allowed.contains(name)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in dropped {
+            headers.remove(name);
+        }
+    }
+}
+
+/// Debug formatting of a header map with the values of some headers replaced by `***`.
+///
+/// Header names are lowercase, so they are matched case-insensitively.
+pub struct RedactedHeaders<'a> {
+    /// Headers to format.
+    pub headers: &'a HeaderMap,
+    /// Headers whose values are hidden.
+    pub redact: &'a [HeaderName],
+}
+
+impl fmt::Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (name, value) in self.headers {
+            if self.redact.contains(name) {
+                map.entry(name, &"***");
+            } else {
+                map.entry(name, value);
+            }
+        }
+        map.finish()
+    }
+}
+
+/// Parse a header name.
+fn parse_name(name: &str) -> Result<HeaderName> {
+    HeaderName::from_bytes(name.trim().as_bytes())
+        .with_context(|| format!("Invalid header name {:?}", name))
+}
+
+/// Parse a `Name: value` entry.
+pub fn parse_header(entry: &str) -> Result<(HeaderName, HeaderValue)> {
+    let (name, value) = entry
+        .split_once(':')
+        .with_context(|| format!("Header {:?} must be in the form Name: value", entry))?;
+
+    let name = parse_name(name)?;
+    let value = HeaderValue::from_str(value.trim())
+        .with_context(|| format!("Invalid value for header {:?}", name.as_str()))?;
+    Ok((name, value))
+}
+
+/// Parse `Name: value` entries, rejecting hop-by-hop headers.
+fn parse_headers(entries: &[String]) -> Result<Vec<(HeaderName, HeaderValue)>> {
+    entries
+        .iter()
+        .map(|entry| {
+            let (name, value) = parse_header(entry)?;
+            ensure!(
+                !is_hop_by_hop(&name),
+                "Hop-by-hop header {:?} cannot be added",
+                name.as_str()
+            );
+            Ok((name, value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    fn rules(add: &[&str], set: &[&str], remove: &[&str]) -> HeaderRules {
+        HeaderRules::new(&strings(add), &strings(set), &strings(remove)).unwrap()
+    }
+
+    fn values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn adds_alongside_existing_values() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tag", HeaderValue::from_static("a"));
+        rules(&["X-Tag: b"], &[], &[]).apply(&mut headers);
+        assert_eq!(values(&headers, "x-tag"), ["a", "b"]);
+    }
+
+    #[test]
+    fn sets_replace_existing_values() {
+        let mut headers = HeaderMap::new();
+        headers.append("x-env", HeaderValue::from_static("dev"));
+        headers.append("x-env", HeaderValue::from_static("test"));
+        rules(&[], &["x-env:  prod "], &[]).apply(&mut headers);
+        assert_eq!(values(&headers, "x-env"), ["prod"]);
+    }
+
+    #[test]
+    fn removes_names_case_insensitively() {
+        let mut headers = HeaderMap::new();
+        headers.insert("server", HeaderValue::from_static("nginx"));
+        headers.insert("x-powered-by", HeaderValue::from_static("php"));
+        headers.insert("x-kept", HeaderValue::from_static("1"));
+        rules(&[], &[], &["SERVER", " X-Powered-By "]).apply(&mut headers);
+        assert!(!headers.contains_key("server"));
+        assert!(!headers.contains_key("x-powered-by"));
+        assert_eq!(values(&headers, "x-kept"), ["1"]);
+    }
+
+    #[test]
+    fn applies_removals_before_additions() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tag", HeaderValue::from_static("old"));
+        rules(&["X-TAG: new"], &[], &["x-tag"]).apply(&mut headers);
+        assert_eq!(values(&headers, "x-tag"), ["new"]);
+    }
+
+    #[test]
+    fn finds_host_case_insensitively() {
+        let rules = rules(&[], &["HOST: example.com"], &[]);
+        assert_eq!(rules.host().unwrap(), "example.com");
+    }
+
+    #[test]
+    fn rejects_hop_by_hop_and_malformed_headers() {
+        assert!(HeaderRules::new(&strings(&["Connection: close"]), &[], &[]).is_err());
+        assert!(HeaderRules::new(&[], &strings(&["Transfer-Encoding: chunked"]), &[]).is_err());
+        assert!(HeaderRules::new(&strings(&["no colon"]), &[], &[]).is_err());
+        assert!(HeaderRules::new(&[], &[], &strings(&["bad name"])).is_err());
+        assert!(HeaderRules::new(&[], &[], &strings(&["Connection"])).is_ok());
+    }
+}
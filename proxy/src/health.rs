@@ -0,0 +1,216 @@
+use std::sync::{Arc, OnceLock};
+
+use dashmap::DashMap;
+use futures_util::future::join_all;
+use http_body_util::Empty;
+use hyper::{Request, Response, StatusCode, Uri, body::Bytes};
+use hyper_util::client::legacy::Client;
+use rustls::pki_types::ServerName;
+use tokio::{
+    spawn,
+    time::{interval, timeout},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    backend::{Backend, BackendHttpVersion},
+    config::Config,
+    proxy::{Connector, ResponseBody, client_builder, empty_response, https_connector},
+};
+
+/// Type alias for the client used for health checks
+type HealthClient = Client<Connector, Empty<Bytes>>;
+
+/// Spawn a background task per backend that periodically probes its health check path.
+///
+/// Does nothing when the health check interval is zero.
+pub fn spawn_health_checks(config: &Arc<Config>) {
+    if config.health_check_interval.is_zero() {
+        info!("Active health checks disabled");
+        return;
+    }
+
+    for backend in config.all_backends() {
+        spawn_health_check(config, &backend);
+    }
+}
+
+/// Spawn a background task that periodically probes the health check path of `backend`.
+///
+/// The task stops once the backend is no longer in use, i.e. once its configuration has been
+/// replaced by a reload or service discovery has dropped it, or once another task starts probing
+/// it, e.g. for a backend kept by a reload. Does nothing when the health check interval is zero.
+pub fn spawn_health_check(config: &Config, backend: &Arc<Backend>) {
+    if config.health_check_interval.is_zero() {
+        return;
+    }
+
+    let uri = match health_check_uri(backend, &config.health_check_path) {
+        Ok(uri) => uri,
+        Err(e) => {
+            warn!("Invalid health check URI for {}: {:?}", backend.url(), e);
+            return;
+        }
+    };
+    let interval_duration = config.health_check_interval;
+    let threshold = config.health_check_threshold;
+    let client = health_client(config, backend.sni());
+    let version = config.backend_http_version.request_version();
+    let weak = Arc::downgrade(backend);
+
+    let task = spawn(async move {
+        let mut ticker = interval(interval_duration);
+        loop {
+            ticker.tick().await;
+
+            let request = Request::get(uri.clone())
+                .version(version)
+                .body(Empty::new())
+                .expect("valid health check request");
+
+            // Only connection errors, timeouts and server errors count as failures, so that
+            // backends without a dedicated health endpoint are not taken out of rotation
+            let failure = match timeout(interval_duration, client.request(request)).await {
+                Ok(Ok(response)) if !response.status().is_server_error() => None,
+                Ok(Ok(response)) => Some(format!("status {}", response.status())),
+                Ok(Err(e)) => Some(format!("{:?}", e)),
+                Err(_) => Some("timed out".to_string()),
+            };
+
+            let Some(backend) = weak.upgrade() else {
+                debug!("Stopping health checks for {}", uri);
+                return;
+            };
+
+            match failure {
+                None => {
+                    if backend.record_success() {
+                        info!("Backend {} is healthy again", backend.url());
+                    }
+                }
+                Some(reason) => {
+                    debug!("Health check for {} failed: {}", backend.url(), reason);
+                    if backend.record_failure(threshold) {
+                        warn!(
+                            "Backend {} marked unhealthy after {} failed health checks",
+                            backend.url(),
+                            threshold
+                        );
+                    }
+                }
+            }
+        }
+    });
+    backend.set_health_check(task.abort_handle());
+}
+
+/// Request the health check path of every backend once, logging whether each is reachable.
+///
+/// Any response counts as reachable, so only connection errors and timeouts are failures. Returns
+/// the number of reachable backends.
+pub async fn verify_backends(config: &Config) -> usize {
+    let checks = config.all_backends().into_iter().map(|backend| async move {
+        let client = health_client(config, backend.sni());
+        let uri = match health_check_uri(&backend, &config.health_check_path) {
+            Ok(uri) => uri,
+            Err(e) => {
+                warn!("Invalid health check URI for {}: {:?}", backend.url(), e);
+                return false;
+            }
+        };
+        let request = Request::get(uri)
+            .version(config.backend_http_version.request_version())
+            .body(Empty::new())
+            .expect("valid health check request");
+
+        match timeout(config.upstream_timeout, client.request(request)).await {
+            Ok(Ok(response)) => {
+                info!(
+                    "✓ Backend {} is reachable (status {})",
+                    backend.url(),
+                    response.status()
+                );
+                true
+            }
+            Ok(Err(e)) => {
+                warn!("Backend {} is unreachable: {:?}", backend.url(), e);
+                false
+            }
+            Err(_) => {
+                warn!(
+                    "Backend {} is unreachable: timed out after {:?}",
+                    backend.url(),
+                    config.upstream_timeout
+                );
+                false
+            }
+        }
+    });
+
+    join_all(checks)
+        .await
+        .into_iter()
+        .filter(|&reachable| reachable)
+        .count()
+}
+
+/// Get the client used for health checks of backends with the given server name override
+///
+/// The clients are built on first use, so later changes to their settings in `config` other than
+/// the backend HTTP version only take effect after a restart.
+fn health_client(config: &Config, sni: Option<&ServerName<'static>>) -> HealthClient {
+    type ClientKey = (Option<ServerName<'static>>, BackendHttpVersion);
+    static HEALTH_CLIENTS: OnceLock<DashMap<ClientKey, HealthClient>> = OnceLock::new();
+    HEALTH_CLIENTS
+        .get_or_init(DashMap::new)
+        .entry((sni.cloned(), config.backend_http_version))
+        .or_insert_with(|| {
+            client_builder(config.backend_http_version, config.pool_max_idle_per_host).build(
+                https_connector(
+                    config.backend_tls.client_config(),
+                    config.dns_resolver.clone(),
+                    config.connect_timeout,
+                    config.outbound_proxy.clone(),
+                    sni.cloned(),
+                    config.backend_http_version,
+                ),
+            )
+        })
+        .clone()
+}
+
+/// Build the health check URI for a backend.
+fn health_check_uri(backend: &Backend, path: &str) -> Result<Uri, hyper::http::uri::InvalidUri> {
+    format!(
+        "{}/{}",
+        backend.url().trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+    .parse()
+}
+
+/// Answer a request for the proxy's own liveness or readiness endpoint, or return `None` if the
+/// path is not one of them.
+///
+/// Liveness always succeeds while the proxy is serving requests. Readiness fails with `503` when
+/// no backend is currently healthy.
+pub fn probe_response(config: &Config, path: &str) -> Option<Response<ResponseBody>> {
+    if config.liveness_path.as_deref() == Some(path) {
+        return Some(empty_response(StatusCode::OK));
+    }
+
+    if config.readiness_path.as_deref() == Some(path) {
+        let ready = config
+            .all_backends()
+            .iter()
+            .any(|backend| backend.is_healthy());
+        let status = if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        return Some(empty_response(status));
+    }
+
+    None
+}
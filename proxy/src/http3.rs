@@ -0,0 +1,576 @@
+use std::{
+    future::pending,
+    net::{IpAddr, SocketAddr},
+    pin::{Pin, pin},
+    sync::{Arc, OnceLock},
+    task::{Context as TaskContext, Poll, ready},
+};
+
+use anyhow::{Context, Result};
+use arc_swap::ArcSwap;
+use h3::server::RequestStream;
+use h3_quinn::{RecvStream, SendStream};
+use http_body_util::BodyExt;
+use hyper::{
+    Response,
+    body::{Body, Buf, Bytes, Frame, SizeHint},
+    header::{CONTENT_LENGTH, HeaderValue},
+};
+use quinn::{Endpoint, Incoming, crypto::rustls::QuicServerConfig};
+use rustls::{ServerConfig, pki_types::CertificateDer};
+use tokio::{
+    select, spawn,
+    sync::watch,
+    time::{Instant, Sleep, sleep_until, timeout},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    access_log::Alpn,
+    config::Config,
+    idle::IdleTimeout,
+    metrics::metrics,
+    proxy::{BoxError, ResponseBody, handle},
+    server::HANDSHAKE_TIMEOUT,
+    throttle::SharedBucket,
+    tls::peer_cert_subject,
+};
+
+/// How long clients may remember that HTTP/3 is available, in seconds
+const ALT_SVC_MAX_AGE: u64 = 24 * 60 * 60;
+
+/// `Alt-Svc` header value advertising the HTTP/3 listener, set once it is running
+static ALT_SVC: OnceLock<HeaderValue> = OnceLock::new();
+
+/// Get the `Alt-Svc` header value advertising HTTP/3, if the HTTP/3 listener is running
+pub fn alt_svc() -> Option<&'static HeaderValue> {
+    ALT_SVC.get()
+}
+
+/// Serve HTTP/3 over QUIC on the UDP port of `addr` in a background task, returning the address
+/// it listens on.
+///
+/// The QUIC handshake uses the same certificate and key as the TCP listener, following reloads
+/// of the TLS configuration for new connections. Requests are proxied like those on the TCP
+/// listener, with the configuration current at the time they arrive. Connections are registered
+/// with `shutdown` so they can be drained, and are told to close with a `GOAWAY` frame.
+///
+/// Like the TCP listener, connections count towards the connection limit of `config`, the
+/// configuration at startup.
+pub fn spawn_http3_server(
+    addr: SocketAddr,
+    config: Arc<Config>,
+    configs: &'static ArcSwap<Config>,
+    tls_config: Arc<ArcSwap<ServerConfig>>,
+    shutdown: &watch::Sender<()>,
+) -> Result<SocketAddr> {
+    let mut current = tls_config.load_full();
+    let mut quic_config = quic_server_config(&current)?;
+    let endpoint = Endpoint::server(quic_config.as_ref().clone(), addr)
+        .with_context(|| format!("Failed to bind HTTP/3 listener on udp://{}", addr))?;
+    let addr = endpoint.local_addr()?;
+    let alt_svc = format!("h3=\":{}\"; ma={}", addr.port(), ALT_SVC_MAX_AGE);
+    let _ = ALT_SVC.set(HeaderValue::from_str(&alt_svc)?);
+    info!("✓ HTTP/3 listening on udp://{}", addr);
+
+    let mut watcher = shutdown.subscribe();
+    spawn(async move {
+        loop {
+            let incoming = select! {
+                incoming = endpoint.accept() => incoming,
+                _ = watcher.changed() => break,
+            };
+            // The endpoint is only closed once it is dropped
+            let Some(incoming) = incoming else {
+                break;
+            };
+
+            // A reloaded certificate applies to connections from then on
+            let latest = tls_config.load_full();
+            if !Arc::ptr_eq(&latest, &current) {
+                match quic_server_config(&latest) {
+                    Ok(config) => quic_config = config,
+                    Err(e) => error!("Failed to reload HTTP/3 TLS configuration: {:?}", e),
+                }
+                current = latest;
+            }
+
+            spawn(accept_connection(
+                incoming,
+                config.clone(),
+                configs,
+                quic_config.clone(),
+                watcher.clone(),
+            ));
+        }
+    });
+
+    Ok(addr)
+}
+
+/// Build the QUIC configuration from the TLS configuration of the TCP listener, offering only
+/// HTTP/3 through ALPN
+fn quic_server_config(tls_config: &ServerConfig) -> Result<Arc<quinn::ServerConfig>> {
+    let mut tls_config = tls_config.clone();
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = QuicServerConfig::try_from(tls_config).context(
+        "TLS configuration can't be used for QUIC, which requires a TLS 1.3 cipher suite",
+    )?;
+    Ok(Arc::new(quinn::ServerConfig::with_crypto(Arc::new(crypto))))
+}
+
+/// Completes the QUIC handshake of a new connection and serves HTTP/3 on it
+///
+/// The IP filter, accept rate limit and the connection limit of `startup` apply as they do on the
+/// TCP listener, and request and response bodies are throttled to `MAX_UPLOAD_BPS` and
+/// `MAX_DOWNLOAD_BPS` across the requests of the connection.
+async fn accept_connection(
+    incoming: Incoming,
+    startup: Arc<Config>,
+    configs: &'static ArcSwap<Config>,
+    quic_config: Arc<quinn::ServerConfig>,
+    watcher: watch::Receiver<()>,
+) {
+    let config = configs.load_full();
+    let client_ip = incoming.remote_address().ip();
+    if !config.ip_filter.is_allowed(client_ip) {
+        debug!("Rejected HTTP/3 connection from {}", client_ip);
+        incoming.refuse();
+        return;
+    }
+    if let Some(ref rate) = config.accept_rate
+        && !rate.admit()
+    {
+        debug!(
+            "Accept rate limit reached, refusing HTTP/3 connection from {}",
+            client_ip
+        );
+        metrics().rejected_connections.inc();
+        rate.record_rejected();
+        incoming.refuse();
+        return;
+    }
+    let Some(admission) = startup.connection_limit.admit(client_ip) else {
+        incoming.refuse();
+        return;
+    };
+    // Held until the connection is done
+    let permit = admission.wait().await;
+    let overloaded = permit.is_none();
+
+    let connecting = match incoming.accept_with(quic_config) {
+        Ok(connecting) => connecting,
+        Err(e) => {
+            warn!("QUIC handshake error from {}: {:?}", client_ip, e);
+            return;
+        }
+    };
+    let connection = match timeout(HANDSHAKE_TIMEOUT, connecting).await {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(e)) => {
+            warn!("QUIC handshake error from {}: {:?}", client_ip, e);
+            return;
+        }
+        Err(_) => {
+            warn!("QUIC handshake timeout from {}", client_ip);
+            return;
+        }
+    };
+    debug!("Accepted HTTP/3 connection from {}", client_ip);
+
+    let subject: Option<Arc<str>> = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .and_then(|certs| peer_cert_subject(&certs))
+        .map(Arc::from);
+    connection.set_max_concurrent_bi_streams(config.h2_max_concurrent_streams.into());
+    let client = Client {
+        ip: client_ip,
+        subject,
+        overloaded,
+        upload: SharedBucket::new(config.max_upload_bps),
+        download: SharedBucket::new(config.max_download_bps),
+    };
+
+    metrics().alpn_connections.with_label_values(&["h3"]).inc();
+    metrics().active_connections.inc();
+    if let Err(e) = serve(connection, &client, &config, configs, watcher).await {
+        warn!("HTTP/3 connection error from {}: {:?}", client_ip, e);
+    }
+    metrics().active_connections.dec();
+}
+
+/// The client of an HTTP/3 connection, shared by its requests.
+#[derive(Clone)]
+struct Client {
+    /// The client IP.
+    ip: IpAddr,
+    /// Subject of the verified client certificate, if any.
+    subject: Option<Arc<str>>,
+    /// Whether the connection didn't get a slot under the connection limit.
+    overloaded: bool,
+    /// Budget for request bodies, if limited.
+    upload: Option<SharedBucket>,
+    /// Budget for response bodies, if limited.
+    download: Option<SharedBucket>,
+}
+
+/// Serve the requests of an HTTP/3 connection until it is done, asking the client to close it
+/// with a `GOAWAY` frame once `watcher` signals the server is shutting down, the connection has
+/// gone `CONNECTION_IDLE_TIMEOUT_SECS` without requests, or an overloaded connection has had its
+/// first request
+///
+/// Each request is handled in its own task, holding a copy of `watcher` until its response has
+/// been sent. The connection lets go of its own copy once it has sent `GOAWAY`, so draining the
+/// server only waits for requests in flight rather than for the client to close the connection.
+async fn serve(
+    connection: quinn::Connection,
+    client: &Client,
+    config: &Config,
+    configs: &'static ArcSwap<Config>,
+    watcher: watch::Receiver<()>,
+) -> Result<(), h3::error::ConnectionError> {
+    let mut conn = h3::server::builder()
+        .max_field_section_size(config.max_header_size as u64)
+        .build(h3_quinn::Connection::new(connection))
+        .await?;
+
+    let client_ip = client.ip;
+    let overloaded = client.overloaded;
+    let idle = config.connection_idle_timeout.map(IdleTimeout::new);
+    let mut expired = pin!(async {
+        match idle {
+            Some(ref idle) => idle.expired().await,
+            None => pending().await,
+        }
+    });
+
+    let mut watcher = Some(watcher);
+    loop {
+        let resolver = match watcher {
+            Some(ref mut shutdown) => select! {
+                resolver = conn.accept() => resolver?,
+                _ = shutdown.changed() => {
+                    watcher = None;
+                    conn.shutdown(0).await?;
+                    continue;
+                }
+                () = &mut expired => {
+                    // No requests are in flight, so the connection can be closed right away
+                    debug!("Closing idle HTTP/3 connection from {}", client_ip);
+                    conn.shutdown(0).await?;
+                    return Ok(());
+                }
+            },
+            None => conn.accept().await?,
+        };
+        let Some(resolver) = resolver else {
+            return Ok(());
+        };
+
+        let active = idle.as_ref().map(IdleTimeout::start_request);
+        let client = client.clone();
+        let request_watcher = watcher.clone();
+        spawn(async move {
+            let (req, stream) = match resolver.resolve_request().await {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    debug!("Failed to read HTTP/3 request from {}: {:?}", client_ip, e);
+                    return;
+                }
+            };
+            let (mut send, recv) = stream.split();
+            let length = req
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse().ok());
+            let body = RecvBody::new(recv, length, client.upload);
+            let mut req = req.map(|()| body.boxed_unsync());
+            req.extensions_mut().insert(Alpn("h3"));
+
+            let handling = handle(
+                client_ip,
+                true,
+                client.subject,
+                client.overloaded,
+                configs.load_full(),
+                req,
+            );
+            let Ok(response) = handling.await;
+            if let Err(e) = send_response(&mut send, response, client.download).await {
+                debug!("Failed to send HTTP/3 response to {}: {:?}", client_ip, e);
+            }
+            drop((active, request_watcher));
+        });
+
+        // Connections without a slot under the connection limit only get their `503`
+        if overloaded && watcher.is_some() {
+            debug!("Closing overloaded HTTP/3 connection from {}", client_ip);
+            watcher = None;
+            conn.shutdown(0).await?;
+        }
+    }
+}
+
+/// Send a response on an HTTP/3 request stream, streaming its body and trailers within the
+/// `download` budget
+async fn send_response(
+    stream: &mut RequestStream<SendStream<Bytes>, Bytes>,
+    response: Response<ResponseBody>,
+    download: Option<SharedBucket>,
+) -> Result<(), BoxError> {
+    let (parts, mut body) = response.into_parts();
+    stream
+        .send_response(Response::from_parts(parts, ()))
+        .await?;
+    while let Some(frame) = body.frame().await {
+        match frame?.into_data() {
+            Ok(data) => {
+                if let Some(ref download) = download {
+                    download.ready().await;
+                    download.consume(data.len());
+                }
+                stream.send_data(data).await?;
+            }
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    stream.send_trailers(trailers).await?;
+                }
+            }
+        }
+    }
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Body of an HTTP/3 request, read from its request stream as the backend asks for it
+struct RecvBody {
+    /// The receiving half of the request stream.
+    stream: RequestStream<RecvStream, Bytes>,
+    /// Bytes announced by `Content-Length` that have not been read yet, if announced.
+    remaining: Option<u64>,
+    /// Whether all the data has been read, leaving only trailers.
+    data_done: bool,
+    /// Whether the trailers have been read too.
+    done: bool,
+    /// Budget for reading the body, if limited.
+    upload: Option<SharedBucket>,
+    /// Timer waiting for the budget to refill.
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl RecvBody {
+    /// Read the body of a request from the receiving half of its stream within the `upload`
+    /// budget, expecting `length` bytes if the request announced them
+    fn new(
+        stream: RequestStream<RecvStream, Bytes>,
+        length: Option<u64>,
+        upload: Option<SharedBucket>,
+    ) -> Self {
+        Self {
+            stream,
+            remaining: length,
+            data_done: false,
+            done: false,
+            upload,
+            sleep: Box::pin(sleep_until(Instant::now())),
+        }
+    }
+}
+
+impl Body for RecvBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        if !self.data_done {
+            let this = &mut *self;
+            if let Some(ref upload) = this.upload {
+                // Data left unread holds back the client through QUIC flow control
+                ready!(upload.poll_ready(cx, &mut this.sleep));
+            }
+            match ready!(self.stream.poll_recv_data(cx)) {
+                Ok(Some(mut data)) => {
+                    let data = data.copy_to_bytes(data.remaining());
+                    if let Some(ref upload) = self.upload {
+                        upload.consume(data.len());
+                    }
+                    if let Some(ref mut remaining) = self.remaining {
+                        *remaining = remaining.saturating_sub(data.len() as u64);
+                    }
+                    return Poll::Ready(Some(Ok(Frame::data(data))));
+                }
+                Ok(None) => self.data_done = true,
+                Err(e) => return Poll::Ready(Some(Err(e.into()))),
+            }
+        }
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        let trailers = ready!(self.stream.poll_recv_trailers(cx));
+        self.done = true;
+        Poll::Ready(
+            trailers
+                .map_err(BoxError::from)
+                .transpose()
+                .map(|trailers| trailers.map(Frame::trailers)),
+        )
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.remaining {
+            _ if self.data_done => SizeHint::with_exact(0),
+            Some(remaining) => SizeHint::with_exact(remaining),
+            None => SizeHint::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::poll_fn, time::Duration};
+
+    use h3::client::SendRequest;
+    use h3_quinn::OpenStreams;
+    use hyper::{Method, Request, StatusCode};
+    use quinn::{ClientConfig, crypto::rustls::QuicClientConfig};
+    use rustls::RootCertStore;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        test_support::{header, read_head, tls_config},
+        tls::load_tls_config,
+    };
+
+    /// Serve HTTP/3 configured with `settings` on an ephemeral port, returning its address and
+    /// certificate.
+    ///
+    /// The server keeps running until the test's runtime shuts down.
+    fn spawn_http3_proxy(settings: &str) -> (SocketAddr, CertificateDer<'static>) {
+        let (config, cert) = tls_config(&format!(
+            "enable_http3 = true\nbind_addr = \"127.0.0.1:0\"\n{}",
+            settings
+        ));
+        let tls_config = Arc::new(ArcSwap::new(load_tls_config(&config).unwrap()));
+        let config = Arc::new(config);
+        let configs = Box::leak(Box::new(ArcSwap::new(config.clone())));
+
+        // Connections are told to drain when the sender goes away, so it never does
+        let (shutdown, _) = watch::channel(());
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let addr = spawn_http3_server(addr, config, configs, tls_config, &shutdown).unwrap();
+        Box::leak(Box::new(shutdown));
+        (addr, cert)
+    }
+
+    /// Open a QUIC connection to `addr`, trusting `cert` for `localhost`.
+    async fn connect(
+        addr: SocketAddr,
+        cert: &CertificateDer<'static>,
+    ) -> Result<quinn::Connection, quinn::ConnectionError> {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.clone()).unwrap();
+        let mut tls = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+
+        let mut endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        let crypto = QuicClientConfig::try_from(tls).unwrap();
+        endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+        endpoint.connect(addr, "localhost").unwrap().await
+    }
+
+    /// Start an HTTP/3 client on a QUIC connection, driving the connection in the background.
+    async fn h3_client(connection: quinn::Connection) -> SendRequest<OpenStreams, Bytes> {
+        let connection = h3_quinn::Connection::new(connection);
+        let (mut driver, send_request) = h3::client::new(connection).await.unwrap();
+        spawn(async move { poll_fn(|cx| driver.poll_close(cx)).await });
+        send_request
+    }
+
+    /// Answer one HTTP/1.1 request on `backend` with its own body, returning the request head.
+    async fn echo_once(backend: TcpListener) -> String {
+        let (mut stream, _) = backend.accept().await.unwrap();
+        let head = read_head(&mut stream).await;
+        let length = header(&head, "content-length").map_or(0, |value| value.parse().unwrap());
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", length);
+        stream.write_all(response.as_bytes()).await.unwrap();
+        stream.write_all(&body).await.unwrap();
+        head
+    }
+
+    #[tokio::test]
+    async fn proxies_requests_over_http3() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let echo = spawn(echo_once(backend));
+        let (addr, cert) =
+            spawn_http3_proxy(&format!("backend_urls = \"http://{}\"", backend_addr));
+
+        let mut client = h3_client(connect(addr, &cert).await.unwrap()).await;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("https://localhost/echo")
+            .header(CONTENT_LENGTH, "15")
+            .body(())
+            .unwrap();
+        let mut stream = client.send_request(req).await.unwrap();
+        stream
+            .send_data(Bytes::from_static(b"hello over quic"))
+            .await
+            .unwrap();
+        stream.finish().await.unwrap();
+
+        let response = stream.recv_response().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = Vec::new();
+        while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+        assert_eq!(body, b"hello over quic");
+
+        let head = echo.await.unwrap();
+        assert!(head.starts_with("POST /echo HTTP/1.1\r\n"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn refuses_quic_connections_over_the_connection_limit() {
+        let (addr, cert) = spawn_http3_proxy(
+            "backend_urls = \"http://127.0.0.1:9\"\nmax_connections = 1\non_overload = \"reject\"",
+        );
+
+        let _held = connect(addr, &cert).await.unwrap();
+        assert!(connect(addr, &cert).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn closes_idle_quic_connections() {
+        let (addr, cert) = spawn_http3_proxy(
+            "backend_urls = \"http://127.0.0.1:9\"\nconnection_idle_timeout_secs = 1",
+        );
+
+        let connection = connect(addr, &cert).await.unwrap();
+        let _client = h3_client(connection.clone()).await;
+        let closed = timeout(Duration::from_secs(5), connection.closed()).await;
+        assert!(
+            closed.is_ok(),
+            "connection still open after the idle timeout"
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use hyper::Response;
+use tokio::time::{Instant, sleep, sleep_until};
+
+use crate::proxy::{ResponseBody, hold_until_body_end};
+
+/// Activity on a connection, shared between its requests.
+struct Activity {
+    /// Number of requests being handled, including their response bodies.
+    in_flight: AtomicUsize,
+    /// When the last request started or finished.
+    last_active: Mutex<Instant>,
+}
+
+impl Activity {
+    /// Record that a request started or finished
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+}
+
+/// Timer closing a connection once it has gone without requests for a while.
+#[derive(Clone)]
+pub struct IdleTimeout {
+    /// How long the connection may sit without requests.
+    timeout: Duration,
+    /// Activity on the connection.
+    activity: Arc<Activity>,
+}
+
+impl IdleTimeout {
+    /// Start timing a new connection, which may go `timeout` without requests
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            activity: Arc::new(Activity {
+                in_flight: AtomicUsize::new(0),
+                last_active: Mutex::new(Instant::now()),
+            }),
+        }
+    }
+
+    /// Record the start of a request, which keeps the connection busy until the returned guard
+    /// is dropped
+    pub fn start_request(&self) -> ActiveRequest {
+        self.activity.in_flight.fetch_add(1, Ordering::SeqCst);
+        self.activity.touch();
+        ActiveRequest(self.activity.clone())
+    }
+
+    /// Wait until the connection has gone the whole timeout without requests
+    pub async fn expired(&self) {
+        loop {
+            if self.activity.in_flight.load(Ordering::SeqCst) > 0 {
+                // The timer starts over once the requests finish, so there is no deadline yet
+                sleep(self.timeout).await;
+                continue;
+            }
+
+            let deadline = *self.activity.last_active.lock().unwrap() + self.timeout;
+            if deadline <= Instant::now() {
+                return;
+            }
+            sleep_until(deadline).await;
+        }
+    }
+}
+
+/// Guard marking a request as in flight on its connection.
+pub struct ActiveRequest(Arc<Activity>);
+
+impl ActiveRequest {
+    /// Keep the request in flight until the response body has been sent or dropped
+    pub fn hold(self, response: Response<ResponseBody>) -> Response<ResponseBody> {
+        hold_until_body_end(self, response)
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.touch();
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
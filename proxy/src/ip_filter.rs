@@ -0,0 +1,46 @@
+use std::net::IpAddr;
+
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+
+/// Allow and deny lists of client IP ranges.
+pub struct IpFilter {
+    /// Ranges allowed to connect; empty means every address is allowed.
+    allow: Vec<IpNet>,
+    /// Ranges never allowed to connect, taking precedence over `allow`.
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Create a filter from CIDR ranges or single addresses.
+    pub fn new(allow: &[String], deny: &[String]) -> Result<Self> {
+        Ok(Self {
+            allow: parse_ranges(allow).context("Failed to parse ALLOW_IPS")?,
+            deny: parse_ranges(deny).context("Failed to parse DENY_IPS")?,
+        })
+    }
+
+    /// Whether a client IP may connect.
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // Clients connecting over IPv6 sockets may show up as IPv4-mapped addresses
+        let ip = ip.to_canonical();
+
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Parse CIDR ranges, treating a bare address as a single-host range.
+pub fn parse_ranges(ranges: &[String]) -> Result<Vec<IpNet>> {
+    ranges
+        .iter()
+        .map(|range| {
+            range
+                .parse::<IpNet>()
+                .or_else(|_| range.parse::<IpAddr>().map(IpNet::from))
+                .with_context(|| format!("Invalid IP range {:?}", range))
+        })
+        .collect()
+}
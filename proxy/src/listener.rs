@@ -0,0 +1,234 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, IoSlice},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    fs::Permissions,
+    net::Ipv4Addr,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::PathBuf,
+};
+
+#[cfg(unix)]
+use anyhow::Context;
+#[cfg(not(unix))]
+use anyhow::bail;
+use anyhow::{Error, Result};
+use hyper::body::Bytes;
+use socket2::{SockRef, TcpKeepalive};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+use tracing::warn;
+
+/// Any bidirectional byte stream a client connection can be served over.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Io for T {}
+
+/// An accepted client connection, independent of the kind of listener it came from.
+pub type Stream = Box<dyn Io>;
+
+/// A stream that replays bytes already read from it before reading any further.
+///
+/// Used to inspect the start of a connection without consuming it.
+pub struct Rewind {
+    /// Bytes read ahead that have not been replayed yet.
+    prefix: Bytes,
+    /// The underlying stream.
+    inner: Stream,
+}
+
+impl Rewind {
+    /// Wrap a stream, replaying `prefix` before reading from it again.
+    pub fn new(prefix: Bytes, inner: Stream) -> Self {
+        Self { prefix, inner }
+    }
+}
+
+impl AsyncRead for Rewind {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.prefix.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+
+        let len = self.prefix.len().min(buf.remaining());
+        buf.put_slice(&self.prefix.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Rewind {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// An address the proxy can listen on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BindAddr {
+    /// A TCP address, e.g. `127.0.0.1:8000`.
+    Tcp(SocketAddr),
+    /// A Unix domain socket path, written as `unix:/path/to.sock`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for BindAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(BindAddr::Unix(path.into()));
+            #[cfg(not(unix))]
+            bail!("Unix socket {:?} is not supported on this platform", path);
+        }
+
+        Ok(BindAddr::Tcp(s.parse()?))
+    }
+}
+
+impl Display for BindAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddr::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            BindAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// TCP options applied to every accepted client connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Whether to disable Nagle's algorithm, sending small writes immediately.
+    pub nodelay: bool,
+    /// Idle time before TCP keepalive probes are sent, or `None` to leave keepalive off.
+    pub keepalive: Option<Duration>,
+}
+
+impl SocketOptions {
+    /// Apply the options to an accepted TCP connection.
+    fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(keepalive) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))?;
+        }
+        Ok(())
+    }
+}
+
+/// A bound listener accepting client connections.
+pub enum Listener {
+    /// Listening on a TCP port, tuning accepted connections with the given options.
+    Tcp(TcpListener, SocketOptions),
+    /// Listening on a Unix domain socket, which is removed again when the listener is dropped.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Bind a listener to the given address.
+    ///
+    /// For Unix sockets a stale socket file is replaced, and `mode` sets the file permissions. The
+    /// socket options only apply to TCP connections.
+    pub async fn bind(
+        addr: &BindAddr,
+        #[allow(unused_variables)] mode: Option<u32>,
+        options: SocketOptions,
+    ) -> Result<Self> {
+        match addr {
+            BindAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?, options)),
+            #[cfg(unix)]
+            BindAddr::Unix(path) => {
+                if std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                    warn!("Removing stale Unix socket {:?}", path);
+                    std::fs::remove_file(path)
+                        .with_context(|| format!("Failed to remove stale socket {:?}", path))?;
+                }
+
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("Failed to bind Unix socket {:?}", path))?;
+
+                if let Some(mode) = mode {
+                    std::fs::set_permissions(path, Permissions::from_mode(mode))
+                        .with_context(|| format!("Failed to set permissions on {:?}", path))?;
+                }
+
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+        }
+    }
+
+    /// Accept the next connection, returning it along with the client IP.
+    ///
+    /// Unix socket clients have no IP address and are reported as `127.0.0.1`.
+    pub async fn accept(&self) -> io::Result<(Stream, IpAddr)> {
+        match self {
+            Listener::Tcp(listener, options) => {
+                let (stream, remote_addr) = listener.accept().await?;
+                // A connection that can't be tuned is still served
+                if let Err(e) = options.apply(&stream) {
+                    warn!("Failed to set socket options for {}: {:?}", remote_addr, e);
+                }
+                Ok((Box::new(stream), remote_addr.ip()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), IpAddr::V4(Ipv4Addr::LOCALHOST)))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let Listener::Unix(_, path) = self else {
+            return;
+        };
+
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("Failed to remove Unix socket {:?}: {:?}", path, e);
+        }
+    }
+}
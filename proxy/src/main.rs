@@ -1,8 +1,11 @@
-use std::{sync::OnceLock, time::Duration};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
-use anyhow::Result;
-use hyper_util::rt::TokioIo;
-use tokio::{net::TcpListener, spawn, time::timeout};
+use anyhow::{Context, Result, ensure};
+use arc_swap::ArcSwap;
+use tokio::{spawn, sync::watch, time::timeout};
 use tokio_rustls::TlsAcceptor;
 use tracing::{debug, error, info, warn};
 use tracing_subscriber::{
@@ -10,45 +13,161 @@ use tracing_subscriber::{
 };
 
 use crate::{
+    acme::{ensure_certificate, spawn_acme_renewal},
+    admin::spawn_admin_server,
     config::Config,
-    server::{handle_connection, handle_plaintext_connection, shutdown_signal},
-    tls::load_tls_config,
+    discovery::{resolve_service_names, spawn_service_discovery},
+    health::{spawn_health_checks, verify_backends},
+    http3::spawn_http3_server,
+    listener::{BindAddr, Listener},
+    maintenance::log_toggled,
+    metrics::{metrics, spawn_metrics_server},
+    rate_limit::spawn_rate_limit_cleanup,
+    redirect::spawn_redirect_server,
+    server::{accept_connection, shutdown_signal, spawn_reload_handler},
+    tls::{load_tls_config, spawn_tls_reloader},
 };
 
+mod access_log;
+mod acme;
+mod admin;
+mod auth;
+mod backend;
+mod cache;
+mod canary;
+mod circuit_breaker;
+mod compression;
 mod config;
+mod connection_limit;
+mod cors;
+mod discovery;
+mod dns;
+mod error_page;
+mod expect;
+mod filter;
+mod headers;
+mod health;
+mod http3;
+mod idle;
+mod ip_filter;
+mod listener;
+mod maintenance;
+mod metrics;
+mod mirror;
+#[cfg(feature = "otel")]
+mod otel;
+mod outbound_proxy;
+mod path_filter;
+mod path_normalize;
 mod proxy;
+mod proxy_protocol;
+mod rate_limit;
+mod redirect;
+mod request_buffer;
+mod request_id;
+mod response_buffer;
+mod rewrite;
+mod routes;
 mod server;
+mod static_files;
+mod status;
+mod status_map;
+mod sticky;
+#[cfg(test)]
+mod test_support;
+mod throttle;
 mod tls;
 
-static GLOBAL_CONFIG: OnceLock<Config> = OnceLock::new();
+static GLOBAL_CONFIG: OnceLock<ArcSwap<Config>> = OnceLock::new();
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    registry()
-        .with(
-            EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "info,hyper_reverse_proxy=debug".into()),
-        )
-        .with(layer())
-        .init();
+    // Slow requests are logged whatever RUST_LOG says, since SLOW_REQUEST_MS asks for them
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "info".into())
+        .add_directive("slow_request=warn".parse()?);
+    let subscriber = registry().with(filter).with(layer());
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(otel::layer()?);
+    subscriber.init();
 
-    let config = Config::from_env()?;
+    let config = Arc::new(Config::from_config_path()?);
+    resolve_service_names(&config).await?;
 
     info!("Starting reverse proxy server");
-    info!("Backend URL: {}", config.backend_url);
+    info!(
+        "Backend URLs: {}",
+        config
+            .all_backends()
+            .iter()
+            .map(|backend| backend.url())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
     info!("Bind address: {}", config.bind_addr);
     info!("TLS enabled: {}", config.use_tls);
+    info!("HTTP/2 enabled: {}", config.enable_h2);
+    info!("HTTP/3 enabled: {}", config.enable_http3);
+    info!(
+        "HTTP/1.1: keep-alive {}, header read timeout {}",
+        if config.h1_keep_alive { "on" } else { "off" },
+        describe_timeout(config.h1_header_read_timeout)
+    );
+    info!(
+        "Backend connection pool: up to {} idle connections per host, closed after {:?} idle",
+        config.pool_max_idle_per_host, config.pool_idle_timeout
+    );
+    info!(
+        "HTTP/2: keep-alive interval {}, keep-alive timeout {:?}, max concurrent streams {}, \
+         window {} bytes per stream and {} per connection",
+        describe_timeout(config.h2_keep_alive_interval),
+        config.h2_keep_alive_timeout,
+        config.h2_max_concurrent_streams,
+        config.h2_initial_window_size,
+        config.h2_initial_connection_window_size
+    );
+    if config.maintenance.is_enabled() {
+        log_toggled(true);
+    }
 
-    let listener = TcpListener::bind(config.bind_addr).await?;
+    let listener = Listener::bind(
+        &config.bind_addr,
+        config.unix_socket_mode,
+        config.socket_options,
+    )
+    .await?;
 
-    let tls_acceptor = if config.use_tls {
-        let tls_config = load_tls_config(&config.cert_path, &config.key_path)?;
-        Some(TlsAcceptor::from(tls_config))
+    // Started before loading TLS so that ACME challenges are answered while a certificate is
+    // obtained
+    if let Some(redirect_http_addr) = config.redirect_http_addr {
+        let https_port = match config.bind_addr {
+            BindAddr::Tcp(addr) if addr.port() != 443 => Some(addr.port()),
+            _ => None,
+        };
+        spawn_redirect_server(redirect_http_addr, https_port).await?;
+    }
+
+    let tls_config = if config.use_tls {
+        ensure_certificate(&config)
+            .await
+            .context("Failed to obtain a certificate with ACME")?;
+        let tls_config = load_tls_config(&config)?;
+        Some(Arc::new(ArcSwap::new(tls_config)))
     } else {
         warn!("Running in plaintext HTTP mode (TLS disabled)");
         None
     };
 
+    if config.verify_backend_on_start {
+        let backends = config.all_backends().len();
+        let reachable = verify_backends(&config).await;
+        info!("{} of {} backends reachable", reachable, backends);
+        ensure!(
+            reachable > 0 || !config.require_backend_on_start,
+            "No backend is reachable"
+        );
+    }
+
     if config.use_tls {
         info!("✓ Server listening on https://{}", config.bind_addr);
     } else {
@@ -56,41 +175,73 @@ async fn main() -> Result<()> {
     }
     info!("Press Ctrl+C to shutdown gracefully");
 
-    let config_ref = GLOBAL_CONFIG.get_or_init(|| config);
+    spawn_health_checks(&config);
+    spawn_service_discovery(&config);
+    spawn_rate_limit_cleanup(&config);
+
+    if let Some(metrics_addr) = config.metrics_addr {
+        spawn_metrics_server(metrics_addr).await?;
+    }
+
+    // The listener and connection limit stay as they were at startup, while everything else
+    // follows the configuration as it is reloaded
+    let configs = GLOBAL_CONFIG.get_or_init(|| ArcSwap::new(config.clone()));
+
+    if let Some(admin_addr) = config.admin_addr {
+        spawn_admin_server(admin_addr, configs, tls_config.clone()).await?;
+    }
+
+    if let Some(ref tls_config) = tls_config {
+        spawn_tls_reloader(configs, tls_config.clone());
+        spawn_acme_renewal(configs, tls_config.clone());
+    }
+    spawn_reload_handler(configs, tls_config.clone());
+
+    // Tells connections to finish their requests and close, and is closed once they all have
+    let (shutdown, _) = watch::channel(());
+
+    if let (true, BindAddr::Tcp(addr), Some(tls_config)) =
+        (config.enable_http3, &config.bind_addr, &tls_config)
+    {
+        spawn_http3_server(
+            *addr,
+            config.clone(),
+            configs,
+            tls_config.clone(),
+            &shutdown,
+        )?;
+    }
 
     loop {
         tokio::select! {
-            result = listener.accept() => {
+            result = config.connection_limit.accept(&listener) => {
                 match result {
-                    Ok((stream, remote_addr)) => {
-                        let client_ip = remote_addr.ip();
-                        debug!("Accepted connection from {}", client_ip);
-
-                        if let Some(ref acceptor) = tls_acceptor {
-                            let acceptor = acceptor.clone();
-                            spawn(async move {
-                                match timeout(
-                                    Duration::from_secs(10),
-                                    acceptor.accept(stream)
-                                ).await {
-                                    Ok(Ok(tls_stream)) => {
-                                        let io = TokioIo::new(tls_stream);
-                                        handle_connection(io, client_ip, config_ref).await;
-                                    }
-                                    Ok(Err(e)) => {
-                                        warn!("TLS handshake error from {}: {:?}", client_ip, e);
-                                    }
-                                    Err(_) => {
-                                        warn!("TLS handshake timeout from {}", client_ip);
-                                    }
-                                }
-                            });
-                        } else {
-                            spawn(async move {
-                                let io = TokioIo::new(stream);
-                                handle_plaintext_connection(io, client_ip, config_ref).await;
-                            });
+                    Ok((stream, peer_ip, admission)) => {
+                        if let Some(ref rate) = config.accept_rate
+                            && !rate.admit()
+                        {
+                            debug!(
+                                "Accept rate limit reached, closing connection from {}",
+                                peer_ip
+                            );
+                            metrics().rejected_connections.inc();
+                            rate.record_rejected();
+                            drop((stream, admission));
+                            continue;
                         }
+                        let acceptor = tls_config
+                            .as_ref()
+                            .map(|tls_config| TlsAcceptor::from(tls_config.load_full()));
+                        let watcher = shutdown.subscribe();
+                        spawn(async move {
+                            // Held until the connection is done, even if serving it panics
+                            let permit = admission.wait().await;
+                            let overloaded = permit.is_none();
+                            accept_connection(
+                                stream, peer_ip, configs, acceptor, watcher, overloaded,
+                            )
+                            .await;
+                        });
                     }
                     Err(e) => {
                         error!("Failed to accept connection: {:?}", e);
@@ -104,6 +255,26 @@ async fn main() -> Result<()> {
         }
     }
 
+    // Stop accepting new connections and give in-flight ones a chance to finish
+    drop(listener);
+    let shutdown_timeout = configs.load().shutdown_timeout;
+    info!("Draining connections (up to {:?})", shutdown_timeout);
+    let _ = shutdown.send(());
+    if timeout(shutdown_timeout, shutdown.closed()).await.is_err() {
+        warn!(
+            "Shutdown timeout elapsed with {} connections still active",
+            metrics().active_connections.get()
+        );
+    }
+
+    configs.load().access_log_output.flush();
+    #[cfg(feature = "otel")]
+    otel::shutdown();
     info!("Server shutdown complete");
     Ok(())
 }
+
+/// Describe an optional timeout or interval for the startup log
+fn describe_timeout(duration: Option<Duration>) -> String {
+    duration.map_or("off".to_string(), |duration| format!("{:?}", duration))
+}
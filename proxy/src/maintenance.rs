@@ -0,0 +1,86 @@
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use hyper::{Response, StatusCode, header::RETRY_AFTER};
+use ipnet::IpNet;
+use tracing::{info, warn};
+
+use crate::{
+    error_page::{ErrorPage, ErrorPages},
+    proxy::{ResponseBody, empty_response},
+};
+
+/// Maintenance mode, answering requests with `503` instead of proxying them.
+pub struct Maintenance {
+    /// Whether maintenance mode is on.
+    enabled: AtomicBool,
+    /// How long clients are told to wait before retrying.
+    retry_after: Duration,
+    /// Page served during maintenance, falling back to the `503` error page.
+    page: Option<ErrorPage>,
+    /// Client IP ranges that still reach the backends during maintenance.
+    allowed: Vec<IpNet>,
+}
+
+impl Maintenance {
+    /// Create maintenance mode settings.
+    pub fn new(
+        enabled: bool,
+        retry_after: Duration,
+        page: Option<ErrorPage>,
+        allowed: Vec<IpNet>,
+    ) -> Self {
+        Self {
+            enabled: AtomicBool::new(enabled),
+            retry_after,
+            page,
+            allowed,
+        }
+    }
+
+    /// Whether maintenance mode is on.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turn maintenance mode on or off until the next reload, logging when this changes it.
+    pub fn set_enabled(&self, enabled: bool) {
+        if self.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+            log_toggled(enabled);
+        }
+    }
+
+    /// Answer a request during maintenance, or return `None` if it should be proxied as usual.
+    pub fn response(
+        &self,
+        client_ip: IpAddr,
+        error_pages: &ErrorPages,
+        request_id: &str,
+    ) -> Option<Response<ResponseBody>> {
+        let client_ip = client_ip.to_canonical();
+        if !self.is_enabled() || self.allowed.iter().any(|net| net.contains(&client_ip)) {
+            return None;
+        }
+
+        let mut response = empty_response(StatusCode::SERVICE_UNAVAILABLE);
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, self.retry_after.as_secs().into());
+        Some(match self.page {
+            Some(ref page) => page.render(response, request_id),
+            None => error_pages.render(response, request_id),
+        })
+    }
+}
+
+/// Log that maintenance mode was turned on or off.
+pub fn log_toggled(enabled: bool) {
+    if enabled {
+        warn!("Maintenance mode on, requests are answered with 503");
+    } else {
+        info!("Maintenance mode off, proxying requests again");
+    }
+}
@@ -0,0 +1,266 @@
+use std::{collections::BTreeMap, convert::Infallible, net::SocketAddr, sync::OnceLock};
+
+use anyhow::Result;
+use http_body_util::Full;
+use hyper::{
+    Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header::CONTENT_TYPE,
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder, core::Collector,
+};
+use serde_json::{Value, json};
+use tokio::{net::TcpListener, spawn};
+use tracing::{debug, error, info, warn};
+
+/// Prometheus metrics collected by the proxy.
+pub struct Metrics {
+    /// Registry all metrics are registered with.
+    registry: Registry,
+    /// Total proxied requests, labelled by method.
+    pub requests_total: IntCounterVec,
+    /// Total responses returned to clients, labelled by status class (e.g. `2xx`).
+    pub responses_total: IntCounterVec,
+    /// Requests that matched no route while a canary is configured, labelled by whether they
+    /// went to the `canary` or `stable` backends.
+    pub canary_requests: IntCounterVec,
+    /// Time spent waiting for backend responses in seconds, labelled by route.
+    pub upstream_latency: HistogramVec,
+    /// Total requests abandoned because the client disconnected before the response was ready.
+    pub client_disconnected: IntCounter,
+    /// Total TLS client connections, labelled by the negotiated ALPN protocol (`h2`, `http/1.1`,
+    /// `other` or `none`).
+    pub alpn_connections: IntCounterVec,
+    /// Number of currently open client connections.
+    pub active_connections: IntGauge,
+    /// Number of accepted connections holding a slot under the connection limit.
+    pub in_flight_connections: IntGauge,
+    /// Total connections closed because the connection limit or accept rate limit was reached.
+    pub rejected_connections: IntCounter,
+    /// Number of connections waiting for a slot under the connection limit.
+    pub connection_queue_depth: IntGauge,
+    /// Time connections spent waiting for a slot under the connection limit in seconds.
+    pub connection_queue_wait: Histogram,
+}
+
+impl Metrics {
+    /// Create and register all metrics.
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("proxy".to_string()), None)?;
+
+        let requests_total = IntCounterVec::new(
+            Opts::new("requests_total", "Total proxied requests"),
+            &["method"],
+        )?;
+        registry.register(Box::new(requests_total.clone()))?;
+
+        let responses_total = IntCounterVec::new(
+            Opts::new("responses_total", "Total responses by status class"),
+            &["status"],
+        )?;
+        registry.register(Box::new(responses_total.clone()))?;
+
+        let canary_requests = IntCounterVec::new(
+            Opts::new(
+                "canary_requests_total",
+                "Total requests split between canary and stable backends",
+            ),
+            &["backend"],
+        )?;
+        registry.register(Box::new(canary_requests.clone()))?;
+
+        let upstream_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_latency_seconds",
+                "Time spent waiting for backend responses",
+            ),
+            &["route"],
+        )?;
+        registry.register(Box::new(upstream_latency.clone()))?;
+
+        let client_disconnected = IntCounter::new(
+            "client_disconnected_total",
+            "Total requests abandoned because the client disconnected before the response",
+        )?;
+        registry.register(Box::new(client_disconnected.clone()))?;
+
+        let alpn_connections = IntCounterVec::new(
+            Opts::new(
+                "tls_connections_total",
+                "Total TLS client connections by negotiated ALPN protocol",
+            ),
+            &["alpn"],
+        )?;
+        registry.register(Box::new(alpn_connections.clone()))?;
+
+        let active_connections =
+            IntGauge::new("active_connections", "Number of open client connections")?;
+        registry.register(Box::new(active_connections.clone()))?;
+
+        let in_flight_connections = IntGauge::new(
+            "in_flight_connections",
+            "Number of connections counted against the connection limit",
+        )?;
+        registry.register(Box::new(in_flight_connections.clone()))?;
+
+        let rejected_connections = IntCounter::new(
+            "rejected_connections_total",
+            "Total connections closed because the connection limit or accept rate limit was \
+             reached",
+        )?;
+        registry.register(Box::new(rejected_connections.clone()))?;
+
+        let connection_queue_depth = IntGauge::new(
+            "connection_queue_depth",
+            "Number of connections waiting for a slot under the connection limit",
+        )?;
+        registry.register(Box::new(connection_queue_depth.clone()))?;
+
+        let connection_queue_wait = Histogram::with_opts(HistogramOpts::new(
+            "connection_queue_wait_seconds",
+            "Time connections spent waiting for a slot under the connection limit",
+        ))?;
+        registry.register(Box::new(connection_queue_wait.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests_total,
+            responses_total,
+            canary_requests,
+            upstream_latency,
+            client_disconnected,
+            alpn_connections,
+            active_connections,
+            in_flight_connections,
+            rejected_connections,
+            connection_queue_depth,
+            connection_queue_wait,
+        })
+    }
+
+    /// Record a response returned to a client.
+    pub fn record_response(&self, status: StatusCode) {
+        let class = format!("{}xx", status.as_u16() / 100);
+        self.responses_total.with_label_values(&[&class]).inc();
+    }
+
+    /// Summarize the metrics as JSON, for the admin API.
+    pub fn stats(&self) -> Value {
+        let requests = label_totals(&self.requests_total);
+        let (upstream_requests, upstream_latency_sum) = self
+            .upstream_latency
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_histogram())
+            .fold((0, 0.0), |(count, sum), histogram| {
+                (
+                    count + histogram.get_sample_count(),
+                    sum + histogram.get_sample_sum(),
+                )
+            });
+        let upstream_latency_avg_ms = (upstream_requests > 0)
+            .then(|| upstream_latency_sum / upstream_requests as f64 * 1000.0);
+
+        json!({
+            "requests_total": requests.values().sum::<u64>(),
+            "requests_by_method": requests,
+            "responses_by_status": label_totals(&self.responses_total),
+            "canary_requests": label_totals(&self.canary_requests),
+            "upstream_requests": upstream_requests,
+            "upstream_latency_avg_ms": upstream_latency_avg_ms,
+            "client_disconnected": self.client_disconnected.get(),
+            "tls_connections_by_alpn": label_totals(&self.alpn_connections),
+            "active_connections": self.active_connections.get(),
+            "in_flight_connections": self.in_flight_connections.get(),
+            "rejected_connections": self.rejected_connections.get(),
+            "connection_queue_depth": self.connection_queue_depth.get(),
+        })
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    fn render(&self) -> prometheus::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// Get the value of a single-label counter for each label value
+fn label_totals(counter: &IntCounterVec) -> BTreeMap<String, u64> {
+    counter
+        .collect()
+        .iter()
+        .flat_map(|family| family.get_metric())
+        .filter_map(|metric| {
+            let label = metric.get_label().first()?.value().to_string();
+            Some((label, metric.get_counter().value() as u64))
+        })
+        .collect()
+}
+
+/// Get the global metrics singleton
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("valid metric definitions"))
+}
+
+/// Serve the metrics endpoint on its own listener in a background task.
+pub async fn spawn_metrics_server(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("✓ Metrics available on http://{}/metrics", addr);
+
+    spawn(async move {
+        loop {
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept metrics connection: {:?}", e);
+                    continue;
+                }
+            };
+            debug!("Accepted metrics connection from {}", remote_addr.ip());
+
+            spawn(async move {
+                if let Err(e) = http1::Builder::new()
+                    .timer(TokioTimer::new())
+                    .serve_connection(TokioIo::new(stream), service_fn(serve_metrics))
+                    .await
+                {
+                    warn!("Metrics connection error from {}: {:?}", remote_addr, e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Respond to a request on the metrics listener
+async fn serve_metrics(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    if req.uri().path() != "/metrics" {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::default())
+            .unwrap());
+    }
+
+    match metrics().render() {
+        Ok(body) => Ok(Response::builder()
+            .header(CONTENT_TYPE, TextEncoder::new().format_type())
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()),
+        Err(e) => {
+            error!("Failed to render metrics: {:?}", e);
+            Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Full::default())
+                .unwrap())
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::{net::IpAddr, sync::Arc};
+
+use http_body_util::BodyExt;
+use hyper::{Request, body::Body, header::HeaderValue};
+use tokio::{spawn, time::timeout};
+use tracing::{Instrument, Span, debug, warn};
+
+use crate::{
+    backend::{Backends, LbStrategy},
+    config::Config,
+    proxy::{BoxError, RequestBody, bodyless_copy, collected_body, forward, upgrade_protocol},
+};
+
+/// Mirroring of a share of requests to a second backend, whose responses are ignored.
+pub struct Mirror {
+    /// The backend requests are mirrored to.
+    backends: Backends,
+    /// Share of requests mirrored, between 0 and 100.
+    percent: f64,
+    /// Largest request body in bytes that will be mirrored.
+    max_body_size: usize,
+}
+
+impl Mirror {
+    /// Mirror `percent` percent of requests with bodies of up to `max_body_size` bytes.
+    pub fn new(backends: Backends, percent: f64, max_body_size: usize) -> Self {
+        Self {
+            backends,
+            percent,
+            max_body_size,
+        }
+    }
+
+    /// Whether to mirror a request, picking a random share of those whose body is known to fit in
+    /// the size limit
+    ///
+    /// Upgrade requests are never mirrored, since their connection is handed over to the tunnel.
+    pub fn selects(&self, req: &Request<RequestBody>) -> bool {
+        if upgrade_protocol(req.headers()).is_some()
+            || req
+                .body()
+                .size_hint()
+                .upper()
+                .is_none_or(|size| size > self.max_body_size as u64)
+        {
+            return false;
+        }
+
+        let mut random = [0; size_of::<u32>()];
+        getrandom::getrandom(&mut random).expect("the OS random number generator is available");
+        u32::from_ne_bytes(random) as f64 / (u32::MAX as f64 + 1.0) * 100.0 < self.percent
+    }
+
+    /// Buffer the body of a request and send a copy to the mirror backend in the background,
+    /// returning the request to send to the primary backend
+    ///
+    /// The mirror's response is discarded and its failures are only logged, so they never affect
+    /// the client.
+    pub async fn send(
+        &self,
+        peer_ip: IpAddr,
+        host: Option<HeaderValue>,
+        config: Arc<Config>,
+        req: Request<RequestBody>,
+    ) -> Result<Request<RequestBody>, BoxError> {
+        let copy = bodyless_copy(&req);
+        let (parts, body) = req.into_parts();
+        let collected = body.collect().await?;
+        let trailers = collected.trailers().cloned();
+        let body = collected.to_bytes();
+
+        let mirrored = copy.map(|()| collected_body(body.clone(), trailers.clone()));
+        let backend = self.backends.next(LbStrategy::RoundRobin, None);
+        spawn(
+            async move {
+                let result = timeout(config.upstream_timeout, async {
+                    let response =
+                        forward(peer_ip, &backend, host.as_ref(), &config, mirrored).await?;
+                    let status = response.status();
+                    // Read to the end so the connection can be reused
+                    response.into_body().collect().await?;
+                    anyhow::Ok(status)
+                })
+                .await;
+
+                match result {
+                    Ok(Ok(status)) => {
+                        debug!("Mirror response: {} from {}", status, backend.url());
+                    }
+                    Ok(Err(e)) => {
+                        warn!("Mirror request to {} failed: {:?}", backend.url(), e);
+                    }
+                    Err(_) => {
+                        warn!(
+                            "Mirror request to {} timed out after {:?}",
+                            backend.url(),
+                            config.upstream_timeout
+                        );
+                    }
+                }
+            }
+            .instrument(Span::current()),
+        );
+
+        Ok(Request::from_parts(parts, collected_body(body, trailers)))
+    }
+}
@@ -0,0 +1,113 @@
+use std::sync::OnceLock;
+
+use anyhow::{Context as _, Result};
+use hyper::{
+    HeaderMap,
+    header::{HeaderName, HeaderValue},
+};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::TracerProvider,
+};
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::{Resource, propagation::TraceContextPropagator, trace::SdkTracerProvider};
+use tracing::{Span, Subscriber, debug, error};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Environment variable holding the URL of the OTLP collector spans are exported to
+const ENDPOINT_VAR: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// Provider of the tracer exporting spans, kept to flush them on shutdown
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Build the layer exporting spans over OTLP/HTTP to the collector at
+/// `OTEL_EXPORTER_OTLP_ENDPOINT`, or `None` if it isn't set.
+///
+/// The W3C trace context propagator is installed too, so incoming `traceparent` headers can be
+/// continued and passed on to backends.
+pub fn layer<S>() -> Result<Option<OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    if std::env::var_os(ENDPOINT_VAR).is_none() {
+        return Ok(None);
+    }
+
+    // The exporter reads the endpoint and other `OTEL_EXPORTER_OTLP_*` settings itself
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .build()
+        .context("Failed to create the OTLP span exporter")?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("proxy").build())
+        .build();
+    let tracer = provider.tracer("proxy");
+    let _ = PROVIDER.set(provider);
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Export the spans that haven't been yet, if exporting
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get()
+        && let Err(e) = provider.shutdown()
+    {
+        error!("Failed to flush spans to the OTLP collector: {:?}", e);
+    }
+}
+
+/// Make `span` part of the trace the client's `traceparent` and `tracestate` headers belong to,
+/// if any
+pub fn continue_trace(span: &Span, headers: &HeaderMap) {
+    if PROVIDER.get().is_none() {
+        return;
+    }
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    if let Err(e) = span.set_parent(parent) {
+        debug!("Failed to continue the client's trace: {:?}", e);
+    }
+}
+
+/// Replace the trace context headers of a request to a backend with those of the current span,
+/// so the backend's spans are part of the same trace
+pub fn propagate_trace(headers: &mut HeaderMap) {
+    if PROVIDER.get().is_none() {
+        return;
+    }
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+/// Reads trace context from request headers.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+/// Writes trace context to request headers.
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
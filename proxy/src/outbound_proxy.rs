@@ -0,0 +1,166 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::{Context as _, Result, bail, ensure};
+use hyper::Uri;
+use hyper_util::{
+    client::{
+        legacy::connect::{HttpConnector, proxy::Tunnel},
+        proxy::matcher::Matcher,
+    },
+    rt::TokioIo,
+};
+use tokio::net::TcpStream;
+use tower_service::Service;
+
+use crate::{dns::DnsResolver, proxy::BoxError};
+
+/// Settings of the forward proxy that backend connections go out through.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxySettings {
+    /// Proxy for plaintext backends, if any.
+    pub http: Option<String>,
+    /// Proxy for HTTPS backends, if any.
+    pub https: Option<String>,
+    /// Comma-separated hosts, domains and IP ranges that are connected to directly.
+    pub no_proxy: Option<String>,
+}
+
+/// Forward proxy that backend connections are tunneled through with `CONNECT`.
+#[derive(Clone)]
+pub struct OutboundProxy {
+    /// The settings the proxy was built from.
+    settings: ProxySettings,
+    /// Picks the proxy for each backend, if any.
+    matcher: Arc<Matcher>,
+}
+
+impl PartialEq for OutboundProxy {
+    fn eq(&self, other: &Self) -> bool {
+        self.settings == other.settings
+    }
+}
+
+impl OutboundProxy {
+    /// Validate the proxy URLs, returning `None` when neither is set.
+    pub fn new(settings: ProxySettings) -> Result<Option<Self>> {
+        if settings.http.is_none() && settings.https.is_none() {
+            return Ok(None);
+        }
+
+        let mut builder = Matcher::builder();
+        if let Some(ref http) = settings.http {
+            validate_proxy_url(http).context("Invalid HTTP_PROXY")?;
+            builder = builder.http(http.clone());
+        }
+        if let Some(ref https) = settings.https {
+            validate_proxy_url(https).context("Invalid HTTPS_PROXY")?;
+            builder = builder.https(https.clone());
+        }
+        if let Some(ref no_proxy) = settings.no_proxy {
+            builder = builder.no(no_proxy.clone());
+        }
+
+        Ok(Some(Self {
+            settings,
+            matcher: Arc::new(builder.build()),
+        }))
+    }
+}
+
+/// Check that a forward proxy URL is in the form `http://[USER:PASSWORD@]HOST[:PORT]`
+fn validate_proxy_url(url: &str) -> Result<()> {
+    let uri = url
+        .parse::<Uri>()
+        .with_context(|| format!("{:?} is not a valid URL", url))?;
+    match uri.scheme_str() {
+        Some("http") => {}
+        Some(scheme) => bail!(
+            "Unsupported scheme {:?} in {:?}, only http proxies are supported",
+            scheme,
+            url
+        ),
+        None => bail!("{:?} must start with http://", url),
+    }
+    ensure!(
+        uri.host().is_some_and(|host| !host.is_empty()),
+        "{:?} has no host",
+        url
+    );
+    ensure!(
+        uri.path_and_query()
+            .is_none_or(|path| path.as_str().is_empty() || path.as_str() == "/"),
+        "{:?} must not have a path",
+        url
+    );
+    Ok(())
+}
+
+/// Connector for backend connections, tunneling them through the outbound proxy unless
+/// `NO_PROXY` exempts the backend.
+#[derive(Clone)]
+pub struct BackendConnector {
+    /// Connector for direct connections, and for connections to the proxy.
+    http: HttpConnector<DnsResolver>,
+    /// The outbound proxy, if configured.
+    proxy: Option<OutboundProxy>,
+}
+
+impl BackendConnector {
+    /// Connect directly with `http`, or through `proxy` when given
+    pub fn new(http: HttpConnector<DnsResolver>, proxy: Option<OutboundProxy>) -> Self {
+        Self { http, proxy }
+    }
+}
+
+impl Service<Uri> for BackendConnector {
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BoxError>> + Send>>;
+    type Response = TokioIo<TcpStream>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let intercept = self
+            .proxy
+            .as_ref()
+            .and_then(|proxy| proxy.matcher.intercept(&dst));
+        let Some(intercept) = intercept else {
+            let connecting = self.http.call(dst);
+            return Box::pin(async move { connecting.await.map_err(Into::into) });
+        };
+
+        let mut tunnel = Tunnel::new(intercept.uri().clone(), self.http.clone());
+        if let Some(auth) = intercept.basic_auth() {
+            tunnel = tunnel.with_auth(auth.clone());
+        }
+        Box::pin(async move {
+            // The tunnel assumes port 443 when the URI has none, which is wrong for plaintext
+            // backends
+            let target = with_explicit_port(&dst)?;
+            Ok(tunnel.call(target).await?)
+        })
+    }
+}
+
+/// Give a URI its scheme's default port if it has none
+fn with_explicit_port(uri: &Uri) -> Result<Uri, BoxError> {
+    if uri.port().is_some() {
+        return Ok(uri.clone());
+    }
+
+    let host = uri.host().ok_or("backend URL has no host")?;
+    let port = match uri.scheme_str() {
+        Some("http") => 80,
+        _ => 443,
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.authority = Some(format!("{}:{}", host, port).parse()?);
+    Ok(Uri::from_parts(parts)?)
+}
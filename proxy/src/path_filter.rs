@@ -0,0 +1,97 @@
+use anyhow::{Context, Result};
+use regex_automata::meta::Regex;
+
+use crate::{path_normalize::normalize_path, static_files::percent_decode};
+
+/// Allow and block lists of request path patterns.
+pub struct PathFilter {
+    /// Patterns of paths that are proxied; empty means every path is.
+    allow: Vec<Regex>,
+    /// Patterns of paths that are never proxied, taking precedence over `allow`.
+    block: Vec<Regex>,
+}
+
+impl PathFilter {
+    /// Create a filter from regular expressions, compiled once here.
+    pub fn new(allow: &[String], block: &[String]) -> Result<Self> {
+        Ok(Self {
+            allow: compile(allow).context("Failed to parse ALLOW_PATHS")?,
+            block: compile(block).context("Failed to parse BLOCK_PATHS")?,
+        })
+    }
+
+    /// Whether a request path may be proxied.
+    ///
+    /// Patterns match anywhere in the path unless anchored, and are checked against the
+    /// percent-decoded, normalized path only, so escapes, repeated slashes and dot segments can't
+    /// sneak a blocked path past them. Paths that can't be decoded, or that climb above the root,
+    /// are refused whenever there are patterns to check.
+    pub fn is_allowed(&self, path: &str) -> bool {
+        if self.allow.is_empty() && self.block.is_empty() {
+            return true;
+        }
+        let Some(path) = percent_decode(path).and_then(|decoded| normalize_path(&decoded)) else {
+            return false;
+        };
+
+        if self.block.iter().any(|regex| regex.is_match(&path)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|regex| regex.is_match(&path))
+    }
+}
+
+/// Compile regular expressions, naming the one that is invalid
+fn compile(patterns: &[String]) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern).with_context(|| format!("Invalid path pattern {:?}", pattern))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(allow: &[&str], block: &[&str]) -> PathFilter {
+        let strings =
+            |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        PathFilter::new(&strings(allow), &strings(block)).unwrap()
+    }
+
+    #[test]
+    fn blocks_escaped_and_unnormalized_paths() {
+        let filter = filter(&[], &["^/admin"]);
+        assert!(!filter.is_allowed("/admin/users"));
+        assert!(!filter.is_allowed("/%61dmin/users"));
+        assert!(!filter.is_allowed("//admin/users"));
+        assert!(!filter.is_allowed("/public/../admin/users"));
+        assert!(filter.is_allowed("/public/admin"));
+    }
+
+    #[test]
+    fn refuses_undecodable_paths() {
+        let filter = filter(&[], &["^/admin"]);
+        assert!(!filter.is_allowed("/%61dmin/%ff"));
+        assert!(!filter.is_allowed("/public/%ff"));
+        assert!(!filter.is_allowed("/public/%00"));
+        assert!(!filter.is_allowed("/public/%zz"));
+        assert!(!filter.is_allowed("/../admin"));
+    }
+
+    #[test]
+    fn allows_everything_without_patterns() {
+        let filter = filter(&[], &[]);
+        assert!(filter.is_allowed("/public/%ff"));
+    }
+
+    #[test]
+    fn allow_list_matches_decoded_path() {
+        let filter = filter(&["^/api/"], &[]);
+        assert!(filter.is_allowed("/%61pi/users"));
+        assert!(!filter.is_allowed("/api/../private"));
+        assert!(!filter.is_allowed("/other"));
+    }
+}
@@ -0,0 +1,57 @@
+use hyper::Uri;
+
+/// Normalize the path of a request URI, preserving the query string.
+///
+/// Repeated slashes are collapsed and `.` and `..` segments resolved, including percent-encoded
+/// ones like `%2e%2e` that a backend might decode. Returns `None` if a `..` segment would climb
+/// above the root. URIs whose path isn't absolute, like the `*` of `OPTIONS *`, are left as is.
+pub fn normalize_uri(uri: &Uri) -> Option<Uri> {
+    let path = uri.path();
+    if !path.starts_with('/') {
+        return Some(uri.clone());
+    }
+
+    let normalized = normalize_path(path)?;
+    if normalized == path {
+        return Some(uri.clone());
+    }
+
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", normalized, query),
+        None => normalized,
+    };
+    let mut parts = uri.clone().into_parts();
+    // Only the slashes and segments of a valid path were taken out, so it is still valid
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+/// Collapse repeated slashes and resolve the dot segments of an absolute path, or return `None`
+/// if it climbs above the root
+///
+/// A trailing slash is kept, and added when the path ends in a dot segment, so `/a/b/..` becomes
+/// `/a/` as in RFC 3986.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    let mut ends_in_dot_segment = false;
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        let decoded = segment.replace("%2e", ".").replace("%2E", ".");
+        ends_in_dot_segment = true;
+        match decoded.as_str() {
+            "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            _ => {
+                segments.push(segment);
+                ends_in_dot_segment = false;
+            }
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    if !segments.is_empty() && (path.ends_with('/') || ends_in_dot_segment) {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
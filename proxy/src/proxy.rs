@@ -1,79 +1,2209 @@
-use std::{convert::Infallible, io::Error, net::IpAddr, sync::OnceLock, time::Duration};
+use std::{
+    convert::Infallible,
+    io::Error,
+    net::{IpAddr, SocketAddr},
+    ptr,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
 
-use http_body_util::{BodyExt, Empty, combinators::UnsyncBoxBody};
+use anyhow::{Context, Result, bail};
+use dashmap::DashMap;
+use futures_util::stream;
+use http_body_util::{
+    BodyExt, Collected, Empty, Full, LengthLimitError, Limited, StreamBody,
+    combinators::UnsyncBoxBody,
+};
 use hyper::{
-    Request, Response, StatusCode,
-    body::{Bytes, Incoming},
+    HeaderMap, Method, Request, Response, StatusCode, Uri, Version,
+    body::{Body, Bytes, Frame},
+    header::{
+        ALLOW, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, FORWARDED, HOST, HeaderName, HeaderValue,
+        REFERER, SET_COOKIE, TE, UPGRADE, USER_AGENT,
+    },
+    upgrade::OnUpgrade,
 };
-use hyper_reverse_proxy::ReverseProxy;
-use hyper_rustls::{ConfigBuilderExt, HttpsConnector};
+use hyper_rustls::{FixedServerNameResolver, HttpsConnector};
 use hyper_util::{
-    client::legacy::{Builder, connect::HttpConnector},
-    rt::{TokioExecutor, TokioTimer},
+    client::legacy::{Builder, Client, connect::HttpConnector},
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+};
+use rustls::{ClientConfig, pki_types::ServerName};
+use tokio::{
+    io::copy_bidirectional,
+    spawn,
+    time::{sleep, timeout},
+    try_join,
+};
+use tracing::{Instrument, Span, debug, error, field, info, info_span, warn};
+
+use crate::{
+    access_log::{AccessLogEntry, Alpn, UpstreamLatency, log_response},
+    backend::{Backend, BackendHttpVersion, Backends, ConcurrencyPermit, InFlight, OnBackendFull},
+    cache::Cache,
+    compression::{Encoding, compress},
+    config::Config,
+    dns::DnsResolver,
+    error_page::Generated,
+    expect::ExpectContinue,
+    filter::FilterContext,
+    headers::RedactedHeaders,
+    health::probe_response,
+    metrics::metrics,
+    outbound_proxy::{BackendConnector, OutboundProxy},
+    path_normalize::normalize_uri,
+    request_id::ensure_request_id,
+    routes::route_label,
+    static_files::StaticFiles,
+    tls::TlsParameters,
 };
-use rustls::ClientConfig;
-use tracing::{debug, error};
 
 /// Type alias for the HTTPS connector and response body
-type Connector = HttpsConnector<HttpConnector>;
+pub type Connector = HttpsConnector<BackendConnector>;
+/// Type alias for boxed errors produced by request bodies
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+/// Type alias for the request body sent to backends
+pub type RequestBody = UnsyncBoxBody<Bytes, BoxError>;
 /// Type alias for the response body
+///
+/// Backend bodies are wrapped rather than collected, so frames are passed on to the client as they
+/// arrive and the backend is only read from as fast as the client reads. Only the cache buffers
+/// bodies, and only those it is going to store.
 pub type ResponseBody = UnsyncBoxBody<Bytes, Error>;
 
-/// Get a singleton reverse proxy client
-fn proxy_client() -> &'static ReverseProxy<Connector> {
-    static PROXY_CLIENT: OnceLock<ReverseProxy<Connector>> = OnceLock::new();
-    PROXY_CLIENT.get_or_init(|| {
-        let connector: Connector = Connector::builder()
-            .with_tls_config(
-                ClientConfig::builder()
-                    .with_native_roots()
-                    .expect("with_native_roots")
-                    .with_no_client_auth(),
-            )
-            .https_or_http()
-            .enable_http1()
-            .enable_http2()
-            .build();
-
-        ReverseProxy::new(
-            Builder::new(TokioExecutor::new())
-                .pool_idle_timeout(Duration::from_secs(90))
-                .pool_max_idle_per_host(32)
+/// Build an HTTPS connector for outbound connections using `tls_config`, resolving host names
+/// with `resolver` and giving up on connections that aren't established within `connect_timeout`
+///
+/// Connections are tunneled through `outbound_proxy` when given. The TLS handshake sends `sni` as
+/// the server name when given, and the URI's host otherwise, and offers the protocols allowed by
+/// `http_version`.
+pub fn https_connector(
+    tls_config: ClientConfig,
+    resolver: DnsResolver,
+    connect_timeout: Option<Duration>,
+    outbound_proxy: Option<OutboundProxy>,
+    sni: Option<ServerName<'static>>,
+    http_version: BackendHttpVersion,
+) -> Connector {
+    let mut http = HttpConnector::new_with_resolver(resolver);
+    http.enforce_http(false);
+    http.set_connect_timeout(connect_timeout);
+    let http = BackendConnector::new(http, outbound_proxy);
+
+    let builder = Connector::builder()
+        .with_tls_config(tls_config)
+        .https_or_http();
+    let builder = match sni {
+        Some(sni) => builder.with_server_name_resolver(FixedServerNameResolver::new(sni)),
+        None => builder,
+    };
+    match http_version {
+        BackendHttpVersion::Http10 | BackendHttpVersion::Http11 => {
+            builder.enable_http1().wrap_connector(http)
+        }
+        BackendHttpVersion::Http2 => builder.enable_http2().wrap_connector(http),
+        BackendHttpVersion::Auto => builder.enable_http1().enable_http2().wrap_connector(http),
+    }
+}
+
+/// Build a client for backends spoken to with `http_version`, pooling up to `max_idle_per_host`
+/// idle connections per host
+///
+/// HTTP/1.0 connections are never reused, and HTTP/2 is used with prior knowledge for plaintext
+/// backends.
+pub fn client_builder(http_version: BackendHttpVersion, max_idle_per_host: usize) -> Builder {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .pool_max_idle_per_host(match http_version {
+            BackendHttpVersion::Http10 => 0,
+            _ => max_idle_per_host,
+        })
+        .http2_only(http_version == BackendHttpVersion::Http2);
+    builder
+}
+
+/// Reverse proxy clients, by backend URL, server name override and HTTP version.
+type ProxyClients = DashMap<(String, Option<ServerName<'static>>, BackendHttpVersion), ProxyClient>;
+
+/// Client used to forward requests to backends.
+type ProxyClient = Client<Connector, RequestBody>;
+
+/// Reverse proxy clients built so far.
+static PROXY_CLIENTS: OnceLock<ProxyClients> = OnceLock::new();
+
+/// Get the reverse proxy client for `backend`
+///
+/// Each backend URL, server name and HTTP version gets its own client and connection pool, so the
+/// pool can be closed once the backend is removed. The clients are built on first use, so later
+/// changes to their other settings in `config` only take effect after a restart.
+fn proxy_client(config: &Config, backend: &Backend) -> ProxyClient {
+    let sni = backend.sni();
+    PROXY_CLIENTS
+        .get_or_init(DashMap::new)
+        .entry((
+            backend.url().to_string(),
+            sni.cloned(),
+            config.backend_http_version,
+        ))
+        .or_insert_with(|| {
+            client_builder(config.backend_http_version, config.pool_max_idle_per_host)
+                .pool_idle_timeout(config.pool_idle_timeout)
                 .pool_timer(TokioTimer::new())
-                .build::<_, Incoming>(connector),
-        )
-    })
+                .build(https_connector(
+                    config.backend_tls.client_config(),
+                    config.dns_resolver.clone(),
+                    config.connect_timeout,
+                    config.outbound_proxy.clone(),
+                    sni.cloned(),
+                    config.backend_http_version,
+                ))
+        })
+        .clone()
+}
+
+/// Drop the connection pools of the backend at `url`, closing its idle connections now and the
+/// rest once the responses still using them have been sent
+pub fn close_pooled_connections(url: &str) {
+    if let Some(clients) = PROXY_CLIENTS.get() {
+        clients.retain(|(client_url, ..), _| client_url != url);
+    }
+}
+
+/// Headers that only apply to a single connection and must not be forwarded (RFC 7230 §6.1)
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Name of the header carrying the chain of client IPs
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+/// Name of the header carrying the scheme the client connected with
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+/// Name of the header carrying the host the client requested
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+/// Name of the header carrying server-side timing metrics
+const SERVER_TIMING: &str = "server-timing";
+/// Name of the header carrying the subject of the verified client certificate
+const X_CLIENT_CERT_SUBJECT: &str = "x-client-cert-subject";
+/// Name of the header carrying the TLS version negotiated with the client
+const X_TLS_VERSION: &str = "x-tls-version";
+/// Name of the header carrying the TLS cipher suite negotiated with the client
+const X_TLS_CIPHER: &str = "x-tls-cipher";
+/// Name of the header clients request a longer or shorter upstream timeout with
+const X_PROXY_TIMEOUT_MS: &str = "x-proxy-timeout-ms";
+
+/// Set the headers describing the client's TLS connection, removing any sent by the client.
+///
+/// Plaintext requests are forwarded without them.
+fn apply_tls_headers(headers: &mut HeaderMap, tls_parameters: Option<TlsParameters>) {
+    headers.remove(X_TLS_VERSION);
+    headers.remove(X_TLS_CIPHER);
+    if let Some(TlsParameters { version, cipher }) = tls_parameters {
+        headers.insert(X_TLS_VERSION, HeaderValue::from_static(version));
+        headers.insert(X_TLS_CIPHER, HeaderValue::from_static(cipher));
+    }
+}
+
+/// Prepare the forwarded headers on an incoming request.
+///
+/// Untrusted forwarding headers are stripped so clients cannot spoof their origin. The client IP
+/// itself is appended to `X-Forwarded-For` in [`forward`], so only `X-Forwarded-Proto` needs to
+/// be set here.
+fn apply_forwarded_headers(
+    headers: &mut HeaderMap,
+    is_tls: bool,
+    client_cert_subject: Option<&str>,
+    trusted: bool,
+) {
+    // The client certificate subject is only ever taken from our own TLS handshake
+    headers.remove(X_CLIENT_CERT_SUBJECT);
+    if let Some(value) = client_cert_subject.and_then(|subject| HeaderValue::from_str(subject).ok())
+    {
+        headers.insert(X_CLIENT_CERT_SUBJECT, value);
+    }
+
+    if !trusted {
+        headers.remove(X_FORWARDED_FOR);
+        headers.remove(X_FORWARDED_PROTO);
+        headers.remove(X_FORWARDED_HOST);
+        headers.remove(FORWARDED);
+    }
+
+    if !headers.contains_key(X_FORWARDED_PROTO) {
+        let proto = if is_tls { "https" } else { "http" };
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+    }
+}
+
+/// Append the client IP to the `X-Forwarded-For` chain, combining any existing headers
+fn append_forwarded_for(headers: &mut HeaderMap, client_ip: IpAddr) {
+    let mut chain = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if !chain.is_empty() {
+        chain.push_str(", ");
+    }
+    chain.push_str(&client_ip.to_string());
+
+    if let Ok(value) = HeaderValue::from_str(&chain) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+}
+
+/// Address a request was received from, which is a proxy rather than the client when
+/// `TRUSTED_PROXY_COUNT` is set.
+#[derive(Clone, Copy)]
+struct PeerIp(IpAddr);
+
+/// Get the client IP from the `X-Forwarded-For` chain of a request that came through
+/// `trusted_proxies` proxies, counting the peer as the first
+///
+/// Each trusted proxy appended the address it was connected from, so the client is found by
+/// skipping the hops they added from the right. When the chain is shorter, every entry was added
+/// by a trusted proxy and the leftmost is the client. Returns `None` when the header is missing
+/// or the entry isn't an IP address.
+fn forwarded_client_ip(headers: &HeaderMap, trusted_proxies: usize) -> Option<IpAddr> {
+    let chain = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .collect::<Vec<_>>();
+    let entry = chain.get(chain.len().saturating_sub(trusted_proxies))?;
+
+    // Some proxies include the port
+    entry
+        .parse()
+        .or_else(|_| entry.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+}
+
+/// Whether a header only applies to a single connection
+pub fn is_hop_by_hop(name: &HeaderName) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name.as_str())
+}
+
+/// Remove hop-by-hop headers that must not cross the proxy, including any named in `Connection`
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|token| HeaderName::from_bytes(token.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/// Remove hop-by-hop headers from a request, keeping `TE: trailers` and, if `upgrades` are
+/// supported, the ones needed to upgrade the connection
+///
+/// Returns the protocol the request asks to upgrade to, if any and kept.
+fn remove_request_hop_by_hop_headers(
+    headers: &mut HeaderMap,
+    upgrades: bool,
+) -> Option<HeaderValue> {
+    let upgrade = upgrade_protocol(headers).filter(|_| upgrades);
+    let accepts_trailers = headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("trailers"));
+    remove_hop_by_hop_headers(headers);
+    if let Some(ref protocol) = upgrade {
+        set_upgrade_headers(headers, protocol.clone());
+    }
+    // The only `TE` allowed over HTTP/2, which gRPC servers expect to see
+    if accepts_trailers {
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+    }
+    upgrade
+}
+
+/// Whether a request or response carries gRPC (including gRPC-Web), whose messages are streamed
+/// and whose status arrives in trailers
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .get(..16)
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case("application/grpc"))
+        })
+}
+
+/// Get the protocol a request or response asks to upgrade to, if any
+pub fn upgrade_protocol(headers: &HeaderMap) -> Option<HeaderValue> {
+    let wants_upgrade = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+
+    wants_upgrade
+        .then(|| headers.get(UPGRADE).cloned())
+        .flatten()
+}
+
+/// Restore the upgrade headers removed along with the other hop-by-hop headers
+fn set_upgrade_headers(headers: &mut HeaderMap, protocol: HeaderValue) {
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, protocol);
+}
+
+/// Copy bytes in both directions between an upgraded client and backend connection, keeping the
+/// request `in_flight` until the tunnel closes
+async fn tunnel(client: OnUpgrade, backend: OnUpgrade, in_flight: InFlight) {
+    let (client, backend) = match try_join!(client, backend) {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            warn!("Failed to upgrade connection: {:?}", e);
+            return;
+        }
+    };
+
+    match copy_bidirectional(&mut TokioIo::new(client), &mut TokioIo::new(backend)).await {
+        Ok((sent, received)) => debug!(
+            "Upgraded connection closed after {} bytes sent and {} bytes received",
+            sent, received
+        ),
+        Err(e) => debug!("Upgraded connection closed with error: {:?}", e),
+    }
+    drop(in_flight);
+}
+
+/// Build the backend URI for a request, keeping the original path and query
+fn upstream_uri(backend_url: &str, uri: &Uri) -> Result<Uri> {
+    let path_and_query = uri.path_and_query().map_or("/", |pq| pq.as_str());
+    format!("{}{}", backend_url.trim_end_matches('/'), path_and_query)
+        .parse()
+        .with_context(|| format!("Invalid upstream URI for backend {}", backend_url))
+}
+
+/// Forward a request to the given backend and return its response
+///
+/// `peer_ip`, the address the request came from, is appended to `X-Forwarded-For`. The `Host`
+/// header is set to the backend's authority unless `host` overrides it. Upgrade
+/// requests (e.g. WebSocket handshakes) keep their `Upgrade` header, and once the backend switches
+/// protocols the two connections are tunnelled together in a background task. Bodies of requests
+/// sent with `Expect: 100-continue` are held back until the backend asks for them.
+///
+/// The request counts as in flight on the backend until the response body ends or the tunnel
+/// closes, or straight away if forwarding fails or is cancelled.
+pub async fn forward(
+    peer_ip: IpAddr,
+    backend: &Arc<Backend>,
+    host: Option<&HeaderValue>,
+    config: &Config,
+    mut req: Request<RequestBody>,
+) -> Result<Response<ResponseBody>> {
+    let in_flight = backend.start_request();
+    let uri = upstream_uri(backend.url(), req.uri())?;
+
+    // Backends that can't switch protocols get upgrade requests as plain requests, which the
+    // upgrade headers are optional for
+    let upgrade = remove_request_hop_by_hop_headers(
+        req.headers_mut(),
+        config.backend_http_version.supports_upgrades(),
+    );
+    let client_upgrade = upgrade.is_some().then(|| hyper::upgrade::on(&mut req));
+    append_forwarded_for(req.headers_mut(), peer_ip);
+    #[cfg(feature = "otel")]
+    crate::otel::propagate_trace(req.headers_mut());
+    if let Some(host) = host {
+        req.headers_mut().insert(HOST, host.clone());
+    } else if let Some(authority) = uri.authority() {
+        req.headers_mut()
+            .insert(HOST, HeaderValue::from_str(authority.as_str())?);
+    }
+    *req.uri_mut() = uri;
+    // Regardless of how the client connected to us
+    *req.version_mut() = config.backend_http_version.request_version();
+    // HTTP/1.0 backends never send `100 Continue`, and HTTP/2 ones can't be heard sending it
+    let (req, expect_continue) = match config.expect_continue_timeout {
+        Some(wait)
+            if matches!(
+                config.backend_http_version,
+                BackendHttpVersion::Http11 | BackendHttpVersion::Auto
+            ) =>
+        {
+            ExpectContinue::hold(req, wait)
+        }
+        _ => (req, None),
+    };
+
+    let mut response = proxy_client(config, backend).request(req).await?;
+    if let Some(expect_continue) = expect_continue {
+        expect_continue.responded();
+    }
+    let backend_upgrade = upgrade_protocol(response.headers());
+    remove_hop_by_hop_headers(response.headers_mut());
+
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        let (Some(client_upgrade), Some(protocol)) = (client_upgrade, backend_upgrade) else {
+            bail!("Backend switched protocols without an upgrade request");
+        };
+        set_upgrade_headers(response.headers_mut(), protocol);
+        spawn(tunnel(
+            client_upgrade,
+            hyper::upgrade::on(&mut response),
+            in_flight,
+        ));
+        return Ok(response.map(|body| body.map_err(Error::other).boxed_unsync()));
+    }
+
+    Ok(in_flight.hold(response.map(|body| body.map_err(Error::other).boxed_unsync())))
+}
+
+/// Answer a request whose body couldn't be read before proxying: `413 Payload Too Large` when it
+/// crossed `MAX_BODY_SIZE` and `400 Bad Request` otherwise
+fn body_error_response(
+    error: BoxError,
+    method: &Method,
+    uri: &Uri,
+    client_ip: IpAddr,
+) -> Response<ResponseBody> {
+    if error.is::<LengthLimitError>() {
+        warn!(
+            "Request body too large for {} {} from {}",
+            method, uri, client_ip
+        );
+        return empty_response(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    warn!(
+        "Failed to read request body for {} {} from {}: {:?}",
+        method, uri, client_ip, error
+    );
+    empty_response(StatusCode::BAD_REQUEST)
+}
+
+/// Whether a forwarding error was caused by the request body exceeding the size limit
+fn is_body_too_large(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<LengthLimitError>())
 }
 
-/// Handle proxying the incoming request to the backend URL
+/// Handle proxying the incoming request to the next healthy backend of the matching route
+///
+/// Requests on `overloaded` connections, which didn't get a slot under the connection limit, are
+/// answered with `503` instead
 pub async fn handle(
     client_ip: IpAddr,
-    backend_url: &str,
-    req: Request<Incoming>,
+    is_tls: bool,
+    client_cert_subject: Option<Arc<str>>,
+    overloaded: bool,
+    config: Arc<Config>,
+    mut req: Request<RequestBody>,
 ) -> Result<Response<ResponseBody>, Infallible> {
+    // The peer is still what gets appended to `X-Forwarded-For`
+    req.extensions_mut().insert(PeerIp(client_ip));
+    let client_ip = match config.trusted_proxy_count {
+        0 => client_ip,
+        hops => forwarded_client_ip(req.headers(), hops).unwrap_or(client_ip),
+    };
+    let request_id = ensure_request_id(req.headers_mut(), &config.request_id_header);
+    let span = info_span!(
+        "request",
+        id = %request_id,
+        method = %req.method(),
+        route = field::Empty,
+        status = field::Empty,
+        upstream_latency_ms = field::Empty,
+    );
+    #[cfg(feature = "otel")]
+    crate::otel::continue_trace(&span, req.headers());
+
+    let started = Instant::now();
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let slow_request_threshold = config.slow_request_threshold;
+    let log_headers = config.log_headers.clone();
+    if let Some(ref redact) = log_headers {
+        span.in_scope(|| {
+            debug!(
+                "Request headers: {:?}",
+                RedactedHeaders {
+                    headers: req.headers(),
+                    redact
+                }
+            )
+        });
+    }
+
+    // Hyper drops this future when the client goes away, which cancels the upstream request
+    let mut disconnect = DisconnectGuard {
+        span: span.clone(),
+        client_ip,
+        started,
+        armed: true,
+    };
+    let response = handle_request(
+        client_ip,
+        is_tls,
+        client_cert_subject,
+        overloaded,
+        config,
+        request_id.clone(),
+        req,
+    )
+    .instrument(span.clone())
+    .await?;
+    disconnect.disarm();
+
+    if let Some(ref redact) = log_headers {
+        span.in_scope(|| {
+            debug!(
+                "Response headers: {:?}",
+                RedactedHeaders {
+                    headers: response.headers(),
+                    redact
+                }
+            )
+        });
+    }
+
+    let elapsed = started.elapsed();
+    if slow_request_threshold.is_some_and(|threshold| elapsed > threshold) {
+        warn!(
+            target: "slow_request",
+            "Slow request: {} {} from {} returned {} after {:?} (request ID {})",
+            method,
+            path,
+            client_ip,
+            response.status().as_u16(),
+            elapsed,
+            request_id
+        );
+    }
+    Ok(response)
+}
+
+/// Logs and counts a request whose client disconnected before the response was ready, unless
+/// disarmed once the response is
+struct DisconnectGuard {
+    /// The request's span.
+    span: Span,
+    /// The client IP.
+    client_ip: IpAddr,
+    /// When the request was received.
+    started: Instant,
+    /// Whether the response is still pending.
+    armed: bool,
+}
+
+impl DisconnectGuard {
+    /// Mark the response as ready
+    fn disarm(&mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for DisconnectGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        metrics().client_disconnected.inc();
+        self.span.in_scope(|| {
+            info!(
+                "Client {} disconnected after {:?}, cancelling the request",
+                self.client_ip,
+                self.started.elapsed()
+            )
+        });
+    }
+}
+
+/// Handle a request that has been assigned a request ID
+async fn handle_request(
+    client_ip: IpAddr,
+    is_tls: bool,
+    client_cert_subject: Option<Arc<str>>,
+    overloaded: bool,
+    config: Arc<Config>,
+    request_id: String,
+    mut req: Request<RequestBody>,
+) -> Result<Response<ResponseBody>, Infallible> {
+    metrics()
+        .requests_total
+        .with_label_values(&[req.method().as_str()])
+        .inc();
+
+    let request_id_value =
+        HeaderValue::from_str(&request_id).expect("request IDs are valid header values");
+    let log_entry = AccessLogEntry {
+        timestamp: SystemTime::now(),
+        client_ip,
+        method: req.method().clone(),
+        path: req.uri().path().to_string(),
+        query: req.uri().query().map(str::to_string),
+        version: req.version(),
+        alpn: req.extensions().get::<Alpn>().map(|&Alpn(alpn)| alpn),
+        user: config
+            .basic_auth
+            .as_ref()
+            .and_then(|basic_auth| basic_auth.user(req.headers())),
+        referer: req.headers().get(REFERER).cloned(),
+        user_agent: req.headers().get(USER_AGENT).cloned(),
+        request_id,
+    };
+
+    // Before anything looks at the path, so `//` and `..` can't get around path filters or routes
+    let mut traversal = false;
+    if config.normalize_path {
+        match normalize_uri(req.uri()) {
+            Some(uri) => *req.uri_mut() = uri,
+            None => traversal = true,
+        }
+    }
+
+    // Answered by the proxy itself and never forwarded upstream
+    let own_response = probe_response(&config, req.uri().path()).or_else(|| {
+        let status = config.status_endpoint.as_ref()?;
+        status.response(&config, client_ip, req.uri().path(), req.headers())
+    });
+    if let Some(mut response) = own_response {
+        response
+            .headers_mut()
+            .insert(&config.request_id_header, request_id_value);
+        record_span_fields(&response);
+        metrics().record_response(response.status());
+        return Ok(log_response(
+            response,
+            config.access_log_format,
+            &config.access_log_output,
+            log_entry,
+        ));
+    }
+
+    let encoding = config
+        .compression
+        .then(|| Encoding::negotiate(req.headers()))
+        .flatten();
+    let is_head = req.method() == Method::HEAD;
+    // Captured before any filter changes the request, and only when there are filters to see it
+    let filter_context = (!config.filters.is_empty()).then(|| FilterContext::new(client_ip, &req));
+    // Responses the proxy sends without running the chain go back through every filter
+    let mut filtered = config.filters.len();
+    // Static assets, if the request is for one
+    let static_files = config
+        .static_files
+        .as_ref()
+        .filter(|_| StaticFiles::matches(req.uri().path()));
+
+    let mut response = if overloaded {
+        debug!(
+            "Connection limit reached, not proxying {} from {}",
+            req.uri(),
+            client_ip
+        );
+        config
+            .error_pages
+            .render(overloaded_response(req.version()), &log_entry.request_id)
+    } else if header_size(req.headers()) > config.max_header_size {
+        debug!(
+            "Request headers too large for {} from {}",
+            req.uri(),
+            client_ip
+        );
+        config.error_pages.render(
+            empty_response(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            &log_entry.request_id,
+        )
+    } else if let Some(ref allowed) = config.allowed_methods
+        && !allowed.contains(req.method())
+    {
+        debug!(
+            "Method {} not allowed for {} from {}",
+            req.method(),
+            req.uri(),
+            client_ip
+        );
+        config
+            .error_pages
+            .render(method_not_allowed(allowed), &log_entry.request_id)
+    } else if traversal {
+        debug!(
+            "Path {} from {} climbs above the root",
+            req.uri().path(),
+            client_ip
+        );
+        config.error_pages.render(
+            empty_response(StatusCode::BAD_REQUEST),
+            &log_entry.request_id,
+        )
+    } else if !config.path_filter.is_allowed(req.uri().path()) {
+        debug!("Blocked path {} from {}", req.uri().path(), client_ip);
+        config
+            .error_pages
+            .render(empty_response(StatusCode::FORBIDDEN), &log_entry.request_id)
+    } else if let Some(response) = config
+        .maintenance
+        .response(client_ip, &config.error_pages, &log_entry.request_id)
+        // Assets are served even during maintenance, since maintenance pages may use them
+        .filter(|_| static_files.is_none())
+    {
+        debug!(
+            "Maintenance mode, not proxying {} from {}",
+            req.uri(),
+            client_ip
+        );
+        response
+    } else {
+        let proxied = async {
+            let answered = match filter_context {
+                Some(ref context) => {
+                    let (ran, answered) = config.filters.on_request(context, &mut req).await;
+                    filtered = ran;
+                    answered
+                }
+                None => None,
+            };
+            match (answered, static_files) {
+                (Some(response), _) => response,
+                // Behind the filters, so basic auth and rate limits cover the assets too
+                (None, Some(static_files)) => {
+                    static_files
+                        .serve(req.method(), req.uri().path(), req.headers())
+                        .await
+                }
+                (None, None) => {
+                    proxy_request(
+                        client_ip,
+                        is_tls,
+                        client_cert_subject,
+                        &log_entry.request_id,
+                        &config,
+                        req,
+                    )
+                    .await
+                }
+            }
+        };
+        // Dropping the request on expiry aborts the upstream request along with reading the body
+        let response = match config.request_timeout {
+            Some(request_timeout) => match timeout(request_timeout, proxied).await {
+                Ok(response) => response,
+                Err(_) => {
+                    warn!(
+                        "Request timeout after {:?} for {} {} from {}",
+                        request_timeout, log_entry.method, log_entry.path, client_ip
+                    );
+                    empty_response(StatusCode::REQUEST_TIMEOUT)
+                }
+            },
+            None => proxied.await,
+        };
+        config.error_pages.render(response, &log_entry.request_id)
+    };
+    response
+        .headers_mut()
+        .insert(&config.request_id_header, request_id_value);
+
+    if let Some(ref context) = filter_context {
+        config
+            .filters
+            .on_response(context, filtered, &mut response)
+            .await;
+    }
+
+    if let (Some(encoding), false) = (encoding, is_head) {
+        response = compress(response, encoding, config.compression_min_size);
+    }
+
+    record_span_fields(&response);
+    metrics().record_response(response.status());
+    Ok(log_response(
+        response,
+        config.access_log_format,
+        &config.access_log_output,
+        log_entry,
+    ))
+}
+
+/// Build a `503` response for a connection over the connection limit
+///
+/// HTTP/1.x connections are closed after the response, so the client retries on a new one.
+fn overloaded_response(version: Version) -> Response<ResponseBody> {
+    let mut response = empty_response(StatusCode::SERVICE_UNAVAILABLE);
+    if version < Version::HTTP_2 {
+        response
+            .headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+    response
+}
+
+/// Build a `405` response listing the allowed methods
+fn method_not_allowed(allowed: &[Method]) -> Response<ResponseBody> {
+    let mut response = empty_response(StatusCode::METHOD_NOT_ALLOWED);
+    let allow = allowed
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(value) = HeaderValue::from_str(&allow) {
+        response.headers_mut().insert(ALLOW, value);
+    }
+    response
+}
+
+/// Get the size of a header block as it would be sent over HTTP/1.1
+///
+/// The server limits the buffer headers are read into, but a request can fit in one read and
+/// still be over the limit, so it is checked again here.
+fn header_size(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum()
+}
+
+/// Record the outcome of a request on the current request span
+fn record_span_fields(response: &Response<ResponseBody>) {
+    let span = Span::current();
+    span.record("status", response.status().as_u16());
+    if let Some(UpstreamLatency(latency)) = response.extensions().get() {
+        span.record("upstream_latency_ms", latency.as_secs_f64() * 1000.0);
+    }
+}
+
+/// Take a concurrency slot for a request on `backend`, or on another backend of the set if
+/// `backend` already `failed` the request, its circuit is open, or it is full and
+/// `ON_BACKEND_FULL=failover`
+async fn acquire_slot(
+    config: &Config,
+    backends: &Backends,
+    backend: Arc<Backend>,
+    failed: &[Arc<Backend>],
+) -> Option<(Arc<Backend>, ConcurrencyPermit)> {
+    let has_failed = |other: &Backend| failed.iter().any(|tried| ptr::eq(tried.as_ref(), other));
+    // Only asked once a slot is taken, since letting a trial request through half-opens a circuit
+    let allowed = |other: &Backend| {
+        config
+            .circuit_breaker
+            .as_ref()
+            .is_none_or(|breaker| breaker.allow(other))
+    };
+
+    let reason = if has_failed(&backend) {
+        "already failed the request"
+    } else {
+        let permit = match backend.try_acquire() {
+            Some(permit) => Some(permit),
+            None if config.on_backend_full == OnBackendFull::Wait => {
+                Some(backend.acquire(config.backend_full_wait).await?)
+            }
+            None => None,
+        };
+        match permit {
+            Some(permit) if allowed(&backend) => return Some((backend, permit)),
+            Some(_) => "has an open circuit",
+            None => "is full",
+        }
+    };
+
+    let failover = backends.failover(|other| {
+        !ptr::eq(other, backend.as_ref()) && !has_failed(other) && allowed(other)
+    });
+    match failover {
+        Some((ref other, _)) => debug!(
+            "Backend {} {}, failing over to {}",
+            backend.url(),
+            reason,
+            other.url()
+        ),
+        None => debug!(
+            "Backend {} {}, and no other backend is available",
+            backend.url(),
+            reason
+        ),
+    }
+    failover
+}
+
+/// Proxy a single request, returning the response to send back to the client
+async fn proxy_request(
+    client_ip: IpAddr,
+    is_tls: bool,
+    client_cert_subject: Option<Arc<str>>,
+    request_id: &str,
+    config: &Arc<Config>,
+    mut req: Request<RequestBody>,
+) -> Response<ResponseBody> {
     let method = req.method().clone();
     let uri = req.uri().clone();
+    let peer_ip = req
+        .extensions()
+        .get::<PeerIp>()
+        .map_or(client_ip, |&PeerIp(peer_ip)| peer_ip);
+    // HTTP/2 clients send the host as the `:authority` pseudo-header rather than `Host`
+    let client_host = req.headers().get(HOST).cloned().or_else(|| {
+        uri.authority()
+            .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+    });
+
+    let route = config.routes.match_route(uri.path());
+    let backends = match route {
+        Some(route) => &route.backends,
+        None => {
+            // With routes configured, only an explicit default backend catches the other paths
+            let Some(stable) = config
+                .default_backend
+                .as_ref()
+                .or(config.routes.is_empty().then_some(&config.backends))
+            else {
+                debug!("No route for {} {} from {}", method, uri, client_ip);
+                return empty_response(StatusCode::NOT_FOUND);
+            };
+
+            match config.canary {
+                Some(ref canary) if canary.selects(req.headers(), client_ip, request_id) => {
+                    debug!(
+                        "Sending {} {} from {} to the canary",
+                        method, uri, client_ip
+                    );
+                    metrics()
+                        .canary_requests
+                        .with_label_values(&["canary"])
+                        .inc();
+                    canary.backends()
+                }
+                Some(_) => {
+                    metrics()
+                        .canary_requests
+                        .with_label_values(&["stable"])
+                        .inc();
+                    stable
+                }
+                None => stable,
+            }
+        }
+    };
+    let route_label = route_label(route);
+    Span::current().record("route", route_label);
+
+    let cache = config.cache.as_ref().zip(Cache::key(&req, is_tls));
+    if let Some(response) = cache.as_ref().and_then(|(cache, key)| cache.get(key)) {
+        debug!("Cache hit for {} {}", method, uri);
+        return response;
+    }
+
+    if let Some(route) = route {
+        match route.rewrite_uri(&uri) {
+            Ok(rewritten) => *req.uri_mut() = rewritten,
+            Err(e) => {
+                warn!(
+                    "Failed to rewrite {} for route {}: {:?}",
+                    uri, route.prefix, e
+                );
+                return empty_response(StatusCode::BAD_REQUEST);
+            }
+        }
+    }
+
+    let requested_timeout = config
+        .timeout_header_max
+        .and_then(|max| take_requested_timeout(req.headers_mut(), max));
+
+    // Stripped before the proxy adds its own headers, so clients can't list those in `Connection`
+    remove_request_hop_by_hop_headers(req.headers_mut(), true);
+    // Filtered before the proxy adds its own headers, which are always forwarded
+    if let Some(ref allowlist) = config.forward_headers_allowlist {
+        allowlist.apply(req.headers_mut(), &config.request_id_header);
+    }
+    apply_forwarded_headers(
+        req.headers_mut(),
+        is_tls,
+        client_cert_subject.as_deref(),
+        config.trust_forwarded_headers,
+    );
+    if config.forward_tls_info {
+        let tls_parameters = req.extensions().get::<TlsParameters>().copied();
+        apply_tls_headers(req.headers_mut(), tls_parameters);
+    }
+    config.request_headers.apply(req.headers_mut());
+
+    if config.max_body_size > 0 {
+        let content_length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if content_length.is_some_and(|length| length > config.max_body_size as u64) {
+            warn!(
+                "Request body too large for {} {} from {}",
+                method, uri, client_ip
+            );
+            return empty_response(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    }
+
+    // HTTP/1.0 has no chunked encoding, so backends speaking it can only be sent bodies whose
+    // length is known up front
+    if config.backend_http_version == BackendHttpVersion::Http10
+        && req.body().size_hint().exact().is_none()
+    {
+        debug!(
+            "Streamed body without a length for {} {} from {}",
+            method, uri, client_ip
+        );
+        return empty_response(StatusCode::LENGTH_REQUIRED);
+    }
+
+    // Streamed bodies without a trustworthy Content-Length are cut off once they cross the limit
+    let mut req = req.map(|body| {
+        if config.max_body_size > 0 {
+            Limited::new(body, config.max_body_size).boxed_unsync()
+        } else {
+            body
+        }
+    });
+
+    // A timeout requested by the client wins over the route's
+    let upstream_timeout = requested_timeout
+        .or(route.and_then(|route| route.timeout))
+        .unwrap_or(config.upstream_timeout);
+    let max_retries = route
+        .and_then(|route| route.retries)
+        .unwrap_or(config.max_retries);
 
-    debug!("Proxying request: {} {} from {}", method, uri, client_ip);
+    // An explicitly configured `Host` wins over the client's
+    let host = config
+        .request_headers
+        .host()
+        .cloned()
+        .or(client_host.filter(|_| config.preserve_host));
 
-    match proxy_client().call(client_ip, backend_url, req).await {
-        Ok(response) => {
+    if let Some(ref mirror) = config.mirror
+        && mirror.selects(&req)
+    {
+        req = match mirror
+            .send(peer_ip, host.clone(), config.clone(), req)
+            .await
+        {
+            Ok(req) => req,
+            Err(e) => return body_error_response(e, &method, &uri, client_ip),
+        };
+    }
+
+    // Read before the first attempt so the body can be sent again on retries
+    let mut buffered = None;
+    if max_retries > 0
+        && let Some(ref buffer) = config.request_buffer
+        && config.retry_methods.contains(req.method())
+        && upgrade_protocol(req.headers()).is_none()
+    {
+        (req, buffered) = match buffer.apply(req).await {
+            Ok(result) => result,
+            Err(e) => return body_error_response(e, &method, &uri, client_ip),
+        };
+    }
+
+    let retry_template = (max_retries > 0
+        && is_retryable(&req, &config.retry_methods, buffered.is_some()))
+    .then(|| (bodyless_copy(&req), buffered));
+
+    let pinned = config
+        .sticky_sessions
+        .as_ref()
+        .and_then(|sticky| sticky.pinned(req.headers(), backends));
+
+    let started = Instant::now();
+
+    let mut attempt = 0;
+    let mut served_by;
+    // Backends this request failed on, which retries don't go back to
+    let mut failed = Vec::new();
+    let result = loop {
+        // Only the first attempt goes to the pinned backend, retries fall back to normal selection
+        let backend = match &pinned {
+            Some(backend) if attempt == 0 => backend.clone(),
+            _ => backends.next(config.lb_strategy, config.slow_start),
+        };
+        served_by = backend.clone();
+        let Some((backend, permit)) = acquire_slot(config, backends, backend, &failed).await else {
+            debug!("No backend available for {} {}, rejecting it", method, uri);
+            break Ok(Ok(empty_response(StatusCode::SERVICE_UNAVAILABLE)));
+        };
+        served_by = backend.clone();
+
+        let backend_url = backend.url();
+        debug!(
+            "Proxying request: {} {} from {} to {}",
+            method, uri, client_ip, backend_url
+        );
+
+        let attempt_started = Instant::now();
+        let result = timeout(
+            upstream_timeout,
+            forward(peer_ip, &backend, host.as_ref(), config, req),
+        )
+        .await;
+        // The slot stays taken until the response body has been sent
+        let result = match result {
+            Ok(Ok(response)) => Ok(Ok(permit.hold(response))),
+            result => result,
+        };
+
+        let succeeded = match &result {
+            Ok(Ok(response)) => !response.status().is_server_error(),
+            Ok(Err(error)) => is_body_too_large(error),
+            Err(_) => false,
+        };
+        if !succeeded {
+            backend.record_error();
+        }
+        if let Some(ref breaker) = config.circuit_breaker {
+            // Only responses have a latency worth judging the backend by
+            let latency = matches!(result, Ok(Ok(_))).then(|| attempt_started.elapsed());
+            breaker.record(&backend, succeeded, latency);
+        }
+
+        let failed_attempt = match &result {
+            Ok(Ok(response)) => config.retry_on_status.contains(&response.status()),
+            Ok(Err(error)) => !is_body_too_large(error),
+            Err(_) => false,
+        };
+        if !failed_attempt {
+            break result;
+        }
+        failed.push(backend.clone());
+        // A retry would only go back to a backend that failed already
+        let untried = backends
+            .all()
+            .iter()
+            .any(|other| other.is_available() && !failed.iter().any(|f| Arc::ptr_eq(f, other)));
+        let (template, body) = match &retry_template {
+            Some((template, body)) if untried && attempt < max_retries => (template, body),
+            _ => break result,
+        };
+
+        attempt += 1;
+        let backoff = config.retry_backoff * 2u32.saturating_pow(attempt - 1);
+        match &result {
+            Ok(Ok(response)) => warn!(
+                "Retrying {} {} after status {} from {} (attempt {}/{}) in {:?}",
+                method,
+                uri,
+                response.status().as_u16(),
+                backend_url,
+                attempt,
+                max_retries,
+                backoff
+            ),
+            _ => warn!(
+                "Retrying {} {} after upstream failure (attempt {}/{}) in {:?}",
+                method, uri, attempt, max_retries, backoff
+            ),
+        }
+        sleep(backoff).await;
+        req = template
+            .clone()
+            .map(|()| body.clone().map_or_else(empty_body, full_body));
+    };
+
+    let mut response = match result {
+        Ok(Ok(mut response)) => {
             debug!(
                 "Proxy response: {} for {} {}",
                 response.status(),
                 method,
                 uri
             );
-            Ok(response)
+            // Only backend statuses are mapped, not those of responses the proxy generated
+            if let Some(ref status_map) = config.status_map
+                && response.extensions().get::<Generated>().is_none()
+            {
+                status_map.apply(&mut response);
+            }
+            response
+        }
+        Ok(Err(error)) if is_body_too_large(&error) => {
+            warn!(
+                "Request body too large for {} {} from {}",
+                method, uri, client_ip
+            );
+            empty_response(StatusCode::PAYLOAD_TOO_LARGE)
         }
-        Err(error) => {
+        Ok(Err(error)) => {
             error!("Proxy error for {} {}: {:?}", method, uri, error);
-            Ok(Response::builder()
-                .status(StatusCode::BAD_GATEWAY)
-                .body(UnsyncBoxBody::new(
-                    Empty::<Bytes>::new().map_err(Error::other),
-                ))
-                .unwrap())
+            empty_response(StatusCode::BAD_GATEWAY)
+        }
+        Err(_) => {
+            warn!(
+                "Upstream timeout after {:?} for {} {}",
+                upstream_timeout, method, uri
+            );
+            empty_response(StatusCode::GATEWAY_TIMEOUT)
+        }
+    };
+
+    // Backends can't claim to be another backend
+    if let Some(ref backend_header) = config.backend_header {
+        response.headers_mut().remove(backend_header);
+    }
+    config.response_headers.apply(response.headers_mut());
+
+    let upstream_latency = started.elapsed();
+    metrics()
+        .upstream_latency
+        .with_label_values(&[route_label])
+        .observe(upstream_latency.as_secs_f64());
+
+    if method != Method::HEAD {
+        if let Some(ref buffer) = config.response_buffer {
+            response = buffer.apply(response).await;
+        }
+        if let Some(ref rewriter) = config.body_rewriter {
+            response = rewriter.apply(response).await;
+        }
+    }
+
+    let mut response = match cache {
+        Some((cache, key)) => cache.store(key, response).await,
+        None => response,
+    };
+    // Added after caching so cached responses don't carry a stale timing
+    if config.emit_server_timing {
+        let timing = format!(
+            "upstream;dur={:.3}",
+            upstream_latency.as_secs_f64() * 1000.0
+        );
+        if let Ok(value) = HeaderValue::from_str(&timing) {
+            response.headers_mut().append(SERVER_TIMING, value);
+        }
+    }
+    // Added after caching so cached responses don't name the backend that first served them
+    if let Some(ref backend_header) = config.backend_header
+        && let Ok(value) = HeaderValue::from_str(served_by.url())
+    {
+        response.headers_mut().insert(backend_header, value);
+    }
+    // Clients are (re)pinned whenever they weren't served by the backend their cookie names
+    if let Some(ref sticky) = config.sticky_sessions
+        && pinned
+            .as_ref()
+            .is_none_or(|pinned| !Arc::ptr_eq(pinned, &served_by))
+    {
+        response
+            .headers_mut()
+            .append(SET_COOKIE, sticky.cookie(&served_by, is_tls));
+    }
+    response
+        .extensions_mut()
+        .insert(UpstreamLatency(upstream_latency));
+    response
+}
+
+/// Remove the `X-Proxy-Timeout-Ms` header from a request, returning the upstream timeout it asks
+/// for capped at `max`, or `None` if it is missing or invalid
+fn take_requested_timeout(headers: &mut HeaderMap, max: Duration) -> Option<Duration> {
+    let value = headers.remove(X_PROXY_TIMEOUT_MS)?;
+    let millis = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .filter(|&millis| millis > 0);
+    let Some(millis) = millis else {
+        debug!("Ignoring invalid {} header {:?}", X_PROXY_TIMEOUT_MS, value);
+        return None;
+    };
+    Some(Duration::from_millis(millis).min(max))
+}
+
+/// Methods whose requests may be retried: the safe ones, which have no side effects on backends
+pub const RETRYABLE_METHODS: [Method; 3] = [Method::GET, Method::HEAD, Method::OPTIONS];
+
+/// Whether a request is safe to send again after an upstream failure
+///
+/// Only requests with one of the `retry_methods` qualify, and only without a body unless it was
+/// `buffered` in full, so a retry never needs body bytes that were already streamed to a backend.
+/// Upgrade requests are excluded since their connection is handed over to the tunnel.
+fn is_retryable(req: &Request<RequestBody>, retry_methods: &[Method], buffered: bool) -> bool {
+    retry_methods.contains(req.method())
+        && (buffered || req.body().size_hint().exact() == Some(0))
+        && upgrade_protocol(req.headers()).is_none()
+}
+
+/// Copy the method, URI, version and headers of a request into a new request without a body
+pub fn bodyless_copy(req: &Request<RequestBody>) -> Request<()> {
+    let mut copy = Request::new(());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    copy
+}
+
+/// Build an empty request body
+fn empty_body() -> RequestBody {
+    Empty::<Bytes>::new()
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+/// Build a request body from buffered bytes
+pub fn full_body(body: Bytes) -> RequestBody {
+    Full::new(body)
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+/// Build a request body from buffered bytes followed by the trailers that came with them, if any
+pub fn collected_body(body: Bytes, trailers: Option<HeaderMap>) -> RequestBody {
+    let Some(trailers) = trailers else {
+        return full_body(body);
+    };
+    let frames = [Frame::data(body), Frame::trailers(trailers)].map(Ok);
+    StreamBody::new(stream::iter(frames)).boxed_unsync()
+}
+
+/// Read the whole body of a backend response, which is never buffered past `limit` bytes even if
+/// its `Content-Length` claimed less
+///
+/// Fails with a `502 Bad Gateway` response to send instead if the body is too long or the backend
+/// fails while sending it. `purpose` says what the body was being buffered for in the log.
+pub async fn buffer_response_body(
+    body: ResponseBody,
+    limit: usize,
+    purpose: &str,
+) -> Result<Collected<Bytes>, Response<ResponseBody>> {
+    Limited::new(body, limit).collect().await.map_err(|e| {
+        warn!("Failed to buffer response {}: {:?}", purpose, e);
+        empty_response(StatusCode::BAD_GATEWAY)
+    })
+}
+
+/// Build a response with the given status and an empty body, marked as generated by the proxy
+pub fn empty_response(status: StatusCode) -> Response<ResponseBody> {
+    Response::builder()
+        .status(status)
+        .extension(Generated)
+        .body(UnsyncBoxBody::new(
+            Empty::<Bytes>::new().map_err(Error::other),
+        ))
+        .unwrap()
+}
+
+/// Keep `guard` alive until the body of `response` has been sent or dropped
+pub fn hold_until_body_end<G: Send + 'static>(
+    guard: G,
+    response: Response<ResponseBody>,
+) -> Response<ResponseBody> {
+    response.map(|body| {
+        body.map_frame(move |frame| {
+            let _ = &guard;
+            frame
+        })
+        .boxed_unsync()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use base64::{Engine, prelude::BASE64_STANDARD};
+    use futures_util::stream::iter;
+    use hyper::{
+        body::Incoming,
+        client::conn::http2 as client_http2,
+        header::{ACCEPT_ENCODING, CONTENT_ENCODING},
+        server::conn::{http1, http2},
+        service::service_fn,
+    };
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use socket2::{Domain, Socket, Type};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::watch,
+    };
+
+    use super::*;
+    use crate::test_support::{config, header, read_head, send, spawn_proxy};
+
+    fn request(method: Method, body: &'static str) -> Request<RequestBody> {
+        let mut req = Request::new(full_body(Bytes::from_static(body.as_bytes())));
+        *req.method_mut() = method;
+        req
+    }
+
+    #[test]
+    fn retries_only_listed_methods() {
+        for method in RETRYABLE_METHODS {
+            assert!(
+                is_retryable(&request(method.clone(), ""), &RETRYABLE_METHODS, false),
+                "{}",
+                method
+            );
+            assert!(is_retryable(
+                &request(method, "body"),
+                &RETRYABLE_METHODS,
+                true
+            ));
+        }
+        for method in [Method::POST, Method::PUT, Method::DELETE, Method::PATCH] {
+            assert!(
+                !is_retryable(&request(method.clone(), ""), &RETRYABLE_METHODS, false),
+                "{}",
+                method
+            );
+            assert!(!is_retryable(
+                &request(method, "body"),
+                &RETRYABLE_METHODS,
+                true
+            ));
+        }
+        assert!(!is_retryable(
+            &request(Method::OPTIONS, ""),
+            &[Method::GET],
+            false
+        ));
+    }
+
+    #[test]
+    fn retries_bodies_only_when_buffered() {
+        assert!(!is_retryable(
+            &request(Method::GET, "body"),
+            &RETRYABLE_METHODS,
+            false
+        ));
+        assert!(is_retryable(
+            &request(Method::GET, "body"),
+            &RETRYABLE_METHODS,
+            true
+        ));
+    }
+
+    /// Serve a backend that answers every request with `status`, returning its address and the
+    /// method and body of each request it received
+    async fn spawn_backend(status: StatusCode) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let requests = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let requests = requests.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let requests = requests.clone();
+                    async move {
+                        let method = req.method().clone();
+                        let body = req.into_body().collect().await.unwrap().to_bytes();
+                        let body = String::from_utf8_lossy(&body);
+                        requests
+                            .lock()
+                            .unwrap()
+                            .push(format!("{} {}", method, body));
+                        let response = Response::builder()
+                            .status(status)
+                            .body(Empty::<Bytes>::new())
+                            .unwrap();
+                        Ok::<_, Infallible>(response)
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        (addr, received)
+    }
+
+    #[tokio::test]
+    async fn retries_buffered_bodies_of_safe_methods_on_another_backend() {
+        let (failing, failed) = spawn_backend(StatusCode::BAD_GATEWAY).await;
+        let (healthy, served) = spawn_backend(StatusCode::OK).await;
+        let proxy = spawn_proxy(&format!(
+            "backend_urls = \"http://{},http://{}\"\nmax_retries = 1\nretry_backoff_ms = 1\n\
+             buffer_request_body = true",
+            failing, healthy
+        ))
+        .await;
+
+        let (_, head) = send(
+            proxy,
+            "GET /search HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nhello",
+        )
+        .await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert_eq!(*failed.lock().unwrap(), ["GET hello"]);
+        assert_eq!(*served.lock().unwrap(), ["GET hello"]);
+
+        // Requests with side effects get the failing backend's response rather than a retry
+        let (_, head) = send(
+            proxy,
+            "PUT /items/1 HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nhello",
+        )
+        .await;
+        assert!(head.starts_with("HTTP/1.1 502"), "{}", head);
+        assert_eq!(*failed.lock().unwrap(), ["GET hello", "PUT hello"]);
+        assert_eq!(*served.lock().unwrap(), ["GET hello"]);
+    }
+
+    #[tokio::test]
+    async fn fails_over_from_backends_with_an_open_circuit() {
+        let config = config(
+            "backend_urls = \"http://127.0.0.1:8001,http://127.0.0.1:8002\"\n\
+             circuit_breaker_failure_ratio = 0.5\ncircuit_breaker_min_requests = 1",
+        );
+        let breaker = config.circuit_breaker.as_ref().unwrap();
+        let all = config.backends.all();
+        breaker.record(&all[0], false, None);
+
+        let (picked, _permit) = acquire_slot(&config, &config.backends, all[0].clone(), &[])
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&picked, &all[1]));
+
+        // Only once every circuit is open are requests turned away
+        breaker.record(&all[1], false, None);
+        assert!(
+            acquire_slot(&config, &config.backends, all[0].clone(), &[])
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_on_a_backend_other_than_the_one_that_failed() {
+        // The dead backend drops every connection, and would be picked again by the rotation
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::Relaxed);
+                drop(stream);
+            }
+        });
+        let (healthy, served) = spawn_backend(StatusCode::OK).await;
+        let proxy = spawn_proxy(&format!(
+            "backend_urls = \"http://{}*3,http://{}\"\nlb_strategy = \"weighted\"\n\
+             max_retries = 1\nretry_backoff_ms = 1",
+            dead, healthy
+        ))
+        .await;
+
+        let (_, head) = send(proxy, "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+        assert_eq!(served.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stops_retrying_once_every_backend_failed() {
+        let (failing, failed) = spawn_backend(StatusCode::BAD_GATEWAY).await;
+        let proxy = spawn_proxy(&format!(
+            "backend_urls = \"http://{}\"\nmax_retries = 3\nretry_backoff_ms = 1",
+            failing
+        ))
+        .await;
+
+        let (_, head) = send(proxy, "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await;
+        assert!(head.starts_with("HTTP/1.1 502"), "{}", head);
+        assert_eq!(failed.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn buffers_response_bodies_only_up_to_the_limit() {
+        let body = || {
+            Full::new(Bytes::from_static(b"hello"))
+                .map_err(|never| match never {})
+                .boxed_unsync()
+        };
+        let collected = buffer_response_body(body(), 5, "in a test").await.unwrap();
+        assert_eq!(collected.to_bytes(), "hello");
+
+        let response = buffer_response_body(body(), 4, "in a test")
+            .await
+            .unwrap_err();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert!(response.extensions().get::<Generated>().is_some());
+    }
+
+    #[tokio::test]
+    async fn requires_basic_auth_for_static_assets() {
+        let dir = std::env::temp_dir().join(format!("proxy-static-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("style.css"), "body {}").unwrap();
+        let proxy = spawn_proxy(&format!(
+            "backend_url = \"http://127.0.0.1:9\"\nstatic_dir = {:?}\n\
+             basic_auth_user = \"admin\"\nbasic_auth_pass = \"secret\"",
+            dir
+        ))
+        .await;
+
+        let request = "GET /__proxy_static/style.css HTTP/1.1\r\nHost: example.com\r\n";
+        let (_, head) = send(proxy, &format!("{}\r\n", request)).await;
+        assert!(head.starts_with("HTTP/1.1 401"), "{}", head);
+
+        let credentials = BASE64_STANDARD.encode("admin:secret");
+        let (_, head) = send(
+            proxy,
+            &format!("{}Authorization: Basic {}\r\n\r\n", request, credentials),
+        )
+        .await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert_eq!(
+            header(&head, "content-type"),
+            Some("text/css; charset=utf-8")
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn removes_headers_listed_in_connection() {
+        let mut headers = headers(&[
+            ("connection", "close, X-Session"),
+            ("x-session", "abc"),
+            ("keep-alive", "timeout=5"),
+            ("x-kept", "1"),
+        ]);
+        remove_hop_by_hop_headers(&mut headers);
+        assert!(!headers.contains_key(CONNECTION));
+        assert!(!headers.contains_key("x-session"));
+        assert!(!headers.contains_key("keep-alive"));
+        assert_eq!(headers["x-kept"], "1");
+    }
+
+    #[test]
+    fn matches_connection_tokens_case_insensitively() {
+        let mut headers = headers(&[
+            ("Connection", "X-SESSION,\tx-Other"),
+            ("x-session", "abc"),
+            ("X-Other", "def"),
+        ]);
+        remove_hop_by_hop_headers(&mut headers);
+        assert!(headers.is_empty(), "{:?}", headers);
+    }
+
+    #[test]
+    fn keeps_upgrade_headers_on_requests() {
+        let mut headers = headers(&[
+            ("connection", "Upgrade, X-Session"),
+            ("upgrade", "websocket"),
+            ("x-session", "abc"),
+        ]);
+        let upgrade = remove_request_hop_by_hop_headers(&mut headers, true);
+        assert_eq!(upgrade.unwrap(), "websocket");
+        assert_eq!(headers[CONNECTION], "upgrade");
+        assert_eq!(headers[UPGRADE], "websocket");
+        assert!(!headers.contains_key("x-session"));
+    }
+
+    #[test]
+    fn keeps_te_trailers_only() {
+        let mut kept = headers(&[("te", "gzip, Trailers")]);
+        remove_request_hop_by_hop_headers(&mut kept, false);
+        assert_eq!(kept[TE], "trailers");
+
+        let mut removed = headers(&[("te", "gzip")]);
+        remove_request_hop_by_hop_headers(&mut removed, false);
+        assert!(!removed.contains_key(TE));
+
+        let mut listed = headers(&[("connection", "te"), ("te", "trailers")]);
+        remove_request_hop_by_hop_headers(&mut listed, false);
+        assert!(!listed.contains_key(CONNECTION));
+        assert_eq!(listed[TE], "trailers");
+    }
+
+    /// The sample handshake key from RFC 6455 §1.3, and the `Sec-WebSocket-Accept` answer to it
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+    const ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+    /// Accept a WebSocket handshake for [`KEY`] on `listener`, agreeing to the `chat` subprotocol,
+    /// and echo short frames back until the client closes the connection, returning the
+    /// handshake head
+    async fn websocket_echo(listener: TcpListener) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let head = read_head(&mut stream).await;
+        let handshake = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\nSec-WebSocket-Protocol: chat\r\n\r\n",
+            ACCEPT
+        );
+        stream.write_all(handshake.as_bytes()).await.unwrap();
+
+        // Client frames are masked, and are sent back unmasked as servers must
+        while let Ok(first) = stream.read_u8().await {
+            let length = stream.read_u8().await.unwrap() & 0x7f;
+            let mut mask = [0; 4];
+            stream.read_exact(&mut mask).await.unwrap();
+            let mut payload = vec![0; length as usize];
+            stream.read_exact(&mut payload).await.unwrap();
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+            stream.write_all(&[first, length]).await.unwrap();
+            stream.write_all(&payload).await.unwrap();
+        }
+        head
+    }
+
+    /// Build a masked WebSocket text frame with a short payload
+    fn masked_text_frame(text: &str) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x81, 0x80 | text.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(text.bytes().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    #[tokio::test]
+    async fn tunnels_websocket_connections() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let echo = tokio::spawn(websocket_echo(backend));
+        let proxy = spawn_proxy(&format!("backend_urls = \"http://{}\"", backend_addr)).await;
+
+        let (mut client, head) = send(
+            proxy,
+            &format!(
+                "GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\
+                 Sec-WebSocket-Protocol: chat, superchat\r\n\r\n",
+                KEY
+            ),
+        )
+        .await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        assert_eq!(header(&head, "upgrade"), Some("websocket"));
+        assert_eq!(header(&head, "sec-websocket-accept"), Some(ACCEPT));
+        assert_eq!(header(&head, "sec-websocket-protocol"), Some("chat"));
+
+        for text in ["hello", "over the tunnel"] {
+            client.write_all(&masked_text_frame(text)).await.unwrap();
+            let mut echoed = vec![0; 2 + text.len()];
+            client.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(echoed[..2], [0x81, text.len() as u8]);
+            assert_eq!(&echoed[2..], text.as_bytes());
+        }
+        drop(client);
+
+        let handshake = echo.await.unwrap();
+        assert_eq!(header(&handshake, "sec-websocket-key"), Some(KEY));
+        assert_eq!(header(&handshake, "sec-websocket-version"), Some("13"));
+        assert_eq!(
+            header(&handshake, "sec-websocket-protocol"),
+            Some("chat, superchat")
+        );
+    }
+
+    /// Listen on a socket whose accept queue is already full, so that further connection attempts
+    /// go unanswered as they would to a blackholed address. The sockets must be kept alive.
+    fn blackhole() -> (SocketAddr, Vec<Socket>) {
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        listener
+            .bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())
+            .unwrap();
+        listener.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap().as_socket().unwrap();
+
+        let mut sockets = vec![listener];
+        for _ in 0..3 {
+            let filler = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+            filler.set_nonblocking(true).unwrap();
+            let _ = filler.connect(&addr.into());
+            sockets.push(filler);
         }
+        (addr, sockets)
+    }
+
+    #[tokio::test]
+    async fn answers_502_when_backend_connect_times_out() {
+        let (backend_addr, _sockets) = blackhole();
+        let proxy = spawn_proxy(&format!(
+            "backend_urls = \"http://{}\"\nconnect_timeout_secs = 1\nupstream_timeout_secs = 30",
+            backend_addr
+        ))
+        .await;
+
+        let started = Instant::now();
+        let (_, head) = send(proxy, "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await;
+        let elapsed = started.elapsed();
+        assert!(head.starts_with("HTTP/1.1 502"), "{}", head);
+        assert!(elapsed >= Duration::from_millis(900), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streams_bodies_larger_than_buffer_limits_with_backpressure() {
+        const SIZE: usize = 256 * 1024 * 1024;
+        const CHUNK: usize = 64 * 1024;
+
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let written = Arc::new(AtomicUsize::new(0));
+        let counter = written.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            read_head(&mut stream).await;
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n\
+                 Content-Length: {}\r\n\r\n",
+                SIZE
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            let chunk = vec![b'x'; CHUNK];
+            for _ in 0..SIZE / CHUNK {
+                stream.write_all(&chunk).await.unwrap();
+                counter.fetch_add(CHUNK, Ordering::Relaxed);
+            }
+        });
+        // Every feature that may buffer a response is on, with limits far below the body size
+        let proxy = spawn_proxy(&format!(
+            "backend_urls = \"http://{}\"\ncache_size = 16\ncache_max_entry_size = 1048576\n\
+             response_buffer = true\nresponse_buffer_max_size = 65536\ncompression = true",
+            backend_addr
+        ))
+        .await;
+
+        let (mut client, head) =
+            send(proxy, "GET /large HTTP/1.1\r\nHost: example.com\r\n\r\n").await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert_eq!(
+            header(&head, "content-length"),
+            Some(SIZE.to_string().as_str())
+        );
+
+        // While the client reads nothing, the backend can only fill the socket and proxy buffers
+        sleep(Duration::from_millis(500)).await;
+        let stalled = written.load(Ordering::Relaxed);
+        assert!(
+            stalled < 32 * 1024 * 1024,
+            "{} bytes written while stalled",
+            stalled
+        );
+
+        let mut received = 0;
+        let mut buf = vec![0; CHUNK];
+        while received < SIZE {
+            let n = client.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0, "body ended after {} bytes", received);
+            received += n;
+        }
+        assert_eq!(received, SIZE);
+    }
+
+    #[tokio::test]
+    async fn cancels_requests_when_the_client_disconnects() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let (received, mut request_received) = watch::channel(false);
+        let backend = tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            read_head(&mut stream).await;
+            received.send(true).unwrap();
+            // Stall without answering until the proxy gives up on the request
+            let mut buf = [0; 1];
+            stream.read(&mut buf).await.unwrap()
+        });
+        let proxy = spawn_proxy(&format!(
+            "backend_urls = \"http://{}\"\nupstream_timeout_secs = 30",
+            backend_addr
+        ))
+        .await;
+
+        let disconnected = metrics().client_disconnected.get();
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        request_received
+            .wait_for(|received| *received)
+            .await
+            .unwrap();
+        drop(client);
+
+        let read = timeout(Duration::from_secs(5), backend).await;
+        assert_eq!(read.expect("backend connection still open").unwrap(), 0);
+        assert!(metrics().client_disconnected.get() > disconnected);
+    }
+
+    /// Send the head of a `POST` with a 5 byte body expecting `100 Continue` to the proxy at
+    /// `addr`, returning the stream and the first response head.
+    async fn send_expecting_continue(addr: SocketAddr) -> (TcpStream, String) {
+        send(
+            addr,
+            "POST /upload HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\
+             Expect: 100-continue\r\n\r\n",
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn relays_100_continue_from_the_backend_before_the_body() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let backend = tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let head = read_head(&mut stream).await;
+            assert_eq!(header(&head, "expect"), Some("100-continue"));
+            stream
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await
+                .unwrap();
+            let mut body = [0; 5];
+            stream.read_exact(&mut body).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            body
+        });
+        // Long enough that only the backend's `100 Continue` gets the body through in time
+        let proxy = spawn_proxy(&format!(
+            "backend_urls = \"http://{}\"\nexpect_continue_timeout_ms = 30000",
+            backend_addr
+        ))
+        .await;
+
+        let (mut client, head) = timeout(Duration::from_secs(5), send_expecting_continue(proxy))
+            .await
+            .unwrap();
+        assert!(head.starts_with("HTTP/1.1 100"), "{}", head);
+        client.write_all(b"hello").await.unwrap();
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 201"), "{}", head);
+        assert_eq!(&backend.await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn never_sends_the_body_when_the_backend_responds_first() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let backend = tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            read_head(&mut stream).await;
+            stream
+                .write_all(b"HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            // Whatever arrives until the proxy closes the connection or a second passes
+            let mut received = Vec::new();
+            let _ = timeout(Duration::from_secs(1), stream.read_to_end(&mut received)).await;
+            received
+        });
+        let proxy = spawn_proxy(&format!(
+            "backend_urls = \"http://{}\"\nexpect_continue_timeout_ms = 30000",
+            backend_addr
+        ))
+        .await;
+
+        let (mut client, head) = send_expecting_continue(proxy).await;
+        assert!(head.starts_with("HTTP/1.1 413"), "{}", head);
+        // A client that sends the body anyway still doesn't get it past the proxy
+        let _ = client.write_all(b"hello").await;
+        assert_eq!(backend.await.unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn sends_the_body_after_the_timeout_when_the_backend_ignores_expect() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let backend = tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            read_head(&mut stream).await;
+            let mut body = [0; 5];
+            stream.read_exact(&mut body).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            body
+        });
+        let proxy = spawn_proxy(&format!(
+            "backend_urls = \"http://{}\"\nexpect_continue_timeout_ms = 300",
+            backend_addr
+        ))
+        .await;
+
+        let started = Instant::now();
+        let (mut client, head) = send_expecting_continue(proxy).await;
+        assert!(head.starts_with("HTTP/1.1 100"), "{}", head);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+        client.write_all(b"hello").await.unwrap();
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert_eq!(&backend.await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn passes_grpc_trailers_through_buffering_and_compression() {
+        const MESSAGE: [u8; 2048] = [b'a'; 2048];
+
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let (te, mut backend_te) = watch::channel(None);
+        tokio::spawn(async move {
+            let (stream, _) = backend.accept().await.unwrap();
+            let service = service_fn(move |req: Request<Incoming>| {
+                te.send_replace(req.headers().get(TE).cloned());
+                async move {
+                    req.into_body().collect().await.unwrap();
+                    let mut trailers = HeaderMap::new();
+                    trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                    trailers.insert("grpc-message", HeaderValue::from_static("ok"));
+                    let frames = [
+                        Frame::data(Bytes::from_static(&MESSAGE)),
+                        Frame::trailers(trailers),
+                    ];
+                    let body = StreamBody::new(iter(frames.map(Ok::<_, Infallible>)));
+                    let response = Response::builder()
+                        .header(CONTENT_TYPE, "application/grpc")
+                        .header(CONTENT_LENGTH, MESSAGE.len())
+                        .body(body)
+                        .unwrap();
+                    Ok::<_, Infallible>(response)
+                }
+            });
+            http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+                .unwrap();
+        });
+        let proxy = spawn_proxy(&format!(
+            "backend_urls = \"http://{}\"\nbackend_http_version = \"http2\"\nenable_h2c = true\n\
+             response_buffer = true\ncompression = true\nbuffer_request_body = true\nmax_retries = 1",
+            backend_addr
+        ))
+        .await;
+
+        let stream = TcpStream::connect(proxy).await.unwrap();
+        let (mut client, connection) =
+            client_http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/pkg.Service/Method", proxy))
+            .header(CONTENT_TYPE, "application/grpc")
+            .header(TE, "trailers")
+            .header(ACCEPT_ENCODING, "gzip, br")
+            .body(Full::new(Bytes::from_static(b"\0\0\0\0\0")))
+            .unwrap();
+        let response = client.send_request(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+        let body = response.into_body().collect().await.unwrap();
+        let trailers = body.trailers().cloned().expect("trailers were dropped");
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers["grpc-message"], "ok");
+        assert_eq!(body.to_bytes(), &MESSAGE[..]);
+
+        let te = backend_te.borrow_and_update().clone();
+        assert_eq!(
+            te.as_ref().map(HeaderValue::as_bytes),
+            Some(&b"trailers"[..])
+        );
+    }
+
+    #[tokio::test]
+    async fn strips_listed_response_headers_case_insensitively() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            read_head(&mut stream).await;
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nServer: BaseHTTP/0.6\r\nX-Powered-By: PHP/5.4\r\n\
+                      X-Kept: 1\r\nContent-Length: 0\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+        let proxy = spawn_proxy(&format!(
+            "backend_urls = \"http://{}\"\nresponse_strip_headers = \"x-POWERED-by, SERVER\"",
+            backend_addr
+        ))
+        .await;
+
+        let (_, head) = send(proxy, "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert_eq!(header(&head, "x-powered-by"), None, "{}", head);
+        assert_eq!(header(&head, "server"), None, "{}", head);
+        assert_eq!(header(&head, "x-kept"), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn counts_requests_in_flight_until_the_response_body_ends() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let (finish, mut finished) = watch::channel(false);
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            read_head(&mut stream).await;
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello")
+                .await
+                .unwrap();
+            finished.wait_for(|finished| *finished).await.unwrap();
+            stream.write_all(b"world").await.unwrap();
+        });
+        let config = config(&format!("backend_urls = \"http://{}\"", backend_addr));
+        let backend = config.backends.all().remove(0);
+
+        let req = Request::builder()
+            .uri("/stream")
+            .body(empty_body())
+            .unwrap();
+        let mut body = forward([127, 0, 0, 1].into(), &backend, None, &config, req)
+            .await
+            .unwrap()
+            .into_body();
+        assert_eq!(backend.in_flight(), 1);
+
+        // Still streaming after the first chunk
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), "hello");
+        assert_eq!(backend.in_flight(), 1);
+
+        finish.send(true).unwrap();
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "world");
+        assert_eq!(backend.in_flight(), 0);
     }
 }
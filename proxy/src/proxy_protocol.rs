@@ -0,0 +1,296 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, Result, bail, ensure};
+use hyper::body::Bytes;
+use tokio::io::AsyncReadExt;
+
+use crate::listener::{Rewind, Stream};
+
+/// Signature that starts every PROXY protocol v2 header.
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Length of the fixed part of a v2 header, up to and including the address length.
+const V2_HEADER_LEN: usize = 16;
+/// Prefix that starts every PROXY protocol v1 header.
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest valid v1 header, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Result of parsing the bytes received so far.
+enum Parsed {
+    /// More bytes are needed.
+    Incomplete,
+    /// A complete header was found.
+    Header {
+        /// The original client address, if the header carries one.
+        source: Option<IpAddr>,
+        /// Length of the header in bytes.
+        len: usize,
+    },
+}
+
+/// Read a PROXY protocol (v1 or v2) header off the front of a stream.
+///
+/// Returns the stream positioned just after the header, along with the original client IP.
+/// Headers that don't carry an address (v1 `UNKNOWN`, v2 `LOCAL` or non-IP families) yield
+/// `None`, in which case the connection's own peer address should be used.
+pub async fn read_header(mut stream: Stream) -> Result<(Stream, Option<IpAddr>)> {
+    let mut buf = Vec::new();
+
+    loop {
+        if let Parsed::Header { source, len } = parse(&buf)? {
+            let rest = Bytes::from(buf).split_off(len);
+            return Ok((Box::new(Rewind::new(rest, stream)), source));
+        }
+
+        let mut chunk = [0; 512];
+        let n = stream.read(&mut chunk).await?;
+        ensure!(
+            n > 0,
+            "Connection closed before the PROXY protocol header was received"
+        );
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Parse a v1 or v2 header from the start of `buf`.
+fn parse(buf: &[u8]) -> Result<Parsed> {
+    if buf.starts_with(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if buf.starts_with(V1_PREFIX) {
+        parse_v1(buf)
+    } else if V2_SIGNATURE.starts_with(buf) || V1_PREFIX.starts_with(buf) {
+        Ok(Parsed::Incomplete)
+    } else {
+        bail!("Missing PROXY protocol header")
+    }
+}
+
+/// Parse a text header, e.g. `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`.
+fn parse_v1(buf: &[u8]) -> Result<Parsed> {
+    let searched = &buf[..buf.len().min(V1_MAX_LEN)];
+    let Some(end) = searched.windows(2).position(|window| window == b"\r\n") else {
+        ensure!(
+            buf.len() < V1_MAX_LEN,
+            "PROXY protocol v1 header is too long"
+        );
+        return Ok(Parsed::Incomplete);
+    };
+
+    let line = std::str::from_utf8(&buf[..end]).context("PROXY protocol v1 header is not ASCII")?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    let source = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _, _, _] => {
+            let source = source
+                .parse::<IpAddr>()
+                .with_context(|| format!("Invalid PROXY protocol source address {:?}", source))?;
+            ensure!(
+                source.is_ipv4() == (*protocol == "TCP4"),
+                "PROXY protocol source address {} does not match {}",
+                source,
+                protocol
+            );
+            Some(source)
+        }
+        _ => bail!("Malformed PROXY protocol v1 header {:?}", line),
+    };
+
+    Ok(Parsed::Header {
+        source,
+        len: end + 2,
+    })
+}
+
+/// Parse a binary header.
+fn parse_v2(buf: &[u8]) -> Result<Parsed> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(Parsed::Incomplete);
+    }
+
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0f;
+    let family = buf[13] >> 4;
+    let addresses_len = usize::from(u16::from_be_bytes([buf[14], buf[15]]));
+    ensure!(
+        version == 2,
+        "Unsupported PROXY protocol version {}",
+        version
+    );
+
+    let len = V2_HEADER_LEN + addresses_len;
+    if buf.len() < len {
+        return Ok(Parsed::Incomplete);
+    }
+    let addresses = &buf[V2_HEADER_LEN..len];
+
+    let source = match (command, family) {
+        // LOCAL connections (e.g. load balancer health checks) carry no client address
+        (0x0, _) => None,
+        (0x1, 0x1) => {
+            let octets: [u8; 4] = addresses
+                .get(..4)
+                .context("PROXY protocol v2 IPv4 addresses are truncated")?
+                .try_into()?;
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        (0x1, 0x2) => {
+            let octets: [u8; 16] = addresses
+                .get(..16)
+                .context("PROXY protocol v2 IPv6 addresses are truncated")?
+                .try_into()?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        // Unspecified and Unix socket families have no IP address to use
+        (0x1, _) => None,
+        _ => bail!("Unsupported PROXY protocol v2 command {:#x}", command),
+    };
+
+    Ok(Parsed::Header { source, len })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncWriteExt, duplex};
+
+    use super::*;
+
+    /// Build a v2 header with the given command and family, followed by `addresses`.
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family << 4 | 0x1);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn parsed(buf: &[u8]) -> (Option<IpAddr>, usize) {
+        match parse(buf).unwrap() {
+            Parsed::Header { source, len } => (source, len),
+            Parsed::Incomplete => panic!("header is incomplete"),
+        }
+    }
+
+    #[test]
+    fn parses_v1_headers() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /";
+        assert_eq!(
+            parsed(header),
+            (Some([192, 0, 2, 1].into()), header.len() - 5)
+        );
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(
+            parsed(header),
+            (Some("2001:db8::1".parse().unwrap()), header.len())
+        );
+
+        assert_eq!(parsed(b"PROXY UNKNOWN\r\n"), (None, 15));
+    }
+
+    #[test]
+    fn rejects_mismatched_v1_families() {
+        assert!(parse(b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n").is_err());
+        assert!(parse(b"PROXY TCP6 192.0.2.1 198.51.100.1 56324 443\r\n").is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_v1_lines() {
+        let mut line = b"PROXY TCP4 ".to_vec();
+        line.resize(V1_MAX_LEN - 1, b'1');
+        assert!(matches!(parse(&line).unwrap(), Parsed::Incomplete));
+
+        line.push(b'1');
+        assert!(parse(&line).is_err());
+    }
+
+    #[test]
+    fn waits_for_truncated_headers() {
+        let v1 = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+        let v2 = v2(
+            0x1,
+            0x1,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+        );
+        for header in [&v1[..], &v2] {
+            for len in 0..header.len() {
+                assert!(
+                    matches!(parse(&header[..len]).unwrap(), Parsed::Incomplete),
+                    "{:?}",
+                    &header[..len]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_v2_addresses_too_short_for_their_family() {
+        assert!(parse(&v2(0x1, 0x1, &[192, 0, 2])).is_err());
+        assert!(parse(&v2(0x1, 0x2, &[0; 15])).is_err());
+    }
+
+    #[test]
+    fn parses_v2_headers() {
+        let header = v2(
+            0x1,
+            0x1,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+        );
+        assert_eq!(parsed(&header), (Some([192, 0, 2, 1].into()), 28));
+
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend_from_slice(&[0; 16]);
+        addresses.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        let header = v2(0x1, 0x2, &addresses);
+        assert_eq!(parsed(&header), (Some("2001:db8::1".parse().unwrap()), 52));
+    }
+
+    #[test]
+    fn ignores_the_addresses_of_v2_local_headers() {
+        let header = v2(
+            0x0,
+            0x1,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+        );
+        assert_eq!(parsed(&header), (None, 28));
+        assert_eq!(parsed(&v2(0x0, 0x0, &[])), (None, 16));
+    }
+
+    #[test]
+    fn skips_v2_tlvs() {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        // An authority TLV naming the requested host
+        addresses.extend_from_slice(&[0x02, 0x00, 0x0b]);
+        addresses.extend_from_slice(b"example.com");
+        let header = v2(0x1, 0x1, &addresses);
+        assert_eq!(parsed(&header), (Some([192, 0, 2, 1].into()), header.len()));
+    }
+
+    #[tokio::test]
+    async fn leaves_the_stream_just_after_the_header() {
+        let (mut client, server) = duplex(1024);
+        let mut sent = v2(
+            0x1,
+            0x1,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+        );
+        sent.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        client.write_all(&sent).await.unwrap();
+        drop(client);
+
+        let (mut stream, source) = read_header(Box::new(server)).await.unwrap();
+        assert_eq!(source, Some([192, 0, 2, 1].into()));
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_connection_closes_mid_header() {
+        let (mut client, server) = duplex(1024);
+        client.write_all(b"PROXY TCP4 192.0.2.1").await.unwrap();
+        drop(client);
+
+        assert!(read_header(Box::new(server)).await.is_err());
+    }
+}
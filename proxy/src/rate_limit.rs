@@ -0,0 +1,206 @@
+use std::{
+    future::ready,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use futures_util::future::BoxFuture;
+use hyper::{
+    Request, StatusCode,
+    header::{HeaderValue, RETRY_AFTER},
+};
+use tokio::{spawn, time::interval};
+use tracing::debug;
+
+use crate::{
+    config::Config,
+    filter::{FilterContext, FilterOutcome, RequestFilter},
+    proxy::{RequestBody, empty_response},
+};
+
+/// How often idle buckets are removed.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token bucket state for a single client.
+struct Bucket {
+    /// Tokens currently available.
+    tokens: f64,
+    /// When `tokens` was last brought up to date.
+    updated: Instant,
+}
+
+/// A per-client-IP token-bucket rate limiter.
+pub struct RateLimiter {
+    /// Tokens added per second.
+    rate: f64,
+    /// Maximum number of tokens a bucket can hold.
+    burst: f64,
+    /// Buckets keyed by client IP.
+    buckets: DashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `rate` requests per second with bursts of up to `burst`.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst.max(1)),
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Take a token for a request from `ip`.
+    ///
+    /// Returns how long the client should wait before retrying when the bucket is empty.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Remove buckets that have refilled completely, since they are equivalent to a new one.
+    fn cleanup(&self) {
+        let now = Instant::now();
+        let before = self.buckets.len();
+
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * self.rate < self.burst
+        });
+
+        debug!(
+            "Rate limiter cleanup removed {} idle buckets",
+            before.saturating_sub(self.buckets.len())
+        );
+    }
+}
+
+impl RequestFilter for RateLimiter {
+    fn on_request<'a>(
+        &'a self,
+        context: &'a FilterContext,
+        _req: &'a mut Request<RequestBody>,
+    ) -> BoxFuture<'a, FilterOutcome> {
+        let outcome = match self.check(context.client_ip) {
+            Ok(()) => FilterOutcome::Continue,
+            Err(retry_after) => {
+                debug!(
+                    "Rate limited {} {} from {}",
+                    context.method, context.uri, context.client_ip
+                );
+                let mut response = empty_response(StatusCode::TOO_MANY_REQUESTS);
+                response.headers_mut().insert(
+                    RETRY_AFTER,
+                    HeaderValue::from(retry_after.as_secs_f64().ceil() as u64),
+                );
+                FilterOutcome::Respond(response)
+            }
+        };
+        Box::pin(ready(outcome))
+    }
+}
+
+/// Spawn a background task that periodically removes idle buckets from the rate limiter of
+/// `config`, stopping once a reload has replaced it.
+///
+/// Does nothing when rate limiting is disabled.
+pub fn spawn_rate_limit_cleanup(config: &Arc<Config>) {
+    if config.rate_limiter.is_none() {
+        return;
+    }
+
+    let config = Arc::downgrade(config);
+    spawn(async move {
+        let mut ticker = interval(CLEANUP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let Some(config) = config.upgrade() else {
+                return;
+            };
+            if let Some(ref limiter) = config.rate_limiter {
+                limiter.cleanup();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, thread::sleep};
+
+    use super::*;
+    use crate::proxy::full_body;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn allows_bursts_then_limits_to_the_rate() {
+        let limiter = RateLimiter::new(10.0, 3);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(CLIENT), Ok(()));
+        }
+        let retry_after = limiter.check(CLIENT).unwrap_err();
+        assert!(
+            retry_after <= Duration::from_millis(100),
+            "{:?}",
+            retry_after
+        );
+
+        sleep(Duration::from_millis(110));
+        assert_eq!(limiter.check(CLIENT), Ok(()));
+        assert!(limiter.check(CLIENT).is_err());
+    }
+
+    #[test]
+    fn limits_each_client_separately() {
+        let limiter = RateLimiter::new(1.0, 1);
+        assert_eq!(limiter.check(CLIENT), Ok(()));
+        assert!(limiter.check(CLIENT).is_err());
+        assert_eq!(limiter.check(OTHER_CLIENT), Ok(()));
+    }
+
+    #[test]
+    fn removes_only_refilled_buckets() {
+        let limiter = RateLimiter::new(100.0, 1);
+        limiter.check(CLIENT).unwrap();
+        sleep(Duration::from_millis(20));
+        limiter.check(OTHER_CLIENT).unwrap();
+
+        limiter.cleanup();
+        assert!(!limiter.buckets.contains_key(&CLIENT));
+        assert!(limiter.buckets.contains_key(&OTHER_CLIENT));
+    }
+
+    #[tokio::test]
+    async fn answers_limited_requests_with_retry_after() {
+        let limiter = RateLimiter::new(0.5, 1);
+        let mut req = Request::new(full_body(Default::default()));
+        let context = FilterContext::new(CLIENT, &req);
+
+        assert!(matches!(
+            limiter.on_request(&context, &mut req).await,
+            FilterOutcome::Continue
+        ));
+        let FilterOutcome::Respond(response) = limiter.on_request(&context, &mut req).await else {
+            panic!("request was not rate limited");
+        };
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "2");
+    }
+}
@@ -0,0 +1,97 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use anyhow::Result;
+use http_body_util::Full;
+use hyper::{
+    Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header::{CONTENT_TYPE, HOST, LOCATION},
+    http::uri::Authority,
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use tokio::{net::TcpListener, spawn};
+use tracing::{debug, error, info, warn};
+
+use crate::acme::challenge_response;
+
+/// Serve permanent redirects from plaintext HTTP to HTTPS on their own listener in a background
+/// task.
+///
+/// Pending ACME HTTP-01 challenges are answered on this listener instead of being redirected.
+///
+/// `https_port` is added to redirect targets, and should be `None` when HTTPS is served on the
+/// default port.
+pub async fn spawn_redirect_server(addr: SocketAddr, https_port: Option<u16>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("✓ Redirecting http://{} to HTTPS", addr);
+
+    spawn(async move {
+        loop {
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept redirect connection: {:?}", e);
+                    continue;
+                }
+            };
+            debug!("Accepted redirect connection from {}", remote_addr.ip());
+
+            spawn(async move {
+                if let Err(e) = http1::Builder::new()
+                    .timer(TokioTimer::new())
+                    .serve_connection(
+                        TokioIo::new(stream),
+                        service_fn(|req| redirect(req, https_port)),
+                    )
+                    .await
+                {
+                    warn!("Redirect connection error from {}: {:?}", remote_addr, e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Respond to a request on the redirect listener
+async fn redirect(
+    req: Request<Incoming>,
+    https_port: Option<u16>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    if let Some(key_authorization) = challenge_response(req.uri().path()) {
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(Full::new(Bytes::from(key_authorization)))
+            .unwrap());
+    }
+
+    // Absolute-form request targets carry the authority in the URI instead of the Host header
+    let authority = req.uri().authority().cloned().or_else(|| {
+        req.headers()
+            .get(HOST)
+            .and_then(|host| Authority::try_from(host.as_bytes()).ok())
+    });
+
+    let Some(authority) = authority else {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Full::default())
+            .unwrap());
+    };
+
+    let port = https_port.map_or(String::new(), |port| format!(":{}", port));
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let location = format!("https://{}{}{}", authority.host(), port, path);
+
+    Ok(Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(LOCATION, location)
+        .body(Full::default())
+        .unwrap())
+}
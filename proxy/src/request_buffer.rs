@@ -0,0 +1,90 @@
+use std::time::Instant;
+
+use futures_util::{StreamExt, stream};
+use http_body_util::{BodyExt, BodyStream, StreamBody};
+use hyper::{
+    Request,
+    body::{Body, Bytes, Frame},
+};
+use tracing::debug;
+
+use crate::proxy::{BoxError, RequestBody, full_body, is_grpc};
+
+/// Buffering of small request bodies, so they can be sent again when a request is retried.
+pub struct RequestBuffer {
+    /// Largest body in bytes that will be buffered.
+    max_size: usize,
+}
+
+impl RequestBuffer {
+    /// Buffer request bodies of up to `max_size` bytes.
+    pub fn new(max_size: usize) -> Self {
+        Self { max_size }
+    }
+
+    /// Read the whole body of a request into memory if it is small enough, returning the request
+    /// along with its buffered body.
+    ///
+    /// Bodies of unknown length, such as chunked uploads, are read until they end or cross the
+    /// size limit. Larger bodies are streamed on as before, starting with the part already read,
+    /// and `None` is returned for them, as it is for gRPC requests, which are never buffered.
+    /// Errors reading the body, including hitting `MAX_BODY_SIZE`, are returned as is.
+    pub async fn apply(
+        &self,
+        req: Request<RequestBody>,
+    ) -> Result<(Request<RequestBody>, Option<Bytes>), BoxError> {
+        // gRPC clients may wait for responses before sending more messages
+        if is_grpc(req.headers()) {
+            debug!("Streaming gRPC request body");
+            return Ok((req, None));
+        }
+        if req.body().size_hint().lower() > self.max_size as u64 {
+            debug!("Streaming request body above the buffer limit");
+            return Ok((req, None));
+        }
+
+        let started = Instant::now();
+        let (parts, mut body) = req.into_parts();
+        let mut chunks = Vec::new();
+        let mut size = 0;
+        while let Some(frame) = body.frame().await {
+            let frame = frame?;
+            let Some(data) = frame.data_ref() else {
+                // Trailers can't be replayed from the buffer, so stream the body instead
+                debug!("Streaming request body with trailers");
+                chunks.push(frame);
+                return Ok((Request::from_parts(parts, replay(chunks, body)), None));
+            };
+            size += data.len();
+            chunks.push(frame);
+            if size > self.max_size {
+                debug!("Streaming request body above the buffer limit");
+                return Ok((Request::from_parts(parts, replay(chunks, body)), None));
+            }
+        }
+
+        let mut buffered = Vec::with_capacity(size);
+        for chunk in chunks {
+            if let Ok(data) = chunk.into_data() {
+                buffered.extend_from_slice(&data);
+            }
+        }
+        let buffered = Bytes::from(buffered);
+        debug!(
+            "Buffered {} byte request body in {:?}",
+            buffered.len(),
+            started.elapsed()
+        );
+
+        Ok((
+            Request::from_parts(parts, full_body(buffered.clone())),
+            Some(buffered),
+        ))
+    }
+}
+
+/// Build a body that sends the frames already read from `rest` before the remainder of it
+fn replay(read: Vec<Frame<Bytes>>, rest: RequestBody) -> RequestBody {
+    let read = stream::iter(read.into_iter().map(Ok));
+    StreamBody::new(read.chain(BodyStream::new(rest))).boxed_unsync()
+}
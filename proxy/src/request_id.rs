@@ -0,0 +1,87 @@
+use std::fmt::Write;
+
+use hyper::{
+    HeaderMap,
+    header::{HeaderName, HeaderValue},
+};
+
+/// Longest request ID accepted from a client, in bytes.
+const MAX_CLIENT_ID_LEN: usize = 128;
+
+/// Get the request ID sent by the client, or generate one if it is missing, longer than
+/// [`MAX_CLIENT_ID_LEN`] or has anything but visible ASCII characters.
+///
+/// The ID ends up in log lines and access logs, so clients don't get to pad them or break them up
+/// with spaces. It is stored in the request headers, replacing a rejected one, so that it is
+/// forwarded to the backend.
+pub fn ensure_request_id(headers: &mut HeaderMap, name: &HeaderName) -> String {
+    if let Some(id) = headers.get(name).and_then(|value| value.to_str().ok())
+        && (1..=MAX_CLIENT_ID_LEN).contains(&id.len())
+        && id.bytes().all(|byte| byte.is_ascii_graphic())
+    {
+        return id.to_string();
+    }
+
+    let id = generate();
+    headers.insert(
+        name,
+        HeaderValue::from_str(&id).expect("generated IDs are valid header values"),
+    );
+    id
+}
+
+/// Generate a random (version 4) UUID.
+fn generate() -> String {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).expect("the OS random number generator is available");
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let mut id = String::with_capacity(36);
+    for (i, byte) in bytes.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            id.push('-');
+        }
+        write!(id, "{:02x}", byte).unwrap();
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ensure(id: &[u8]) -> (String, HeaderMap) {
+        let name = HeaderName::from_static("x-request-id");
+        let mut headers = HeaderMap::new();
+        headers.insert(&name, HeaderValue::from_bytes(id).unwrap());
+        let id = ensure_request_id(&mut headers, &name);
+        (id, headers)
+    }
+
+    #[test]
+    fn keeps_valid_client_ids() {
+        let (id, headers) = ensure(b"abc-123_DEF");
+        assert_eq!(id, "abc-123_DEF");
+        assert_eq!(headers["x-request-id"], "abc-123_DEF");
+
+        let longest = "a".repeat(MAX_CLIENT_ID_LEN);
+        assert_eq!(ensure(longest.as_bytes()).0, longest);
+    }
+
+    #[test]
+    fn replaces_overlong_or_invisible_client_ids() {
+        let too_long = "a".repeat(MAX_CLIENT_ID_LEN + 1);
+        for rejected in [
+            too_long.as_bytes(),
+            b"with space",
+            b"with\ttab",
+            b"caf\xc3\xa9",
+            b"",
+        ] {
+            let (id, headers) = ensure(rejected);
+            assert_eq!(id.len(), 36, "{:?}", rejected);
+            assert_eq!(headers["x-request-id"], id.as_str());
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::Instant;
+
+use http_body_util::BodyExt;
+use hyper::{Response, header::CONTENT_LENGTH};
+use tracing::debug;
+
+use crate::proxy::{ResponseBody, buffer_response_body};
+
+/// Buffering of small backend responses, so their backend connection is released as soon as the
+/// body has arrived rather than once a slow client has read it.
+pub struct ResponseBuffer {
+    /// Largest body in bytes that will be buffered.
+    max_size: usize,
+}
+
+impl ResponseBuffer {
+    /// Buffer responses of up to `max_size` bytes.
+    pub fn new(max_size: usize) -> Self {
+        Self { max_size }
+    }
+
+    /// Read the whole body of a response from the backend if it is small enough.
+    ///
+    /// Only responses with a `Content-Length` of at most the size limit are buffered, so large
+    /// bodies and streams of unknown length (e.g. server-sent events) pass through untouched.
+    /// Must not be called for responses to `HEAD` requests, which have no body.
+    pub async fn apply(&self, response: Response<ResponseBody>) -> Response<ResponseBody> {
+        let content_length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+        let len = match content_length {
+            Some(len) if len <= self.max_size => len,
+            Some(len) => {
+                debug!("Streaming {} byte response above the buffer limit", len);
+                return response;
+            }
+            None => {
+                debug!("Streaming response without a Content-Length");
+                return response;
+            }
+        };
+
+        let started = Instant::now();
+        let (parts, body) = response.into_parts();
+        // Collected rather than turned into bytes to keep any trailers, such as gRPC's status
+        let body = match buffer_response_body(body, self.max_size, "ahead of the client").await {
+            Ok(collected) => collected,
+            Err(response) => return response,
+        };
+        debug!("Buffered {} byte response in {:?}", len, started.elapsed());
+
+        Response::from_parts(parts, body.map_err(|never| match never {}).boxed_unsync())
+    }
+}
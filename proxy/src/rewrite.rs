@@ -0,0 +1,108 @@
+use anyhow::{Context, Result, ensure};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    Response, StatusCode,
+    header::{ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG},
+};
+use tracing::debug;
+
+use crate::proxy::{ResponseBody, buffer_response_body};
+
+/// Find-and-replace rules applied to small HTML responses, e.g. to rewrite absolute backend URLs
+/// to the proxy's external hostname.
+pub struct BodyRewriter {
+    /// Substitutions applied in order, as `(from, to)` pairs.
+    rules: Vec<(String, String)>,
+    /// Largest body in bytes that will be rewritten.
+    max_size: usize,
+}
+
+impl BodyRewriter {
+    /// Parse rules in the form `FROM=TO`.
+    pub fn new(rules: &[String], max_size: usize) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let (from, to) = rule.split_once('=').with_context(|| {
+                    format!("Rewrite rule {:?} must be in the form from=to", rule)
+                })?;
+                ensure!(
+                    !from.is_empty(),
+                    "Rewrite rule {:?} has nothing to replace",
+                    rule
+                );
+                Ok((from.to_string(), to.to_string()))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { rules, max_size })
+    }
+
+    /// Apply the rules to the body of an HTML response.
+    ///
+    /// Only uncompressed responses with a `Content-Length` of at most the size limit are buffered
+    /// and rewritten, so streamed and large bodies pass through untouched. Must not be called for
+    /// responses to `HEAD` requests, which have no body.
+    pub async fn apply(&self, response: Response<ResponseBody>) -> Response<ResponseBody> {
+        if !self.should_rewrite(&response) {
+            return response;
+        }
+
+        let (mut parts, body) = response.into_parts();
+        let body = match buffer_response_body(body, self.max_size, "for rewriting").await {
+            Ok(collected) => collected.to_bytes(),
+            Err(response) => return response,
+        };
+
+        let body = match String::from_utf8(body.to_vec()) {
+            Ok(text) => {
+                let rewritten = self
+                    .rules
+                    .iter()
+                    .fold(text, |text, (from, to)| text.replace(from, to));
+                if body != rewritten.as_bytes() {
+                    debug!("Rewrote response body");
+                    // The representation changed, so validators and ranges of the original no
+                    // longer apply
+                    parts.headers.remove(ETAG);
+                    parts.headers.remove(ACCEPT_RANGES);
+                    parts.headers.insert(CONTENT_LENGTH, rewritten.len().into());
+                }
+                rewritten.into()
+            }
+            Err(_) => {
+                debug!("Not rewriting response body that isn't valid UTF-8");
+                body
+            }
+        };
+
+        Response::from_parts(
+            parts,
+            Full::new(body)
+                .map_err(|never| match never {})
+                .boxed_unsync(),
+        )
+    }
+
+    /// Whether a response is HTML, uncompressed and small enough to rewrite.
+    fn should_rewrite(&self, response: &Response<ResponseBody>) -> bool {
+        let headers = response.headers();
+        if response.status() == StatusCode::PARTIAL_CONTENT
+            || headers.contains_key(CONTENT_ENCODING)
+        {
+            return false;
+        }
+
+        let is_html = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/html"));
+        let content_length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+
+        is_html && content_length.is_some_and(|len| len > 0 && len <= self.max_size)
+    }
+}
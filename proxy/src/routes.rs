@@ -0,0 +1,250 @@
+use std::{cmp::Reverse, str::FromStr, time::Duration};
+
+use anyhow::{Context, Error, Result, bail, ensure};
+use hyper::Uri;
+
+use crate::backend::Backends;
+
+/// Label used in spans and metrics for requests that match no route.
+pub const UNMATCHED_ROUTE: &str = "__other__";
+
+/// A path prefix mapped to its own set of backends.
+pub struct Route {
+    /// Normalized path prefix, without a trailing slash (except for the root route `/`).
+    pub prefix: String,
+    /// The backends requests matching this route are sent to.
+    pub backends: Backends,
+    /// Whether to remove the route prefix from the path before forwarding.
+    pub strip_prefix: bool,
+    /// Path prepended before forwarding, without a trailing slash.
+    pub add_prefix: Option<String>,
+    /// Upstream timeout overriding the global one.
+    pub timeout: Option<Duration>,
+    /// Maximum number of retries overriding the global one.
+    pub retries: Option<u32>,
+}
+
+impl Route {
+    /// Whether the route matches the given request path.
+    ///
+    /// Matching is done on whole path segments, so `/api` matches `/api` and `/api/users` but not
+    /// `/apiary`.
+    fn matches(&self, path: &str) -> bool {
+        if self.prefix == "/" {
+            return true;
+        }
+
+        path.strip_prefix(self.prefix.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Rewrite a request path for this route's backends.
+    ///
+    /// The prefix is only stripped on a segment boundary, so an exact match of the prefix becomes
+    /// `/` (or the added prefix). Repeated slashes in the rest of the path are left untouched.
+    fn rewrite_path(&self, path: &str) -> String {
+        let path = if self.strip_prefix && self.prefix != "/" {
+            path.strip_prefix(self.prefix.as_str()).unwrap_or(path)
+        } else {
+            path
+        };
+
+        let path = match &self.add_prefix {
+            Some(add_prefix) => format!("{}{}", add_prefix, path),
+            None => path.to_string(),
+        };
+
+        if path.is_empty() {
+            "/".to_string()
+        } else {
+            path
+        }
+    }
+
+    /// Rewrite a request URI for this route's backends, preserving the query string.
+    pub fn rewrite_uri(&self, uri: &Uri) -> Result<Uri> {
+        if !self.strip_prefix && self.add_prefix.is_none() {
+            return Ok(uri.clone());
+        }
+
+        let path = self.rewrite_path(uri.path());
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse()?);
+        Ok(Uri::from_parts(parts)?)
+    }
+}
+
+impl FromStr for Route {
+    type Err = Error;
+
+    /// Parse a route in the form `PREFIX=URL[;OPTION...]`, where `URL` may list several backends
+    /// separated by `|`, each optionally followed by `*WEIGHT`. Supported options are
+    /// `strip_prefix`, `add_prefix=PATH`, `timeout=SECS` and `retries=N`.
+    ///
+    /// Trailing slashes on the prefix are ignored, so `/api/` and `/api` are the same route.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(';');
+        let target = parts.next().unwrap_or_default();
+
+        let (prefix, urls) = target
+            .split_once('=')
+            .with_context(|| format!("Route {:?} must be in the form PREFIX=URL", s))?;
+
+        ensure!(
+            prefix.starts_with('/'),
+            "Route prefix {:?} must start with '/'",
+            prefix
+        );
+        let prefix = match prefix.trim_end_matches('/') {
+            "" => "/".to_string(),
+            prefix => prefix.to_string(),
+        };
+
+        let urls = urls
+            .split('|')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        ensure!(!urls.is_empty(), "Route {:?} has no backend URL", prefix);
+
+        let mut strip_prefix = false;
+        let mut add_prefix = None;
+        let mut timeout = None;
+        let mut retries = None;
+        for option in parts.map(str::trim).filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                None if option == "strip_prefix" => strip_prefix = true,
+                Some(("add_prefix", path)) => {
+                    ensure!(
+                        path.starts_with('/'),
+                        "add_prefix {:?} for route {:?} must start with '/'",
+                        path,
+                        prefix
+                    );
+                    let path = path.trim_end_matches('/');
+                    add_prefix = (!path.is_empty()).then(|| path.to_string());
+                }
+                Some(("timeout", secs)) => {
+                    let secs = secs.parse::<u64>().with_context(|| {
+                        format!("Invalid timeout {:?} for route {:?}", secs, prefix)
+                    })?;
+                    ensure!(
+                        secs > 0,
+                        "timeout for route {:?} must be at least 1",
+                        prefix
+                    );
+                    timeout = Some(Duration::from_secs(secs));
+                }
+                Some(("retries", count)) => {
+                    retries = Some(count.parse().with_context(|| {
+                        format!("Invalid retries {:?} for route {:?}", count, prefix)
+                    })?);
+                }
+                _ => bail!("Unknown option {:?} for route {:?}", option, prefix),
+            }
+        }
+
+        Ok(Self {
+            prefix,
+            backends: Backends::new(urls)?,
+            strip_prefix,
+            add_prefix,
+            timeout,
+            retries,
+        })
+    }
+}
+
+/// A routing table matching request paths to backends by longest prefix.
+pub struct Routes {
+    /// Routes sorted from the longest prefix to the shortest.
+    routes: Vec<Route>,
+}
+
+impl Routes {
+    /// Create a routing table from the given routes.
+    pub fn new(mut routes: Vec<Route>) -> Self {
+        routes.sort_by_key(|route| Reverse(route.prefix.len()));
+        Self { routes }
+    }
+
+    /// Whether no routes are configured.
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Find the route with the longest prefix matching `path`, if any.
+    pub fn match_route(&self, path: &str) -> Option<&Route> {
+        self.routes.iter().find(|route| route.matches(path))
+    }
+
+    /// Iterate over the backend sets of every route.
+    pub fn backend_sets(&self) -> impl Iterator<Item = &Backends> {
+        self.routes.iter().map(|route| &route.backends)
+    }
+}
+
+/// Get the label of a matched route: its prefix, or [`UNMATCHED_ROUTE`] when no route matched.
+///
+/// Unlike the request path, this takes one value per configured route, so it is safe to use as a
+/// metric label.
+pub fn route_label(route: Option<&Route>) -> &str {
+    route.map_or(UNMATCHED_ROUTE, |route| route.prefix.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(spec: &str) -> Route {
+        spec.parse().unwrap()
+    }
+
+    fn rewrite(route: &Route, uri: &str) -> String {
+        route
+            .rewrite_uri(&uri.parse().unwrap())
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn strips_exact_prefix_to_root() {
+        let route = route("/api=http://127.0.0.1:8080;strip_prefix");
+        assert_eq!(route.rewrite_path("/api"), "/");
+        assert_eq!(route.rewrite_path("/api/users"), "/users");
+    }
+
+    #[test]
+    fn keeps_trailing_slash() {
+        let route = route("/api/=http://127.0.0.1:8080;strip_prefix");
+        assert_eq!(route.prefix, "/api");
+        assert_eq!(route.rewrite_path("/api/"), "/");
+        assert_eq!(route.rewrite_path("/api/users/"), "/users/");
+    }
+
+    #[test]
+    fn replaces_prefix() {
+        let route = route("/api=http://127.0.0.1:8080;strip_prefix;add_prefix=/v1/");
+        assert_eq!(route.rewrite_path("/api"), "/v1");
+        assert_eq!(route.rewrite_path("/api/users"), "/v1/users");
+    }
+
+    #[test]
+    fn keeps_query_string() {
+        let route = route("/api=http://127.0.0.1:8080;strip_prefix");
+        assert_eq!(rewrite(&route, "/api?page=2&q=a%20b"), "/?page=2&q=a%20b");
+        assert_eq!(rewrite(&route, "/api/users?page=2"), "/users?page=2");
+    }
+
+    #[test]
+    fn leaves_uri_alone_without_rewriting() {
+        let route = route("/api=http://127.0.0.1:8080");
+        assert_eq!(rewrite(&route, "/api/users?page=2"), "/api/users?page=2");
+    }
+}
@@ -1,79 +1,482 @@
-#[cfg(not(unix))]
-use std::future::pending;
-use std::{net::IpAddr, time::Duration};
+use std::{
+    convert::Infallible,
+    future::pending,
+    io,
+    net::IpAddr,
+    pin::{Pin, pin},
+    sync::Arc,
+    time::Duration,
+};
 
+use arc_swap::ArcSwap;
+use http_body_util::BodyExt;
 use hyper::{
-    server::conn::{http1, http2},
-    service::service_fn,
+    Request, Response, body::Incoming, header::ALT_SVC, server::conn::http2, service::service_fn,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use rustls::ServerConfig;
+use tokio::{
+    io::AsyncReadExt,
+    select, signal, spawn,
+    sync::{Notify, watch},
+    task::spawn_blocking,
+    time::timeout,
 };
-use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
-use tokio::{net::TcpStream, signal};
-use tokio_rustls::server::TlsStream;
-use tracing::{debug, info, warn};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    access_log::Alpn,
+    backend::drain_removed,
+    config::Config,
+    discovery::{resolve_service_names, spawn_service_discovery},
+    health::spawn_health_checks,
+    http3::alt_svc,
+    idle::IdleTimeout,
+    listener::{Rewind, Stream},
+    maintenance::log_toggled,
+    metrics::metrics,
+    proxy::{BoxError, RequestBody, ResponseBody, handle},
+    proxy_protocol::read_header as read_proxy_header,
+    rate_limit::spawn_rate_limit_cleanup,
+    throttle::throttle,
+    tls::{client_cert_subject, load_tls_config, negotiated_parameters},
+};
+
+/// Maximum time to wait for the PROXY protocol header and TLS or QUIC handshake of a new connection
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves a newly accepted connection, over TLS when `acceptor` is given
+///
+/// When the PROXY protocol is enabled, the client IP is taken from the header sent by the load
+/// balancer in front of the proxy, and connections without one are dropped. The IP filter is
+/// applied to the resulting client IP, and the connection is throttled to `MAX_UPLOAD_BPS` and
+/// `MAX_DOWNLOAD_BPS`. Requests on `overloaded` connections are answered with `503`, and the
+/// connection is closed after the first one.
+pub async fn accept_connection(
+    stream: Stream,
+    peer_ip: IpAddr,
+    configs: &'static ArcSwap<Config>,
+    acceptor: Option<TlsAcceptor>,
+    watcher: watch::Receiver<()>,
+    overloaded: bool,
+) {
+    let config = configs.load_full();
+    let (stream, client_ip) = if config.accept_proxy_protocol {
+        match timeout(HANDSHAKE_TIMEOUT, read_proxy_header(stream)).await {
+            Ok(Ok((stream, source))) => (stream, source.unwrap_or(peer_ip)),
+            Ok(Err(e)) => {
+                warn!(
+                    "Rejected PROXY protocol connection from {}: {:?}",
+                    peer_ip, e
+                );
+                return;
+            }
+            Err(_) => {
+                warn!("PROXY protocol header timeout from {}", peer_ip);
+                return;
+            }
+        }
+    } else {
+        (stream, peer_ip)
+    };
+
+    if !config.ip_filter.is_allowed(client_ip) {
+        debug!("Rejected connection from {}", client_ip);
+        return;
+    }
+    debug!("Accepted connection from {}", client_ip);
+    let stream = throttle(stream, config.max_upload_bps, config.max_download_bps);
+
+    let Some(acceptor) = acceptor else {
+        handle_plaintext_connection(stream, client_ip, overloaded, &config, configs, watcher).await;
+        return;
+    };
 
-use crate::{config::Config, proxy::handle};
+    match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(tls_stream)) => {
+            handle_connection(
+                TokioIo::new(tls_stream),
+                client_ip,
+                overloaded,
+                &config,
+                configs,
+                watcher,
+            )
+            .await;
+        }
+        Ok(Err(e)) => {
+            warn!("TLS handshake error from {}: {:?}", client_ip, e);
+        }
+        Err(_) => {
+            warn!("TLS handshake timeout from {}", client_ip);
+        }
+    }
+}
 
 /// Handles an incoming TLS connection, negotiating between HTTP/1.1 and HTTP/2
-pub async fn handle_connection(
-    io: TokioIo<TlsStream<TcpStream>>,
+///
+/// The server is tuned following `config`, while each request is handled with the configuration
+/// current at the time it arrives. The connection is registered with `watcher` so it can be
+/// drained on shutdown.
+async fn handle_connection(
+    io: TokioIo<TlsStream<Stream>>,
     client_ip: IpAddr,
-    config: &'static Config,
+    overloaded: bool,
+    config: &Config,
+    configs: &'static ArcSwap<Config>,
+    watcher: watch::Receiver<()>,
 ) {
-    let service = service_fn(move |req| handle(client_ip, &config.backend_url, req));
-
-    // Get the negotiated ALPN protocol
+    // Get the negotiated ALPN protocol, TLS parameters and verified client certificate
     let (_, tls_session) = io.inner().get_ref();
+    let subject: Option<Arc<str>> = client_cert_subject(tls_session).map(Arc::from);
+    let tls_parameters = negotiated_parameters(tls_session);
+    debug!("Negotiated {:?} with {}", tls_parameters, client_ip);
     let protocol = tls_session
         .alpn_protocol()
         .and_then(|p| std::str::from_utf8(p).ok());
+    let alpn = alpn_label(protocol);
+    debug!("Negotiated ALPN protocol {} with {}", alpn, client_ip);
+    metrics().alpn_connections.with_label_values(&[alpn]).inc();
 
-    match protocol {
-        Some("h2") => {
-            debug!("Using HTTP/2 for connection from {}", client_ip);
-            if let Err(e) = http2::Builder::new(TokioExecutor::new())
-                .timer(TokioTimer::new())
-                .keep_alive_interval(Some(Duration::from_secs(20)))
-                .keep_alive_timeout(Duration::from_secs(10))
-                .serve_connection(io, service)
-                .await
-            {
-                warn!("HTTP/2 connection error from {}: {:?}", client_ip, e);
-            }
+    // Clients that negotiated HTTP/2 under a previous configuration are served HTTP/1.1 too
+    let is_h2 = protocol == Some("h2") && config.enable_h2;
+    // Clients learn about HTTP/3 from their HTTP/2 responses
+    let alt_svc = alt_svc().filter(|_| is_h2);
+
+    let idle = config.connection_idle_timeout.map(IdleTimeout::new);
+    let requests = idle.clone();
+    let answered = overloaded.then(|| Arc::new(Notify::new()));
+    let responses = answered.clone();
+    let service = service_fn(move |req: Request<Incoming>| {
+        let mut req = boxed_request(req);
+        req.extensions_mut().insert(Alpn(alpn));
+        if let Some(tls_parameters) = tls_parameters {
+            req.extensions_mut().insert(tls_parameters);
         }
-        _ => {
-            debug!("Using HTTP/1.1 for connection from {}", client_ip);
-            if let Err(e) = http1::Builder::new()
-                .timer(TokioTimer::new())
-                .keep_alive(true)
-                .serve_connection(io, service)
-                .await
-            {
-                warn!("HTTP/1.1 connection error from {}: {:?}", client_ip, e);
+        let handling = handle(
+            client_ip,
+            true,
+            subject.clone(),
+            overloaded,
+            configs.load_full(),
+            req,
+        );
+        handle_tracked(requests.clone(), responses.clone(), async move {
+            let mut response = handling.await?;
+            if let Some(alt_svc) = alt_svc {
+                response.headers_mut().insert(ALT_SVC, alt_svc.clone());
             }
+            Ok(response)
+        })
+    });
+    metrics().active_connections.inc();
+
+    if is_h2 {
+        debug!("Using HTTP/2 for connection from {}", client_ip);
+        let conn = http2_builder(config).serve_connection(io, service);
+        let shutdown = http2::Connection::graceful_shutdown;
+        if let Err(e) = serve(conn, shutdown, watcher, idle, answered, client_ip).await {
+            warn!("HTTP/2 connection error from {}: {:?}", client_ip, e);
         }
+    } else {
+        debug!("Using HTTP/1.1 for connection from {}", client_ip);
+        let builder = http1_builder(config);
+        let conn = builder.serve_connection_with_upgrades(io, service);
+        let shutdown = auto::UpgradeableConnection::graceful_shutdown;
+        if let Err(e) = serve(conn, shutdown, watcher, idle, answered, client_ip).await {
+            warn!("HTTP/1.1 connection error from {}: {:?}", client_ip, e);
+        }
+    }
+
+    metrics().active_connections.dec();
+}
+
+/// Name a negotiated ALPN protocol for metrics and logs, as `none` when the client didn't offer
+/// one the proxy supports
+fn alpn_label(protocol: Option<&str>) -> &'static str {
+    match protocol {
+        Some("h2") => "h2",
+        Some("http/1.1") => "http/1.1",
+        Some(_) => "other",
+        None => "none",
     }
 }
 
-/// Handles an incoming plaintext TCP connection using HTTP/1.1
-pub async fn handle_plaintext_connection(
-    io: TokioIo<TcpStream>,
+/// The connection preface every HTTP/2 client sends first (RFC 9113 §3.4)
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Maximum time to wait for a plaintext client to send enough bytes to detect its protocol
+const PREFACE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read the start of a stream to check for the HTTP/2 preface, returning the stream with the
+/// bytes read put back and whether the preface was found
+async fn detect_h2_preface(mut stream: Stream) -> io::Result<(Stream, bool)> {
+    let mut buf = Vec::with_capacity(H2_PREFACE.len());
+    while buf.len() < H2_PREFACE.len() && H2_PREFACE.starts_with(&buf) {
+        let mut chunk = [0; H2_PREFACE.len()];
+        let n = stream
+            .read(&mut chunk[..H2_PREFACE.len() - buf.len()])
+            .await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let is_h2 = buf == H2_PREFACE;
+    Ok((Box::new(Rewind::new(buf.into(), stream)), is_h2))
+}
+
+/// Handles an incoming plaintext connection using HTTP/1.1, or HTTP/2 with prior knowledge
+/// (h2c) when enabled and the client starts with the HTTP/2 preface
+///
+/// Protocol detection and server tuning follow `config`, while each request is handled with the
+/// configuration current at the time it arrives. The connection is registered with `watcher` so
+/// it can be drained on shutdown.
+async fn handle_plaintext_connection(
+    stream: Stream,
     client_ip: IpAddr,
-    config: &'static Config,
+    overloaded: bool,
+    config: &Config,
+    configs: &'static ArcSwap<Config>,
+    watcher: watch::Receiver<()>,
 ) {
-    let service = service_fn(move |req| handle(client_ip, &config.backend_url, req));
+    let (stream, is_h2) = if config.enable_h2c {
+        match timeout(PREFACE_TIMEOUT, detect_h2_preface(stream)).await {
+            Ok(Ok(detected)) => detected,
+            Ok(Err(e)) => {
+                debug!("Failed to read from {}: {:?}", client_ip, e);
+                return;
+            }
+            Err(_) => {
+                debug!("Timed out waiting for a request from {}", client_ip);
+                return;
+            }
+        }
+    } else {
+        (stream, false)
+    };
+
+    let io = TokioIo::new(stream);
+    let idle = config.connection_idle_timeout.map(IdleTimeout::new);
+    let requests = idle.clone();
+    let answered = overloaded.then(|| Arc::new(Notify::new()));
+    let responses = answered.clone();
+    let service = service_fn(move |req| {
+        let req = boxed_request(req);
+        let handling = handle(client_ip, false, None, overloaded, configs.load_full(), req);
+        handle_tracked(requests.clone(), responses.clone(), handling)
+    });
+    metrics().active_connections.inc();
+
+    if is_h2 {
+        debug!("Using HTTP/2 (h2c) for connection from {}", client_ip);
+        let conn = http2_builder(config).serve_connection(io, service);
+        let shutdown = http2::Connection::graceful_shutdown;
+        if let Err(e) = serve(conn, shutdown, watcher, idle, answered, client_ip).await {
+            warn!("HTTP/2 connection error from {}: {:?}", client_ip, e);
+        }
+    } else {
+        debug!(
+            "Using HTTP/1.1 (plaintext) for connection from {}",
+            client_ip
+        );
+        let builder = http1_builder(config);
+        let conn = builder.serve_connection_with_upgrades(io, service);
+        let shutdown = auto::UpgradeableConnection::graceful_shutdown;
+        if let Err(e) = serve(conn, shutdown, watcher, idle, answered, client_ip).await {
+            warn!("HTTP/1.1 connection error from {}: {:?}", client_ip, e);
+        }
+    }
+
+    metrics().active_connections.dec();
+}
+
+/// Box the body of a request received by hyper, as requests are proxied whichever protocol they
+/// arrived over
+fn boxed_request(req: Request<Incoming>) -> Request<RequestBody> {
+    req.map(|body| body.map_err(BoxError::from).boxed_unsync())
+}
+
+/// Handle a request, keeping its connection from timing out under `idle` until the response has
+/// been sent, and notifying `answered`, if given, once there is a response
+async fn handle_tracked(
+    idle: Option<IdleTimeout>,
+    answered: Option<Arc<Notify>>,
+    handling: impl Future<Output = Result<Response<ResponseBody>, Infallible>>,
+) -> Result<Response<ResponseBody>, Infallible> {
+    let active = idle.as_ref().map(IdleTimeout::start_request);
+    let response = handling.await?;
+    if let Some(answered) = answered {
+        answered.notify_one();
+    }
+    Ok(match active {
+        Some(active) => active.hold(response),
+        None => response,
+    })
+}
+
+/// Serve a connection until it is done, shutting it down gracefully with `graceful_shutdown` once
+/// `watcher` signals the server is shutting down, the connection has gone the `idle` timeout
+/// without requests, or an overloaded connection has been `answered`
+///
+/// Requests in flight are finished first, and HTTP/2 clients are told with a `GOAWAY` frame.
+/// `watcher` is held until the connection is done, so draining the server waits for it.
+async fn serve<C, E>(
+    conn: C,
+    graceful_shutdown: impl FnOnce(Pin<&mut C>),
+    mut watcher: watch::Receiver<()>,
+    idle: Option<IdleTimeout>,
+    answered: Option<Arc<Notify>>,
+    client_ip: IpAddr,
+) -> Result<(), E>
+where
+    C: Future<Output = Result<(), E>>,
+{
+    let mut conn = pin!(conn);
+    let expired = async {
+        match idle {
+            Some(idle) => idle.expired().await,
+            None => pending().await,
+        }
+    };
+    // Connections without a slot under the connection limit only get their `503`
+    let answered = async {
+        match answered {
+            Some(answered) => answered.notified().await,
+            None => pending().await,
+        }
+    };
+
+    select! {
+        result = conn.as_mut() => return result,
+        _ = watcher.changed() => {}
+        () = expired => debug!("Closing idle connection from {}", client_ip),
+        () = answered => debug!("Closing overloaded connection from {}", client_ip),
+    }
+    graceful_shutdown(conn.as_mut());
+    conn.await
+}
+
+/// Build the server for HTTP/1.1 connections, supporting protocol upgrades
+///
+/// Requests whose headers don't fit in `MAX_HEADER_SIZE` are answered with `431`.
+fn http1_builder(config: &Config) -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new()).http1_only();
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .keep_alive(config.h1_keep_alive)
+        .header_read_timeout(config.h1_header_read_timeout)
+        .max_buf_size(config.max_header_size);
+    builder
+}
 
-    debug!(
-        "Using HTTP/1.1 (plaintext) for connection from {}",
-        client_ip
-    );
-    if let Err(e) = http1::Builder::new()
+/// Build the server for HTTP/2 connections
+///
+/// Requests whose headers exceed `MAX_HEADER_SIZE` are answered with `431`.
+fn http2_builder(config: &Config) -> http2::Builder<TokioExecutor> {
+    let mut builder = http2::Builder::new(TokioExecutor::new());
+    builder
         .timer(TokioTimer::new())
-        .keep_alive(true)
-        .serve_connection(io, service)
-        .await
-    {
-        warn!("HTTP/1.1 connection error from {}: {:?}", client_ip, e);
+        .keep_alive_interval(config.h2_keep_alive_interval)
+        .keep_alive_timeout(config.h2_keep_alive_timeout)
+        .max_concurrent_streams(config.h2_max_concurrent_streams)
+        .initial_stream_window_size(config.h2_initial_window_size)
+        .initial_connection_window_size(config.h2_initial_connection_window_size)
+        .max_header_list_size(config.max_header_size as u32);
+    builder
+}
+
+/// Spawns a task that reloads the configuration whenever the process receives SIGHUP
+#[cfg(unix)]
+pub fn spawn_reload_handler(
+    configs: &'static ArcSwap<Config>,
+    tls_config: Option<Arc<ArcSwap<ServerConfig>>>,
+) {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+        .expect("failed to install SIGHUP handler");
+
+    spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading configuration");
+            match reload(configs, tls_config.clone()).await {
+                Ok(()) => info!("Configuration reloaded"),
+                Err(e) => error!("Failed to reload configuration, keeping previous: {:?}", e),
+            }
+        }
+    });
+}
+
+/// Spawns nothing, since SIGHUP is only available on Unix
+#[cfg(not(unix))]
+pub fn spawn_reload_handler(
+    _configs: &'static ArcSwap<Config>,
+    _tls_config: Option<Arc<ArcSwap<ServerConfig>>>,
+) {
+}
+
+/// Loads the configuration again and swaps it in, along with the matching TLS configuration
+///
+/// The new configuration is validated (including its TLS files, if TLS is enabled) and its DNS SRV
+/// names are resolved before it is swapped in; if anything fails the previous configuration stays
+/// in place. Settings that only apply at startup are reported but keep their old behaviour until a
+/// restart.
+pub async fn reload(
+    configs: &'static ArcSwap<Config>,
+    tls_config: Option<Arc<ArcSwap<ServerConfig>>>,
+) -> anyhow::Result<()> {
+    // Reading the configuration and TLS files blocks
+    let use_tls = tls_config.is_some();
+    let (config, new_tls_config) = spawn_blocking(move || load_config(use_tls)).await??;
+    resolve_service_names(&config).await?;
+    swap_config(configs, tls_config.as_deref(), config, new_tls_config);
+    Ok(())
+}
+
+/// Loads the configuration again, along with the matching TLS configuration if TLS is in use
+fn load_config(use_tls: bool) -> anyhow::Result<(Arc<Config>, Option<Arc<ServerConfig>>)> {
+    let config = Arc::new(Config::from_config_path()?);
+    let tls_config = if use_tls && config.use_tls {
+        Some(load_tls_config(&config)?)
+    } else {
+        None
+    };
+    Ok((config, tls_config))
+}
+
+/// Swaps in a newly loaded configuration
+fn swap_config(
+    configs: &'static ArcSwap<Config>,
+    tls_config: Option<&ArcSwap<ServerConfig>>,
+    config: Arc<Config>,
+    new_tls_config: Option<Arc<ServerConfig>>,
+) {
+    let previous = configs.load();
+    config.reuse_backends(&previous);
+    for setting in previous.restart_required(&config) {
+        warn!(
+            "{} changed, restart required for it to take effect",
+            setting
+        );
+    }
+    if previous.maintenance.is_enabled() != config.maintenance.is_enabled() {
+        log_toggled(config.maintenance.is_enabled());
     }
+
+    if let (Some(current), Some(new)) = (tls_config, new_tls_config) {
+        current.store(new);
+    }
+    spawn_health_checks(&config);
+    spawn_service_discovery(&config);
+    spawn_rate_limit_cleanup(&config);
+    let current = config.all_backends();
+    configs.store(config);
+    drain_removed(previous.all_backends(), &current);
 }
 
 /// Waits for a shutdown signal (Ctrl+C or terminate)
@@ -104,3 +507,78 @@ pub async fn shutdown_signal() {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::Empty;
+    use hyper::{Method, StatusCode, body::Bytes, client::conn::http2 as client_http2};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::test_support::{read_head, spawn_overloaded_proxy};
+
+    /// Settings for a proxy whose backend is never reached, as overloaded connections only get
+    /// `503` responses
+    const SETTINGS: &str = "backend_urls = \"http://127.0.0.1:9\"\nenable_h2c = true";
+
+    #[tokio::test]
+    async fn closes_overloaded_http1_connections_after_the_503() {
+        let proxy = spawn_overloaded_proxy(SETTINGS).await;
+
+        // Both requests are sent up front, so the second is waiting once the first is answered
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(
+                b"GET /first HTTP/1.1\r\nHost: example.com\r\n\r\n\
+                  GET /second HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 503"), "{}", head);
+
+        let mut rest = Vec::new();
+        timeout(Duration::from_secs(5), client.read_to_end(&mut rest))
+            .await
+            .expect("connection still open")
+            .unwrap();
+        assert!(
+            !String::from_utf8_lossy(&rest).contains("HTTP/1.1"),
+            "second request was answered: {}",
+            String::from_utf8_lossy(&rest)
+        );
+    }
+
+    #[tokio::test]
+    async fn closes_overloaded_http2_connections_after_the_503() {
+        let proxy = spawn_overloaded_proxy(SETTINGS).await;
+
+        let stream = TcpStream::connect(proxy).await.unwrap();
+        let (mut client, connection) =
+            client_http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        let connection = spawn(connection);
+        let request = || {
+            Request::builder()
+                .method(Method::GET)
+                .uri(format!("http://{}/", proxy))
+                .body(Empty::<Bytes>::new())
+                .unwrap()
+        };
+
+        let response = client.send_request(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // The server sends `GOAWAY` and closes the connection rather than serving more streams
+        timeout(Duration::from_secs(5), connection)
+            .await
+            .expect("connection still open")
+            .unwrap()
+            .unwrap();
+        assert!(client.send_request(request()).await.is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    io::SeekFrom,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result, ensure};
+use futures_util::TryStreamExt;
+use http_body_util::{BodyExt, Empty, StreamBody};
+use hyper::{
+    HeaderMap, Method, Response, StatusCode,
+    body::Frame,
+    header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, HeaderValue, RANGE},
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, warn};
+
+use crate::proxy::{ResponseBody, empty_response};
+
+/// Path prefix static assets are served under, reserved so it is never proxied.
+pub const STATIC_PREFIX: &str = "/__proxy_static/";
+
+/// Static files served by the proxy itself, e.g. the stylesheets and images of error and
+/// maintenance pages.
+pub struct StaticFiles {
+    /// Directory the assets are served from, with symlinks resolved.
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    /// Serve the files in the `root` directory.
+    pub fn new(root: &Path) -> Result<Self> {
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("Failed to open static directory {}", root.display()))?;
+        ensure!(
+            root.is_dir(),
+            "Static directory {} is not a directory",
+            root.display()
+        );
+        Ok(Self { root })
+    }
+
+    /// Whether a request path is under the reserved static prefix.
+    pub fn matches(path: &str) -> bool {
+        path.starts_with(STATIC_PREFIX)
+    }
+
+    /// Answer a request for a static asset.
+    ///
+    /// Only `GET` and `HEAD` are allowed, and a single `Range` is honoured. Paths that would leave
+    /// the static directory, including through symlinks, are answered `404` like missing files.
+    pub async fn serve(
+        &self,
+        method: &Method,
+        path: &str,
+        headers: &HeaderMap,
+    ) -> Response<ResponseBody> {
+        if method != Method::GET && method != Method::HEAD {
+            let mut response = empty_response(StatusCode::METHOD_NOT_ALLOWED);
+            response
+                .headers_mut()
+                .insert(hyper::header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return response;
+        }
+
+        let Some(file_path) = self.resolve(path).await else {
+            debug!("Static asset {} not found", path);
+            return empty_response(StatusCode::NOT_FOUND);
+        };
+        let mut file = match File::open(&file_path).await {
+            Ok(file) => file,
+            Err(e) => {
+                debug!(
+                    "Failed to open static asset {}: {:?}",
+                    file_path.display(),
+                    e
+                );
+                return empty_response(StatusCode::NOT_FOUND);
+            }
+        };
+        let size = match file.metadata().await {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            _ => return empty_response(StatusCode::NOT_FOUND),
+        };
+
+        let range = match headers.get(RANGE).and_then(|value| value.to_str().ok()) {
+            Some(range) => match parse_range(range, size) {
+                Ok(range) => range,
+                Err(()) => {
+                    let mut response = empty_response(StatusCode::RANGE_NOT_SATISFIABLE);
+                    response.headers_mut().insert(
+                        CONTENT_RANGE,
+                        HeaderValue::from_str(&format!("bytes */{}", size))
+                            .expect("valid Content-Range"),
+                    );
+                    return response;
+                }
+            },
+            None => None,
+        };
+        let (status, start, length) = match range {
+            Some((start, end)) => (StatusCode::PARTIAL_CONTENT, start, end - start + 1),
+            None => (StatusCode::OK, 0, size),
+        };
+
+        let mut response = Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, content_type(&file_path))
+            .header(CONTENT_LENGTH, length)
+            .header(ACCEPT_RANGES, "bytes");
+        if status == StatusCode::PARTIAL_CONTENT {
+            response = response.header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, start + length - 1, size),
+            );
+        }
+
+        if method == Method::HEAD {
+            return response
+                .body(Empty::new().map_err(|never| match never {}).boxed_unsync())
+                .expect("valid static asset response");
+        }
+        if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+            warn!(
+                "Failed to read static asset {}: {:?}",
+                file_path.display(),
+                e
+            );
+            return empty_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        let body = StreamBody::new(ReaderStream::new(file.take(length)).map_ok(Frame::data));
+        response
+            .body(body.boxed_unsync())
+            .expect("valid static asset response")
+    }
+
+    /// Map a request path to a file in the static directory, or `None` if it would leave it
+    async fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let relative = percent_decode(path.strip_prefix(STATIC_PREFIX)?)?;
+        let relative = Path::new(&relative);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+
+        // Symlinks inside the directory may only point within it
+        let resolved = fs::canonicalize(self.root.join(relative)).await.ok()?;
+        resolved.starts_with(&self.root).then_some(resolved)
+    }
+}
+
+/// Decode the percent-escapes of a URL path, or return `None` if they are malformed or decode to
+/// something that can't be a file name
+pub fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+
+    let decoded = String::from_utf8(bytes).ok()?;
+    (!decoded.contains(['\0', '\\'])).then_some(decoded)
+}
+
+/// Parse a `Range` header against a file of `size` bytes into the inclusive byte range to send
+///
+/// Returns `Ok(None)` when the whole file should be sent instead, as for malformed or multiple
+/// ranges and units other than bytes, and `Err` when the range can't be satisfied.
+fn parse_range(range: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some((start, end)) = range
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.split_once('-'))
+    else {
+        return Ok(None);
+    };
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        // The last `suffix` bytes
+        ("", suffix) => {
+            let Ok(suffix) = suffix.parse::<u64>() else {
+                return Ok(None);
+            };
+            if suffix == 0 || size == 0 {
+                return Err(());
+            }
+            (size.saturating_sub(suffix), size - 1)
+        }
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else {
+                return Ok(None);
+            };
+            let end = match end {
+                "" => u64::MAX,
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => end,
+                    _ => return Ok(None),
+                },
+            };
+            (start, end.min(size.saturating_sub(1)))
+        }
+    };
+    if start >= size {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// Guess the content type of a file from its extension
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bounded_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=900-1999", 1000), Ok(Some((900, 999))));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        // A suffix longer than the file is the whole file
+        assert_eq!(parse_range("bytes=-5000", 1000), Ok(Some((0, 999))));
+        assert_eq!(parse_range("bytes=-0", 1000), Err(()));
+        assert_eq!(parse_range("bytes=-10", 0), Err(()));
+    }
+
+    #[test]
+    fn parses_open_ended_ranges() {
+        assert_eq!(parse_range("bytes=500-", 1000), Ok(Some((500, 999))));
+        assert_eq!(parse_range("bytes=999-", 1000), Ok(Some((999, 999))));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=2000-3000", 1000), Err(()));
+        assert_eq!(parse_range("bytes=0-", 0), Err(()));
+    }
+
+    #[test]
+    fn sends_the_whole_file_for_multiple_or_malformed_ranges() {
+        assert_eq!(parse_range("bytes=0-9,20-29", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=9-0", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=a-b", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-9", 1000), Ok(None));
+    }
+}
@@ -0,0 +1,93 @@
+use std::net::IpAddr;
+
+use http_body_util::{BodyExt, Full};
+use humantime::format_rfc3339_seconds;
+use hyper::{
+    HeaderMap, Response, StatusCode,
+    body::Bytes,
+    header::{CACHE_CONTROL, CONTENT_TYPE, HeaderValue, WWW_AUTHENTICATE},
+};
+use ipnet::IpNet;
+use serde_json::json;
+
+use crate::{
+    admin::is_authorized,
+    config::Config,
+    proxy::{ResponseBody, empty_response},
+};
+
+/// A JSON endpoint on the proxy listener reporting the state of every backend, for dashboards.
+pub struct StatusEndpoint {
+    /// Path the endpoint is answered on.
+    path: String,
+    /// Client IP ranges let in without the admin token.
+    allowed: Vec<IpNet>,
+}
+
+impl StatusEndpoint {
+    /// Answer on `path` to clients in the `allowed` ranges or sending the admin token.
+    pub fn new(path: String, allowed: Vec<IpNet>) -> Self {
+        Self { path, allowed }
+    }
+
+    /// Answer a request for the status endpoint, or return `None` if the path is not its own.
+    ///
+    /// Clients outside the allowed ranges must send `ADMIN_TOKEN` as a bearer token, and are
+    /// answered with `401` (or `403` when there is no admin token) otherwise.
+    pub fn response(
+        &self,
+        config: &Config,
+        client_ip: IpAddr,
+        path: &str,
+        headers: &HeaderMap,
+    ) -> Option<Response<ResponseBody>> {
+        if path != self.path {
+            return None;
+        }
+
+        let client_ip = client_ip.to_canonical();
+        if !self.allowed.iter().any(|net| net.contains(&client_ip))
+            && !is_authorized(headers, config.admin_token.as_deref())
+        {
+            if config.admin_token.is_none() {
+                return Some(empty_response(StatusCode::FORBIDDEN));
+            }
+            let mut response = empty_response(StatusCode::UNAUTHORIZED);
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return Some(response);
+        }
+
+        let backends = config
+            .all_backends()
+            .iter()
+            .map(|backend| {
+                json!({
+                    "url": backend.url(),
+                    "healthy": backend.is_healthy(),
+                    "draining": backend.is_draining(),
+                    "in_flight": backend.in_flight(),
+                    "circuit": backend.circuit().state_label(),
+                    "last_error": backend
+                        .last_error()
+                        .map(|time| format_rfc3339_seconds(time).to_string()),
+                })
+            })
+            .collect::<Vec<_>>();
+        let body = json!({ "backends": backends }).to_string();
+
+        Some(
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .header(CACHE_CONTROL, "no-store")
+                .body(
+                    Full::new(Bytes::from(body))
+                        .map_err(|never| match never {})
+                        .boxed_unsync(),
+                )
+                .unwrap(),
+        )
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result, ensure};
+use hyper::{Response, StatusCode, ext::ReasonPhrase, header::HeaderValue};
+use tracing::debug;
+
+use crate::proxy::ResponseBody;
+
+/// Name of the header carrying the backend's status when it was rewritten
+const X_UPSTREAM_STATUS: &str = "x-upstream-status";
+
+/// Rewriting of backend response statuses, e.g. for legacy clients that don't understand some
+/// of them.
+pub struct StatusMap {
+    /// Status each rewritten backend status is replaced with.
+    map: HashMap<StatusCode, StatusCode>,
+}
+
+impl StatusMap {
+    /// Parse mappings in the form `FROM=TO`.
+    pub fn new(mappings: &[String]) -> Result<Self> {
+        let map = mappings
+            .iter()
+            .map(|mapping| {
+                let (from, to) = mapping.split_once('=').with_context(|| {
+                    format!("Status mapping {:?} must be in the form from=to", mapping)
+                })?;
+                let from = parse_status(from, mapping)?;
+                let to = parse_status(to, mapping)?;
+                Ok((from, to))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { map })
+    }
+
+    /// Replace the status of a backend response if it is mapped, keeping the original in
+    /// `X-Upstream-Status`
+    pub fn apply(&self, response: &mut Response<ResponseBody>) {
+        let upstream = response.status();
+        let Some(&status) = self.map.get(&upstream) else {
+            return;
+        };
+
+        debug!("Rewriting status {} to {}", upstream, status);
+        response
+            .headers_mut()
+            .insert(X_UPSTREAM_STATUS, HeaderValue::from(upstream.as_u16()));
+        *response.status_mut() = status;
+        // Otherwise the backend's reason phrase would be sent with the new status
+        response.extensions_mut().remove::<ReasonPhrase>();
+    }
+}
+
+/// Parse one side of a status mapping
+///
+/// Informational statuses are rejected, since they would break upgrades or leave the client
+/// waiting for a final response.
+fn parse_status(status: &str, mapping: &str) -> Result<StatusCode> {
+    let status = status
+        .trim()
+        .parse::<StatusCode>()
+        .with_context(|| format!("Invalid status in status mapping {:?}", mapping))?;
+    ensure!(
+        !status.is_informational(),
+        "Status mapping {:?} can't map informational statuses",
+        mapping
+    );
+    Ok(status)
+}
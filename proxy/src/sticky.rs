@@ -0,0 +1,72 @@
+use std::{sync::Arc, time::Duration};
+
+use hyper::{
+    HeaderMap,
+    header::{COOKIE, HeaderValue},
+};
+
+use crate::backend::{Backend, Backends};
+
+/// Session affinity, pinning each client to a backend through a cookie.
+///
+/// The cookie holds a hash of the backend URL rather than its position, so it stays valid when
+/// backends are added or reordered, and a client can be pinned separately within each route.
+pub struct StickySessions {
+    /// Name of the affinity cookie.
+    cookie_name: String,
+    /// How long the cookie lasts in the client.
+    ttl: Duration,
+}
+
+impl StickySessions {
+    /// Create sticky sessions using the given cookie name and lifetime.
+    pub fn new(cookie_name: String, ttl: Duration) -> Self {
+        Self { cookie_name, ttl }
+    }
+
+    /// Get the backend the request is pinned to, if its cookie names one of `backends` that is
+    /// healthy and whose circuit is not open.
+    pub fn pinned(&self, headers: &HeaderMap, backends: &Backends) -> Option<Arc<Backend>> {
+        let value = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .flat_map(|header| header.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(name, _)| *name == self.cookie_name)
+            .map(|(_, value)| value)?;
+
+        backends
+            .all()
+            .into_iter()
+            .find(|backend| backend_hash(backend) == value)
+            .filter(|backend| backend.is_available())
+    }
+
+    /// Build the `Set-Cookie` value pinning a client to `backend`.
+    pub fn cookie(&self, backend: &Backend, secure: bool) -> HeaderValue {
+        let mut cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            self.cookie_name,
+            backend_hash(backend),
+            self.ttl.as_secs()
+        );
+        if secure {
+            cookie.push_str("; Secure");
+        }
+
+        HeaderValue::from_str(&cookie).expect("cookie names are validated when loading the config")
+    }
+}
+
+/// Hash a backend URL into a stable cookie value (in hex).
+fn backend_hash(backend: &Backend) -> String {
+    format!("{:016x}", fnv1a(backend.url().as_bytes()))
+}
+
+/// Hash bytes with 64-bit FNV-1a, which is stable across runs and platforms.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325_u64, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
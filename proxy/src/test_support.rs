@@ -0,0 +1,112 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use arc_swap::ArcSwap;
+use rcgen::{CertifiedKey, generate_simple_self_signed};
+use rustls::pki_types::CertificateDer;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+
+use crate::{config::Config, server::accept_connection};
+
+/// Load a configuration from TOML settings keyed by the lowercase setting names, without TLS.
+pub fn config(settings: &str) -> Config {
+    load_config(&format!("use_tls = false\n{}", settings))
+}
+
+/// Load a configuration from TOML settings like [`config`], with TLS using a newly generated
+/// self-signed certificate for `localhost`, returning it along with the certificate.
+pub fn tls_config(settings: &str) -> (Config, CertificateDer<'static>) {
+    let CertifiedKey { cert, signing_key } =
+        generate_simple_self_signed(["localhost".to_string()]).unwrap();
+    let config = load_config(&format!(
+        "cert_pem = '''\n{}'''\nkey_pem = '''\n{}'''\n{}",
+        cert.pem(),
+        signing_key.serialize_pem(),
+        settings
+    ));
+    (config, cert.der().clone())
+}
+
+/// Load a configuration from the given TOML.
+fn load_config(toml: &str) -> Config {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "proxy-test-{}-{}.toml",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&path, toml).unwrap();
+    let config = Config::from_file(&path);
+    let _ = std::fs::remove_file(&path);
+    config.unwrap()
+}
+
+/// Serve a proxy configured with `settings` on an ephemeral port, returning its address.
+///
+/// The proxy keeps running until the test's runtime shuts down.
+pub async fn spawn_proxy(settings: &str) -> SocketAddr {
+    spawn_server(settings, false).await
+}
+
+/// Serve a proxy like [`spawn_proxy`], treating every connection as over the connection limit.
+pub async fn spawn_overloaded_proxy(settings: &str) -> SocketAddr {
+    spawn_server(settings, true).await
+}
+
+/// Serve a proxy configured with `settings`, whose connections are all `overloaded` or none are.
+async fn spawn_server(settings: &str, overloaded: bool) -> SocketAddr {
+    let configs = Box::leak(Box::new(ArcSwap::from_pointee(config(settings))));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // Connections are told to drain when the sender goes away, so it never does
+    let (shutdown, watcher) = watch::channel(());
+    Box::leak(Box::new(shutdown));
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = listener.accept().await.unwrap();
+            tokio::spawn(accept_connection(
+                Box::new(stream),
+                peer.ip(),
+                configs,
+                None,
+                watcher.clone(),
+                overloaded,
+            ));
+        }
+    });
+    addr
+}
+
+/// Read an HTTP/1.1 message head up to and including the blank line, returning it as text.
+pub async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let byte = stream.read_u8().await.unwrap();
+        head.push(byte);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+/// Send a raw HTTP/1.1 request to `addr` and read the response head.
+pub async fn send(addr: SocketAddr, request: &str) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let head = read_head(&mut stream).await;
+    (stream, head)
+}
+
+/// Get the value of a header in a message head, matching its name case-insensitively.
+pub fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
@@ -0,0 +1,288 @@
+use std::{
+    future::poll_fn,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll, ready},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep, sleep_until},
+};
+
+use crate::listener::Stream;
+
+/// Bytes available for one direction of a connection, refilled continuously at a fixed rate.
+///
+/// The budget holds up to one second's worth of bytes, so short bursts go through at full speed.
+struct Budget {
+    /// Bytes added per second.
+    rate: f64,
+    /// Bytes currently available, negative when overdrawn.
+    tokens: f64,
+    /// When `tokens` was last brought up to date.
+    updated: Instant,
+}
+
+impl Budget {
+    /// Create a full budget allowing `rate` bytes per second.
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Add the bytes earned since the last refill, returning the current time.
+    fn refill(&mut self) -> Instant {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+        now
+    }
+
+    /// Spend the budget for bytes that were transferred.
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Byte budget for one direction of a connection, with a timer to wait for it to refill.
+struct Bucket {
+    /// Bytes available.
+    budget: Budget,
+    /// Timer waking the task once enough bytes are available again.
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl Bucket {
+    /// Create a full bucket allowing `rate` bytes per second.
+    fn new(rate: u64) -> Self {
+        Self {
+            budget: Budget::new(rate),
+            sleep: Box::pin(sleep_until(Instant::now())),
+        }
+    }
+
+    /// Wait until some of `wanted` bytes may be transferred, returning how many.
+    ///
+    /// When the bucket is empty, this waits for a tenth of a second's worth of bytes rather than
+    /// a single one, so slow connections aren't woken for every byte.
+    fn poll_available(&mut self, cx: &mut TaskContext<'_>, wanted: usize) -> Poll<usize> {
+        let wanted = wanted.max(1) as f64;
+        loop {
+            let now = self.budget.refill();
+            let Budget { rate, tokens, .. } = self.budget;
+            if tokens >= 1.0 {
+                return Poll::Ready(tokens.min(wanted) as usize);
+            }
+
+            let needed = wanted.min(rate / 10.0).max(1.0) - tokens;
+            self.sleep
+                .as_mut()
+                .reset(now + Duration::from_secs_f64(needed / rate));
+            ready!(self.sleep.as_mut().poll(cx));
+        }
+    }
+
+    /// Spend the budget for bytes that were transferred.
+    fn consume(&mut self, bytes: usize) {
+        self.budget.consume(bytes);
+    }
+}
+
+/// Byte budget for one direction of a connection whose streams are served by separate tasks,
+/// like the requests of an HTTP/3 connection.
+///
+/// Transfers aren't split to fit the budget. Instead they wait until it isn't overdrawn and may
+/// then overdraw it, holding back the transfers that follow.
+#[derive(Clone)]
+pub struct SharedBucket(Arc<Mutex<Budget>>);
+
+impl SharedBucket {
+    /// Create a full bucket allowing `rate` bytes per second, or `None` if `rate` is `0` for
+    /// unlimited.
+    pub fn new(rate: u64) -> Option<Self> {
+        (rate > 0).then(|| Self(Arc::new(Mutex::new(Budget::new(rate)))))
+    }
+
+    /// Wait until the budget isn't overdrawn, using `sleep` as the timer of the calling task.
+    pub fn poll_ready(&self, cx: &mut TaskContext<'_>, sleep: &mut Pin<Box<Sleep>>) -> Poll<()> {
+        loop {
+            let wake_at = {
+                let mut budget = self.0.lock().unwrap();
+                let now = budget.refill();
+                if budget.tokens >= 1.0 {
+                    return Poll::Ready(());
+                }
+                now + Duration::from_secs_f64((1.0 - budget.tokens) / budget.rate)
+            };
+            sleep.as_mut().reset(wake_at);
+            ready!(sleep.as_mut().poll(cx));
+        }
+    }
+
+    /// Wait until the budget isn't overdrawn.
+    pub async fn ready(&self) {
+        let mut sleep = Box::pin(sleep_until(Instant::now()));
+        poll_fn(|cx| self.poll_ready(cx, &mut sleep)).await
+    }
+
+    /// Spend the budget for bytes that were transferred.
+    pub fn consume(&self, bytes: usize) {
+        self.0.lock().unwrap().consume(bytes);
+    }
+}
+
+/// A stream limited to a number of bytes per second in each direction.
+struct Throttled {
+    /// The underlying stream.
+    inner: Stream,
+    /// Budget for bytes read from the client, if limited.
+    upload: Option<Bucket>,
+    /// Budget for bytes written to the client, if limited.
+    download: Option<Bucket>,
+}
+
+/// Limit a client connection to `upload_bps` bytes per second read and `download_bps` written,
+/// where `0` means unlimited.
+pub fn throttle(stream: Stream, upload_bps: u64, download_bps: u64) -> Stream {
+    if upload_bps == 0 && download_bps == 0 {
+        return stream;
+    }
+
+    Box::new(Throttled {
+        inner: stream,
+        upload: (upload_bps > 0).then(|| Bucket::new(upload_bps)),
+        download: (download_bps > 0).then(|| Bucket::new(download_bps)),
+    })
+}
+
+impl AsyncRead for Throttled {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let Some(ref mut bucket) = this.upload else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+
+        let available = ready!(bucket.poll_available(cx, buf.remaining())).min(buf.remaining());
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(available));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        bucket.consume(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Throttled {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let Some(ref mut bucket) = this.download else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+
+        let available = ready!(bucket.poll_available(cx, buf.len())).min(buf.len());
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..available]))?;
+        bucket.consume(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn lets_a_second_of_bytes_through_at_once() {
+        let (client, mut server) = duplex(64 * 1024);
+        let mut throttled = throttle(Box::new(client), 0, 1000);
+
+        let started = Instant::now();
+        throttled.write_all(&[0; 1000]).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        let mut received = [0; 1000];
+        server.read_exact(&mut received).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_writes_to_the_download_rate() {
+        let (client, mut server) = duplex(64 * 1024);
+        let mut throttled = throttle(Box::new(client), 0, 1000);
+
+        let started = Instant::now();
+        throttled.write_all(&[0; 3000]).await.unwrap();
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_secs(2) && elapsed < Duration::from_millis(2200),
+            "{:?}",
+            elapsed
+        );
+
+        let mut received = vec![0; 3000];
+        server.read_exact(&mut received).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_reads_to_the_upload_rate() {
+        let (client, mut server) = duplex(64 * 1024);
+        let mut throttled = throttle(Box::new(client), 500, 0);
+        server.write_all(&[0; 1500]).await.unwrap();
+
+        let started = Instant::now();
+        let mut received = vec![0; 1500];
+        throttled.read_exact(&mut received).await.unwrap();
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_secs(2) && elapsed < Duration::from_millis(2200),
+            "{:?}",
+            elapsed
+        );
+
+        // Writes aren't limited
+        let started = Instant::now();
+        throttled.write_all(&[0; 5000]).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn holds_back_shared_transfers_until_an_overdraft_is_repaid() {
+        assert!(SharedBucket::new(0).is_none());
+        let bucket = SharedBucket::new(1000).unwrap();
+
+        // A transfer may overdraw the budget, and the next one waits for it to be repaid
+        bucket.ready().await;
+        bucket.consume(2500);
+        let started = Instant::now();
+        bucket.ready().await;
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(1500) && elapsed < Duration::from_millis(1600),
+            "{:?}",
+            elapsed
+        );
+    }
+}
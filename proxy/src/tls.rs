@@ -1,36 +1,463 @@
-use std::{io::BufReader, path::Path, sync::Arc};
+use std::{
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::SystemTime,
+};
 
-use anyhow::{Context, Result, ensure};
-use tracing::info;
+use anyhow::{Context, Error, Result, bail, ensure};
+use arc_swap::ArcSwap;
+use hyper_rustls::ConfigBuilderExt;
+use rustls::{
+    ClientConfig, DigitallySignedStruct, ProtocolVersion, RootCertStore, ServerConfig,
+    ServerConnection, SignatureScheme, SupportedProtocolVersion,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, aws_lc_rs, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::{WebPkiClientVerifier, danger::ClientCertVerifier},
+    version::{TLS12, TLS13},
+};
+use tokio::{spawn, time::interval};
+use tracing::{debug, error, info, warn};
+use x509_parser::parse_x509_certificate;
 
-/// Load TLS configuration from certificate and private key files.
-pub fn load_tls_config(cert_path: &Path, key_path: &Path) -> Result<Arc<rustls::ServerConfig>> {
-    info!("Loading TLS certificate from {:?}", cert_path);
-    info!("Loading TLS private key from {:?}", key_path);
+use crate::config::Config;
 
-    let cert_file = std::fs::File::open(cert_path)
-        .with_context(|| format!("Failed to open cert file {:?}", cert_path))?;
+/// The lowest TLS protocol version the server accepts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsVersion {
+    /// TLS 1.2 and 1.3.
+    Tls12,
+    /// TLS 1.3 only.
+    Tls13,
+}
 
-    let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file))
-        .collect::<Result<Vec<_>, _>>()
-        .context("Failed to parse certificates")?;
+impl TlsVersion {
+    /// The protocol versions enabled with this minimum.
+    fn enabled_versions(self) -> &'static [&'static SupportedProtocolVersion] {
+        static TLS12_AND_UP: &[&SupportedProtocolVersion] = &[&TLS13, &TLS12];
+        static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&TLS13];
 
-    ensure!(!certs.is_empty(), "No certificates found in cert file");
+        match self {
+            TlsVersion::Tls12 => TLS12_AND_UP,
+            TlsVersion::Tls13 => TLS13_ONLY,
+        }
+    }
+}
 
-    let key_file = std::fs::File::open(key_path)
-        .with_context(|| format!("Failed to open key file {:?}", key_path))?;
+impl FromStr for TlsVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "1.2" => Ok(Self::Tls12),
+            "1.3" => Ok(Self::Tls13),
+            _ => bail!("Unsupported TLS version {:?}, expected 1.2 or 1.3", s),
+        }
+    }
+}
 
-    let key = rustls_pemfile::private_key(&mut BufReader::new(key_file))
-        .context("Failed to parse private key")?
-        .context("No private key found in key file")?;
+/// Build the crypto provider, restricted to the allowed cipher suites if any are given.
+///
+/// Suite names are matched case-insensitively against the IANA names used by rustls (e.g.
+/// `TLS13_AES_256_GCM_SHA384`).
+fn crypto_provider(cipher_suites: &[String]) -> Result<CryptoProvider> {
+    let mut provider = aws_lc_rs::default_provider();
+    if cipher_suites.is_empty() {
+        return Ok(provider);
+    }
 
-    let mut config = rustls::ServerConfig::builder()
-        .with_no_client_auth()
+    let available = provider
+        .cipher_suites
+        .iter()
+        .map(|suite| format!("{:?}", suite.suite()))
+        .collect::<Vec<_>>();
+    for name in cipher_suites {
+        ensure!(
+            available
+                .iter()
+                .any(|suite| suite.eq_ignore_ascii_case(name)),
+            "Unknown TLS cipher suite {:?}, expected one of: {}",
+            name,
+            available.join(", ")
+        );
+    }
+
+    provider.cipher_suites.retain(|suite| {
+        let suite = format!("{:?}", suite.suite());
+        cipher_suites
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(&suite))
+    });
+    Ok(provider)
+}
+
+/// Load TLS configuration from the configured certificate and private key, either inline PEM or
+/// files, applying the configured protocol version and cipher suite restrictions.
+pub fn load_tls_config(config: &Config) -> Result<Arc<ServerConfig>> {
+    let (certs, key) = match &config.inline_pem {
+        Some((cert_pem, key_pem)) => {
+            info!("Loading TLS certificate and private key from CERT_PEM and KEY_PEM");
+            (
+                parse_certs(cert_pem.as_bytes(), "CERT_PEM")?,
+                parse_key(key_pem.as_bytes(), "KEY_PEM")?,
+            )
+        }
+        None => {
+            info!("Loading TLS certificate from {:?}", config.cert_path);
+            info!("Loading TLS private key from {:?}", config.key_path);
+            load_cert_and_key(&config.cert_path, &config.key_path)?
+        }
+    };
+
+    let provider = Arc::new(crypto_provider(&config.tls_cipher_suites)?);
+    let versions = config.tls_min_version.enabled_versions();
+    info!(
+        "TLS protocol versions: {}",
+        versions
+            .iter()
+            .map(|version| format!("{:?}", version.version))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    info!(
+        "TLS cipher suites: {}",
+        provider
+            .cipher_suites
+            .iter()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(versions)
+        .context("No allowed cipher suite supports the enabled TLS versions")?;
+    let builder = match &config.client_ca_path {
+        Some(ca_path) => builder.with_client_cert_verifier(client_cert_verifier(
+            ca_path,
+            config.require_client_cert,
+            provider,
+        )?),
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut tls_config = builder
         .with_single_cert(certs, key)
         .context("Failed to build TLS config")?;
 
-    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    tls_config.alpn_protocols = if config.enable_h2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
 
     info!("TLS configuration loaded successfully");
-    Ok(Arc::new(config))
+    Ok(Arc::new(tls_config))
+}
+
+/// Load a PEM certificate chain and its private key.
+fn load_cert_and_key(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let cert_file = std::fs::File::open(cert_path)
+        .with_context(|| format!("Failed to open cert file {:?}", cert_path))?;
+    let certs = parse_certs(BufReader::new(cert_file), "cert file")?;
+
+    let key_file = std::fs::File::open(key_path)
+        .with_context(|| format!("Failed to open key file {:?}", key_path))?;
+    let key = parse_key(BufReader::new(key_file), "key file")?;
+
+    Ok((certs, key))
+}
+
+/// Parse a PEM certificate chain.
+///
+/// `description` names where the PEM came from in error messages.
+fn parse_certs(mut pem: impl BufRead, description: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut pem)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificates in {}", description))?;
+
+    ensure!(
+        !certs.is_empty(),
+        "No certificates found in {}",
+        description
+    );
+    Ok(certs)
+}
+
+/// Parse a PEM private key.
+///
+/// `description` names where the PEM came from in error messages.
+fn parse_key(mut pem: impl BufRead, description: &str) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut pem)
+        .with_context(|| format!("Failed to parse private key in {}", description))?
+        .with_context(|| format!("No private key found in {}", description))
+}
+
+/// Build a verifier for client certificates signed by the CAs in `ca_path`.
+///
+/// When `required` is false, clients without a certificate are still accepted, but any
+/// certificate that is presented must be valid.
+fn client_cert_verifier(
+    ca_path: &Path,
+    required: bool,
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn ClientCertVerifier>> {
+    info!("Loading client CA bundle from {:?}", ca_path);
+    let roots = load_root_store(ca_path, "client CA")?;
+
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+    let builder = if required {
+        builder
+    } else {
+        builder.allow_unauthenticated()
+    };
+    builder
+        .build()
+        .context("Failed to build client certificate verifier")
+}
+
+/// Load the CA certificates in the PEM bundle at `path` into a root store.
+///
+/// `description` names the bundle in error messages.
+fn load_root_store(path: &Path, description: &str) -> Result<RootCertStore> {
+    let ca_file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open {} file {:?}", description, path))?;
+
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(ca_file)) {
+        roots
+            .add(cert.with_context(|| format!("Failed to parse {} certificates", description))?)
+            .with_context(|| format!("Invalid {} certificate", description))?;
+    }
+    ensure!(
+        !roots.is_empty(),
+        "No certificates found in {} file",
+        description
+    );
+    Ok(roots)
+}
+
+/// Build a client configuration trusting the platform's native root certificates.
+pub fn native_roots_client_config() -> Result<ClientConfig> {
+    Ok(ClientConfig::builder()
+        .with_native_roots()
+        .context("Failed to load native root certificates")?
+        .with_no_client_auth())
+}
+
+/// TLS settings for connections to HTTPS backends.
+#[derive(Clone)]
+pub struct BackendTls {
+    /// Path to the CA bundle trusted instead of the native roots, if any.
+    ca_path: Option<PathBuf>,
+    /// Whether backend certificates are accepted without verification.
+    skip_verify: bool,
+    /// Paths to the certificate and key presented to backends that request one, if any.
+    client_cert: Option<(PathBuf, PathBuf)>,
+    /// Client configuration built from the settings above.
+    client_config: ClientConfig,
+}
+
+impl BackendTls {
+    /// Build the backend TLS settings, loading the CA bundle and client certificate if given.
+    pub fn load(
+        ca_path: Option<PathBuf>,
+        skip_verify: bool,
+        client_cert: Option<(PathBuf, PathBuf)>,
+    ) -> Result<Self> {
+        let builder = ClientConfig::builder();
+        let builder = if skip_verify {
+            warn!(
+                "INSECURE_SKIP_BACKEND_VERIFY is set, backend TLS certificates are NOT verified. \
+                 Never use this in production!"
+            );
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification(Arc::new(
+                    aws_lc_rs::default_provider(),
+                ))))
+        } else if let Some(ref ca_path) = ca_path {
+            builder.with_root_certificates(load_root_store(ca_path, "backend CA")?)
+        } else {
+            builder
+                .with_native_roots()
+                .context("Failed to load native root certificates")?
+        };
+
+        let client_config = match client_cert {
+            Some((ref cert_path, ref key_path)) => {
+                info!("Loading backend client certificate from {:?}", cert_path);
+                let (certs, key) = load_cert_and_key(cert_path, key_path)
+                    .context("Failed to load backend client certificate")?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .context("Invalid backend client certificate or key")?
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(Self {
+            ca_path,
+            skip_verify,
+            client_cert,
+            client_config,
+        })
+    }
+
+    /// Get the client configuration for connections to backends.
+    pub fn client_config(&self) -> ClientConfig {
+        self.client_config.clone()
+    }
+}
+
+impl PartialEq for BackendTls {
+    fn eq(&self, other: &Self) -> bool {
+        self.ca_path == other.ca_path
+            && self.skip_verify == other.skip_verify
+            && self.client_cert == other.client_cert
+    }
+}
+
+/// A verifier that accepts any backend certificate, for `INSECURE_SKIP_BACKEND_VERIFY`.
+///
+/// Handshake signatures are still checked, so only the certificate chain goes unverified.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Get the subject of the client certificate verified during the handshake, if any.
+pub fn client_cert_subject(connection: &ServerConnection) -> Option<String> {
+    peer_cert_subject(connection.peer_certificates()?)
+}
+
+/// Get the subject of the end-entity certificate of a chain presented by a client, if any.
+pub fn peer_cert_subject(certs: &[CertificateDer<'_>]) -> Option<String> {
+    let cert = certs.first()?;
+    match parse_x509_certificate(cert) {
+        Ok((_, cert)) => Some(cert.subject().to_string()),
+        Err(e) => {
+            debug!("Failed to parse client certificate: {:?}", e);
+            None
+        }
+    }
+}
+
+/// The protocol version and cipher suite negotiated with a client.
+#[derive(Clone, Copy, Debug)]
+pub struct TlsParameters {
+    /// Protocol version, e.g. `TLSv1.3`.
+    pub version: &'static str,
+    /// IANA name of the cipher suite, e.g. `TLS13_AES_256_GCM_SHA384`.
+    pub cipher: &'static str,
+}
+
+/// Get the protocol version and cipher suite negotiated during the handshake.
+pub fn negotiated_parameters(connection: &ServerConnection) -> Option<TlsParameters> {
+    let version = match connection.protocol_version()? {
+        ProtocolVersion::TLSv1_2 => "TLSv1.2",
+        ProtocolVersion::TLSv1_3 => "TLSv1.3",
+        version => version.as_str().unwrap_or("unknown"),
+    };
+    let cipher = connection
+        .negotiated_cipher_suite()?
+        .suite()
+        .as_str()
+        .unwrap_or("unknown");
+    Some(TlsParameters { version, cipher })
+}
+
+/// Spawn a background task that reloads the TLS configuration whenever the certificate or key
+/// file of the current configuration changes on disk.
+///
+/// A reload that fails validation keeps the previous configuration in place. Does nothing when
+/// the reload interval is zero.
+pub fn spawn_tls_reloader(configs: &'static ArcSwap<Config>, current: Arc<ArcSwap<ServerConfig>>) {
+    let reload_interval = configs.load().tls_reload_interval;
+    if reload_interval.is_zero() {
+        return;
+    }
+
+    spawn(async move {
+        let mut last_modified = {
+            let config = configs.load();
+            modified_times(&config.cert_path, &config.key_path)
+        };
+        let mut ticker = interval(reload_interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            let config = configs.load();
+            let modified = modified_times(&config.cert_path, &config.key_path);
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            info!("TLS certificate or key changed, reloading");
+            match load_tls_config(&config) {
+                Ok(tls_config) => current.store(tls_config),
+                Err(e) => error!(
+                    "Failed to reload TLS configuration, keeping previous: {:?}",
+                    e
+                ),
+            }
+        }
+    });
+}
+
+/// Get the last modification times of the certificate and key files, if available.
+fn modified_times(cert_path: &Path, key_path: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(cert_path), modified(key_path))
 }